@@ -0,0 +1,24 @@
+use std::env;
+use std::path::Path;
+
+/// Set to also refresh the committed `include/rustboyadvance.h`
+const UPDATE_HEADER_VAR: &str = "RBA_UPDATE_HEADER";
+
+fn main() {
+    let crate_dir = env::var("CARGO_MANIFEST_DIR").unwrap();
+    let out_dir = env::var("OUT_DIR").unwrap();
+
+    println!("cargo:rerun-if-changed=src/lib.rs");
+    println!("cargo:rerun-if-changed=cbindgen.toml");
+    println!("cargo:rerun-if-env-changed={}", UPDATE_HEADER_VAR);
+
+    let bindings = cbindgen::generate(&crate_dir).expect("failed to generate the C header");
+    bindings.write_to_file(Path::new(&out_dir).join("rustboyadvance.h"));
+    if env::var_os(UPDATE_HEADER_VAR).is_some() {
+        bindings.write_to_file(
+            Path::new(&crate_dir)
+                .join("include")
+                .join("rustboyadvance.h"),
+        );
+    }
+}
@@ -0,0 +1,110 @@
+#ifndef RUSTBOYADVANCE_H
+#define RUSTBOYADVANCE_H
+
+/* Generated by cbindgen from src/lib.rs when building the crate, do not edit */
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+#define RBA_SCREEN_WIDTH 240
+
+#define RBA_SCREEN_HEIGHT 160
+
+#define RBA_AUDIO_SAMPLE_RATE 44100
+
+#define RBA_KEY_A (1 << 0)
+
+#define RBA_KEY_B (1 << 1)
+
+#define RBA_KEY_SELECT (1 << 2)
+
+#define RBA_KEY_START (1 << 3)
+
+#define RBA_KEY_RIGHT (1 << 4)
+
+#define RBA_KEY_LEFT (1 << 5)
+
+#define RBA_KEY_UP (1 << 6)
+
+#define RBA_KEY_DOWN (1 << 7)
+
+#define RBA_KEY_R (1 << 8)
+
+#define RBA_KEY_L (1 << 9)
+
+// An emulator instance
+typedef struct RbaEmulator RbaEmulator;
+
+// A buffer allocated by the library, released with `rba_free_buffer`
+typedef struct RbaBuffer {
+  uint8_t *data;
+  uintptr_t size;
+} RbaBuffer;
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+// Describes the last failure on this thread, or NULL if nothing failed yet.
+// The string stays valid until the next failure on the same thread.
+const char *rba_last_error(void);
+
+// Creates an emulator running `rom`.
+// When `bios` is NULL the builtin bios is used, which can't show the boot logo, so the bios is
+// always skipped. Returns NULL if the rom could not be loaded.
+struct RbaEmulator *rba_create(const uint8_t *bios,
+                               uintptr_t bios_size,
+                               const uint8_t *rom,
+                               uintptr_t rom_size,
+                               bool skip_bios);
+
+// Destroys an emulator created by `rba_create`, NULL is ignored
+void rba_destroy(struct RbaEmulator *emu);
+
+// Runs the emulator until the next frame
+void rba_run_frame(struct RbaEmulator *emu);
+
+// Sets the keys that are held down, a mask of `RBA_KEY_*` bits
+void rba_set_keys(struct RbaEmulator *emu, uint16_t keys);
+
+// The last frame, `RBA_SCREEN_WIDTH * RBA_SCREEN_HEIGHT` pixels in XRGB8888.
+// The pointer stays valid until the next `rba_run_frame` or `rba_load_state`.
+const uint32_t *rba_get_framebuffer(const struct RbaEmulator *emu);
+
+// Moves up to `max_samples` audio samples into `samples`, returns how many were moved.
+// Samples are signed 16bit stereo, interleaved left and right, at `RBA_AUDIO_SAMPLE_RATE`.
+uintptr_t rba_pull_audio(struct RbaEmulator *emu, int16_t *samples, uintptr_t max_samples);
+
+// Saves the emulator state into a new buffer, the data is NULL if saving failed
+struct RbaBuffer rba_save_state(const struct RbaEmulator *emu);
+
+// Restores a state made by `rba_save_state` with the same rom
+bool rba_load_state(struct RbaEmulator *emu, const uint8_t *state, uintptr_t size);
+
+// Releases a buffer returned by the library
+void rba_free_buffer(struct RbaBuffer buffer);
+
+// Reads `size` bytes starting at `addr` into `out`, like the cpu would see them.
+// Reads don't trigger watchpoints or any other side effect
+void rba_read_memory(const struct RbaEmulator *emu, uint32_t addr, uint8_t *out, uintptr_t size);
+
+uint8_t rba_read_8(const struct RbaEmulator *emu, uint32_t addr);
+
+uint16_t rba_read_16(const struct RbaEmulator *emu, uint32_t addr);
+
+uint32_t rba_read_32(const struct RbaEmulator *emu, uint32_t addr);
+
+// Writes go through the bus like cpu writes, so e.g writing to the rom does nothing
+void rba_write_8(struct RbaEmulator *emu, uint32_t addr, uint8_t value);
+
+void rba_write_16(struct RbaEmulator *emu, uint32_t addr, uint16_t value);
+
+void rba_write_32(struct RbaEmulator *emu, uint32_t addr, uint32_t value);
+
+#ifdef __cplusplus
+} // extern "C"
+#endif // __cplusplus
+
+#endif /* RUSTBOYADVANCE_H */
@@ -0,0 +1,288 @@
+//! C API for embedding the emulator, the header is generated into `include/rustboyadvance.h`
+//!
+//! Every function that takes an `RbaEmulator` expects a pointer returned by `rba_create` that
+//! wasn't destroyed yet. Functions that can fail return false or NULL, and `rba_last_error`
+//! tells what went wrong.
+use std::cell::RefCell;
+use std::ffi::CString;
+use std::os::raw::c_char;
+use std::ptr;
+use std::rc::Rc;
+use std::slice;
+
+use rustboyadvance_core::bios::builtin_bios;
+use rustboyadvance_core::keypad::KEYINPUT_ALL_RELEASED;
+use rustboyadvance_core::prelude::*;
+use rustboyadvance_core::util::audio::AudioRingBuffer;
+use rustboyadvance_core::StereoSample;
+
+pub const RBA_SCREEN_WIDTH: u32 = 240;
+pub const RBA_SCREEN_HEIGHT: u32 = 160;
+pub const RBA_AUDIO_SAMPLE_RATE: u32 = 44100;
+
+// Key bits for `rba_set_keys`, in the same order as the KEYINPUT register
+pub const RBA_KEY_A: u16 = 1 << 0;
+pub const RBA_KEY_B: u16 = 1 << 1;
+pub const RBA_KEY_SELECT: u16 = 1 << 2;
+pub const RBA_KEY_START: u16 = 1 << 3;
+pub const RBA_KEY_RIGHT: u16 = 1 << 4;
+pub const RBA_KEY_LEFT: u16 = 1 << 5;
+pub const RBA_KEY_UP: u16 = 1 << 6;
+pub const RBA_KEY_DOWN: u16 = 1 << 7;
+pub const RBA_KEY_R: u16 = 1 << 8;
+pub const RBA_KEY_L: u16 = 1 << 9;
+
+struct Hardware {
+    audio_buffer: AudioRingBuffer,
+    key_state: u16,
+}
+
+impl VideoInterface for Hardware {}
+
+impl AudioInterface for Hardware {
+    fn get_sample_rate(&self) -> i32 {
+        RBA_AUDIO_SAMPLE_RATE as i32
+    }
+
+    // Samples are dropped when the buffer is full, until the embedder pulls some
+    fn push_sample(&mut self, sample: StereoSample<i16>) {
+        if self.audio_buffer.prod.remaining() >= 2 {
+            self.audio_buffer.prod.push(sample.0).unwrap();
+            self.audio_buffer.prod.push(sample.1).unwrap();
+        }
+    }
+}
+
+impl InputInterface for Hardware {
+    fn poll(&mut self) -> u16 {
+        self.key_state
+    }
+}
+
+/// An emulator instance
+pub struct RbaEmulator {
+    hw: Rc<RefCell<Hardware>>,
+    gba: GameBoyAdvance,
+}
+
+/// A buffer allocated by the library, released with `rba_free_buffer`
+#[repr(C)]
+pub struct RbaBuffer {
+    pub data: *mut u8,
+    pub size: usize,
+}
+
+impl RbaBuffer {
+    fn null() -> RbaBuffer {
+        RbaBuffer {
+            data: ptr::null_mut(),
+            size: 0,
+        }
+    }
+}
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = RefCell::new(None);
+}
+
+fn set_last_error(error: String) {
+    let error = CString::new(error).unwrap_or_default();
+    LAST_ERROR.with(|last_error| *last_error.borrow_mut() = Some(error));
+}
+
+/// Describes the last failure on this thread, or NULL if nothing failed yet.
+/// The string stays valid until the next failure on the same thread.
+#[no_mangle]
+pub extern "C" fn rba_last_error() -> *const c_char {
+    LAST_ERROR.with(|last_error| match &*last_error.borrow() {
+        Some(error) => error.as_ptr(),
+        None => ptr::null(),
+    })
+}
+
+unsafe fn make_slice<'a>(data: *const u8, size: usize) -> &'a [u8] {
+    if data.is_null() {
+        &[]
+    } else {
+        slice::from_raw_parts(data, size)
+    }
+}
+
+/// Creates an emulator running `rom`.
+/// When `bios` is NULL the builtin bios is used, which can't show the boot logo, so the bios is
+/// always skipped. Returns NULL if the rom could not be loaded.
+#[no_mangle]
+pub unsafe extern "C" fn rba_create(
+    bios: *const u8,
+    bios_size: usize,
+    rom: *const u8,
+    rom_size: usize,
+    skip_bios: bool,
+) -> *mut RbaEmulator {
+    let (bios_rom, bios_hle) = if bios.is_null() {
+        (builtin_bios(), true)
+    } else {
+        (
+            make_slice(bios, bios_size).to_vec().into_boxed_slice(),
+            false,
+        )
+    };
+    let gamepak = match GamepakBuilder::new()
+        .buffer(make_slice(rom, rom_size))
+        .without_backup_to_file()
+        .build()
+    {
+        Ok(gamepak) => gamepak,
+        Err(e) => {
+            set_last_error(format!("failed to load rom: {:?}", e));
+            return ptr::null_mut();
+        }
+    };
+
+    let hw = Rc::new(RefCell::new(Hardware {
+        audio_buffer: AudioRingBuffer::new(),
+        key_state: KEYINPUT_ALL_RELEASED,
+    }));
+    let mut gba = GameBoyAdvance::new(bios_rom, gamepak, hw.clone(), hw.clone(), hw.clone());
+    gba.set_bios_hle(bios_hle);
+    if skip_bios || bios_hle {
+        gba.skip_bios();
+    }
+
+    Box::into_raw(Box::new(RbaEmulator { hw: hw, gba: gba }))
+}
+
+/// Destroys an emulator created by `rba_create`, NULL is ignored
+#[no_mangle]
+pub unsafe extern "C" fn rba_destroy(emu: *mut RbaEmulator) {
+    if !emu.is_null() {
+        drop(Box::from_raw(emu));
+    }
+}
+
+/// Runs the emulator until the next frame
+#[no_mangle]
+pub unsafe extern "C" fn rba_run_frame(emu: *mut RbaEmulator) {
+    (*emu).gba.frame();
+}
+
+/// Sets the keys that are held down, a mask of `RBA_KEY_*` bits
+#[no_mangle]
+pub unsafe extern "C" fn rba_set_keys(emu: *mut RbaEmulator, keys: u16) {
+    // KEYINPUT bits are cleared while the keys are pressed
+    (*emu).hw.borrow_mut().key_state = !keys & KEYINPUT_ALL_RELEASED;
+}
+
+/// The last frame, `RBA_SCREEN_WIDTH * RBA_SCREEN_HEIGHT` pixels in XRGB8888.
+/// The pointer stays valid until the next `rba_run_frame` or `rba_load_state`.
+#[no_mangle]
+pub unsafe extern "C" fn rba_get_framebuffer(emu: *const RbaEmulator) -> *const u32 {
+    (*emu).gba.get_frame_buffer().as_ptr()
+}
+
+/// Moves up to `max_samples` audio samples into `samples`, returns how many were moved.
+/// Samples are signed 16bit stereo, interleaved left and right, at `RBA_AUDIO_SAMPLE_RATE`.
+#[no_mangle]
+pub unsafe extern "C" fn rba_pull_audio(
+    emu: *mut RbaEmulator,
+    samples: *mut i16,
+    max_samples: usize,
+) -> usize {
+    if samples.is_null() {
+        return 0;
+    }
+    let samples = slice::from_raw_parts_mut(samples, max_samples);
+    (*emu).hw.borrow_mut().audio_buffer.cons.pop_slice(samples)
+}
+
+/// Saves the emulator state into a new buffer, the data is NULL if saving failed
+#[no_mangle]
+pub unsafe extern "C" fn rba_save_state(emu: *const RbaEmulator) -> RbaBuffer {
+    match (*emu).gba.save_state() {
+        Ok(state) => {
+            let state = state.into_boxed_slice();
+            let size = state.len();
+            RbaBuffer {
+                data: Box::into_raw(state) as *mut u8,
+                size: size,
+            }
+        }
+        Err(e) => {
+            set_last_error(format!("failed to save state: {:?}", e));
+            RbaBuffer::null()
+        }
+    }
+}
+
+/// Restores a state made by `rba_save_state` with the same rom
+#[no_mangle]
+pub unsafe extern "C" fn rba_load_state(
+    emu: *mut RbaEmulator,
+    state: *const u8,
+    size: usize,
+) -> bool {
+    match (*emu).gba.restore_state(make_slice(state, size)) {
+        Ok(_) => true,
+        Err(e) => {
+            set_last_error(format!("failed to load state: {:?}", e));
+            false
+        }
+    }
+}
+
+/// Releases a buffer returned by the library
+#[no_mangle]
+pub unsafe extern "C" fn rba_free_buffer(buffer: RbaBuffer) {
+    if !buffer.data.is_null() {
+        drop(Box::from_raw(ptr::slice_from_raw_parts_mut(
+            buffer.data,
+            buffer.size,
+        )));
+    }
+}
+
+/// Reads `size` bytes starting at `addr` into `out`, like the cpu would see them.
+/// Reads don't trigger watchpoints or any other side effect
+#[no_mangle]
+pub unsafe extern "C" fn rba_read_memory(
+    emu: *const RbaEmulator,
+    addr: u32,
+    out: *mut u8,
+    size: usize,
+) {
+    let out = slice::from_raw_parts_mut(out, size);
+    for (i, byte) in out.iter_mut().enumerate() {
+        *byte = (*emu).gba.sysbus.debug_read_8(addr.wrapping_add(i as u32));
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn rba_read_8(emu: *const RbaEmulator, addr: u32) -> u8 {
+    (*emu).gba.sysbus.debug_read_8(addr)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn rba_read_16(emu: *const RbaEmulator, addr: u32) -> u16 {
+    (*emu).gba.sysbus.debug_read_16(addr)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn rba_read_32(emu: *const RbaEmulator, addr: u32) -> u32 {
+    (*emu).gba.sysbus.debug_read_32(addr)
+}
+
+/// Writes go through the bus like cpu writes, so e.g writing to the rom does nothing
+#[no_mangle]
+pub unsafe extern "C" fn rba_write_8(emu: *mut RbaEmulator, addr: u32, value: u8) {
+    (*emu).gba.sysbus.write_8(addr, value)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn rba_write_16(emu: *mut RbaEmulator, addr: u32, value: u16) {
+    (*emu).gba.sysbus.write_16(addr, value)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn rba_write_32(emu: *mut RbaEmulator, addr: u32, value: u32) {
+    (*emu).gba.sysbus.write_32(addr, value)
+}
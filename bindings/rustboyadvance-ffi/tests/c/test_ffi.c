@@ -0,0 +1,80 @@
+/* Drives the emulator through the C API, built and run by tests/c_program.rs */
+#include <stdio.h>
+#include <string.h>
+
+#include "rustboyadvance.h"
+
+#define CHECK(cond)                                                    \
+    do {                                                               \
+        if (!(cond)) {                                                 \
+            fprintf(stderr, "%s:%d: check failed: %s\n", __FILE__,     \
+                    __LINE__, #cond);                                  \
+            return 1;                                                  \
+        }                                                              \
+    } while (0)
+
+/* Paints the backdrop red, then keeps copying KEYINPUT to the start of ewram */
+static const uint32_t program[] = {
+    0xe3a00405, /* mov r0, #0x05000000 */
+    0xe3a0101f, /* mov r1, #0x1f */
+    0xe1c010b0, /* strh r1, [r0] */
+    0xe3a00301, /* mov r0, #0x04000000 */
+    0xe3a01000, /* mov r1, #0 */
+    0xe1c010b0, /* strh r1, [r0] */
+    0xe3a02301, /* mov r2, #0x04000000 */
+    0xe2822e13, /* add r2, r2, #0x130 */
+    0xe3a00402, /* mov r0, #0x02000000 */
+    0xe1d230b0, /* loop: ldrh r3, [r2] */
+    0xe1c030b0, /* strh r3, [r0] */
+    0xeafffffc, /* b loop */
+};
+
+int main(void) {
+    uint8_t rom[0x200];
+    memset(rom, 0, sizeof(rom));
+    for (size_t i = 0; i < sizeof(program) / sizeof(program[0]); i++) {
+        rom[i * 4 + 0] = program[i] & 0xff;
+        rom[i * 4 + 1] = (program[i] >> 8) & 0xff;
+        rom[i * 4 + 2] = (program[i] >> 16) & 0xff;
+        rom[i * 4 + 3] = (program[i] >> 24) & 0xff;
+    }
+
+    CHECK(rba_last_error() == NULL);
+    RbaEmulator *emu = rba_create(NULL, 0, rom, sizeof(rom), true);
+    CHECK(emu != NULL);
+
+    rba_run_frame(emu);
+    const uint32_t *frame = rba_get_framebuffer(emu);
+    for (size_t i = 0; i < RBA_SCREEN_WIDTH * RBA_SCREEN_HEIGHT; i++) {
+        CHECK((frame[i] & 0xffffff) == 0xf80000);
+    }
+    CHECK(rba_read_16(emu, 0x02000000) == 0x3ff);
+
+    rba_set_keys(emu, RBA_KEY_A | RBA_KEY_START);
+    rba_run_frame(emu);
+    CHECK(rba_read_16(emu, 0x02000000) == (0x3ff & ~(RBA_KEY_A | RBA_KEY_START)));
+    rba_set_keys(emu, 0);
+
+    int16_t samples[4096];
+    size_t pulled = rba_pull_audio(emu, samples, 4096);
+    CHECK(pulled > 0 && pulled % 2 == 0);
+    CHECK(rba_pull_audio(emu, samples, 4096) == 0);
+
+    rba_write_32(emu, 0x02000100, 0xdeadbeef);
+    RbaBuffer state = rba_save_state(emu);
+    CHECK(state.data != NULL && state.size > 0);
+    rba_write_32(emu, 0x02000100, 0x12345678);
+    CHECK(rba_read_32(emu, 0x02000100) == 0x12345678);
+    CHECK(rba_load_state(emu, state.data, state.size));
+    uint8_t bytes[4];
+    rba_read_memory(emu, 0x02000100, bytes, sizeof(bytes));
+    CHECK(bytes[0] == 0xef && bytes[1] == 0xbe && bytes[2] == 0xad && bytes[3] == 0xde);
+    rba_free_buffer(state);
+
+    CHECK(!rba_load_state(emu, rom, sizeof(rom)));
+    CHECK(rba_last_error() != NULL);
+
+    rba_destroy(emu);
+    printf("ok\n");
+    return 0;
+}
@@ -0,0 +1,60 @@
+//! Builds `tests/c/test_ffi.c` against the shared library and the committed header, and runs it
+#![cfg(unix)]
+
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// Where cargo put the library, next to this test in `target/debug/deps`
+fn library_dir() -> PathBuf {
+    let exe = env::current_exe().unwrap();
+    exe.parent().unwrap().to_path_buf()
+}
+
+#[test]
+fn test_c_program() {
+    let crate_dir = Path::new(env!("CARGO_MANIFEST_DIR"));
+    let lib_dir = library_dir();
+    let exe = lib_dir.join("test_ffi");
+    let cc = env::var("CC").unwrap_or_else(|_| "cc".to_string());
+
+    let status = Command::new(cc)
+        .arg(crate_dir.join("tests/c/test_ffi.c"))
+        .arg("-std=c99")
+        .arg("-Wall")
+        .arg("-Werror")
+        .arg("-I")
+        .arg(crate_dir.join("include"))
+        .arg("-L")
+        .arg(&lib_dir)
+        .arg(format!("-Wl,-rpath,{}", lib_dir.display()))
+        .arg("-lrustboyadvance_ffi")
+        .arg("-o")
+        .arg(&exe)
+        .status()
+        .expect("failed to run the C compiler");
+    assert!(status.success(), "failed to compile test_ffi.c");
+
+    let output = Command::new(&exe).output().unwrap();
+    assert!(
+        output.status.success(),
+        "test_ffi failed:\n{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "ok\n");
+}
+
+/// The build script only writes the header to `OUT_DIR`, refresh the committed one with
+/// `RBA_UPDATE_HEADER=1 cargo build`
+#[test]
+fn test_header_up_to_date() {
+    let crate_dir = Path::new(env!("CARGO_MANIFEST_DIR"));
+    let generated =
+        fs::read_to_string(Path::new(env!("OUT_DIR")).join("rustboyadvance.h")).unwrap();
+    let committed = fs::read_to_string(crate_dir.join("include/rustboyadvance.h")).unwrap();
+    assert!(
+        generated == committed,
+        "include/rustboyadvance.h is out of date, run `RBA_UPDATE_HEADER=1 cargo build`"
+    );
+}
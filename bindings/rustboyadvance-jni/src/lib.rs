@@ -63,6 +63,7 @@ impl InputInterface for Hardware {
 struct Context {
     hwif: Rc<RefCell<Hardware>>,
     gba: GameBoyAdvance,
+    rewinder: Rewinder,
 }
 
 static mut DID_LOAD: bool = false;
@@ -122,6 +123,7 @@ unsafe fn internal_open_context(
     let context = Context {
         gba: gba,
         hwif: hw.clone(),
+        rewinder: Rewinder::default(),
     };
 
     Ok(context)
@@ -192,9 +194,19 @@ pub mod bindings {
 
     fn internal_open_saved_state(
         env: &JNIEnv,
+        bios: jbyteArray,
+        rom: jbyteArray,
         state: jbyteArray,
         frame_buffer: jintArray,
     ) -> Result<Context, String> {
+        let bios = env
+            .convert_byte_array(bios)
+            .map_err(|e| format!("could not get bios buffer, error {}", e))?
+            .into_boxed_slice();
+        let rom = env
+            .convert_byte_array(rom)
+            .map_err(|e| format!("could not get rom buffer, error {}", e))?
+            .into_boxed_slice();
         let state = env
             .convert_byte_array(state)
             .map_err(|e| format!("could not get state buffer, error {}", e))?;
@@ -211,17 +223,19 @@ pub mod bindings {
         };
         let hw = Rc::new(RefCell::new(hw));
 
-        let gba = GameBoyAdvance::from_saved_state(&state, hw.clone(), hw.clone(), hw.clone())
-            .map_err(|e| {
-                format!(
-                    "failed to create GameBoyAdvance from saved state, error {:?}",
-                    e
-                )
-            })?;
+        let gba =
+            GameBoyAdvance::from_saved_state(&state, bios, rom, hw.clone(), hw.clone(), hw.clone())
+                .map_err(|e| {
+                    format!(
+                        "failed to create GameBoyAdvance from saved state, error {:?}",
+                        e
+                    )
+                })?;
 
         Ok(Context {
             gba: gba,
             hwif: hw.clone(),
+            rewinder: Rewinder::default(),
         })
     }
 
@@ -229,10 +243,12 @@ pub mod bindings {
     pub unsafe extern "C" fn Java_com_mrmichel_rustboyadvance_EmulatorBindings_openSavedState(
         env: JNIEnv,
         _obj: JClass,
+        bios: jbyteArray,
+        rom: jbyteArray,
         state: jbyteArray,
         frame_buffer: jintArray,
     ) -> jlong {
-        match internal_open_saved_state(&env, state, frame_buffer) {
+        match internal_open_saved_state(&env, bios, rom, state, frame_buffer) {
             Ok(ctx) => Box::into_raw(Box::new(Mutex::new(ctx))) as jlong,
             Err(msg) => {
                 env.throw_new(NATIVE_EXCEPTION_CLASS, msg).unwrap();
@@ -260,8 +276,36 @@ pub mod bindings {
         frame_buffer: jintArray,
     ) {
         let mut ctx = lock_ctx(ctx);
+        let ctx = &mut *ctx;
 
         ctx.gba.frame();
+        if let Err(e) = ctx.rewinder.on_frame(&ctx.gba) {
+            env.throw_new(NATIVE_EXCEPTION_CLASS, format!("{}", e))
+                .unwrap();
+        }
+    }
+
+    #[no_mangle]
+    pub unsafe extern "C" fn Java_com_mrmichel_rustboyadvance_EmulatorBindings_rewindOneStep(
+        env: JNIEnv,
+        _obj: JClass,
+        ctx: jlong,
+    ) -> jboolean {
+        let mut ctx = lock_ctx(ctx);
+        let ctx = &mut *ctx;
+
+        match ctx.rewinder.rewind_one_step(&mut ctx.gba) {
+            Ok(rewound) => {
+                // run the restored frame to have it rendered
+                ctx.gba.frame();
+                rewound as jboolean
+            }
+            Err(e) => {
+                env.throw_new(NATIVE_EXCEPTION_CLASS, format!("{}", e))
+                    .unwrap();
+                JNI_FALSE
+            }
+        }
     }
 
     #[no_mangle]
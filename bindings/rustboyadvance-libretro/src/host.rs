@@ -0,0 +1,173 @@
+//! A tiny libretro frontend that drives the core through its C API, without a window or an audio
+//! device, so the core can be tested headlessly.
+use std::cell::RefCell;
+use std::collections::HashSet;
+use std::mem::MaybeUninit;
+use std::os::raw::{c_uint, c_void};
+use std::slice;
+use std::sync::{Mutex, MutexGuard};
+
+use super::libretro::*;
+use super::*;
+
+#[derive(Default)]
+struct HostState {
+    pixel_format: Option<c_uint>,
+    frame: Vec<u32>,
+    frame_count: usize,
+    audio_frames: usize,
+    input_polls: usize,
+    pressed: HashSet<c_uint>,
+}
+
+thread_local! {
+    static STATE: RefCell<HostState> = RefCell::new(HostState::default());
+}
+
+/// The core has global state, only one host can run at a time
+static HOST_LOCK: Mutex<()> = Mutex::new(());
+
+extern "C" fn environment(cmd: c_uint, data: *mut c_void) -> bool {
+    STATE.with(|state| {
+        match cmd {
+            RETRO_ENVIRONMENT_SET_PIXEL_FORMAT => {
+                state.borrow_mut().pixel_format = Some(unsafe { *(data as *const c_uint) });
+                true
+            }
+            // no system directory, so the core uses the builtin bios
+            _ => false,
+        }
+    })
+}
+
+extern "C" fn video_refresh(data: *const c_void, width: c_uint, height: c_uint, pitch: usize) {
+    assert_eq!(pitch, width as usize * 4);
+    let pixels = unsafe { slice::from_raw_parts(data as *const u32, (width * height) as usize) };
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        state.frame = pixels.to_vec();
+        state.frame_count += 1;
+    })
+}
+
+extern "C" fn audio_sample_batch(_data: *const i16, frames: usize) -> usize {
+    STATE.with(|state| state.borrow_mut().audio_frames += frames);
+    frames
+}
+
+extern "C" fn input_poll() {
+    STATE.with(|state| state.borrow_mut().input_polls += 1);
+}
+
+extern "C" fn input_state(port: c_uint, device: c_uint, _index: c_uint, id: c_uint) -> i16 {
+    STATE.with(|state| {
+        let pressed =
+            port == 0 && device == RETRO_DEVICE_JOYPAD && state.borrow().pressed.contains(&id);
+        pressed as i16
+    })
+}
+
+pub struct Host {
+    _lock: MutexGuard<'static, ()>,
+}
+
+impl Host {
+    pub fn new() -> Host {
+        let lock = HOST_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        STATE.with(|state| *state.borrow_mut() = HostState::default());
+        assert_eq!(retro_api_version(), RETRO_API_VERSION);
+        retro_set_environment(environment);
+        retro_set_video_refresh(video_refresh);
+        retro_set_audio_sample_batch(audio_sample_batch);
+        retro_set_input_poll(input_poll);
+        retro_set_input_state(input_state);
+        retro_init();
+        Host { _lock: lock }
+    }
+
+    pub fn load_game(&mut self, rom: &[u8]) -> bool {
+        let game = retro_game_info {
+            path: ptr::null(),
+            data: rom.as_ptr() as *const c_void,
+            size: rom.len(),
+            meta: ptr::null(),
+        };
+        unsafe { retro_load_game(&game) }
+    }
+
+    pub fn av_info(&self) -> retro_system_av_info {
+        let mut info = MaybeUninit::uninit();
+        unsafe {
+            retro_get_system_av_info(info.as_mut_ptr());
+            info.assume_init()
+        }
+    }
+
+    pub fn run(&mut self, frames: usize) {
+        for _ in 0..frames {
+            retro_run();
+        }
+    }
+
+    pub fn set_pressed(&mut self, id: c_uint, pressed: bool) {
+        STATE.with(|state| {
+            let mut state = state.borrow_mut();
+            if pressed {
+                state.pressed.insert(id);
+            } else {
+                state.pressed.remove(&id);
+            }
+        })
+    }
+
+    pub fn pixel_format(&self) -> Option<c_uint> {
+        STATE.with(|state| state.borrow().pixel_format)
+    }
+
+    /// The last frame the core sent
+    pub fn frame(&self) -> Vec<u32> {
+        STATE.with(|state| state.borrow().frame.clone())
+    }
+
+    pub fn frame_count(&self) -> usize {
+        STATE.with(|state| state.borrow().frame_count)
+    }
+
+    pub fn audio_frames(&self) -> usize {
+        STATE.with(|state| state.borrow().audio_frames)
+    }
+
+    pub fn input_polls(&self) -> usize {
+        STATE.with(|state| state.borrow().input_polls)
+    }
+
+    /// The save memory as the frontend sees it, frontends read and write it directly
+    pub fn save_ram(&mut self) -> &mut [u8] {
+        let data = retro_get_memory_data(RETRO_MEMORY_SAVE_RAM);
+        let size = retro_get_memory_size(RETRO_MEMORY_SAVE_RAM);
+        if data.is_null() {
+            return &mut [];
+        }
+        unsafe { slice::from_raw_parts_mut(data as *mut u8, size) }
+    }
+
+    pub fn serialize(&self) -> Option<Vec<u8>> {
+        let mut state = vec![0xaa; retro_serialize_size()];
+        if unsafe { retro_serialize(state.as_mut_ptr() as *mut c_void, state.len()) } {
+            Some(state)
+        } else {
+            None
+        }
+    }
+
+    pub fn unserialize(&mut self, state: &[u8]) -> bool {
+        unsafe { retro_unserialize(state.as_ptr() as *const c_void, state.len()) }
+    }
+}
+
+impl Drop for Host {
+    fn drop(&mut self) {
+        retro_unload_game();
+        retro_deinit();
+    }
+}
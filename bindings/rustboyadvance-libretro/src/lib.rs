@@ -0,0 +1,591 @@
+//! libretro core for rustboyadvance
+//!
+//! Lets any libretro frontend (e.g RetroArch) run the emulator, the frontend takes care of
+//! input mapping, audio output and keeping the save files.
+//! The frontend owns the save memory buffer, it fills it with the save after `retro_load_game`
+//! and writes it to disk whenever it likes, so it is handed to the cartridge on the first
+//! `retro_run` and copied back after every frame.
+#[cfg(test)]
+mod host;
+mod libretro;
+
+use std::cell::RefCell;
+use std::ffi::CStr;
+use std::os::raw::{c_char, c_uint, c_void};
+use std::path::Path;
+use std::ptr;
+use std::rc::Rc;
+use std::slice;
+
+#[macro_use]
+extern crate log;
+
+use bit::BitIndex;
+
+use rustboyadvance_core::bios::builtin_bios;
+use rustboyadvance_core::keypad::{Keys, KEYINPUT_ALL_RELEASED};
+use rustboyadvance_core::prelude::*;
+use rustboyadvance_core::StereoSample;
+
+use libretro::*;
+
+const SAMPLE_RATE: i32 = 44100;
+const CYCLES_PER_FRAME: f64 = 280896.0;
+const CPU_FREQUENCY: f64 = 16777216.0;
+const BIOS_FILE_NAME: &'static str = "gba_bios.bin";
+/// Save states don't have a fixed size, so they get some room to grow
+const SERIALIZE_SLACK: usize = 64 * 1024;
+
+const KEYMAP: &'static [(c_uint, usize)] = &[
+    (RETRO_DEVICE_ID_JOYPAD_A, Keys::ButtonA as usize),
+    (RETRO_DEVICE_ID_JOYPAD_B, Keys::ButtonB as usize),
+    (RETRO_DEVICE_ID_JOYPAD_SELECT, Keys::Select as usize),
+    (RETRO_DEVICE_ID_JOYPAD_START, Keys::Start as usize),
+    (RETRO_DEVICE_ID_JOYPAD_RIGHT, Keys::Right as usize),
+    (RETRO_DEVICE_ID_JOYPAD_LEFT, Keys::Left as usize),
+    (RETRO_DEVICE_ID_JOYPAD_UP, Keys::Up as usize),
+    (RETRO_DEVICE_ID_JOYPAD_DOWN, Keys::Down as usize),
+    (RETRO_DEVICE_ID_JOYPAD_R, Keys::ButtonR as usize),
+    (RETRO_DEVICE_ID_JOYPAD_L, Keys::ButtonL as usize),
+];
+
+struct Hardware {
+    key_state: u16,
+    /// Interleaved stereo samples of the current frame
+    audio_buffer: Vec<i16>,
+}
+
+// The frame buffer is handed to the frontend at the end of `retro_run`
+impl VideoInterface for Hardware {}
+
+impl AudioInterface for Hardware {
+    fn get_sample_rate(&self) -> i32 {
+        SAMPLE_RATE
+    }
+
+    fn push_sample(&mut self, sample: StereoSample<i16>) {
+        self.audio_buffer.push(sample.0);
+        self.audio_buffer.push(sample.1);
+    }
+}
+
+impl InputInterface for Hardware {
+    fn poll(&mut self) -> u16 {
+        self.key_state
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Callbacks {
+    environment: Option<retro_environment_t>,
+    video_refresh: Option<retro_video_refresh_t>,
+    audio_sample_batch: Option<retro_audio_sample_batch_t>,
+    input_poll: Option<retro_input_poll_t>,
+    input_state: Option<retro_input_state_t>,
+}
+
+struct Core {
+    hw: Rc<RefCell<Hardware>>,
+    gba: GameBoyAdvance,
+    bios: Box<[u8]>,
+    bios_hle: bool,
+    save_ram: Vec<u8>,
+    /// Whether the save the frontend put in `save_ram` was handed to the cartridge yet
+    save_ram_loaded: bool,
+    serialize_size: usize,
+}
+
+// libretro is a C API without any context pointers, so the core lives in globals.
+// The frontend calls into the core from a single thread.
+static mut CALLBACKS: Callbacks = Callbacks {
+    environment: None,
+    video_refresh: None,
+    audio_sample_batch: None,
+    input_poll: None,
+    input_state: None,
+};
+static mut CORE: *mut Core = ptr::null_mut();
+
+fn callbacks() -> Callbacks {
+    unsafe { CALLBACKS }
+}
+
+fn core() -> Option<&'static mut Core> {
+    unsafe { CORE.as_mut() }
+}
+
+fn environment(cmd: c_uint, data: *mut c_void) -> bool {
+    match callbacks().environment {
+        Some(environment) => environment(cmd, data),
+        None => false,
+    }
+}
+
+/// Looks for a real bios in the frontend's system directory
+fn load_system_bios() -> Option<Box<[u8]>> {
+    let mut system_dir: *const c_char = ptr::null();
+    if !environment(
+        RETRO_ENVIRONMENT_GET_SYSTEM_DIRECTORY,
+        &mut system_dir as *mut _ as *mut c_void,
+    ) || system_dir.is_null()
+    {
+        return None;
+    }
+    let system_dir = unsafe { CStr::from_ptr(system_dir) }.to_string_lossy();
+    let path = Path::new(system_dir.as_ref()).join(BIOS_FILE_NAME);
+    match read_bin_file(&path) {
+        Ok(bios) => {
+            info!("using bios from {}", path.display());
+            Some(bios.into_boxed_slice())
+        }
+        Err(_) => None,
+    }
+}
+
+impl Core {
+    fn new(rom: Box<[u8]>, bios: Box<[u8]>, bios_hle: bool) -> GBAResult<Core> {
+        let hw = Rc::new(RefCell::new(Hardware {
+            key_state: KEYINPUT_ALL_RELEASED,
+            audio_buffer: Vec::new(),
+        }));
+        let gba = Core::create_gba(&hw, rom, bios.clone(), bios_hle)?;
+        let save_ram = vec![0xff; gba.sysbus.cartridge.get_backup_capacity()];
+        let serialize_size = gba.save_state().map_or(0, |state| state.len()) + SERIALIZE_SLACK;
+
+        Ok(Core {
+            hw: hw,
+            gba: gba,
+            bios: bios,
+            bios_hle: bios_hle,
+            save_ram: save_ram,
+            save_ram_loaded: false,
+            serialize_size: serialize_size,
+        })
+    }
+
+    fn create_gba(
+        hw: &Rc<RefCell<Hardware>>,
+        rom: Box<[u8]>,
+        bios: Box<[u8]>,
+        bios_hle: bool,
+    ) -> GBAResult<GameBoyAdvance> {
+        let gamepak = GamepakBuilder::new()
+            .take_buffer(rom)
+            .without_backup_to_file()
+            .build()?;
+        let mut gba = GameBoyAdvance::new(bios, gamepak, hw.clone(), hw.clone(), hw.clone());
+        gba.set_bios_hle(bios_hle);
+        // the builtin bios can't show the boot logo
+        if bios_hle {
+            gba.skip_bios();
+        }
+        Ok(gba)
+    }
+
+    fn sync_save_ram(&mut self) {
+        if self.save_ram_loaded {
+            self.gba.sysbus.cartridge.copy_backup_to(&mut self.save_ram);
+        } else {
+            self.gba.sysbus.cartridge.load_backup(&self.save_ram);
+            self.save_ram_loaded = true;
+        }
+    }
+
+    fn reset(&mut self) {
+        self.sync_save_ram();
+        let rom = self.gba.sysbus.cartridge.get_rom_bytes().to_vec();
+        // the rom was already loaded once, so this can't fail
+        self.gba = Core::create_gba(
+            &self.hw,
+            rom.into_boxed_slice(),
+            self.bios.clone(),
+            self.bios_hle,
+        )
+        .unwrap();
+        self.gba.sysbus.cartridge.load_backup(&self.save_ram);
+    }
+
+    fn poll_input(&mut self) {
+        let callbacks = callbacks();
+        if let Some(input_poll) = callbacks.input_poll {
+            input_poll();
+        }
+        let mut key_state = KEYINPUT_ALL_RELEASED;
+        if let Some(input_state) = callbacks.input_state {
+            for (id, key) in KEYMAP {
+                if input_state(0, RETRO_DEVICE_JOYPAD, 0, *id) != 0 {
+                    key_state.set_bit(*key, false);
+                }
+            }
+        }
+        self.hw.borrow_mut().key_state = key_state;
+    }
+
+    fn run_frame(&mut self) {
+        if !self.save_ram_loaded {
+            self.sync_save_ram();
+        }
+        self.poll_input();
+        self.gba.frame();
+
+        let callbacks = callbacks();
+        if let Some(video_refresh) = callbacks.video_refresh {
+            video_refresh(
+                self.gba.get_frame_buffer().as_ptr() as *const c_void,
+                DISPLAY_WIDTH as c_uint,
+                DISPLAY_HEIGHT as c_uint,
+                DISPLAY_WIDTH * 4,
+            );
+        }
+        let mut hw = self.hw.borrow_mut();
+        if let Some(audio_sample_batch) = callbacks.audio_sample_batch {
+            audio_sample_batch(hw.audio_buffer.as_ptr(), hw.audio_buffer.len() / 2);
+        }
+        hw.audio_buffer.clear();
+        drop(hw);
+
+        self.sync_save_ram();
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn retro_api_version() -> c_uint {
+    RETRO_API_VERSION
+}
+
+#[no_mangle]
+pub extern "C" fn retro_set_environment(cb: retro_environment_t) {
+    unsafe { CALLBACKS.environment = Some(cb) }
+}
+
+#[no_mangle]
+pub extern "C" fn retro_set_video_refresh(cb: retro_video_refresh_t) {
+    unsafe { CALLBACKS.video_refresh = Some(cb) }
+}
+
+/// Unused, samples are always sent in batches
+#[no_mangle]
+pub extern "C" fn retro_set_audio_sample(_cb: retro_audio_sample_t) {}
+
+#[no_mangle]
+pub extern "C" fn retro_set_audio_sample_batch(cb: retro_audio_sample_batch_t) {
+    unsafe { CALLBACKS.audio_sample_batch = Some(cb) }
+}
+
+#[no_mangle]
+pub extern "C" fn retro_set_input_poll(cb: retro_input_poll_t) {
+    unsafe { CALLBACKS.input_poll = Some(cb) }
+}
+
+#[no_mangle]
+pub extern "C" fn retro_set_input_state(cb: retro_input_state_t) {
+    unsafe { CALLBACKS.input_state = Some(cb) }
+}
+
+#[no_mangle]
+pub extern "C" fn retro_init() {}
+
+#[no_mangle]
+pub extern "C" fn retro_deinit() {
+    retro_unload_game();
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn retro_get_system_info(info: *mut retro_system_info) {
+    *info = retro_system_info {
+        library_name: "RustBoyAdvance\0".as_ptr() as *const c_char,
+        library_version: concat!(env!("CARGO_PKG_VERSION"), "\0").as_ptr() as *const c_char,
+        valid_extensions: "gba|bin\0".as_ptr() as *const c_char,
+        need_fullpath: false,
+        block_extract: false,
+    };
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn retro_get_system_av_info(info: *mut retro_system_av_info) {
+    *info = retro_system_av_info {
+        geometry: retro_game_geometry {
+            base_width: DISPLAY_WIDTH as c_uint,
+            base_height: DISPLAY_HEIGHT as c_uint,
+            max_width: DISPLAY_WIDTH as c_uint,
+            max_height: DISPLAY_HEIGHT as c_uint,
+            aspect_ratio: DISPLAY_WIDTH as f32 / DISPLAY_HEIGHT as f32,
+        },
+        timing: retro_system_timing {
+            fps: CPU_FREQUENCY / CYCLES_PER_FRAME,
+            sample_rate: SAMPLE_RATE as f64,
+        },
+    };
+}
+
+#[no_mangle]
+pub extern "C" fn retro_set_controller_port_device(_port: c_uint, _device: c_uint) {}
+
+#[no_mangle]
+pub unsafe extern "C" fn retro_load_game(game: *const retro_game_info) -> bool {
+    if game.is_null() || (*game).data.is_null() {
+        error!("no rom was given");
+        return false;
+    }
+    let mut pixel_format = RETRO_PIXEL_FORMAT_XRGB8888;
+    if !environment(
+        RETRO_ENVIRONMENT_SET_PIXEL_FORMAT,
+        &mut pixel_format as *mut _ as *mut c_void,
+    ) {
+        error!("the frontend doesn't support XRGB8888");
+        return false;
+    }
+
+    let rom = slice::from_raw_parts((*game).data as *const u8, (*game).size);
+    let (bios, bios_hle) = match load_system_bios() {
+        Some(bios) => (bios, false),
+        None => {
+            info!("{} not found, using the builtin bios", BIOS_FILE_NAME);
+            (builtin_bios(), true)
+        }
+    };
+
+    retro_unload_game();
+    match Core::new(rom.to_vec().into_boxed_slice(), bios, bios_hle) {
+        Ok(core) => {
+            CORE = Box::into_raw(Box::new(core));
+            true
+        }
+        Err(e) => {
+            error!("failed to load rom: {:?}", e);
+            false
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn retro_load_game_special(
+    _game_type: c_uint,
+    _info: *const retro_game_info,
+    _num_info: usize,
+) -> bool {
+    false
+}
+
+#[no_mangle]
+pub extern "C" fn retro_unload_game() {
+    unsafe {
+        if !CORE.is_null() {
+            drop(Box::from_raw(CORE));
+            CORE = ptr::null_mut();
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn retro_get_region() -> c_uint {
+    RETRO_REGION_NTSC
+}
+
+#[no_mangle]
+pub extern "C" fn retro_run() {
+    if let Some(core) = core() {
+        core.run_frame();
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn retro_reset() {
+    if let Some(core) = core() {
+        core.reset();
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn retro_serialize_size() -> usize {
+    core().map_or(0, |core| core.serialize_size)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn retro_serialize(data: *mut c_void, size: usize) -> bool {
+    let core = match core() {
+        Some(core) => core,
+        None => return false,
+    };
+    let state = match core.gba.save_state() {
+        Ok(state) => state,
+        Err(e) => {
+            error!("failed to save state: {:?}", e);
+            return false;
+        }
+    };
+    if state.len() > size {
+        error!("save state is {} bytes, only {} fit", state.len(), size);
+        return false;
+    }
+    let data = slice::from_raw_parts_mut(data as *mut u8, size);
+    data[..state.len()].copy_from_slice(&state);
+    // the state is decoded as a stream, the padding is never read
+    for b in data[state.len()..].iter_mut() {
+        *b = 0;
+    }
+    true
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn retro_unserialize(data: *const c_void, size: usize) -> bool {
+    let core = match core() {
+        Some(core) => core,
+        None => return false,
+    };
+    let data = slice::from_raw_parts(data as *const u8, size);
+    match core.gba.restore_state(data) {
+        Ok(_) => {
+            // save states don't contain the save memory, it stays as it was before restoring
+            core.gba.sysbus.cartridge.load_backup(&core.save_ram);
+            core.save_ram_loaded = true;
+            true
+        }
+        Err(e) => {
+            error!("failed to restore state: {:?}", e);
+            false
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn retro_cheat_reset() {}
+
+#[no_mangle]
+pub extern "C" fn retro_cheat_set(_index: c_uint, _enabled: bool, _code: *const c_char) {}
+
+#[no_mangle]
+pub extern "C" fn retro_get_memory_data(id: c_uint) -> *mut c_void {
+    match (id, core()) {
+        (RETRO_MEMORY_SAVE_RAM, Some(core)) if !core.save_ram.is_empty() => {
+            core.save_ram.as_mut_ptr() as *mut c_void
+        }
+        _ => ptr::null_mut(),
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn retro_get_memory_size(id: c_uint) -> usize {
+    match (id, core()) {
+        (RETRO_MEMORY_SAVE_RAM, Some(core)) => core.save_ram.len(),
+        _ => 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::host::Host;
+    use super::*;
+
+    /// Paints the backdrop red, copies the first save byte to the second one, and keeps storing
+    /// KEYINPUT into the third one
+    fn make_rom() -> Vec<u8> {
+        let code: &[u32] = &[
+            0xe3a00405, // mov r0, #0x05000000
+            0xe3a0101f, // mov r1, #0x1f
+            0xe1c010b0, // strh r1, [r0]
+            0xe3a00301, // mov r0, #0x04000000
+            0xe3a01000, // mov r1, #0
+            0xe1c010b0, // strh r1, [r0]
+            0xe3a0040e, // mov r0, #0x0e000000
+            0xe5d01000, // ldrb r1, [r0]
+            0xe5c01001, // strb r1, [r0, #1]
+            0xe3a02301, // mov r2, #0x04000000
+            0xe2822e13, // add r2, r2, #0x130
+            0xe1d230b0, // loop: ldrh r3, [r2]
+            0xe5c03002, // strb r3, [r0, #2]
+            0xeafffffc, // b loop
+        ];
+        let mut rom = vec![0; 0x200];
+        for (i, word) in code.iter().enumerate() {
+            rom[i * 4..i * 4 + 4].copy_from_slice(&word.to_le_bytes());
+        }
+        // lets the gamepak builder detect sram
+        rom[0x100..0x109].copy_from_slice(b"SRAM_V110");
+        rom
+    }
+
+    #[test]
+    fn test_run_headless() {
+        let mut host = Host::new();
+        assert!(host.load_game(&make_rom()));
+        assert_eq!(host.pixel_format(), Some(RETRO_PIXEL_FORMAT_XRGB8888));
+
+        let av_info = host.av_info();
+        assert_eq!(av_info.geometry.base_width, 240);
+        assert_eq!(av_info.geometry.base_height, 160);
+
+        host.run(10);
+        assert_eq!(host.frame_count(), 10);
+        assert_eq!(host.input_polls(), 10);
+        let expected_audio = 10.0 * av_info.timing.sample_rate / av_info.timing.fps;
+        assert!((host.audio_frames() as f64 - expected_audio).abs() < 10.0);
+
+        let frame = host.frame();
+        assert_eq!(frame.len(), 240 * 160);
+        assert!(frame.iter().all(|&pixel| pixel == frame[0]));
+        assert_eq!(frame[0] & 0x00ff_ffff, 0x00f8_0000);
+    }
+
+    #[test]
+    fn test_input() {
+        let mut host = Host::new();
+        assert!(host.load_game(&make_rom()));
+        host.run(1);
+        assert_eq!(host.save_ram()[2], 0xff);
+
+        host.set_pressed(RETRO_DEVICE_ID_JOYPAD_A, true);
+        host.set_pressed(RETRO_DEVICE_ID_JOYPAD_START, true);
+        host.run(1);
+        assert_eq!(host.save_ram()[2], !0b1001);
+
+        host.set_pressed(RETRO_DEVICE_ID_JOYPAD_A, false);
+        host.run(1);
+        assert_eq!(host.save_ram()[2], !0b1000);
+    }
+
+    #[test]
+    fn test_save_ram() {
+        let mut host = Host::new();
+        assert!(host.load_game(&make_rom()));
+        assert_eq!(host.save_ram().len(), 0x8000);
+        // frontends load the save after `retro_load_game`
+        host.save_ram()[0] = 0x42;
+        host.run(1);
+        assert_eq!(host.save_ram()[0], 0x42);
+        assert_eq!(host.save_ram()[1], 0x42);
+    }
+
+    #[test]
+    fn test_serialize() {
+        let mut host = Host::new();
+        assert!(host.load_game(&make_rom()));
+        host.run(5);
+        let state = host.serialize().unwrap();
+        assert_eq!(state.len(), retro_serialize_size());
+
+        host.run(3);
+        let frame = host.frame();
+        host.save_ram()[1] = 0x17;
+        assert!(host.unserialize(&state));
+        host.run(3);
+        assert_eq!(host.frame(), frame);
+        // restoring a state keeps the save memory
+        assert_eq!(host.save_ram()[1], 0x17);
+
+        assert!(!host.unserialize(&[0; 64]));
+    }
+
+    #[test]
+    fn test_reset() {
+        let mut host = Host::new();
+        assert!(host.load_game(&make_rom()));
+        host.save_ram()[0] = 0x42;
+        host.run(2);
+        retro_reset();
+        host.run(1);
+        assert_eq!(host.frame_count(), 3);
+        // the save memory survives resets
+        assert_eq!(&host.save_ram()[..2], &[0x42, 0x42]);
+    }
+}
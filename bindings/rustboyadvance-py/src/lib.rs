@@ -0,0 +1,467 @@
+//! Python bindings, built into the `rustboyadvance` module with maturin
+//!
+//! Frames and audio are returned as memoryviews, which numpy wraps without copying:
+//! `np.asarray(gba.framebuffer())` is a (160, 240, 3) uint8 RGB array, and
+//! `np.asarray(gba.audio()).reshape(-1, 2)` gives the stereo samples.
+//!
+//! `VecGameBoyAdvance` runs a batch of emulators in parallel, each on its own thread.
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::thread::{self, JoinHandle};
+
+use pyo3::exceptions::{PyIndexError, PyRuntimeError, PyValueError};
+use pyo3::prelude::*;
+use pyo3::types::{PyBytes, PyMemoryView};
+
+use rustboyadvance_core::bios::builtin_bios;
+use rustboyadvance_core::keypad::{Keys, KEYINPUT_ALL_RELEASED};
+use rustboyadvance_core::prelude::*;
+use rustboyadvance_core::util::audio::AudioRingBuffer;
+use rustboyadvance_core::StereoSample;
+
+const AUDIO_SAMPLE_RATE: i32 = 44100;
+
+struct Hardware {
+    audio_buffer: AudioRingBuffer,
+    key_state: u16,
+}
+
+impl VideoInterface for Hardware {}
+
+impl AudioInterface for Hardware {
+    fn get_sample_rate(&self) -> i32 {
+        AUDIO_SAMPLE_RATE
+    }
+
+    // Samples are dropped when the buffer is full, until `audio()` is called
+    fn push_sample(&mut self, sample: StereoSample<i16>) {
+        if self.audio_buffer.prod.remaining() >= 2 {
+            self.audio_buffer.prod.push(sample.0).unwrap();
+            self.audio_buffer.prod.push(sample.1).unwrap();
+        }
+    }
+}
+
+impl InputInterface for Hardware {
+    fn poll(&mut self) -> u16 {
+        self.key_state
+    }
+}
+
+/// Wraps `bytes` in a read only memoryview of the given format and shape
+fn memory_view<'py>(
+    py: Python<'py>,
+    bytes: &[u8],
+    format: &str,
+    shape: Vec<usize>,
+) -> PyResult<Bound<'py, PyMemoryView>> {
+    let view = PyMemoryView::from_bound(&PyBytes::new_bound(py, bytes))?;
+    // memoryviews can't have zeros in their shape
+    let view = if bytes.is_empty() {
+        view.call_method1("cast", (format,))?
+    } else {
+        view.call_method1("cast", (format, shape))?
+    };
+    Ok(view.downcast_into::<PyMemoryView>()?)
+}
+
+/// Errors are plain strings until they reach python, so the worker threads can make them
+/// without the GIL
+fn error_message<E: std::fmt::Debug>(context: &str, err: E) -> String {
+    format!("{}: {:?}", context, err)
+}
+
+/// A headless emulator, shared by the python classes
+struct Emulator {
+    hw: Rc<RefCell<Hardware>>,
+    gba: GameBoyAdvance,
+}
+
+impl Emulator {
+    /// Without a bios the builtin one is used, which can't show the boot logo,
+    /// so the bios is always skipped.
+    fn new(rom: &[u8], bios: Option<&[u8]>, skip_bios: bool) -> Result<Emulator, String> {
+        let gamepak = GamepakBuilder::new()
+            .buffer(rom)
+            .without_backup_to_file()
+            .build()
+            .map_err(|e| error_message("failed to load rom", e))?;
+        let (bios_rom, bios_hle) = match bios {
+            Some(bios) => (bios.to_vec().into_boxed_slice(), false),
+            None => (builtin_bios(), true),
+        };
+
+        let hw = Rc::new(RefCell::new(Hardware {
+            audio_buffer: AudioRingBuffer::new(),
+            key_state: KEYINPUT_ALL_RELEASED,
+        }));
+        let mut gba = GameBoyAdvance::new(bios_rom, gamepak, hw.clone(), hw.clone(), hw.clone());
+        gba.set_bios_hle(bios_hle);
+        if skip_bios || bios_hle {
+            gba.skip_bios();
+        }
+
+        Ok(Emulator { hw: hw, gba: gba })
+    }
+
+    fn run_frame(&mut self, keys: u16) {
+        // KEYINPUT bits are cleared while the keys are pressed
+        self.hw.borrow_mut().key_state = !keys & KEYINPUT_ALL_RELEASED;
+        self.gba.frame();
+    }
+
+    /// Appends the frame as RGB bytes
+    fn copy_frame(&self, rgb: &mut Vec<u8>) {
+        for pixel in self.gba.get_frame_buffer() {
+            rgb.extend_from_slice(&[(pixel >> 16) as u8, (pixel >> 8) as u8, *pixel as u8]);
+        }
+    }
+
+    fn frame_rgb(&self) -> Vec<u8> {
+        let mut rgb = Vec::with_capacity(DISPLAY_WIDTH * DISPLAY_HEIGHT * 3);
+        self.copy_frame(&mut rgb);
+        rgb
+    }
+
+    fn read_memory(&self, addr: u32, size: u32) -> Vec<u8> {
+        self.gba.sysbus.get_bytes(addr..addr.wrapping_add(size))
+    }
+
+    fn write_memory(&mut self, addr: u32, data: &[u8]) {
+        for (i, byte) in data.iter().enumerate() {
+            self.gba.sysbus.write_8(addr.wrapping_add(i as u32), *byte);
+        }
+    }
+
+    fn save_state(&self) -> Result<Vec<u8>, String> {
+        self.gba
+            .save_state()
+            .map_err(|e| error_message("failed to save state", e))
+    }
+
+    fn load_state(&mut self, state: &[u8]) -> Result<(), String> {
+        self.gba
+            .restore_state(state)
+            .map_err(|e| error_message("failed to load state", e))
+    }
+}
+
+/// A headless emulator running a single game
+#[pyclass(unsendable, name = "GameBoyAdvance")]
+struct PyGameBoyAdvance {
+    emulator: Emulator,
+}
+
+#[pymethods]
+impl PyGameBoyAdvance {
+    #[new]
+    #[pyo3(signature = (rom, bios=None, skip_bios=true))]
+    fn new(rom: &[u8], bios: Option<&[u8]>, skip_bios: bool) -> PyResult<Self> {
+        Ok(PyGameBoyAdvance {
+            emulator: Emulator::new(rom, bios, skip_bios).map_err(PyValueError::new_err)?,
+        })
+    }
+
+    #[getter]
+    fn game_title(&self) -> String {
+        self.emulator.gba.get_game_title()
+    }
+
+    #[getter]
+    fn game_code(&self) -> String {
+        self.emulator.gba.get_game_code()
+    }
+
+    /// Runs a single frame with `keys` held down, a mask of the `KEY_*` constants
+    #[pyo3(signature = (keys=0))]
+    fn step_frame(&mut self, keys: u16) {
+        self.emulator.run_frame(keys);
+    }
+
+    /// The last frame as a (160, 240, 3) RGB memoryview
+    fn framebuffer<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyMemoryView>> {
+        let rgb = self.emulator.frame_rgb();
+        memory_view(py, &rgb, "B", vec![DISPLAY_HEIGHT, DISPLAY_WIDTH, 3])
+    }
+
+    /// Takes the audio samples produced since the last call, as an int16 memoryview of
+    /// interleaved left and right samples at `AUDIO_SAMPLE_RATE`
+    fn audio<'py>(&mut self, py: Python<'py>) -> PyResult<Bound<'py, PyMemoryView>> {
+        let mut hw = self.emulator.hw.borrow_mut();
+        let mut samples = vec![0; hw.audio_buffer.cons.len()];
+        hw.audio_buffer.cons.pop_slice(&mut samples);
+        let bytes: Vec<u8> = samples.iter().flat_map(|s| s.to_ne_bytes()).collect();
+        memory_view(py, &bytes, "h", vec![samples.len()])
+    }
+
+    /// Reads memory like the cpu would see it
+    fn read_memory<'py>(&self, py: Python<'py>, addr: u32, size: u32) -> Bound<'py, PyBytes> {
+        PyBytes::new_bound(py, &self.emulator.read_memory(addr, size))
+    }
+
+    /// Writes byte by byte through the bus like the cpu would, so e.g writes to the rom are ignored
+    fn write_memory(&mut self, addr: u32, data: &[u8]) {
+        self.emulator.write_memory(addr, data);
+    }
+
+    fn save_state<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyBytes>> {
+        let state = self.emulator.save_state().map_err(PyValueError::new_err)?;
+        Ok(PyBytes::new_bound(py, &state))
+    }
+
+    fn load_state(&mut self, state: &[u8]) -> PyResult<()> {
+        self.emulator
+            .load_state(state)
+            .map_err(PyValueError::new_err)
+    }
+}
+
+enum Request {
+    Step(u16),
+    ReadMemory(u32, u32),
+    WriteMemory(u32, Vec<u8>),
+    SaveState,
+    LoadState(Vec<u8>),
+}
+
+/// An emulator owned by a worker thread, which serves one request at a time
+struct Worker {
+    requests: Sender<Request>,
+    responses: Receiver<Result<Vec<u8>, String>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Worker {
+    fn spawn(rom: Vec<u8>, bios: Option<Vec<u8>>, skip_bios: bool) -> PyResult<Worker> {
+        let (requests, worker_requests) = channel::<Request>();
+        let (worker_responses, responses) = channel();
+        let thread = thread::spawn(move || {
+            let mut emulator = match Emulator::new(&rom, bios.as_deref(), skip_bios) {
+                Ok(emulator) => emulator,
+                Err(e) => {
+                    let _ = worker_responses.send(Err(e));
+                    return;
+                }
+            };
+            let _ = worker_responses.send(Ok(vec![]));
+            for request in worker_requests {
+                let response = match request {
+                    Request::Step(keys) => {
+                        emulator.run_frame(keys);
+                        Ok(emulator.frame_rgb())
+                    }
+                    Request::ReadMemory(addr, size) => Ok(emulator.read_memory(addr, size)),
+                    Request::WriteMemory(addr, data) => {
+                        emulator.write_memory(addr, &data);
+                        Ok(vec![])
+                    }
+                    Request::SaveState => emulator.save_state(),
+                    Request::LoadState(state) => emulator.load_state(&state).map(|_| vec![]),
+                };
+                if worker_responses.send(response).is_err() {
+                    break;
+                }
+            }
+        });
+        let mut worker = Worker {
+            requests: requests,
+            responses: responses,
+            thread: Some(thread),
+        };
+        worker.response()?;
+        Ok(worker)
+    }
+
+    fn send(&self, request: Request) -> PyResult<()> {
+        self.requests
+            .send(request)
+            .map_err(|_| PyRuntimeError::new_err("the emulator thread stopped"))
+    }
+
+    fn response(&mut self) -> PyResult<Vec<u8>> {
+        match self.responses.recv() {
+            Ok(response) => response.map_err(PyValueError::new_err),
+            Err(_) => Err(PyRuntimeError::new_err("the emulator thread stopped")),
+        }
+    }
+
+    /// Waits for the response without holding the GIL
+    fn request(&mut self, py: Python, request: Request) -> PyResult<Vec<u8>> {
+        self.send(request)?;
+        py.allow_threads(|| self.response())
+    }
+}
+
+impl Drop for Worker {
+    fn drop(&mut self) {
+        // closing the channel ends the worker loop
+        let (requests, _) = channel();
+        drop(std::mem::replace(&mut self.requests, requests));
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// A batch of emulators running the same game, stepped together in parallel.
+/// Each emulator lives on its own thread, and the GIL is released while they run.
+#[pyclass(name = "VecGameBoyAdvance")]
+struct PyVecGameBoyAdvance {
+    workers: Vec<Worker>,
+}
+
+impl PyVecGameBoyAdvance {
+    fn worker(&mut self, index: usize) -> PyResult<&mut Worker> {
+        let count = self.workers.len();
+        self.workers.get_mut(index).ok_or_else(|| {
+            PyIndexError::new_err(format!(
+                "emulator {} out of range, there are {}",
+                index, count
+            ))
+        })
+    }
+}
+
+#[pymethods]
+impl PyVecGameBoyAdvance {
+    #[new]
+    #[pyo3(signature = (rom, num_emulators, bios=None, skip_bios=true))]
+    fn new(
+        py: Python,
+        rom: &[u8],
+        num_emulators: usize,
+        bios: Option<&[u8]>,
+        skip_bios: bool,
+    ) -> PyResult<Self> {
+        let rom = rom.to_vec();
+        let bios = bios.map(|bios| bios.to_vec());
+        let workers = py.allow_threads(|| {
+            (0..num_emulators)
+                .map(|_| Worker::spawn(rom.clone(), bios.clone(), skip_bios))
+                .collect::<PyResult<Vec<Worker>>>()
+        })?;
+        Ok(PyVecGameBoyAdvance { workers: workers })
+    }
+
+    fn __len__(&self) -> usize {
+        self.workers.len()
+    }
+
+    /// Runs a frame on every emulator at once, with the matching entry of `keys` held down (no
+    /// keys if not given), and returns the new frames as an (N, 160, 240, 3) RGB memoryview
+    #[pyo3(signature = (keys=None))]
+    fn step_frames<'py>(
+        &mut self,
+        py: Python<'py>,
+        keys: Option<Vec<u16>>,
+    ) -> PyResult<Bound<'py, PyMemoryView>> {
+        let count = self.workers.len();
+        let keys = check_keys(keys, count)?;
+        let workers = &mut self.workers;
+        let rgb = py.allow_threads(|| -> PyResult<Vec<u8>> {
+            for (worker, keys) in workers.iter().zip(keys) {
+                worker.send(Request::Step(keys))?;
+            }
+            let mut rgb = Vec::with_capacity(count * DISPLAY_WIDTH * DISPLAY_HEIGHT * 3);
+            for worker in workers.iter_mut() {
+                rgb.extend_from_slice(&worker.response()?);
+            }
+            Ok(rgb)
+        })?;
+        memory_view(py, &rgb, "B", vec![count, DISPLAY_HEIGHT, DISPLAY_WIDTH, 3])
+    }
+
+    fn read_memory<'py>(
+        &mut self,
+        py: Python<'py>,
+        index: usize,
+        addr: u32,
+        size: u32,
+    ) -> PyResult<Bound<'py, PyBytes>> {
+        let bytes = self
+            .worker(index)?
+            .request(py, Request::ReadMemory(addr, size))?;
+        Ok(PyBytes::new_bound(py, &bytes))
+    }
+
+    fn write_memory(&mut self, py: Python, index: usize, addr: u32, data: &[u8]) -> PyResult<()> {
+        self.worker(index)?
+            .request(py, Request::WriteMemory(addr, data.to_vec()))?;
+        Ok(())
+    }
+
+    fn save_state<'py>(&mut self, py: Python<'py>, index: usize) -> PyResult<Bound<'py, PyBytes>> {
+        let state = self.worker(index)?.request(py, Request::SaveState)?;
+        Ok(PyBytes::new_bound(py, &state))
+    }
+
+    fn load_state(&mut self, py: Python, index: usize, state: &[u8]) -> PyResult<()> {
+        self.worker(index)?
+            .request(py, Request::LoadState(state.to_vec()))?;
+        Ok(())
+    }
+}
+
+fn check_keys(keys: Option<Vec<u16>>, count: usize) -> PyResult<Vec<u16>> {
+    let keys = keys.unwrap_or_else(|| vec![0; count]);
+    if keys.len() != count {
+        return Err(PyValueError::new_err(format!(
+            "got keys for {} emulators, expected {}",
+            keys.len(),
+            count
+        )));
+    }
+    Ok(keys)
+}
+
+/// Runs a frame on each emulator one after the other on the calling thread, with the matching
+/// entry of `keys` held down (no keys if not given), and returns all the new frames as an
+/// (N, 160, 240, 3) RGB memoryview. `VecGameBoyAdvance` runs its emulators in parallel.
+#[pyfunction]
+#[pyo3(signature = (emulators, keys=None))]
+fn step_frames<'py>(
+    py: Python<'py>,
+    mut emulators: Vec<PyRefMut<PyGameBoyAdvance>>,
+    keys: Option<Vec<u16>>,
+) -> PyResult<Bound<'py, PyMemoryView>> {
+    let keys = check_keys(keys, emulators.len())?;
+
+    let mut rgb = Vec::with_capacity(emulators.len() * DISPLAY_WIDTH * DISPLAY_HEIGHT * 3);
+    for (emulator, keys) in emulators.iter_mut().zip(keys) {
+        emulator.emulator.run_frame(keys);
+        emulator.emulator.copy_frame(&mut rgb);
+    }
+    memory_view(
+        py,
+        &rgb,
+        "B",
+        vec![emulators.len(), DISPLAY_HEIGHT, DISPLAY_WIDTH, 3],
+    )
+}
+
+#[pymodule]
+fn rustboyadvance(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_class::<PyGameBoyAdvance>()?;
+    m.add_class::<PyVecGameBoyAdvance>()?;
+    m.add_function(wrap_pyfunction!(step_frames, m)?)?;
+
+    m.add("SCREEN_WIDTH", DISPLAY_WIDTH)?;
+    m.add("SCREEN_HEIGHT", DISPLAY_HEIGHT)?;
+    m.add("AUDIO_SAMPLE_RATE", AUDIO_SAMPLE_RATE)?;
+    for (name, key) in &[
+        ("KEY_A", Keys::ButtonA as usize),
+        ("KEY_B", Keys::ButtonB as usize),
+        ("KEY_SELECT", Keys::Select as usize),
+        ("KEY_START", Keys::Start as usize),
+        ("KEY_RIGHT", Keys::Right as usize),
+        ("KEY_LEFT", Keys::Left as usize),
+        ("KEY_UP", Keys::Up as usize),
+        ("KEY_DOWN", Keys::Down as usize),
+        ("KEY_R", Keys::ButtonR as usize),
+        ("KEY_L", Keys::ButtonL as usize),
+    ] {
+        m.add(*name, 1u16 << *key)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,34 @@
+//! Runs `tests/test_rustboyadvance.py` against the built module
+#![cfg(unix)]
+
+use std::env;
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+
+#[test]
+fn test_python_bindings() {
+    let crate_dir = Path::new(env!("CARGO_MANIFEST_DIR"));
+    // cargo puts the library next to this test in `target/debug/deps`
+    let deps_dir = env::current_exe().unwrap().parent().unwrap().to_path_buf();
+    let module_dir = deps_dir.join("python");
+    fs::create_dir_all(&module_dir).unwrap();
+    // python looks for `<module>.so` rather than `lib<crate>.so`
+    fs::copy(
+        deps_dir.join("librustboyadvance.so"),
+        module_dir.join("rustboyadvance.so"),
+    )
+    .unwrap();
+
+    let python = env::var("PYTHON").unwrap_or_else(|_| "python3".to_string());
+    let output = Command::new(python)
+        .arg(crate_dir.join("tests/test_rustboyadvance.py"))
+        .env("PYTHONPATH", &module_dir)
+        .output()
+        .expect("failed to run python");
+    assert!(
+        output.status.success(),
+        "python tests failed:\n{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+}
@@ -0,0 +1,111 @@
+"""Tests of the python bindings, run by tests/python.rs once the module is built"""
+import struct
+import unittest
+
+import rustboyadvance
+
+# Paints the backdrop red, then keeps copying KEYINPUT to the start of ewram
+PROGRAM = [
+    0xE3A00405,  # mov r0, #0x05000000
+    0xE3A0101F,  # mov r1, #0x1f
+    0xE1C010B0,  # strh r1, [r0]
+    0xE3A00301,  # mov r0, #0x04000000
+    0xE3A01000,  # mov r1, #0
+    0xE1C010B0,  # strh r1, [r0]
+    0xE3A02301,  # mov r2, #0x04000000
+    0xE2822E13,  # add r2, r2, #0x130
+    0xE3A00402,  # mov r0, #0x02000000
+    0xE1D230B0,  # loop: ldrh r3, [r2]
+    0xE1C030B0,  # strh r3, [r0]
+    0xEAFFFFFC,  # b loop
+]
+ROM = struct.pack("<%dI" % len(PROGRAM), *PROGRAM).ljust(0x200, b"\0")
+RED = [0xF8, 0, 0]
+
+
+class TestGameBoyAdvance(unittest.TestCase):
+    def test_framebuffer(self):
+        gba = rustboyadvance.GameBoyAdvance(ROM)
+        gba.step_frame()
+        frame = gba.framebuffer()
+        self.assertEqual(frame.shape, (160, 240, 3))
+        self.assertEqual(frame.format, "B")
+        self.assertEqual(frame[0, 0, 0], 0xF8)
+        self.assertTrue(all(frame[159, 239, i] == RED[i] for i in range(3)))
+        self.assertEqual(frame.tobytes(), bytes(RED) * (160 * 240))
+
+    def test_keys(self):
+        gba = rustboyadvance.GameBoyAdvance(ROM)
+        gba.step_frame()
+        self.assertEqual(gba.read_memory(0x02000000, 2), b"\xff\x03")
+        gba.step_frame(rustboyadvance.KEY_A | rustboyadvance.KEY_START)
+        keyinput = struct.unpack("<H", gba.read_memory(0x02000000, 2))[0]
+        self.assertEqual(keyinput, 0x3FF & ~0b1001)
+
+    def test_audio(self):
+        gba = rustboyadvance.GameBoyAdvance(ROM)
+        gba.step_frame()
+        audio = gba.audio()
+        self.assertEqual(audio.format, "h")
+        self.assertGreater(len(audio), 0)
+        self.assertEqual(len(audio) % 2, 0)
+        self.assertEqual(len(gba.audio()), 0)
+
+    def test_memory_and_state(self):
+        gba = rustboyadvance.GameBoyAdvance(ROM)
+        gba.step_frame()
+        gba.write_memory(0x02000100, b"\xef\xbe\xad\xde")
+        state = gba.save_state()
+        self.assertIsInstance(state, bytes)
+        gba.write_memory(0x02000100, b"\0\0\0\0")
+        gba.load_state(state)
+        self.assertEqual(gba.read_memory(0x02000100, 4), b"\xef\xbe\xad\xde")
+        with self.assertRaises(ValueError):
+            gba.load_state(b"not a state")
+
+    def test_step_frames(self):
+        emulators = [rustboyadvance.GameBoyAdvance(ROM) for _ in range(3)]
+        frames = rustboyadvance.step_frames(emulators, [0, rustboyadvance.KEY_B, 0])
+        self.assertEqual(frames.shape, (3, 160, 240, 3))
+        self.assertEqual(frames.tobytes(), bytes(RED) * (3 * 160 * 240))
+        keyinput = struct.unpack("<H", emulators[1].read_memory(0x02000000, 2))[0]
+        self.assertEqual(keyinput, 0x3FF & ~rustboyadvance.KEY_B)
+
+        frames = rustboyadvance.step_frames(emulators)
+        self.assertEqual(frames.shape, (3, 160, 240, 3))
+        with self.assertRaises(ValueError):
+            rustboyadvance.step_frames(emulators, [0])
+
+    def test_vec_step_frames(self):
+        emulators = rustboyadvance.VecGameBoyAdvance(ROM, 3)
+        self.assertEqual(len(emulators), 3)
+        frames = emulators.step_frames([0, rustboyadvance.KEY_B, 0])
+        self.assertEqual(frames.shape, (3, 160, 240, 3))
+        self.assertEqual(frames.tobytes(), bytes(RED) * (3 * 160 * 240))
+        keyinput = struct.unpack("<H", emulators.read_memory(1, 0x02000000, 2))[0]
+        self.assertEqual(keyinput, 0x3FF & ~rustboyadvance.KEY_B)
+        self.assertEqual(emulators.read_memory(0, 0x02000000, 2), b"\xff\x03")
+
+        emulators.write_memory(2, 0x02000100, b"\x2a")
+        state = emulators.save_state(2)
+        emulators.load_state(0, state)
+        self.assertEqual(emulators.read_memory(0, 0x02000100, 1), b"\x2a")
+
+        frames = emulators.step_frames()
+        self.assertEqual(frames.shape, (3, 160, 240, 3))
+        with self.assertRaises(ValueError):
+            emulators.step_frames([0])
+        with self.assertRaises(IndexError):
+            emulators.save_state(3)
+        with self.assertRaises(ValueError):
+            emulators.load_state(0, b"not a state")
+        with self.assertRaises(ValueError):
+            rustboyadvance.VecGameBoyAdvance(b"", 2)
+
+    def test_bad_rom(self):
+        with self.assertRaises(ValueError):
+            rustboyadvance.GameBoyAdvance(b"")
+
+
+if __name__ == "__main__":
+    unittest.main()
@@ -29,12 +29,15 @@ public class EmulatorBindings {
 
     /**
      * Open a new emulator context from a saved state buffer
+     * Saved states don't contain the bios and the rom, so they have to be passed again
+     * @param bios
+     * @param rom
      * @param savedState
      * @param frameBuffer
      * @return
      * @throws NativeBindingException
      */
-    public static native long openSavedState(byte[] savedState, int[] frameBuffer) throws NativeBindingException;
+    public static native long openSavedState(byte[] bios, byte[] rom, byte[] savedState, int[] frameBuffer) throws NativeBindingException;
 
     /**
      * Make the emulator boot directly into the cartridge
@@ -59,6 +62,14 @@ public class EmulatorBindings {
      */
     public static native void runFrame(long ctx, int[] frame_buffer);
 
+    /**
+     * Steps the emulation back to the previous rewind snapshot, and renders it
+     * @param ctx
+     * @return false if there was nothing left to rewind
+     * @throws NativeBindingException
+     */
+    public static native boolean rewindOneStep(long ctx) throws NativeBindingException;
+
     /**
      * Collect pending audio samples
      * @param ctx
@@ -16,6 +16,10 @@ public class Emulator {
     private int[] frameBuffer;
     public Keypad keypad;
 
+    /// kept to open saved states, which don't contain them
+    private byte[] bios;
+    private byte[] rom;
+
     public Emulator() {
         this.frameBuffer = new int[240 * 160];
         this.keypad = new Keypad();
@@ -44,6 +48,10 @@ public class Emulator {
         EmulatorBindings.runFrame(ctx, frameBuffer);
     }
 
+    public synchronized boolean rewindOneStep() throws EmulatorBindings.NativeBindingException {
+        return EmulatorBindings.rewindOneStep(ctx);
+    }
+
     public synchronized short[] collectAudioSamples() {
         return EmulatorBindings.collectAudioSamples(ctx);
     }
@@ -62,17 +70,21 @@ public class Emulator {
         if (ctx != -1) {
             EmulatorBindings.loadState(this.ctx, state);
         } else {
-            openSavedState(state);
+            openSavedState(this.bios, this.rom, state);
         }
     }
 
 
     public synchronized void open(byte[] bios, byte[] rom, String saveName, boolean skipBios) throws EmulatorBindings.NativeBindingException {
         this.ctx = EmulatorBindings.openEmulator(bios, rom, this.frameBuffer, saveName, skipBios);
+        this.bios = bios;
+        this.rom = rom;
     }
 
-    public synchronized void openSavedState(byte[] savedState) throws EmulatorBindings.NativeBindingException {
-        this.ctx = EmulatorBindings.openSavedState(savedState, this.frameBuffer);
+    public synchronized void openSavedState(byte[] bios, byte[] rom, byte[] savedState) throws EmulatorBindings.NativeBindingException {
+        this.ctx = EmulatorBindings.openSavedState(bios, rom, savedState, this.frameBuffer);
+        this.bios = bios;
+        this.rom = rom;
     }
 
     public synchronized void close() {
@@ -399,7 +399,12 @@ public class EmulatorActivity extends AppCompatActivity implements View.OnClickL
                 saveFile.delete();
 
                 byte[] savedState = outputStream.toByteArray();
-                emulator.openSavedState(savedState);
+
+                int romId = getIntent().getIntExtra("romId", -1);
+                this.romMetadata = RomManager.getInstance(this).getRomMetadata(romId);
+                byte[] romData = Util.readFile(romMetadata.getRomFile());
+
+                emulator.openSavedState(bios, romData, savedState);
 
                 createThreads();
 
@@ -41,8 +41,13 @@ use audio::create_audio_player;
 use input::create_input;
 use video::{create_video_interface, SCREEN_HEIGHT, SCREEN_WIDTH};
 
+use rustboyadvance_core::bios::builtin_bios;
 use rustboyadvance_core::cartridge::BackupType;
+use rustboyadvance_core::link::LinkCable;
+use rustboyadvance_core::movie::{Movie, MoviePlayer, MovieRecorder};
 use rustboyadvance_core::prelude::*;
+#[cfg(feature = "scripting")]
+use rustboyadvance_core::scripting::ScriptEngine;
 use rustboyadvance_core::util::spawn_and_run_gdb_server;
 use rustboyadvance_core::util::FpsCounter;
 
@@ -150,6 +155,10 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     let matches = clap::App::from_yaml(yaml).get_matches();
 
     let skip_bios = matches.occurrences_of("skip_bios") != 0;
+    let hle_bios = matches.occurrences_of("hle_bios") != 0;
+    let link_host = matches.occurrences_of("link_host") != 0;
+    let force_rtc = matches.occurrences_of("rtc") != 0;
+    let prefetch = matches.occurrences_of("no_prefetch") == 0;
 
     let debug = matches.occurrences_of("debug") != 0;
     let with_gdbserver = matches.occurrences_of("with_gdbserver") != 0;
@@ -158,24 +167,28 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     let sdl_context = sdl2::init().expect("failed to initialize sdl2");
 
     let bios_path = Path::new(matches.value_of("bios").unwrap_or_default());
-    let bios_bin = match read_bin_file(bios_path) {
-        Ok(bios) => bios,
-        _ => match ask_download_bios() {
-            Ok(Some(bios)) => {
-                info!("saving downloaded bios to {}", bios_path.display());
-                write_bin_file(bios_path, &bios)?;
-
-                bios
-            }
-            Ok(None) => {
-                info!("Exiting");
-                std::process::exit(0);
-            }
-            Err(e) => {
-                error!("error when downloading bios: {}", e);
-                std::process::exit(1);
-            }
-        },
+    let bios_bin = if hle_bios {
+        builtin_bios().into_vec()
+    } else {
+        match read_bin_file(bios_path) {
+            Ok(bios) => bios,
+            _ => match ask_download_bios() {
+                Ok(Some(bios)) => {
+                    info!("saving downloaded bios to {}", bios_path.display());
+                    write_bin_file(bios_path, &bios)?;
+
+                    bios
+                }
+                Ok(None) => {
+                    info!("Exiting");
+                    std::process::exit(0);
+                }
+                Err(e) => {
+                    error!("error when downloading bios: {}", e);
+                    std::process::exit(1);
+                }
+            },
+        }
     };
 
     let mut event_pump = sdl_context.event_pump()?;
@@ -229,25 +242,77 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     let mut rom_name = Path::new(&rom_path).file_name().unwrap().to_str().unwrap();
 
-    let gamepak = GamepakBuilder::new()
+    let mut gamepak_builder = GamepakBuilder::new()
         .save_type(BackupType::try_from(
             matches.value_of("save_type").unwrap(),
         )?)
-        .file(Path::new(&rom_path))
-        .build()?;
+        .file(Path::new(&rom_path));
+    if force_rtc {
+        gamepak_builder = gamepak_builder.with_rtc();
+    }
+    let gamepak = gamepak_builder.build()?;
+
+    let link_cable = match matches.value_of("link") {
+        Some(addr) if link_host => {
+            let num_players = value_t!(matches, "link_players", usize).unwrap();
+            info!(
+                "Waiting for {} players to connect to {}",
+                num_players - 1,
+                addr
+            );
+            Some(Rc::new(RefCell::new(LinkCable::host_tcp(
+                addr,
+                num_players,
+            )?)))
+        }
+        Some(addr) => {
+            info!("Connecting link cable to {}", addr);
+            Some(Rc::new(RefCell::new(LinkCable::connect_tcp(addr)?)))
+        }
+        None => None,
+    };
 
     let mut gba = GameBoyAdvance::new(
-        bios_bin.into_boxed_slice(),
+        bios_bin.clone().into_boxed_slice(),
         gamepak,
         video.clone(),
         audio.clone(),
         input.clone(),
     );
+    gba.set_bios_hle(hle_bios);
+    gba.set_prefetch_emulation(prefetch);
+    if let Some(link_cable) = &link_cable {
+        gba.set_serial_device(link_cable.clone());
+    }
 
     if skip_bios {
         gba.skip_bios();
     }
 
+    let mut movie_recorder = match matches.value_of("record") {
+        Some(path) => {
+            info!("Recording movie to {}", path);
+            let recorder = Rc::new(RefCell::new(MovieRecorder::new(
+                input.clone(),
+                Movie::power_on(&gba, skip_bios),
+            )));
+            gba.set_input_device(recorder.clone());
+            Some((PathBuf::from(path), recorder))
+        }
+        None => None,
+    };
+    let mut movie_player = match matches.value_of("play") {
+        Some(path) => {
+            let movie = Movie::from_bytes(&read_bin_file(Path::new(path))?)?;
+            info!("Playing movie {} ({} frames)", path, movie.frame_count());
+            movie.start(&mut gba)?;
+            let player = Rc::new(RefCell::new(MoviePlayer::new(input.clone(), movie)));
+            gba.set_input_device(player.clone());
+            Some(player)
+        }
+        None => None,
+    };
+
     if debug {
         #[cfg(feature = "debugger")]
         {
@@ -268,6 +333,26 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         spawn_and_run_gdb_server(&mut gba, DEFAULT_GDB_SERVER_ADDR)?;
     }
 
+    #[cfg(feature = "scripting")]
+    let mut script_engine = match matches.value_of("lua_script") {
+        Some(path) => {
+            let mut engine = ScriptEngine::new()?;
+            engine.load_file(&mut gba, Path::new(path))?;
+            info!("Running lua script {}", path);
+            Some(engine)
+        }
+        None => None,
+    };
+    #[cfg(not(feature = "scripting"))]
+    {
+        if matches.is_present("lua_script") {
+            panic!("Please compile me with 'scripting' feature");
+        }
+    }
+
+    let mut rewinder = Rewinder::default();
+    let mut rewinding = false;
+
     let mut fps_counter = FpsCounter::default();
     let frame_time = time::Duration::new(0, 1_000_000_000u32 / 60);
     'running: loop {
@@ -280,6 +365,7 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                     ..
                 } => match scancode {
                     Scancode::Space => frame_limiter = false,
+                    Scancode::R => rewinding = true,
                     k => input.borrow_mut().on_keyboard_key_down(k),
                 },
                 Event::KeyUp {
@@ -310,13 +396,16 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                         if savestate_path.is_file() {
                             let save = read_bin_file(&savestate_path)?;
                             info!("Restoring state from {:?}...", savestate_path);
-                            gba.restore_state(&save)?;
-                            info!("Restored!");
+                            match gba.restore_state(&save) {
+                                Ok(_) => info!("Restored!"),
+                                Err(e) => error!("Failed to restore state: {}", e),
+                            }
                         } else {
                             info!("Savestate not created, please create one by pressing F5");
                         }
                     }
                     Scancode::Space => frame_limiter = true,
+                    Scancode::R => rewinding = false,
                     k => input.borrow_mut().on_keyboard_key_up(k),
                 },
                 Event::ControllerButtonDown { button, .. } => {
@@ -330,28 +419,94 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                 }
                 Event::Quit { .. } => break 'running,
                 Event::DropFile { filename, .. } => {
+                    if let Some((path, recorder)) = movie_recorder.take() {
+                        save_movie(&path, &recorder.borrow())?;
+                    }
+                    movie_player = None;
+
                     // load the new rom
                     rom_path = filename;
                     savestate_path = get_savestate_path(&Path::new(&rom_path));
                     rom_name = Path::new(&rom_path).file_name().unwrap().to_str().unwrap();
                     let gamepak = GamepakBuilder::new().file(Path::new(&rom_path)).build()?;
-                    let bios_bin = read_bin_file(bios_path).unwrap();
 
                     // create a new emulator - TODO, export to a function
                     gba = GameBoyAdvance::new(
-                        bios_bin.into_boxed_slice(),
+                        bios_bin.clone().into_boxed_slice(),
                         gamepak,
                         video.clone(),
                         audio.clone(),
                         input.clone(),
                     );
+                    gba.set_bios_hle(hle_bios);
+                    gba.set_prefetch_emulation(prefetch);
+                    if let Some(link_cable) = &link_cable {
+                        gba.set_serial_device(link_cable.clone());
+                    }
                     gba.skip_bios();
+                    rewinder.clear();
                 }
                 _ => {}
             }
         }
 
-        gba.frame();
+        if rewinding && movie_recorder.is_none() && movie_player.is_none() {
+            match rewinder.rewind_one_step(&mut gba) {
+                // the restored state holds the frame it was taken at
+                Ok(_) => video.borrow_mut().render(gba.get_frame_buffer()),
+                Err(e) => {
+                    error!("Failed to rewind: {}", e);
+                    rewinding = false;
+                }
+            }
+        } else {
+            #[cfg(feature = "scripting")]
+            let script_failed = match &mut script_engine {
+                Some(engine) => match engine.run_frame(&mut gba) {
+                    Ok(_) => {
+                        // the frame went to the screen before the script drew over it
+                        video.borrow_mut().render(gba.get_frame_buffer());
+                        false
+                    }
+                    Err(e) => {
+                        error!("Stopping the lua script: {}", e);
+                        true
+                    }
+                },
+                None => {
+                    gba.frame();
+                    false
+                }
+            };
+            #[cfg(feature = "scripting")]
+            {
+                if script_failed {
+                    script_engine = None;
+                }
+            }
+            #[cfg(not(feature = "scripting"))]
+            gba.frame();
+            if let Err(e) = rewinder.on_frame(&gba) {
+                error!("Failed to take a rewind snapshot: {}", e);
+            }
+            if let Some((_, recorder)) = &movie_recorder {
+                recorder.borrow_mut().on_frame(&gba);
+            }
+            let movie_finished = match &movie_player {
+                Some(player) => {
+                    let mut player = player.borrow_mut();
+                    if let Err(desync) = player.on_frame(&gba) {
+                        warn!("{}", desync);
+                    }
+                    player.is_finished()
+                }
+                None => false,
+            };
+            if movie_finished {
+                info!("Movie playback finished");
+                movie_player = None;
+            }
+        }
 
         if let Some(fps) = fps_counter.tick() {
             let title = format!("{} ({} fps)", rom_name, fps);
@@ -370,5 +525,16 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         }
     }
 
+    if let Some((path, recorder)) = movie_recorder {
+        save_movie(&path, &recorder.borrow())?;
+    }
+
+    Ok(())
+}
+
+fn save_movie(path: &Path, recorder: &MovieRecorder) -> Result<(), Box<dyn std::error::Error>> {
+    let movie = recorder.movie();
+    write_bin_file(path, &movie.to_bytes()?)?;
+    info!("Saved movie to {:?} ({} frames)", path, movie.frame_count());
     Ok(())
 }
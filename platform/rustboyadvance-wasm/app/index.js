@@ -7,6 +7,7 @@ var intervalId = 0;
 var romData = null;
 var biosData = null;
 let emulator = null;
+let rewinding = false;
 
 document.getElementById("skipBios").checked = JSON.parse(localStorage.getItem("skipBios"));
 var shouldSkipBios = document.getElementById("skipBios").checked;
@@ -95,7 +96,11 @@ const playAudio = emulator => {
 }
 
 const emulatorLoop = function() {
-    emulator.run_frame(ctx);
+    if (rewinding) {
+        emulator.rewind_one_step(ctx);
+    } else {
+        emulator.run_frame(ctx);
+    }
     fps_text.innerHTML = fpsCounter();
     playAudio(emulator);
 }
@@ -224,13 +229,17 @@ document.getElementById("maxFps").addEventListener('change', e => {
 })
 
 document.addEventListener("keydown", e => {
-    if (null != emulator) {
+    if (e.key == "r") {
+        rewinding = true;
+    } else if (null != emulator) {
         emulator.key_down(e.key)
     }
 }, false);
 
 document.addEventListener("keyup", e => {
-    if (null != emulator) {
+    if (e.key == "r") {
+        rewinding = false;
+    } else if (null != emulator) {
         emulator.key_up(e.key)
     }
 }, false);
\ No newline at end of file
@@ -19,6 +19,7 @@ use bit::BitIndex;
 pub struct Emulator {
     gba: GameBoyAdvance,
     interface: Rc<RefCell<Interface>>,
+    rewinder: Rewinder,
 }
 
 struct Interface {
@@ -102,7 +103,11 @@ impl Emulator {
             interface.clone(),
         );
 
-        Ok(Emulator { gba, interface })
+        Ok(Emulator {
+            gba,
+            interface,
+            rewinder: Rewinder::default(),
+        })
     }
 
     pub fn skip_bios(&mut self) {
@@ -111,6 +116,25 @@ impl Emulator {
 
     pub fn run_frame(&mut self, ctx: &CanvasRenderingContext2d) -> Result<(), JsValue> {
         self.gba.frame();
+        self.rewinder
+            .on_frame(&self.gba)
+            .map_err(|e| JsValue::from_str(&e.to_string()))?;
+        self.draw(ctx)
+    }
+
+    /// Steps the game back in time, returns false once there is nothing left to rewind
+    pub fn rewind_one_step(&mut self, ctx: &CanvasRenderingContext2d) -> Result<bool, JsValue> {
+        let rewound = self
+            .rewinder
+            .rewind_one_step(&mut self.gba)
+            .map_err(|e| JsValue::from_str(&e.to_string()))?;
+        // run the restored frame to have it rendered
+        self.gba.frame();
+        self.draw(ctx)?;
+        Ok(rewound)
+    }
+
+    fn draw(&self, ctx: &CanvasRenderingContext2d) -> Result<(), JsValue> {
         let mut frame_buffer = &mut self.interface.borrow_mut().frame;
         let data = web_sys::ImageData::new_with_u8_clamped_array_and_sh(
             Clamped(&mut frame_buffer),
@@ -0,0 +1,91 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process;
+
+#[macro_use]
+extern crate clap;
+
+use rustboyadvance_core::prelude::*;
+
+mod manifest;
+mod report;
+mod runner;
+mod screenshot;
+
+use manifest::Manifest;
+use runner::{Outcome, RunOptions};
+
+fn main() {
+    let yaml = load_yaml!("cli.yml");
+    let matches = clap::App::from_yaml(yaml).get_matches();
+
+    let manifest_path = Path::new(matches.value_of("manifest").unwrap());
+    let manifest = Manifest::load(manifest_path).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        process::exit(2);
+    });
+    let bios = manifest.bios.as_ref().map(|path| {
+        read_bin_file(path).unwrap_or_else(|e| {
+            eprintln!("failed to read bios {}: {}", path.display(), e);
+            process::exit(2);
+        })
+    });
+
+    let tests = match matches.value_of("filter") {
+        Some(filter) => manifest
+            .tests
+            .into_iter()
+            .filter(|test| test.name.contains(filter))
+            .collect(),
+        None => manifest.tests,
+    };
+    let jobs = if matches.is_present("jobs") {
+        value_t_or_exit!(matches, "jobs", usize)
+    } else {
+        num_cpus::get()
+    };
+
+    let options = RunOptions {
+        update_goldens: matches.is_present("update_goldens"),
+        artifacts_dir: PathBuf::from(matches.value_of("artifacts").unwrap()),
+    };
+
+    let results = runner::run_all(bios, tests, jobs, &options);
+
+    for result in &results {
+        match &result.outcome {
+            Outcome::Passed => println!("PASS  {}", result.name),
+            Outcome::Failed(failures) => {
+                println!("FAIL  {}", result.name);
+                for failure in failures {
+                    println!("        {}", failure);
+                }
+            }
+            Outcome::Error(msg) => {
+                println!("ERROR {}", result.name);
+                println!("        {}", msg);
+            }
+        }
+    }
+    let passed = results
+        .iter()
+        .filter(|r| matches!(r.outcome, Outcome::Passed))
+        .count();
+    println!("\n{}/{} tests passed", passed, results.len());
+
+    let suite_name = manifest_path
+        .file_stem()
+        .and_then(|s| s.to_str())
+        .unwrap_or("rba-test");
+    if let Some(path) = matches.value_of("json") {
+        fs::write(path, report::to_json(&results)).expect("failed to write json report");
+    }
+    if let Some(path) = matches.value_of("junit") {
+        fs::write(path, report::to_junit(suite_name, &results))
+            .expect("failed to write junit report");
+    }
+
+    if passed != results.len() {
+        process::exit(1);
+    }
+}
@@ -0,0 +1,158 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use serde::Deserialize;
+
+use rustboyadvance_core::keypad::Keys;
+
+/// A manifest looks like this:
+///
+/// ```toml
+/// # optional, defaults to the built-in bios
+/// bios = "gba_bios.bin"
+///
+/// [[test]]
+/// name = "arm"
+/// rom = "../external/gba-suite/arm/arm.gba"
+/// frames = 10
+///
+/// [[test.check]]
+/// kind = "register"
+/// register = 12
+/// value = 0
+///
+/// [[test]]
+/// name = "obj-mosaic"
+/// rom = "roms/obj-mosaic.gba"
+/// frames = 120
+///
+/// # keys are held from the given frame until the next input
+/// [[test.input]]
+/// frame = 30
+/// keys = ["A", "Right"]
+///
+/// [[test.check]]
+/// kind = "screenshot"
+/// golden = "goldens/obj-mosaic.png"
+/// ```
+///
+/// Relative paths are resolved against the directory of the manifest.
+#[derive(Deserialize, Debug)]
+pub struct Manifest {
+    pub bios: Option<PathBuf>,
+    #[serde(rename = "test", default)]
+    pub tests: Vec<TestCase>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct TestCase {
+    pub name: String,
+    pub rom: PathBuf,
+    /// Number of frames to run, or the most to run when waiting for a halt loop
+    #[serde(default = "default_frames")]
+    pub frames: usize,
+    #[serde(default = "default_skip_bios")]
+    pub skip_bios: bool,
+    #[serde(rename = "input", default)]
+    pub inputs: Vec<InputEvent>,
+    #[serde(rename = "check", default)]
+    pub checks: Vec<Check>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct InputEvent {
+    pub frame: usize,
+    /// Names of the keys to hold: A, B, Select, Start, Right, Left, Up, Down, R, L
+    #[serde(default)]
+    pub keys: Vec<String>,
+}
+
+pub fn parse_key(name: &str) -> Option<Keys> {
+    match name {
+        "A" => Some(Keys::ButtonA),
+        "B" => Some(Keys::ButtonB),
+        "Select" => Some(Keys::Select),
+        "Start" => Some(Keys::Start),
+        "Right" => Some(Keys::Right),
+        "Left" => Some(Keys::Left),
+        "Up" => Some(Keys::Up),
+        "Down" => Some(Keys::Down),
+        "R" => Some(Keys::ButtonR),
+        "L" => Some(Keys::ButtonL),
+        _ => None,
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Check {
+    /// The rom ended up in a branch-to-self loop, with a register holding the value
+    Register { register: usize, value: u32 },
+    /// sha256 of the frame buffer after running all the frames
+    Framebuffer { sha256: String },
+    Memory {
+        address: u32,
+        value: u32,
+        /// Access width in bits
+        #[serde(default = "default_width")]
+        width: u32,
+    },
+    /// Compares the frame buffer after running all the frames against a png
+    Screenshot {
+        golden: PathBuf,
+        /// Largest difference allowed in each color channel
+        #[serde(default)]
+        tolerance: u8,
+        /// Number of pixels allowed to differ beyond the tolerance
+        #[serde(default)]
+        max_pixels: usize,
+    },
+}
+
+fn default_frames() -> usize {
+    60
+}
+
+fn default_skip_bios() -> bool {
+    true
+}
+
+fn default_width() -> u32 {
+    32
+}
+
+impl Manifest {
+    pub fn load(path: &Path) -> Result<Manifest, String> {
+        let text = fs::read_to_string(path)
+            .map_err(|e| format!("failed to read {}: {}", path.display(), e))?;
+        let mut manifest: Manifest = toml::from_str(&text)
+            .map_err(|e| format!("failed to parse {}: {}", path.display(), e))?;
+
+        let base_dir = path.parent().unwrap_or(Path::new("."));
+        if let Some(bios) = &manifest.bios {
+            manifest.bios = Some(base_dir.join(bios));
+        }
+        for test in &mut manifest.tests {
+            test.rom = base_dir.join(&test.rom);
+            for check in &mut test.checks {
+                match check {
+                    Check::Memory { width, .. } if *width != 8 && *width != 16 && *width != 32 => {
+                        return Err(format!(
+                            "{}: invalid memory access width {}",
+                            test.name, width
+                        ));
+                    }
+                    Check::Screenshot { golden, .. } => *golden = base_dir.join(&golden),
+                    _ => {}
+                }
+            }
+            for input in &test.inputs {
+                if let Some(key) = input.keys.iter().find(|key| parse_key(key).is_none()) {
+                    return Err(format!("{}: unknown key {:?}", test.name, key));
+                }
+            }
+            test.inputs.sort_by_key(|input| input.frame);
+        }
+        Ok(manifest)
+    }
+}
@@ -0,0 +1,91 @@
+use serde_json::json;
+
+use super::runner::{Outcome, TestResult};
+
+fn status(result: &TestResult) -> &'static str {
+    match result.outcome {
+        Outcome::Passed => "passed",
+        Outcome::Failed(_) => "failed",
+        Outcome::Error(_) => "error",
+    }
+}
+
+fn messages(result: &TestResult) -> Vec<String> {
+    match &result.outcome {
+        Outcome::Passed => vec![],
+        Outcome::Failed(failures) => failures.clone(),
+        Outcome::Error(msg) => vec![msg.clone()],
+    }
+}
+
+fn count(results: &[TestResult], status_name: &str) -> usize {
+    results.iter().filter(|r| status(r) == status_name).count()
+}
+
+pub fn to_json(results: &[TestResult]) -> String {
+    let tests: Vec<_> = results
+        .iter()
+        .map(|r| {
+            json!({
+                "name": r.name,
+                "status": status(r),
+                "messages": messages(r),
+                "frames": r.frames,
+                "time": r.duration.as_secs_f64(),
+            })
+        })
+        .collect();
+    let report = json!({
+        "total": results.len(),
+        "passed": count(results, "passed"),
+        "failed": count(results, "failed"),
+        "errors": count(results, "error"),
+        "tests": tests,
+    });
+    serde_json::to_string_pretty(&report).unwrap()
+}
+
+fn escape_xml(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
+pub fn to_junit(suite_name: &str, results: &[TestResult]) -> String {
+    let total_time: f64 = results.iter().map(|r| r.duration.as_secs_f64()).sum();
+    let mut xml = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    xml += &format!(
+        "<testsuite name=\"{}\" tests=\"{}\" failures=\"{}\" errors=\"{}\" time=\"{:.3}\">\n",
+        escape_xml(suite_name),
+        results.len(),
+        count(results, "failed"),
+        count(results, "error"),
+        total_time
+    );
+    for r in results {
+        xml += &format!(
+            "  <testcase name=\"{}\" classname=\"{}\" time=\"{:.3}\"",
+            escape_xml(&r.name),
+            escape_xml(suite_name),
+            r.duration.as_secs_f64()
+        );
+        let tag = match r.outcome {
+            Outcome::Passed => {
+                xml += "/>\n";
+                continue;
+            }
+            Outcome::Failed(_) => "failure",
+            Outcome::Error(_) => "error",
+        };
+        let text = escape_xml(&messages(r).join("\n"));
+        xml += &format!(
+            ">\n    <{tag} message=\"{text}\">{text}</{tag}>\n  </testcase>\n",
+            tag = tag,
+            text = text
+        );
+    }
+    xml += "</testsuite>\n";
+    xml
+}
@@ -0,0 +1,330 @@
+use std::cell::RefCell;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use sha2::{Digest, Sha256};
+
+use rustboyadvance_core::arm7tdmi::CpuState;
+use rustboyadvance_core::bios::builtin_bios;
+use rustboyadvance_core::keypad::KEYINPUT_ALL_RELEASED;
+use rustboyadvance_core::prelude::*;
+
+use super::manifest::{parse_key, Check, InputEvent, TestCase};
+use super::screenshot;
+
+#[derive(Debug, Clone)]
+pub struct RunOptions {
+    /// Write the screenshots as the new goldens instead of comparing against them
+    pub update_goldens: bool,
+    /// Where the actual and the diff images of failed screenshots go
+    pub artifacts_dir: PathBuf,
+}
+
+/// Runs the emulator without any video or audio attached
+struct Headless {}
+
+impl VideoInterface for Headless {}
+impl AudioInterface for Headless {}
+
+/// Holds the keys of the latest input event, input is polled once per frame
+struct ScriptedInput {
+    inputs: Vec<InputEvent>,
+    frame: usize,
+    keyinput: u16,
+}
+
+impl InputInterface for ScriptedInput {
+    fn poll(&mut self) -> u16 {
+        let frame = self.frame;
+        if let Some(event) = self.inputs.iter().rev().find(|e| e.frame == frame) {
+            self.keyinput = event
+                .keys
+                .iter()
+                .filter_map(|key| parse_key(key))
+                .fold(KEYINPUT_ALL_RELEASED, |keyinput, key| {
+                    keyinput & !(1 << key as u16)
+                });
+        }
+        self.frame += 1;
+        self.keyinput
+    }
+}
+
+#[derive(Debug)]
+pub enum Outcome {
+    Passed,
+    /// Messages of the checks that failed
+    Failed(Vec<String>),
+    /// The test could not run to completion
+    Error(String),
+}
+
+#[derive(Debug)]
+pub struct TestResult {
+    pub name: String,
+    pub outcome: Outcome,
+    pub frames: usize,
+    pub duration: Duration,
+}
+
+/// Checks if the cpu is stuck in a branch-to-self loop, which test roms use to signal they are done
+fn is_halt_loop(gba: &GameBoyAdvance) -> bool {
+    let pc = gba.cpu.get_next_pc();
+    match gba.cpu.get_cpu_state() {
+        CpuState::ARM => gba.sysbus.read_32(pc) == 0xeaff_fffe,
+        CpuState::THUMB => gba.sysbus.read_16(pc) == 0xe7fe,
+    }
+}
+
+fn frame_buffer_digest(gba: &GameBoyAdvance) -> String {
+    let mut hasher = Sha256::new();
+    for pixel in gba.get_frame_buffer() {
+        hasher.input(pixel.to_le_bytes());
+    }
+    hasher
+        .result()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+fn check_screenshot(
+    gba: &GameBoyAdvance,
+    test: &TestCase,
+    golden: &Path,
+    tolerance: u8,
+    max_pixels: usize,
+    options: &RunOptions,
+) -> Result<(), String> {
+    let actual = gba.get_frame_buffer();
+    if options.update_goldens {
+        return screenshot::write_png(golden, actual);
+    }
+    let expected = screenshot::read_png(golden)?;
+    let (differing, diff_image) = screenshot::compare(actual, &expected, tolerance);
+    if differing > max_pixels {
+        let stem = golden.file_stem().unwrap().to_string_lossy();
+        let name = format!("{}-{}", test.name, stem);
+        let actual_path = options.artifacts_dir.join(format!("{}.actual.png", name));
+        let diff_path = options.artifacts_dir.join(format!("{}.diff.png", name));
+        screenshot::write_png(&actual_path, actual)?;
+        screenshot::write_png(&diff_path, &diff_image)?;
+        return Err(format!(
+            "{} pixels differ from {}, see {}",
+            differing,
+            golden.display(),
+            diff_path.display()
+        ));
+    }
+    Ok(())
+}
+
+fn evaluate(
+    gba: &GameBoyAdvance,
+    test: &TestCase,
+    check: &Check,
+    halted: bool,
+    options: &RunOptions,
+) -> Result<(), String> {
+    match check {
+        Check::Register { register, value } => {
+            if !halted {
+                return Err("did not reach a halt loop".to_string());
+            }
+            let found = gba.cpu.get_reg(*register);
+            if found != *value {
+                return Err(format!(
+                    "r{} is {:#010x}, expected {:#010x}",
+                    register, found, value
+                ));
+            }
+        }
+        Check::Framebuffer { sha256 } => {
+            let found = frame_buffer_digest(gba);
+            if found != sha256.to_lowercase() {
+                return Err(format!(
+                    "frame buffer hash is {}, expected {}",
+                    found, sha256
+                ));
+            }
+        }
+        Check::Memory {
+            address,
+            value,
+            width,
+        } => {
+            let found = match width {
+                8 => gba.sysbus.read_8(*address) as u32,
+                16 => gba.sysbus.read_16(*address) as u32,
+                _ => gba.sysbus.read_32(*address),
+            };
+            if found != *value {
+                return Err(format!(
+                    "[{:#010x}] is {:#x}, expected {:#x}",
+                    address, found, value
+                ));
+            }
+        }
+        Check::Screenshot {
+            golden,
+            tolerance,
+            max_pixels,
+        } => check_screenshot(gba, test, golden, *tolerance, *max_pixels, options)?,
+    }
+    Ok(())
+}
+
+fn run_checks(
+    bios: Option<&[u8]>,
+    test: &TestCase,
+    options: &RunOptions,
+) -> Result<(usize, Vec<String>), String> {
+    let rom = read_bin_file(&test.rom)
+        .map_err(|e| format!("failed to read {}: {}", test.rom.display(), e))?;
+    let gamepak = GamepakBuilder::new()
+        .take_buffer(rom.into_boxed_slice())
+        .without_backup_to_file()
+        .build()
+        .map_err(|e| format!("failed to load rom: {:?}", e))?;
+
+    let bios_rom = match bios {
+        Some(bios) => bios.to_vec().into_boxed_slice(),
+        None => builtin_bios(),
+    };
+    let headless = Rc::new(RefCell::new(Headless {}));
+    let input = Rc::new(RefCell::new(ScriptedInput {
+        inputs: test.inputs.clone(),
+        frame: 0,
+        keyinput: KEYINPUT_ALL_RELEASED,
+    }));
+    let mut gba = GameBoyAdvance::new(bios_rom, gamepak, headless.clone(), headless, input);
+    gba.set_bios_hle(bios.is_none());
+    if test.skip_bios {
+        gba.skip_bios();
+    }
+
+    let wait_for_halt = test
+        .checks
+        .iter()
+        .any(|check| matches!(check, Check::Register { .. }));
+
+    let mut frames = 0;
+    let mut halted = false;
+    while frames < test.frames {
+        gba.frame();
+        frames += 1;
+        if wait_for_halt && is_halt_loop(&gba) {
+            halted = true;
+            break;
+        }
+    }
+    if wait_for_halt && !halted {
+        halted = is_halt_loop(&gba);
+    }
+
+    let failures = test
+        .checks
+        .iter()
+        .filter_map(|check| evaluate(&gba, test, check, halted, options).err())
+        .collect();
+    Ok((frames, failures))
+}
+
+/// Without a bios, the built-in one is used
+pub fn run_test(bios: Option<&[u8]>, test: &TestCase, options: &RunOptions) -> TestResult {
+    let start = Instant::now();
+    let result = panic::catch_unwind(AssertUnwindSafe(|| run_checks(bios, test, options)));
+    let (outcome, frames) = match result {
+        Ok(Ok((frames, failures))) => {
+            if failures.is_empty() {
+                (Outcome::Passed, frames)
+            } else {
+                (Outcome::Failed(failures), frames)
+            }
+        }
+        Ok(Err(msg)) => (Outcome::Error(msg), 0),
+        Err(panic) => {
+            let msg = if let Some(msg) = panic.downcast_ref::<&str>() {
+                msg.to_string()
+            } else if let Some(msg) = panic.downcast_ref::<String>() {
+                msg.clone()
+            } else {
+                "unknown reason".to_string()
+            };
+            (Outcome::Error(format!("emulator panicked: {}", msg)), 0)
+        }
+    };
+    TestResult {
+        name: test.name.clone(),
+        outcome,
+        frames,
+        duration: start.elapsed(),
+    }
+}
+
+/// Runs the tests on `jobs` threads, the results are in the order of the tests
+pub fn run_all(
+    bios: Option<Vec<u8>>,
+    tests: Vec<TestCase>,
+    jobs: usize,
+    options: &RunOptions,
+) -> Vec<TestResult> {
+    let num_tests = tests.len();
+    let bios = Arc::new(bios);
+    let queue = Arc::new(Mutex::new(tests.into_iter().enumerate()));
+    let (tx, rx) = mpsc::channel();
+
+    let workers: Vec<_> = (0..jobs.max(1).min(num_tests))
+        .map(|_| {
+            let bios = bios.clone();
+            let queue = queue.clone();
+            let tx = tx.clone();
+            let options = options.clone();
+            thread::spawn(move || loop {
+                let next = queue.lock().unwrap().next();
+                match next {
+                    Some((index, test)) => {
+                        let result = run_test(bios.as_deref(), &test, &options);
+                        tx.send((index, result)).unwrap();
+                    }
+                    None => break,
+                }
+            })
+        })
+        .collect();
+    drop(tx);
+
+    let mut results: Vec<(usize, TestResult)> = rx.iter().collect();
+    for worker in workers {
+        worker.join().unwrap();
+    }
+    results.sort_by_key(|(index, _)| *index);
+    results.into_iter().map(|(_, result)| result).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::manifest::Manifest;
+
+    #[test]
+    fn test_selftest_suite() {
+        let manifest_path = Path::new(env!("CARGO_MANIFEST_DIR")).join("suites/selftest.toml");
+        let manifest = Manifest::load(&manifest_path).unwrap();
+        let options = RunOptions {
+            update_goldens: false,
+            artifacts_dir: std::env::temp_dir().join("rba-test-selftest"),
+        };
+        for result in run_all(None, manifest.tests, 1, &options) {
+            match result.outcome {
+                Outcome::Passed => {}
+                outcome => panic!("{}: {:?}", result.name, outcome),
+            }
+        }
+    }
+}
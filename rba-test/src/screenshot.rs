@@ -0,0 +1,111 @@
+use std::fs::{self, File};
+use std::io::BufWriter;
+use std::path::Path;
+
+use rustboyadvance_core::prelude::{DISPLAY_HEIGHT, DISPLAY_WIDTH};
+
+/// Pixels are 0x00RRGGBB, as in the frame buffer of the gpu
+pub fn write_png(path: &Path, pixels: &[u32]) -> Result<(), String> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).map_err(|e| format!("{}: {}", dir.display(), e))?;
+    }
+    let file = File::create(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    let mut encoder = png::Encoder::new(
+        BufWriter::new(file),
+        DISPLAY_WIDTH as u32,
+        DISPLAY_HEIGHT as u32,
+    );
+    encoder.set_color(png::ColorType::RGB);
+    encoder.set_depth(png::BitDepth::Eight);
+    let data: Vec<u8> = pixels
+        .iter()
+        .flat_map(|&pixel| vec![(pixel >> 16) as u8, (pixel >> 8) as u8, pixel as u8])
+        .collect();
+    encoder
+        .write_header()
+        .and_then(|mut writer| writer.write_image_data(&data))
+        .map_err(|e| format!("{}: {}", path.display(), e))
+}
+
+pub fn read_png(path: &Path) -> Result<Vec<u32>, String> {
+    let file = File::open(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    let (info, mut reader) = png::Decoder::new(file)
+        .read_info()
+        .map_err(|e| format!("{}: {}", path.display(), e))?;
+    if info.width as usize != DISPLAY_WIDTH || info.height as usize != DISPLAY_HEIGHT {
+        return Err(format!(
+            "{}: expected a {}x{} image",
+            path.display(),
+            DISPLAY_WIDTH,
+            DISPLAY_HEIGHT
+        ));
+    }
+    let channels = match info.color_type {
+        png::ColorType::RGB => 3,
+        png::ColorType::RGBA => 4,
+        _ => return Err(format!("{}: expected an RGB image", path.display())),
+    };
+    let mut data = vec![0; info.buffer_size()];
+    reader
+        .next_frame(&mut data)
+        .map_err(|e| format!("{}: {}", path.display(), e))?;
+    Ok(data
+        .chunks(channels)
+        .map(|c| (c[0] as u32) << 16 | (c[1] as u32) << 8 | c[2] as u32)
+        .collect())
+}
+
+fn channel_diff(a: u32, b: u32, shift: u32) -> u8 {
+    let a = (a >> shift) as u8;
+    let b = (b >> shift) as u8;
+    a.max(b) - a.min(b)
+}
+
+/// Counts the pixels that differ by more than `tolerance` in any channel,
+/// and draws them red on top of a faded copy of the actual image.
+pub fn compare(actual: &[u32], golden: &[u32], tolerance: u8) -> (usize, Vec<u32>) {
+    let mut differing = 0;
+    let diff_image = actual
+        .iter()
+        .zip(golden)
+        .map(|(&a, &g)| {
+            let max_diff = [0, 8, 16]
+                .iter()
+                .map(|&shift| channel_diff(a, g, shift))
+                .max()
+                .unwrap();
+            if max_diff > tolerance {
+                differing += 1;
+                0x00ff_0000
+            } else {
+                let luma = ((a >> 16 & 0xff) + (a >> 8 & 0xff) + (a & 0xff)) / 3;
+                let faded = luma / 4;
+                faded << 16 | faded << 8 | faded
+            }
+        })
+        .collect();
+    (differing, diff_image)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_png_roundtrip_and_compare() {
+        let pixels: Vec<u32> = (0..(DISPLAY_WIDTH * DISPLAY_HEIGHT) as u32)
+            .map(|i| i.wrapping_mul(0x010203) & 0x00ff_ffff)
+            .collect();
+        let path = std::env::temp_dir().join("rba-test-roundtrip.png");
+        write_png(&path, &pixels).unwrap();
+        assert_eq!(read_png(&path).unwrap(), pixels);
+        let _ = fs::remove_file(&path);
+
+        let mut other = pixels.clone();
+        other[0] ^= 0x000004;
+        other[1] ^= 0x100000;
+        assert_eq!(compare(&pixels, &other, 8).0, 1);
+        assert_eq!(compare(&pixels, &other, 0).0, 2);
+        assert_eq!(compare(&pixels, &pixels, 0).0, 0);
+    }
+}
@@ -138,6 +138,12 @@ impl Core {
                     self.bs_carry_out = (val >> (amount - 1) & 1) == 1;
                     val >> amount
                 }
+                // (From the ARM7TDMI data sheet, 4.5.2) LSR by 32 has result zero, carry out equal
+                // to bit 31 of Rm.
+                32 => {
+                    self.bs_carry_out = val.bit(31);
+                    0
+                }
                 _ => {
                     self.bs_carry_out = false;
                     0
@@ -286,7 +292,7 @@ impl Core {
                 if added {
                     abs as u32
                 } else {
-                    (-(abs as i32)) as u32
+                    abs.wrapping_neg()
                 }
             }
             _ => panic!("bad barrel shift"),
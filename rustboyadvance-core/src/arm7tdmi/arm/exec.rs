@@ -46,7 +46,7 @@ impl Core {
 
     /// Cycles 2S+1N
     pub fn exec_arm_b_bl(&mut self, sb: &mut SysBus, insn: &ArmInstruction) -> CpuAction {
-        self.S_cycle32(sb, self.pc);
+        self.S_fetch32(sb, self.pc);
         if insn.link_flag() {
             self.set_reg(REG_LR, (insn.pc + (self.word_size() as u32)) & !0b1);
         }
@@ -59,8 +59,8 @@ impl Core {
 
     pub fn branch_exchange(&mut self, sb: &mut SysBus, mut addr: Addr) -> CpuAction {
         match self.cpsr.state() {
-            CpuState::ARM => self.S_cycle32(sb, self.pc),
-            CpuState::THUMB => self.S_cycle16(sb, self.pc),
+            CpuState::ARM => self.S_fetch32(sb, self.pc),
+            CpuState::THUMB => self.S_fetch16(sb, self.pc),
         }
         if addr.bit(0) {
             addr = addr & !0x1;
@@ -94,7 +94,7 @@ impl Core {
             self.cpsr.get()
         };
         self.set_reg(rd, result);
-        self.S_cycle32(sb, self.pc);
+        self.S_fetch32(sb, self.pc);
 
         CpuAction::AdvancePC
     }
@@ -164,7 +164,7 @@ impl Core {
                 }
             }
         }
-        self.S_cycle32(sb, self.pc);
+        self.S_fetch32(sb, self.pc);
 
         CpuAction::AdvancePC
     }
@@ -189,7 +189,7 @@ impl Core {
         use AluOpCode::*;
 
         let raw_insn = insn.raw;
-        self.S_cycle32(sb, self.pc);
+        self.S_fetch32(sb, self.pc);
 
         let rn = raw_insn.bit_range(16..20) as usize;
         let rd = raw_insn.bit_range(12..16) as usize;
@@ -331,7 +331,7 @@ impl Core {
         };
 
         if load {
-            self.S_cycle32(sb, self.pc);
+            self.S_fetch32(sb, self.pc);
             let data = if insn.transfer_size() == 1 {
                 self.N_cycle8(sb, addr);
                 sb.read_8(addr) as u32
@@ -362,7 +362,7 @@ impl Core {
                 self.N_cycle32(sb, addr);
                 self.write_32(addr & !0x3, value, sb);
             };
-            self.N_cycle32(sb, self.pc);
+            self.N_fetch32(sb, self.pc);
         }
 
         if !load || base_reg != dest_reg {
@@ -438,7 +438,7 @@ impl Core {
         };
 
         if load {
-            self.S_cycle32(sb, self.pc);
+            self.S_fetch32(sb, self.pc);
             let data = match insn.halfword_data_transfer_type().unwrap() {
                 ArmHalfwordTransferType::SignedByte => {
                     self.N_cycle8(sb, addr);
@@ -474,7 +474,7 @@ impl Core {
                 ArmHalfwordTransferType::UnsignedHalfwords => {
                     self.N_cycle32(sb, addr);
                     self.write_16(addr, value as u16, sb);
-                    self.N_cycle32(sb, self.pc);
+                    self.N_fetch32(sb, self.pc);
                 }
                 _ => panic!("invalid HS flags for L=0"),
             };
@@ -548,7 +548,7 @@ impl Core {
         if rlist != 0 {
             if is_load {
                 self.add_cycle();
-                self.N_cycle32(sb, self.pc);
+                self.N_fetch32(sb, self.pc);
                 for r in 0..16 {
                     if rlist.bit(r) {
                         if r == base_reg {
@@ -559,7 +559,7 @@ impl Core {
                         }
 
                         let val = sb.read_32(addr);
-                        self.S_cycle32(sb, self.pc);
+                        self.S_fetch32(sb, self.pc);
 
                         self.set_reg(r, val);
 
@@ -616,16 +616,23 @@ impl Core {
                         }
                     }
                 }
-                self.N_cycle32(sb, self.pc);
+                self.N_fetch32(sb, self.pc);
             }
         } else {
+            // From gbatek.htm: Empty Rlist: R15 loaded/stored (ARMv4 only), and Rb=Rb+40h (ARMv4-v5).
+            // The timing is the same as for a list holding r15 alone.
             if is_load {
+                self.add_cycle();
+                self.N_fetch32(sb, self.pc);
                 let val = self.ldr_word(addr, sb);
+                self.S_fetch32(sb, self.pc);
                 self.set_reg(REG_PC, val & !3);
                 self.reload_pipeline32(sb);
                 result = CpuAction::FlushPipeline;
             } else {
+                self.N_cycle32(sb, addr);
                 self.write_32(addr, self.pc + 4, sb);
+                self.N_fetch32(sb, self.pc);
             }
             addr = addr.wrapping_add(0x40);
         }
@@ -674,7 +681,7 @@ impl Core {
             self.cpsr.set_V(false);
         }
 
-        self.S_cycle32(sb, self.pc);
+        self.S_fetch32(sb, self.pc);
 
         CpuAction::AdvancePC
     }
@@ -717,7 +724,7 @@ impl Core {
             self.cpsr.set_V(false);
         }
 
-        self.S_cycle32(sb, self.pc);
+        self.S_fetch32(sb, self.pc);
 
         CpuAction::AdvancePC
     }
@@ -739,13 +746,12 @@ impl Core {
             self.set_reg(rd, t as u32);
         }
         self.add_cycle();
-        self.N_cycle32(sb, self.pc);
+        self.N_fetch32(sb, self.pc);
 
         CpuAction::AdvancePC
     }
 
     pub fn exec_arm_swi(&mut self, sb: &mut SysBus, insn: &ArmInstruction) -> CpuAction {
-        self.software_interrupt(sb, self.pc - 4, insn.swi_comment());
-        CpuAction::FlushPipeline
+        self.software_interrupt(sb, self.pc - 4, insn.swi_comment())
     }
 }
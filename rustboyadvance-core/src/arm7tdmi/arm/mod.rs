@@ -112,7 +112,8 @@ impl InstructionDecoder for ArmInstruction {
             BranchExchange
         } else if (0x0e00_0000 & raw) == 0x0a00_0000 {
             BranchLink
-        } else if (0xe000_0010 & raw) == 0x0600_0000 {
+        } else if (0x0e00_0010 & raw) == 0x0600_0010 {
+            // GBATEK "ARM Opcodes: Instruction Set Summary": cond 011x xxxx xxxx xxxx xxxx xxx1 xxxx
             Undefined
         } else if (0x0fb0_0ff0 & raw) == 0x0100_0090 {
             SingleDataSwap
@@ -128,7 +129,8 @@ impl InstructionDecoder for ArmInstruction {
             MoveToFlags
         } else if (0x0c00_0000 & raw) == 0x0400_0000 {
             SingleDataTransfer
-        } else if (0x0e40_0F90 & raw) == 0x0000_0090 {
+        } else if (0x0e40_0090 & raw) == 0x0000_0090 {
+            // bits 11-8 should be zero, but the cpu ignores them, like the dispatch table does
             HalfwordDataTransferRegOffset
         } else if (0x0e40_0090 & raw) == 0x0040_0090 {
             HalfwordDataTransferImmediateOffset
@@ -362,186 +364,3 @@ impl ArmInstruction {
         self.raw.bit_range(0..24)
     }
 }
-
-// #[cfg(test)]
-// /// All instructions constants were generated using an ARM assembler.
-// mod tests {
-//     use super::*;
-//     use crate::arm7tdmi::*;
-//     use crate::sysbus::BoxedMemory;
-
-//     #[test]
-//     fn swi() {
-//         let mut core = Core::new();
-
-//         let bytes = vec![];
-//         let mut mem = BoxedMemory::new(bytes.into_boxed_slice(), 0xffff_ffff);
-
-//         // swi #0x1337
-//         let decoded = ArmInstruction::decode(0xef001337, 0).unwrap();
-//         assert_eq!(decoded.fmt, ArmFormat::SoftwareInterrupt);
-//         assert_eq!(decoded.swi_comment(), 0x1337);
-//         assert_eq!(format!("{}", decoded), "swi\t#0x1337");
-
-//         core.exec_arm(&mut mem, decoded).unwrap();
-//         assert_eq!(core.did_pipeline_flush(), true);
-
-//         assert_eq!(core.cpsr.mode(), CpuMode::Supervisor);
-//         assert_eq!(core.pc, Exception::SoftwareInterrupt as u32);
-//     }
-
-//     #[test]
-//     fn branch_forwards() {
-//         // 0x20:   b 0x30
-//         let decoded = ArmInstruction::decode(0xea_00_00_02, 0x20).unwrap();
-//         assert_eq!(decoded.fmt, ArmFormat::BranchLink);
-//         assert_eq!(decoded.link_flag(), false);
-//         assert_eq!(
-//             (decoded.pc as i32).wrapping_add(decoded.branch_offset()) + 8,
-//             0x30
-//         );
-//         assert_eq!(format!("{}", decoded), "b\t0x30");
-
-//         let mut core = Core::new();
-//         core.pc = 0x20 + 8;
-
-//         let bytes = vec![];
-//         let mut mem = BoxedMemory::new(bytes.into_boxed_slice(), 0xffff_ffff);
-
-//         core.exec_arm(&mut mem, decoded).unwrap();
-//         assert_eq!(core.did_pipeline_flush(), true);
-//         assert_eq!(core.pc, 0x30);
-//     }
-
-//     #[test]
-//     fn branch_link_backwards() {
-//         // 0x20:   bl 0x10
-//         let decoded = ArmInstruction::decode(0xeb_ff_ff_fa, 0x20).unwrap();
-//         assert_eq!(decoded.fmt, ArmFormat::BranchLink);
-//         assert_eq!(decoded.link_flag(), true);
-//         assert_eq!(
-//             (decoded.pc as i32).wrapping_add(decoded.branch_offset()) + 8,
-//             0x10
-//         );
-//         assert_eq!(format!("{}", decoded), "bl\t0x10");
-
-//         let mut core = Core::new();
-//         core.pc = 0x20 + 8;
-
-//         let bytes = vec![];
-//         let mut mem = BoxedMemory::new(bytes.into_boxed_slice(), 0xffff_ffff);
-
-//         core.exec_arm(&mut mem, decoded).unwrap();
-//         assert_eq!(core.did_pipeline_flush(), true);
-//         assert_eq!(core.pc, 0x10);
-//     }
-
-//     #[test]
-//     fn ldr_pre_index() {
-//         // ldreq r2, [r5, -r6, lsl #5]
-//         let decoded = ArmInstruction::decode(0x07_15_22_86, 0).unwrap();
-//         assert_eq!(decoded.fmt, ArmFormat::SingleDataTransfer);
-//         assert_eq!(decoded.cond, ArmCond::EQ);
-//         assert_eq!(decoded.load_flag(), true);
-//         assert_eq!(decoded.pre_index_flag(), true);
-//         assert_eq!(decoded.write_back_flag(), false);
-//         assert_eq!(decoded.rd(), 2);
-//         assert_eq!(decoded.rn(), 5);
-//         assert_eq!(
-//             decoded.ldr_str_offset(),
-//             BarrelShifterValue::ShiftedRegister(ShiftedRegister {
-//                 reg: 6,
-//                 shift_by: ShiftRegisterBy::ByAmount(5),
-//                 bs_op: BarrelShiftOpCode::LSL,
-//                 added: Some(false)
-//             })
-//         );
-
-//         assert_eq!(format!("{}", decoded), "ldreq\tr2, [r5, -r6, lsl #5]");
-
-//         let mut core = Core::new();
-//         core.cpsr.set_Z(true);
-//         core.gpr[5] = 0x34;
-//         core.gpr[6] = 1;
-//         core.gpr[2] = 0;
-
-//         #[rustfmt::skip]
-//         let bytes = vec![
-//             /* 00h: */ 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-//             /* 10h: */ 0x00, 0x00, 0x00, 0x00, 0x37, 0x13, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-//             /* 20h: */ 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-//             /* 30h: */ 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-//         ];
-//         let mut mem = BoxedMemory::new(bytes.into_boxed_slice(), 0xffff_ffff);
-
-//         core.exec_arm(&mut mem, decoded).unwrap();
-//         assert_eq!(core.gpr[2], 0x1337);
-//     }
-
-//     #[test]
-//     fn str_post_index() {
-//         // strteq r2, [r4], -r7, asr #8
-//         let decoded = ArmInstruction::decode(0x06_24_24_47, 0).unwrap();
-//         assert_eq!(decoded.fmt, ArmFormat::SingleDataTransfer);
-//         assert_eq!(decoded.cond, ArmCond::EQ);
-//         assert_eq!(decoded.load_flag(), false);
-//         assert_eq!(decoded.pre_index_flag(), false);
-//         assert_eq!(decoded.write_back_flag(), true);
-//         assert_eq!(decoded.rd(), 2);
-//         assert_eq!(decoded.rn(), 4);
-//         assert_eq!(
-//             decoded.ldr_str_offset(),
-//             BarrelShifterValue::ShiftedRegister(ShiftedRegister {
-//                 reg: 7,
-//                 shift_by: ShiftRegisterBy::ByAmount(8),
-//                 bs_op: BarrelShiftOpCode::ASR,
-//                 added: Some(false)
-//             })
-//         );
-//         assert_eq!(format!("{}", decoded), "strteq\tr2, [r4], -r7, asr #8");
-
-//         let mut core = Core::new();
-//         core.cpsr.set_Z(true);
-//         core.gpr[4] = 0x0;
-//         core.gpr[7] = 1;
-//         core.gpr[2] = 0xabababab;
-
-//         #[rustfmt::skip]
-//         let bytes = vec![
-//             /* 00h: */ 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-//             /* 10h: */ 0x00, 0x00, 0x00, 0x00, 0x37, 0x13, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-//             /* 20h: */ 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-//             /* 30h: */ 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-//         ];
-//         let mut mem = BoxedMemory::new(bytes.into_boxed_slice(), 0xffff_ffff);
-
-//         core.exec_arm(&mut mem, decoded).unwrap();
-//         assert_eq!(mem.read_32(0), 0xabababab);
-//     }
-
-//     #[test]
-//     fn str_pre_index() {
-//         // str r4, [sp, 0x10]
-//         let decoded = ArmInstruction::decode(0xe58d4010, 0).unwrap();
-//         assert_eq!(decoded.fmt, ArmFormat::SingleDataTransfer);
-//         assert_eq!(decoded.cond, ArmCond::AL);
-
-//         let mut core = Core::new();
-//         core.set_reg(4, 0x12345678);
-//         core.set_reg(REG_SP, 0);
-
-//         #[rustfmt::skip]
-//         let bytes = vec![
-//             /*  0: */ 0xaa, 0xbb, 0xcc, 0xdd,
-//             /*  4: */ 0xaa, 0xbb, 0xcc, 0xdd,
-//             /*  8: */ 0xaa, 0xbb, 0xcc, 0xdd,
-//             /*  c: */ 0xaa, 0xbb, 0xcc, 0xdd,
-//             /* 10: */ 0xaa, 0xbb, 0xcc, 0xdd,
-//         ];
-//         let mut mem = BoxedMemory::new(bytes.into_boxed_slice(), 0xffff_ffff);
-
-//         assert_ne!(mem.read_32(core.get_reg(REG_SP) + 0x10), 0x12345678);
-//         core.exec_arm(&mut mem, decoded).unwrap();
-//         assert_eq!(mem.read_32(core.get_reg(REG_SP) + 0x10), 0x12345678);
-//     }
-// }
@@ -0,0 +1,368 @@
+//! Cache of pre-decoded basic blocks.
+//!
+//! When execution enters an address for the first time, the instructions from there up to the next
+//! one that can change the flow of execution are decoded ahead of time into a block, together with
+//! their handlers. The cpu looks a block up once when it enters it, and then follows it instruction
+//! by instruction, so running through a block again doesn't decode or look up anything.
+//! The cpu still executes the blocks one instruction at a time, so timing stays exactly the same
+//! as with the interpreter. Blocks in work ram are dropped when their memory is written to, and
+//! every cached instruction is checked against the opcode in the pipeline before it is used.
+use std::collections::HashMap;
+use std::fmt;
+use std::rc::Rc;
+
+use bit::BitIndex;
+
+use super::arm::{ArmFormat, ArmInstruction};
+use super::cpu::{ArmInstructionHandler, ThumbInstructionHandler, ARM_LUT, THUMB_LUT};
+use super::thumb::{ThumbFormat, ThumbInstruction};
+use super::Addr;
+use crate::sysbus::{code_page, SysBus};
+
+type ArmEntry = (ArmInstruction, ArmInstructionHandler);
+type ThumbEntry = (ThumbInstruction, ThumbInstructionHandler);
+
+/// Straight-line code longer than this is split into several blocks
+const MAX_BLOCK_LEN: usize = 64;
+
+pub(super) fn decode_arm(raw: u32, addr: Addr) -> ArmEntry {
+    let hash = (((raw >> 16) & 0xff0) | ((raw >> 4) & 0x00f)) as usize;
+    let arm_info = &ARM_LUT[hash];
+    (
+        ArmInstruction::new(raw, addr, arm_info.fmt),
+        arm_info.handler_fn,
+    )
+}
+
+pub(super) fn decode_thumb(raw: u16, addr: Addr) -> ThumbEntry {
+    let thumb_info = &THUMB_LUT[(raw >> 6) as usize];
+    (
+        ThumbInstruction::new(raw, addr, thumb_info.fmt),
+        thumb_info.handler_fn,
+    )
+}
+
+trait CachedInstruction: Clone {
+    const SIZE: u32;
+
+    fn decode_at(sb: &SysBus, addr: Addr) -> Self;
+
+    fn raw(&self) -> u32;
+
+    /// Whether the instruction can branch or write the pc
+    fn ends_block(&self) -> bool;
+}
+
+impl CachedInstruction for ArmEntry {
+    const SIZE: u32 = 4;
+
+    fn decode_at(sb: &SysBus, addr: Addr) -> ArmEntry {
+        decode_arm(sb.debug_read_32(addr), addr)
+    }
+
+    fn raw(&self) -> u32 {
+        self.0.raw
+    }
+
+    fn ends_block(&self) -> bool {
+        let raw = self.0.raw;
+        match self.0.fmt {
+            ArmFormat::BranchExchange
+            | ArmFormat::BranchLink
+            | ArmFormat::SoftwareInterrupt
+            | ArmFormat::Undefined => true,
+            ArmFormat::DataProcessing
+            | ArmFormat::SingleDataTransfer
+            | ArmFormat::HalfwordDataTransferRegOffset
+            | ArmFormat::HalfwordDataTransferImmediateOffset => raw.bit_range(12..16) == 15,
+            ArmFormat::BlockDataTransfer => raw.bit(20) && raw.bit(15),
+            _ => false,
+        }
+    }
+}
+
+impl CachedInstruction for ThumbEntry {
+    const SIZE: u32 = 2;
+
+    fn decode_at(sb: &SysBus, addr: Addr) -> ThumbEntry {
+        decode_thumb(sb.debug_read_16(addr), addr)
+    }
+
+    fn raw(&self) -> u32 {
+        self.0.raw as u32
+    }
+
+    fn ends_block(&self) -> bool {
+        let raw = self.0.raw;
+        match self.0.fmt {
+            // bx, or an operation other than cmp on the pc
+            ThumbFormat::HiRegOpOrBranchExchange => {
+                let op = raw.bit_range(8..10);
+                let rd = raw.bit_range(0..3) | (raw.bit(7) as u16) << 3;
+                op == 3 || (op != 1 && rd == 15)
+            }
+            ThumbFormat::PushPop => raw.bit(11) && raw.bit(8),
+            ThumbFormat::BranchLongWithLink => raw.bit(11),
+            ThumbFormat::BranchConditional
+            | ThumbFormat::Swi
+            | ThumbFormat::Branch
+            | ThumbFormat::Undefined => true,
+            _ => false,
+        }
+    }
+}
+
+struct Block<T> {
+    start: Addr,
+    insns: Vec<T>,
+}
+
+impl<T: CachedInstruction> Block<T> {
+    fn build(sb: &SysBus, start: Addr) -> Block<T> {
+        let mut insns = Vec::new();
+        let mut addr = start;
+        loop {
+            let entry = T::decode_at(sb, addr);
+            let ends_block = entry.ends_block();
+            insns.push(entry);
+            addr = addr.wrapping_add(T::SIZE);
+            if ends_block || insns.len() == MAX_BLOCK_LEN {
+                break;
+            }
+        }
+        Block { start, insns }
+    }
+
+    fn addr_of(&self, index: usize) -> Addr {
+        self.start.wrapping_add(index as u32 * T::SIZE)
+    }
+}
+
+#[derive(Clone)]
+struct Blocks<T> {
+    /// Blocks by the address of their first instruction
+    blocks: HashMap<Addr, Rc<Block<T>>>,
+    /// The running block, and the index of the instruction it continues with
+    current: Option<(Rc<Block<T>>, usize)>,
+}
+
+impl<T: CachedInstruction> Blocks<T> {
+    fn new() -> Blocks<T> {
+        Blocks {
+            blocks: HashMap::new(),
+            current: None,
+        }
+    }
+
+    /// Returns the next instruction of the running block if it is the one at `addr`
+    #[inline(always)]
+    fn follow(&mut self, addr: Addr, raw: u32) -> Option<T> {
+        let (block, index) = self.current.as_mut()?;
+        let entry = block.insns.get(*index)?;
+        if block.addr_of(*index) != addr || entry.raw() != raw {
+            return None;
+        }
+        *index += 1;
+        Some(entry.clone())
+    }
+
+    /// Enters the block that starts at `addr`, returns its first instruction and the block if
+    /// it was built just now. Returns None when the memory doesn't hold `raw` anymore.
+    fn enter(&mut self, sb: &SysBus, addr: Addr, raw: u32) -> (Option<T>, Option<Rc<Block<T>>>) {
+        self.current = None;
+        let mut built = None;
+        let block = match self.blocks.get(&addr) {
+            Some(block) if block.insns[0].raw() == raw => block.clone(),
+            _ => {
+                // not cached yet, or memory changed that isn't tracked for writes
+                let block = Rc::new(Block::<T>::build(sb, addr));
+                if block.insns[0].raw() != raw {
+                    // the opcode in the pipeline is older than a write that was just made to it
+                    self.blocks.remove(&addr);
+                    return (None, None);
+                }
+                self.blocks.insert(addr, block.clone());
+                built = Some(block.clone());
+                block
+            }
+        };
+        let entry = block.insns[0].clone();
+        self.current = Some((block, 1));
+        (Some(entry), built)
+    }
+
+    fn remove(&mut self, start: Addr) {
+        self.blocks.remove(&start);
+        self.current = None;
+    }
+
+    fn clear(&mut self) {
+        self.blocks.clear();
+        self.current = None;
+    }
+}
+
+#[derive(Clone)]
+pub struct BlockCache {
+    arm: Blocks<ArmEntry>,
+    thumb: Blocks<ThumbEntry>,
+    /// The blocks that have instructions in each page of work ram, with whether they are THUMB
+    pages: HashMap<usize, Vec<(bool, Addr)>>,
+}
+
+impl fmt::Debug for BlockCache {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BlockCache")
+            .field("arm_blocks", &self.arm.blocks.len())
+            .field("thumb_blocks", &self.thumb.blocks.len())
+            .finish()
+    }
+}
+
+impl Default for BlockCache {
+    fn default() -> BlockCache {
+        BlockCache {
+            arm: Blocks::new(),
+            thumb: Blocks::new(),
+            pages: HashMap::new(),
+        }
+    }
+}
+
+impl BlockCache {
+    pub fn new() -> BlockCache {
+        BlockCache::default()
+    }
+
+    pub fn clear(&mut self) {
+        self.arm.clear();
+        self.thumb.clear();
+        self.pages.clear();
+    }
+
+    /// Returns the decoded ARM instruction `raw` that is executed at `addr`
+    #[inline]
+    pub(super) fn get_arm(&mut self, addr: Addr, raw: u32, sb: &mut SysBus) -> ArmEntry {
+        self.invalidate_written_pages(sb);
+        if let Some(entry) = self.arm.follow(addr, raw) {
+            return entry;
+        }
+        let (entry, built) = self.arm.enter(sb, addr, raw);
+        if let Some(block) = built {
+            self.watch(sb, &block, false);
+        }
+        entry.unwrap_or_else(|| decode_arm(raw, addr))
+    }
+
+    /// Returns the decoded THUMB instruction `raw` that is executed at `addr`
+    #[inline]
+    pub(super) fn get_thumb(&mut self, addr: Addr, raw: u16, sb: &mut SysBus) -> ThumbEntry {
+        self.invalidate_written_pages(sb);
+        if let Some(entry) = self.thumb.follow(addr, raw as u32) {
+            return entry;
+        }
+        let (entry, built) = self.thumb.enter(sb, addr, raw as u32);
+        if let Some(block) = built {
+            self.watch(sb, &block, true);
+        }
+        entry.unwrap_or_else(|| decode_thumb(raw, addr))
+    }
+
+    /// Drops the block when one of the pages of work ram it was decoded from is written to
+    fn watch<T: CachedInstruction>(&mut self, sb: &mut SysBus, block: &Block<T>, thumb: bool) {
+        for index in 0..block.insns.len() {
+            if let Some(page) = code_page(block.addr_of(index)) {
+                sb.code_pages.watch(page);
+                let blocks = self.pages.entry(page).or_default();
+                if !blocks.contains(&(thumb, block.start)) {
+                    blocks.push((thumb, block.start));
+                }
+            }
+        }
+    }
+
+    #[inline]
+    fn invalidate_written_pages(&mut self, sb: &mut SysBus) {
+        if !sb.code_pages.any_written() {
+            return;
+        }
+        for page in sb.code_pages.take_written() {
+            for (thumb, start) in self.pages.remove(&page).unwrap_or_default() {
+                if thumb {
+                    self.thumb.remove(start);
+                } else {
+                    self.arm.remove(start);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::BlockCache;
+    use crate::gba::tests::{make_mock_gba, make_rom};
+    use crate::prelude::*;
+
+    fn make_gba(rom: &[u8], block_cache: bool) -> GameBoyAdvance {
+        let mut gba = make_mock_gba(rom);
+        gba.cpu.set_block_cache(block_cache);
+        gba
+    }
+
+    #[test]
+    fn test_blocks_are_decoded_once() {
+        let program: [u32; 4] = [
+            0xe3a0_0001, // loop: mov r0, #1
+            0xe280_0001, // add r0, r0, #1
+            0xeaff_fffc, // b loop
+            0xe3a0_1001, // mov r1, #1
+        ];
+        let rom = make_rom(&program);
+        let mut gba = make_gba(&rom, false);
+        let mut cache = BlockCache::new();
+        for _ in 0..3 {
+            for (i, &insn) in program[..3].iter().enumerate() {
+                let addr = 0x0800_0000 + 4 * i as u32;
+                let (decoded, _) = cache.get_arm(addr, insn, &mut gba.sysbus);
+                assert_eq!((decoded.raw, decoded.pc), (insn, addr));
+            }
+        }
+        // the block ends at the branch
+        assert_eq!(cache.arm.blocks.len(), 1);
+        assert_eq!(cache.arm.blocks[&0x0800_0000].insns.len(), 3);
+    }
+
+    #[test]
+    fn test_self_modifying_code_in_lockstep() {
+        let program: [u32; 15] = [
+            0xe3a0_0403, // mov r0, #0x03000000
+            0xe59f_1028, // ldr r1, =0xe2822001 (add r2, r2, #1)
+            0xe59f_3028, // ldr r3, =0xe12fff1e (bx lr)
+            0xe580_1000, // str r1, [r0]
+            0xe580_3004, // str r3, [r0, #4]
+            0xe1a0_e00f, // loop: mov lr, pc
+            0xe1a0_f000, // mov pc, r0
+            0xe281_1001, // add r1, r1, #1
+            0xe580_1000, // str r1, [r0]
+            0xe285_5001, // add r5, r5, #1
+            0xe355_000a, // cmp r5, #10
+            0x1aff_fff8, // bne loop
+            0xeaff_fffe, // b .
+            0xe282_2001,
+            0xe12f_ff1e,
+        ];
+        let rom = make_rom(&program);
+
+        let mut interpreter = make_gba(&rom, false);
+        let mut cached = make_gba(&rom, true);
+        for _ in 0..200 {
+            assert_eq!(interpreter.step_cpu(), cached.step_cpu());
+            assert_eq!(interpreter.cpu.pc, cached.cpu.pc);
+            assert_eq!(interpreter.cpu.get_registers(), cached.cpu.get_registers());
+            assert_eq!(interpreter.cpu.cpsr.get(), cached.cpu.cpsr.get());
+        }
+        // the routine in iwram was rewritten before every call
+        assert_eq!(cached.cpu.get_reg(2), (1..=10).sum::<u32>());
+    }
+}
@@ -15,9 +15,12 @@ include!(concat!(env!("OUT_DIR"), "/arm_lut.rs"));
 #[cfg(feature = "arm7tdmi_dispatch_table")]
 include!(concat!(env!("OUT_DIR"), "/thumb_lut.rs"));
 
-#[cfg(not(feature = "arm7tdmi_dispatch_table"))]
+#[cfg(any(test, feature = "lockstep", not(feature = "arm7tdmi_dispatch_table")))]
 use super::InstructionDecoder;
 
+#[cfg(feature = "block_cache")]
+use super::block_cache::{self, BlockCache};
+
 pub use super::exception::Exception;
 
 use super::CpuAction;
@@ -26,7 +29,10 @@ use super::DecodedInstruction;
 use super::{arm::*, psr::RegPSR, thumb::ThumbInstruction, Addr, CpuMode, CpuState};
 
 use crate::bus::Bus;
-use crate::sysbus::{MemoryAccessType::*, MemoryAccessWidth::*, SysBus};
+use crate::sysbus::consts::{PAGE_BIOS, PAGE_IWRAM, PAGE_OAM};
+use crate::sysbus::{
+    MemoryAccessType, MemoryAccessType::*, MemoryAccessWidth, MemoryAccessWidth::*, SysBus,
+};
 
 use bit::BitIndex;
 use num::FromPrimitive;
@@ -54,6 +60,9 @@ pub struct Core {
     pub last_executed: Option<DecodedInstruction>,
 
     pub cycles: usize,
+    /// `cycles` when the current instruction started, the scheduler only catches up after it
+    #[serde(skip)]
+    step_start_cycles: usize,
 
     // store the gpr before executing an instruction to show diff in the Display impl
     gpr_previous: [u32; 15],
@@ -66,6 +75,20 @@ pub struct Core {
     pub trace_opcodes: bool,
 
     pub trace_exceptions: bool,
+
+    /// Handle software interrupts natively instead of jumping to the bios
+    pub bios_hle: bool,
+    pub(crate) hle_intr_waiting: bool,
+
+    /// Executes through `InstructionDecoder` and a `match` even with the dispatch table built in
+    #[cfg(any(test, feature = "lockstep"))]
+    #[serde(skip)]
+    decoder_dispatch: bool,
+
+    /// Decoded instructions, None when the cache is turned off
+    #[cfg(feature = "block_cache")]
+    #[serde(skip)]
+    block_cache: Option<Box<BlockCache>>,
 }
 
 impl Core {
@@ -74,10 +97,33 @@ impl Core {
         Core {
             memreq: 0xffff_0000, // set memreq to an invalid addr so the first load cycle will be non-sequential
             cpsr: cpsr,
+            #[cfg(feature = "block_cache")]
+            block_cache: Some(Box::new(BlockCache::new())),
             ..Default::default()
         }
     }
 
+    /// Bypasses the dispatch table and the block cache, to test them against the decoder
+    #[cfg(any(test, feature = "lockstep"))]
+    pub fn set_decoder_dispatch(&mut self, enable: bool) {
+        self.decoder_dispatch = enable;
+    }
+
+    /// Turns the cache of decoded instructions on or off, see `block_cache`
+    #[cfg(feature = "block_cache")]
+    pub fn set_block_cache(&mut self, enable: bool) {
+        self.block_cache = if enable {
+            Some(Box::new(BlockCache::new()))
+        } else {
+            None
+        };
+    }
+
+    #[cfg(feature = "block_cache")]
+    pub fn is_block_cache_enabled(&self) -> bool {
+        self.block_cache.is_some()
+    }
+
     pub fn set_verbose(&mut self, v: bool) {
         self.verbose = v;
     }
@@ -188,7 +234,7 @@ impl Core {
         self.gpr.clone()
     }
 
-    pub(super) fn change_mode(&mut self, old_mode: CpuMode, new_mode: CpuMode) {
+    pub(crate) fn change_mode(&mut self, old_mode: CpuMode, new_mode: CpuMode) {
         let new_index = new_mode.bank_index();
         let old_index = old_mode.bank_index();
 
@@ -253,38 +299,75 @@ impl Core {
 
     #[allow(non_snake_case)]
     #[inline(always)]
-    pub(super) fn S_cycle32(&mut self, sb: &SysBus, addr: u32) {
-        self.cycles += sb.get_cycles(addr, Seq, MemoryAccess32);
+    pub(super) fn S_cycle32(&mut self, sb: &mut SysBus, addr: u32) {
+        self.cycles += sb.get_cpu_cycles(addr, Seq, MemoryAccess32);
+    }
+
+    #[allow(non_snake_case)]
+    #[inline(always)]
+    pub(super) fn S_cycle16(&mut self, sb: &mut SysBus, addr: u32) {
+        self.cycles += sb.get_cpu_cycles(addr, Seq, MemoryAccess16);
+    }
+
+    #[allow(non_snake_case)]
+    #[inline(always)]
+    pub(super) fn S_cycle8(&mut self, sb: &mut SysBus, addr: u32) {
+        self.cycles += sb.get_cpu_cycles(addr, Seq, MemoryAccess8);
     }
 
     #[allow(non_snake_case)]
     #[inline(always)]
-    pub(super) fn S_cycle16(&mut self, sb: &SysBus, addr: u32) {
-        self.cycles += sb.get_cycles(addr, Seq, MemoryAccess16);
+    pub(super) fn N_cycle32(&mut self, sb: &mut SysBus, addr: u32) {
+        self.cycles += sb.get_cpu_cycles(addr, NonSeq, MemoryAccess32);
     }
 
     #[allow(non_snake_case)]
     #[inline(always)]
-    pub(super) fn S_cycle8(&mut self, sb: &SysBus, addr: u32) {
-        self.cycles += sb.get_cycles(addr, Seq, MemoryAccess8);
+    pub(super) fn N_cycle16(&mut self, sb: &mut SysBus, addr: u32) {
+        self.cycles += sb.get_cpu_cycles(addr, NonSeq, MemoryAccess16);
+    }
+
+    #[allow(non_snake_case)]
+    #[inline(always)]
+    pub(super) fn N_cycle8(&mut self, sb: &mut SysBus, addr: u32) {
+        self.cycles += sb.get_cpu_cycles(addr, NonSeq, MemoryAccess8);
+    }
+
+    /// Opcode fetches from the gamepak go through the prefetch buffer
+    #[inline(always)]
+    fn fetch_cycles(
+        &mut self,
+        sb: &mut SysBus,
+        addr: u32,
+        access: MemoryAccessType,
+        width: MemoryAccessWidth,
+    ) {
+        let elapsed = self.cycles - self.step_start_cycles;
+        self.cycles += sb.get_fetch_cycles(elapsed, addr, access, width);
     }
 
     #[allow(non_snake_case)]
     #[inline(always)]
-    pub(super) fn N_cycle32(&mut self, sb: &SysBus, addr: u32) {
-        self.cycles += sb.get_cycles(addr, NonSeq, MemoryAccess32);
+    pub(super) fn S_fetch32(&mut self, sb: &mut SysBus, addr: u32) {
+        self.fetch_cycles(sb, addr, Seq, MemoryAccess32);
     }
 
     #[allow(non_snake_case)]
     #[inline(always)]
-    pub(super) fn N_cycle16(&mut self, sb: &SysBus, addr: u32) {
-        self.cycles += sb.get_cycles(addr, NonSeq, MemoryAccess16);
+    pub(super) fn S_fetch16(&mut self, sb: &mut SysBus, addr: u32) {
+        self.fetch_cycles(sb, addr, Seq, MemoryAccess16);
     }
 
     #[allow(non_snake_case)]
     #[inline(always)]
-    pub(super) fn N_cycle8(&mut self, sb: &SysBus, addr: u32) {
-        self.cycles += sb.get_cycles(addr, NonSeq, MemoryAccess8);
+    pub(super) fn N_fetch32(&mut self, sb: &mut SysBus, addr: u32) {
+        self.fetch_cycles(sb, addr, NonSeq, MemoryAccess32);
+    }
+
+    #[allow(non_snake_case)]
+    #[inline(always)]
+    pub(super) fn N_fetch16(&mut self, sb: &mut SysBus, addr: u32) {
+        self.fetch_cycles(sb, addr, NonSeq, MemoryAccess16);
     }
 
     #[inline]
@@ -315,7 +398,35 @@ impl Core {
         self.last_executed = Some(d);
     }
 
-    #[cfg(feature = "arm7tdmi_dispatch_table")]
+    #[cfg(feature = "block_cache")]
+    fn step_arm_exec(&mut self, insn: u32, sb: &mut SysBus) -> CpuAction {
+        let addr = self.pc.wrapping_sub(8);
+        let (arm_insn, handler_fn) = match &mut self.block_cache {
+            Some(cache) => cache.get_arm(addr, insn, sb),
+            None => block_cache::decode_arm(insn, addr),
+        };
+
+        #[cfg(feature = "debugger")]
+        self.debugger_record_step(DecodedInstruction::Arm(arm_insn.clone()));
+
+        handler_fn(self, sb, &arm_insn)
+    }
+
+    #[cfg(feature = "block_cache")]
+    fn step_thumb_exec(&mut self, insn: u16, sb: &mut SysBus) -> CpuAction {
+        let addr = self.pc.wrapping_sub(4);
+        let (thumb_insn, handler_fn) = match &mut self.block_cache {
+            Some(cache) => cache.get_thumb(addr, insn, sb),
+            None => block_cache::decode_thumb(insn, addr),
+        };
+
+        #[cfg(feature = "debugger")]
+        self.debugger_record_step(DecodedInstruction::Thumb(thumb_insn.clone()));
+
+        handler_fn(self, sb, &thumb_insn)
+    }
+
+    #[cfg(all(feature = "arm7tdmi_dispatch_table", not(feature = "block_cache")))]
     fn step_arm_exec(&mut self, insn: u32, sb: &mut SysBus) -> CpuAction {
         let hash = (((insn >> 16) & 0xff0) | ((insn >> 4) & 0x00f)) as usize;
         let arm_info = &ARM_LUT[hash];
@@ -327,7 +438,7 @@ impl Core {
         (arm_info.handler_fn)(self, sb, &arm_insn)
     }
 
-    #[cfg(feature = "arm7tdmi_dispatch_table")]
+    #[cfg(all(feature = "arm7tdmi_dispatch_table", not(feature = "block_cache")))]
     fn step_thumb_exec(&mut self, insn: u16, sb: &mut SysBus) -> CpuAction {
         let thumb_info = &THUMB_LUT[(insn >> 6) as usize];
         let thumb_insn = ThumbInstruction::new(insn, self.pc.wrapping_sub(4), thumb_info.fmt);
@@ -338,8 +449,9 @@ impl Core {
         (thumb_info.handler_fn)(self, sb, &thumb_insn)
     }
 
-    #[cfg(not(feature = "arm7tdmi_dispatch_table"))]
-    fn step_arm_exec(&mut self, insn: u32, sb: &mut SysBus) -> CpuAction {
+    /// Decodes through `InstructionDecoder` and executes through a `match`, without any table
+    #[cfg(any(test, feature = "lockstep", not(feature = "arm7tdmi_dispatch_table")))]
+    fn decode_exec_arm(&mut self, insn: u32, sb: &mut SysBus) -> CpuAction {
         let arm_insn = ArmInstruction::decode(insn, self.pc.wrapping_sub(8));
 
         #[cfg(feature = "debugger")]
@@ -348,8 +460,8 @@ impl Core {
         self.exec_arm(sb, &arm_insn)
     }
 
-    #[cfg(not(feature = "arm7tdmi_dispatch_table"))]
-    fn step_thumb_exec(&mut self, insn: u16, sb: &mut SysBus) -> CpuAction {
+    #[cfg(any(test, feature = "lockstep", not(feature = "arm7tdmi_dispatch_table")))]
+    fn decode_exec_thumb(&mut self, insn: u16, sb: &mut SysBus) -> CpuAction {
         let thumb_insn = ThumbInstruction::decode(insn, self.pc.wrapping_sub(4));
 
         #[cfg(feature = "debugger")]
@@ -358,23 +470,35 @@ impl Core {
         self.exec_thumb(sb, &thumb_insn)
     }
 
+    #[cfg(not(feature = "arm7tdmi_dispatch_table"))]
+    #[inline(always)]
+    fn step_arm_exec(&mut self, insn: u32, sb: &mut SysBus) -> CpuAction {
+        self.decode_exec_arm(insn, sb)
+    }
+
+    #[cfg(not(feature = "arm7tdmi_dispatch_table"))]
+    #[inline(always)]
+    fn step_thumb_exec(&mut self, insn: u16, sb: &mut SysBus) -> CpuAction {
+        self.decode_exec_thumb(insn, sb)
+    }
+
     #[inline(always)]
     pub fn reload_pipeline16(&mut self, sb: &mut SysBus) {
-        self.pipeline[0] = sb.read_16(self.pc) as u32;
-        self.N_cycle16(sb, self.pc);
+        self.pipeline[0] = sb.fetch_16(self.pc) as u32;
+        self.N_fetch16(sb, self.pc);
         self.advance_thumb();
-        self.pipeline[1] = sb.read_16(self.pc) as u32;
-        self.S_cycle16(sb, self.pc);
+        self.pipeline[1] = sb.fetch_16(self.pc) as u32;
+        self.S_fetch16(sb, self.pc);
         self.advance_thumb();
     }
 
     #[inline(always)]
     pub fn reload_pipeline32(&mut self, sb: &mut SysBus) {
-        self.pipeline[0] = sb.read_32(self.pc);
-        self.N_cycle16(sb, self.pc);
+        self.pipeline[0] = sb.fetch_32(self.pc);
+        self.N_fetch32(sb, self.pc);
         self.advance_arm();
-        self.pipeline[1] = sb.read_32(self.pc);
-        self.S_cycle16(sb, self.pc);
+        self.pipeline[1] = sb.fetch_32(self.pc);
+        self.S_fetch32(sb, self.pc);
         self.advance_arm();
     }
 
@@ -388,37 +512,75 @@ impl Core {
         self.pc = self.pc.wrapping_add(4)
     }
 
+    /// In THUMB state the word left on the bus depends on the region the opcode at `pc` was
+    /// fetched from, see GBATEK "Unpredictable Things"
+    fn update_thumb_open_bus(&self, sb: &mut SysBus, pc: Addr) {
+        let (decoded, fetched) = (self.pipeline[0], self.pipeline[1]);
+        let aligned = pc & 2 == 0;
+        let value = match (pc >> 24) as usize {
+            PAGE_BIOS | PAGE_OAM if aligned => fetched | (sb.debug_read_16(pc + 2) as u32) << 16,
+            PAGE_BIOS | PAGE_OAM => decoded | fetched << 16,
+            PAGE_IWRAM if aligned => (sb.open_bus_value() & 0xffff_0000) | fetched,
+            PAGE_IWRAM => (sb.open_bus_value() & 0xffff) | fetched << 16,
+            _ => fetched | fetched << 16,
+        };
+        sb.set_open_bus_value(value);
+    }
+
     /// Perform a pipeline step
     /// If an instruction was executed in this step, return it.
     pub fn step(&mut self, bus: &mut SysBus) {
+        self.step_start_cycles = self.cycles;
+        #[cfg(any(test, feature = "lockstep"))]
+        {
+            if self.decoder_dispatch {
+                return self.step_with(bus, Core::decode_exec_arm, Core::decode_exec_thumb);
+            }
+        }
+        self.step_with(bus, Core::step_arm_exec, Core::step_thumb_exec)
+    }
+
+    #[inline(always)]
+    fn step_with<A, T>(&mut self, bus: &mut SysBus, exec_arm: A, exec_thumb: T)
+    where
+        A: Fn(&mut Core, u32, &mut SysBus) -> CpuAction,
+        T: Fn(&mut Core, u16, &mut SysBus) -> CpuAction,
+    {
         let pc = self.pc;
 
         match self.cpsr.state() {
             CpuState::ARM => {
-                let fetched_now = bus.read_32(pc);
+                let fetched_now = bus.fetch_32(pc);
                 let insn = self.pipeline[0];
                 self.pipeline[0] = self.pipeline[1];
                 self.pipeline[1] = fetched_now;
+                bus.set_open_bus_value(fetched_now);
                 let cond =
                     ArmCond::from_u32(insn.bit_range(28..32)).expect("invalid arm condition");
                 if cond != ArmCond::AL {
                     if !self.check_arm_cond(cond) {
-                        self.S_cycle32(bus, self.pc);
+                        // skipped instructions are part of the block too
+                        #[cfg(feature = "block_cache")]
+                        if let Some(cache) = &mut self.block_cache {
+                            cache.get_arm(pc.wrapping_sub(8), insn, bus);
+                        }
+                        self.S_fetch32(bus, self.pc);
                         self.advance_arm();
                         return;
                     }
                 }
-                match self.step_arm_exec(insn, bus) {
+                match exec_arm(self, insn, bus) {
                     CpuAction::AdvancePC => self.advance_arm(),
                     CpuAction::FlushPipeline => {}
                 }
             }
             CpuState::THUMB => {
-                let fetched_now = bus.read_16(pc);
+                let fetched_now = bus.fetch_16(pc);
                 let insn = self.pipeline[0];
                 self.pipeline[0] = self.pipeline[1];
                 self.pipeline[1] = fetched_now as u32;
-                match self.step_thumb_exec(insn as u16, bus) {
+                self.update_thumb_open_bus(bus, pc);
+                match exec_thumb(self, insn as u16, bus) {
                     CpuAction::AdvancePC => self.advance_thumb(),
                     CpuAction::FlushPipeline => {}
                 }
@@ -432,6 +594,11 @@ impl Core {
         self.pc - 2 * insn_size
     }
 
+    /// The opcode of the next instruction, as it was fetched into the pipeline
+    pub fn get_next_opcode(&self) -> u32 {
+        self.pipeline[0]
+    }
+
     pub fn get_cpu_state(&self) -> CpuState {
         self.cpsr.state()
     }
@@ -1,6 +1,6 @@
 use super::super::sysbus::SysBus;
 use super::cpu::Core;
-use super::{CpuMode, CpuState};
+use super::{CpuAction, CpuMode, CpuState};
 use colored::*;
 
 #[derive(Debug, Clone, Copy, PartialEq)]
@@ -66,11 +66,23 @@ impl Core {
         }
     }
 
-    pub fn software_interrupt(&mut self, sb: &mut SysBus, lr: u32, _cmt: u32) {
-        match self.cpsr.state() {
-            CpuState::ARM => self.N_cycle32(sb, self.pc),
-            CpuState::THUMB => self.N_cycle16(sb, self.pc),
+    pub fn software_interrupt(&mut self, sb: &mut SysBus, lr: u32, cmt: u32) -> CpuAction {
+        let swi = match self.cpsr.state() {
+            CpuState::ARM => {
+                self.N_fetch32(sb, self.pc);
+                (cmt >> 16) & 0xff
+            }
+            CpuState::THUMB => {
+                self.N_fetch16(sb, self.pc);
+                cmt & 0xff
+            }
         };
+        if self.bios_hle {
+            if let Some(action) = crate::bios::hle_swi(self, sb, swi as u8) {
+                return action;
+            }
+        }
         self.exception(sb, Exception::SoftwareInterrupt, lr);
+        CpuAction::FlushPipeline
     }
 }
@@ -9,6 +9,8 @@ pub mod thumb;
 use arm::ArmInstruction;
 use thumb::ThumbInstruction;
 
+#[cfg(feature = "block_cache")]
+pub mod block_cache;
 pub mod cpu;
 pub use cpu::*;
 pub mod alu;
@@ -17,6 +19,9 @@ pub mod exception;
 pub mod psr;
 pub use psr::*;
 
+#[cfg(test)]
+mod tests;
+
 pub const REG_PC: usize = 15;
 pub const REG_LR: usize = 14;
 pub const REG_SP: usize = 13;
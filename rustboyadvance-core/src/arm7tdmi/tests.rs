@@ -0,0 +1,269 @@
+//! Table driven tests of single instructions.
+//!
+//! The vectors live in `tests/arm7tdmi/{arm,thumb}.json`, each one looks like this:
+//!
+//! ```json
+//! {
+//!     "name": "adds r0, r1, r2 (carry out)",
+//!     "opcode": "0xe0910002",
+//!     "initial": { "regs": { "r1": "0xffffffff", "r2": "0x1" } },
+//!     "expected": { "regs": { "r0": "0x0" }, "cpsr": "0x6000001f" },
+//!     "cycles": 1
+//! }
+//! ```
+//!
+//! The opcode is executed from `INSN_ADDR` in iwram, where every access takes a single cycle, in
+//! system mode after `skip_bios`. Registers that aren't given start at zero, and the cpsr starts as
+//! 0x1f in ARM vectors and 0x3f in THUMB vectors. `memory` holds words to write before, and words
+//! to check after the instruction. Everything that isn't expected to change has to stay the same,
+//! and `pc` is the address of the next instruction to execute.
+//! Every vector runs on each of the back-ends in `lockstep::Backend`.
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Deserializer};
+
+use super::arm::{ArmFormat, ArmInstruction};
+use super::thumb::{ThumbFormat, ThumbInstruction};
+use super::{CpuState, InstructionDecoder, REG_PC};
+use crate::gba::tests::make_mock_gba;
+use crate::lockstep::Backend;
+use crate::prelude::*;
+use crate::Addr;
+
+const INSN_ADDR: Addr = 0x0300_1000;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+struct Hex(u32);
+
+impl<'de> Deserialize<'de> for Hex {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Hex, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        u32::from_str_radix(s.trim_start_matches("0x"), 16)
+            .map(Hex)
+            .map_err(|_| serde::de::Error::custom(format!("bad hex value {:?}", s)))
+    }
+}
+
+#[derive(Deserialize, Debug, Default)]
+struct Snapshot {
+    #[serde(default)]
+    regs: BTreeMap<String, Hex>,
+    cpsr: Option<Hex>,
+    #[serde(default)]
+    memory: BTreeMap<Hex, Hex>,
+}
+
+#[derive(Deserialize, Debug)]
+struct Vector {
+    name: String,
+    opcode: Hex,
+    #[serde(default)]
+    initial: Snapshot,
+    #[serde(default)]
+    expected: Snapshot,
+    cycles: usize,
+}
+
+fn reg_index(name: &str) -> usize {
+    match name {
+        "sp" => 13,
+        "lr" => 14,
+        "pc" => REG_PC,
+        _ => name
+            .trim_start_matches('r')
+            .parse()
+            .ok()
+            .filter(|&r| r <= REG_PC)
+            .unwrap_or_else(|| panic!("bad register name {:?}", name)),
+    }
+}
+
+fn make_gba(backend: Backend) -> GameBoyAdvance {
+    let mut gba = make_mock_gba(&[0; 0x200]);
+    backend.configure(&mut gba);
+    gba
+}
+
+/// Returns what went differently than expected
+fn run_vector(vector: &Vector, state: CpuState, backend: Backend) -> Vec<String> {
+    let mut gba = make_gba(backend);
+    let insn_size = match state {
+        CpuState::ARM => 4,
+        CpuState::THUMB => 2,
+    };
+
+    match state {
+        CpuState::ARM => gba.sysbus.write_32(INSN_ADDR, vector.opcode.0),
+        CpuState::THUMB => gba.sysbus.write_16(INSN_ADDR, vector.opcode.0 as u16),
+    }
+    for (addr, value) in &vector.initial.memory {
+        gba.sysbus.write_32(addr.0, value.0);
+    }
+    let mut regs = [0; 15];
+    for (name, value) in &vector.initial.regs {
+        regs[reg_index(name)] = value.0;
+    }
+    let cpsr = vector
+        .initial
+        .cpsr
+        .map_or(0x1f | (state as u32) << 5, |cpsr| cpsr.0);
+    for (r, value) in regs.iter().enumerate() {
+        gba.cpu.set_reg(r, *value);
+    }
+    gba.cpu.cpsr.set(cpsr);
+    gba.cpu.pc = INSN_ADDR;
+    match state {
+        CpuState::ARM => gba.cpu.reload_pipeline32(&mut gba.sysbus),
+        CpuState::THUMB => gba.cpu.reload_pipeline16(&mut gba.sysbus),
+    }
+
+    let cycles = gba.step_cpu();
+
+    let mut expected_regs = [0; 16];
+    expected_regs[..15].copy_from_slice(&regs);
+    expected_regs[REG_PC] = INSN_ADDR + insn_size;
+    for (name, value) in &vector.expected.regs {
+        expected_regs[reg_index(name)] = value.0;
+    }
+    let expected_cpsr = vector.expected.cpsr.map_or(cpsr, |cpsr| cpsr.0);
+
+    let mut errors = Vec::new();
+    for (r, expected) in expected_regs.iter().enumerate() {
+        let actual = match r {
+            REG_PC => gba.cpu.get_next_pc(),
+            _ => gba.cpu.get_reg(r),
+        };
+        if actual != *expected {
+            errors.push(format!(
+                "r{} is {:08x}, expected {:08x}",
+                r, actual, expected
+            ));
+        }
+    }
+    if gba.cpu.cpsr.get() != expected_cpsr {
+        errors.push(format!(
+            "cpsr is {:08x}, expected {:08x}",
+            gba.cpu.cpsr.get(),
+            expected_cpsr
+        ));
+    }
+    for (addr, expected) in &vector.expected.memory {
+        let actual = gba.sysbus.read_32(addr.0);
+        if actual != expected.0 {
+            errors.push(format!(
+                "[{:08x}] is {:08x}, expected {:08x}",
+                addr.0, actual, expected.0
+            ));
+        }
+    }
+    if cycles != vector.cycles {
+        errors.push(format!(
+            "took {} cycles, expected {}",
+            cycles, vector.cycles
+        ));
+    }
+    errors
+}
+
+fn run_vectors(json: &str, state: CpuState) -> Vec<Vector> {
+    let vectors: Vec<Vector> = serde_json::from_str(json).unwrap();
+    let mut failures = Vec::new();
+    for backend in Backend::all() {
+        for vector in &vectors {
+            let errors = run_vector(vector, state, backend);
+            if !errors.is_empty() {
+                failures.push(format!(
+                    "{} ({:?}): {}",
+                    vector.name,
+                    backend,
+                    errors.join(", ")
+                ));
+            }
+        }
+    }
+    assert!(failures.is_empty(), "\n{}\n", failures.join("\n"));
+    vectors
+}
+
+#[test]
+fn test_arm_vectors() {
+    let vectors = run_vectors(include_str!("../../tests/arm7tdmi/arm.json"), CpuState::ARM);
+
+    use ArmFormat::*;
+    for fmt in &[
+        BranchExchange,
+        BranchLink,
+        SoftwareInterrupt,
+        Multiply,
+        MultiplyLong,
+        SingleDataTransfer,
+        HalfwordDataTransferRegOffset,
+        HalfwordDataTransferImmediateOffset,
+        DataProcessing,
+        BlockDataTransfer,
+        SingleDataSwap,
+        MoveFromStatus,
+        MoveToStatus,
+        MoveToFlags,
+    ] {
+        assert!(
+            vectors
+                .iter()
+                .any(|v| ArmInstruction::decode(v.opcode.0, INSN_ADDR).fmt == *fmt),
+            "no vector for {:?}",
+            fmt
+        );
+    }
+}
+
+#[test]
+fn test_arm_decoder_edge_cases() {
+    use ArmFormat::*;
+    let decode = |raw| ArmInstruction::decode(raw, INSN_ADDR).fmt;
+    // register offset ldr/str with bit 4 set
+    assert_eq!(decode(0xe610_0010), Undefined);
+    assert_eq!(decode(0x07f0_00f0), Undefined);
+    // bit 4 clear is a regular register offset transfer
+    assert_eq!(decode(0xe790_0001), SingleDataTransfer);
+    assert_eq!(decode(0xe191_00b3), HalfwordDataTransferRegOffset);
+    assert_eq!(decode(0xe191_02b3), HalfwordDataTransferRegOffset);
+}
+
+#[test]
+fn test_thumb_vectors() {
+    let vectors = run_vectors(
+        include_str!("../../tests/arm7tdmi/thumb.json"),
+        CpuState::THUMB,
+    );
+
+    use ThumbFormat::*;
+    for fmt in &[
+        MoveShiftedReg,
+        AddSub,
+        DataProcessImm,
+        AluOps,
+        HiRegOpOrBranchExchange,
+        LdrPc,
+        LdrStrRegOffset,
+        LdrStrSHB,
+        LdrStrImmOffset,
+        LdrStrHalfWord,
+        LdrStrSp,
+        LoadAddress,
+        AddSp,
+        PushPop,
+        LdmStm,
+        BranchConditional,
+        Swi,
+        Branch,
+        BranchLongWithLink,
+    ] {
+        assert!(
+            vectors
+                .iter()
+                .any(|v| ThumbInstruction::decode(v.opcode.0 as u16, INSN_ADDR).fmt == *fmt),
+            "no vector for {:?}",
+            fmt
+        );
+    }
+}
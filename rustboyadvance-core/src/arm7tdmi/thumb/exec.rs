@@ -37,7 +37,7 @@ impl Core {
         self.gpr[rd] = op2;
         self.alu_update_flags(op2, false, self.bs_carry_out, self.cpsr.V());
 
-        self.S_cycle16(sb, self.pc + 2);
+        self.S_fetch16(sb, self.pc + 2);
 
         CpuAction::AdvancePC
     }
@@ -66,7 +66,7 @@ impl Core {
         self.alu_update_flags(result, true, carry, overflow);
         self.set_reg(rd, result as u32);
 
-        self.S_cycle16(sb, self.pc + 2);
+        self.S_fetch16(sb, self.pc + 2);
 
         CpuAction::AdvancePC
     }
@@ -94,7 +94,7 @@ impl Core {
         if op != CMP {
             self.gpr[rd] = result as u32;
         }
-        self.S_cycle16(sb, self.pc + 2);
+        self.S_fetch16(sb, self.pc + 2);
 
         CpuAction::AdvancePC
     }
@@ -155,7 +155,7 @@ impl Core {
         if !op.is_setting_flags() {
             self.set_reg(rd, result as u32);
         }
-        self.S_cycle16(sb, self.pc + 2);
+        self.S_fetch16(sb, self.pc + 2);
 
         CpuAction::AdvancePC
     }
@@ -207,7 +207,7 @@ impl Core {
                 }
             }
         }
-        self.S_cycle16(sb, self.pc + 2);
+        self.S_fetch16(sb, self.pc + 2);
 
         result
     }
@@ -223,7 +223,7 @@ impl Core {
         let ofs = insn.word8() as Addr;
         let addr = (self.pc & !3) + ofs;
 
-        self.S_cycle16(sb, self.pc + 2);
+        self.S_fetch16(sb, self.pc + 2);
         let data = self.ldr_word(addr, sb);
         self.N_cycle16(sb, addr);
 
@@ -267,7 +267,7 @@ impl Core {
             };
         }
 
-        self.N_cycle16(sb, self.pc + 2);
+        self.N_fetch16(sb, self.pc + 2);
 
         CpuAction::AdvancePC
     }
@@ -328,7 +328,7 @@ impl Core {
             }
         }
 
-        self.N_cycle16(sb, self.pc + 2);
+        self.N_fetch16(sb, self.pc + 2);
 
         CpuAction::AdvancePC
     }
@@ -369,7 +369,7 @@ impl Core {
             self.write_16(addr, self.gpr[rd] as u16, sb);
             self.N_cycle16(sb, addr);
         }
-        self.N_cycle16(sb, self.pc + 2);
+        self.N_fetch16(sb, self.pc + 2);
 
         CpuAction::AdvancePC
     }
@@ -391,7 +391,7 @@ impl Core {
             self.write_32(addr, self.gpr[rd], sb);
             self.N_cycle16(sb, addr);
         }
-        self.N_cycle16(sb, self.pc + 2);
+        self.N_fetch16(sb, self.pc + 2);
 
         CpuAction::AdvancePC
     }
@@ -409,7 +409,7 @@ impl Core {
             (insn.pc & !0b10) + 4 + (insn.word8() as Addr)
         };
         self.gpr[rd] = result;
-        self.S_cycle16(sb, self.pc + 2);
+        self.S_fetch16(sb, self.pc + 2);
 
         CpuAction::AdvancePC
     }
@@ -424,7 +424,7 @@ impl Core {
         let op2 = insn.sword7();
 
         self.gpr[REG_SP] = op1.wrapping_add(op2) as u32;
-        self.S_cycle16(sb, self.pc + 2);
+        self.S_fetch16(sb, self.pc + 2);
 
         CpuAction::AdvancePC
     }
@@ -441,7 +441,7 @@ impl Core {
         let is_pop = insn.is_load();
         let pc_lr_flag = insn.flag(ThumbInstruction::FLAG_R);
         let rlist = insn.register_list();
-        self.N_cycle16(sb, self.pc);
+        self.N_fetch16(sb, self.pc);
         let mut first = true;
         if is_pop {
             for r in 0..8 {
@@ -457,14 +457,20 @@ impl Core {
             }
             if pc_lr_flag {
                 pop(self, sb, REG_PC);
+                if first {
+                    self.add_cycle();
+                } else {
+                    self.S_cycle16(sb, self.gpr[REG_SP]);
+                }
                 self.pc = self.pc & !1;
                 result = CpuAction::FlushPipeline;
                 self.reload_pipeline16(sb);
             }
-            self.S_cycle16(sb, self.pc + 2);
+            self.S_fetch16(sb, self.pc + 2);
         } else {
             if pc_lr_flag {
                 push(self, sb, REG_LR);
+                first = false;
             }
             for r in (0..8).rev() {
                 if rlist.bit(r) {
@@ -476,6 +482,7 @@ impl Core {
                     }
                 }
             }
+            self.N_fetch16(sb, self.pc + 2);
         }
 
         result
@@ -489,7 +496,7 @@ impl Core {
     ) -> CpuAction {
         let mut result = CpuAction::AdvancePC;
 
-        // (From GBATEK) Execution Time: nS+1N+1I (POP), (n+1)S+2N+1I (POP PC), or (n-1)S+2N (PUSH).
+        // (From GBATEK) Execution Time: nS+1N+1I for LDM, or (n-1)S+2N for STM.
 
         let rb = insn.raw.bit_range(8..11) as usize;
         let base_reg = rb;
@@ -498,7 +505,7 @@ impl Core {
         let align_preserve = self.gpr[base_reg] & 3;
         let mut addr = self.gpr[base_reg] & !3;
         let rlist = insn.register_list();
-        self.N_cycle16(sb, self.pc);
+        self.N_fetch16(sb, self.pc);
         let mut first = true;
 
         if rlist != 0 {
@@ -514,15 +521,17 @@ impl Core {
                             self.S_cycle16(sb, addr);
                         }
                         addr += 4;
-                        self.add_cycle();
                         self.set_reg(r, val);
                     }
                 }
-                self.S_cycle16(sb, self.pc + 2);
+                self.S_fetch16(sb, self.pc + 2);
                 if writeback {
                     self.gpr[base_reg] = addr + align_preserve;
                 }
             } else {
+                // From gbatek.htm: STM stores OLD base if Rb is first entry in Rlist, otherwise
+                // stores NEW base.
+                let new_base = addr + rlist.count_ones() * 4 + align_preserve;
                 for r in 0..8 {
                     if rlist.bit(r) {
                         let v = if r != base_reg {
@@ -531,7 +540,7 @@ impl Core {
                             if first {
                                 addr
                             } else {
-                                addr + (rlist.count_ones() - 1) * 4
+                                new_base
                             }
                         };
                         if first {
@@ -544,16 +553,20 @@ impl Core {
                     }
                     self.gpr[base_reg] = addr + align_preserve;
                 }
+                self.N_fetch16(sb, self.pc + 2);
             }
         } else {
             // From gbatek.htm: Empty Rlist: R15 loaded/stored (ARMv4 only), and Rb=Rb+40h (ARMv4-v5).
             if is_load {
                 let val = sb.read_32(addr);
+                self.add_cycle();
                 self.set_reg(REG_PC, val & !1);
                 result = CpuAction::FlushPipeline;
                 self.reload_pipeline16(sb);
+                self.S_fetch16(sb, self.pc + 2);
             } else {
                 sb.write_32(addr, self.pc + 2);
+                self.N_fetch16(sb, self.pc + 2);
             }
             addr += 0x40;
             self.gpr[base_reg] = addr + align_preserve;
@@ -569,11 +582,11 @@ impl Core {
         insn: &ThumbInstruction,
     ) -> CpuAction {
         if !self.check_arm_cond(insn.cond()) {
-            self.S_cycle16(sb, self.pc + 2);
+            self.S_fetch16(sb, self.pc + 2);
             CpuAction::AdvancePC
         } else {
             let offset = insn.bcond_offset();
-            self.S_cycle16(sb, self.pc);
+            self.S_fetch16(sb, self.pc);
             self.pc = (self.pc as i32).wrapping_add(offset) as u32;
             self.reload_pipeline16(sb);
             CpuAction::FlushPipeline
@@ -584,11 +597,9 @@ impl Core {
     pub(in super::super) fn exec_thumb_swi(
         &mut self,
         sb: &mut SysBus,
-        _insn: &ThumbInstruction,
+        insn: &ThumbInstruction,
     ) -> CpuAction {
-        self.N_cycle16(sb, self.pc);
-        self.exception(sb, Exception::SoftwareInterrupt, self.pc - 2);
-        CpuAction::FlushPipeline
+        self.software_interrupt(sb, self.pc - 2, insn.raw as u32)
     }
 
     /// Format 18
@@ -599,7 +610,7 @@ impl Core {
     ) -> CpuAction {
         let offset = ((insn.offset11() << 21) >> 20) as i32;
         self.pc = (self.pc as i32).wrapping_add(offset) as u32;
-        self.S_cycle16(sb, self.pc);
+        self.S_fetch16(sb, self.pc);
         self.reload_pipeline16(sb);
         CpuAction::FlushPipeline
     }
@@ -612,7 +623,7 @@ impl Core {
     ) -> CpuAction {
         let mut off = insn.offset11();
         if insn.flag(ThumbInstruction::FLAG_LOW_OFFSET) {
-            self.S_cycle16(sb, self.pc);
+            self.S_fetch16(sb, self.pc);
             off = off << 1;
             let next_pc = (self.pc - 2) | 1;
             self.pc = ((self.gpr[REG_LR] & !1) as i32).wrapping_add(off) as u32;
@@ -622,7 +633,7 @@ impl Core {
         } else {
             off = (off << 21) >> 9;
             self.gpr[REG_LR] = (self.pc as i32).wrapping_add(off) as u32;
-            self.S_cycle16(sb, self.pc);
+            self.S_fetch16(sb, self.pc);
 
             CpuAction::AdvancePC
         }
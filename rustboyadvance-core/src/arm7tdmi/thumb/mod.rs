@@ -315,127 +315,3 @@ impl ThumbInstruction {
         }
     }
 }
-
-// #[cfg(test)]
-// /// All instructions constants were generated using an ARM assembler.
-// mod tests {
-//     use super::super::Core;
-//     use super::*;
-//     use crate::sysbus::BoxedMemory;
-//     use crate::Bus;
-
-//     #[test]
-//     fn mov_low_reg() {
-//         let bytes = vec![];
-//         let mut mem = BoxedMemory::new(bytes.into_boxed_slice(), 0xffff_ffff);
-//         let mut core = Core::new();
-//         core.set_reg(0, 0);
-
-//         // movs r0, #0x27
-//         let insn = ThumbInstruction::decode(0x2027, 0).unwrap();
-
-//         assert_eq!(format!("{}", insn), "mov\tr0, #0x27");
-//         core.exec_thumb(&mut mem, insn).unwrap();
-//         assert_eq!(core.get_reg(0), 0x27);
-//     }
-
-//     // #[test]
-//     // fn decode_add_sub() {
-//     //     let insn = ThumbInstruction::decode(0xac19, 0).unwrap();
-//     //     assert!(format!("add\tr4, r4"))
-//     // }
-
-//     #[test]
-//     fn ldr_pc() {
-//         // ldr r0, [pc, #4]
-//         let insn = ThumbInstruction::decode(0x4801, 0x6).unwrap();
-
-//         #[rustfmt::skip]
-//         let bytes = vec![
-//             /* 0: */ 0x00, 0x00,
-//             /* 2: */ 0x00, 0x00,
-//             /* 4: */ 0x00, 0x00,
-//             /* 6: <pc> */ 0x00, 0x00,
-//             /* 8: */ 0x00, 0x00, 0x00, 0x00,
-//             /* c: */ 0x78, 0x56, 0x34, 0x12,
-//         ];
-//         let mut mem = BoxedMemory::new(bytes.into_boxed_slice(), 0xffff_ffff);
-//         let mut core = Core::new();
-//         core.set_reg(0, 0);
-
-//         assert_eq!(format!("{}", insn), "ldr\tr0, [pc, #0x4] ; = #0xc");
-//         core.exec_thumb(&mut mem, insn).unwrap();
-//         assert_eq!(core.get_reg(0), 0x12345678);
-//     }
-
-//     #[test]
-//     fn ldr_str_reg_offset() {
-//         // str	r0, [r4, r1]
-//         let str_insn = ThumbInstruction::decode(0x5060, 0x6).unwrap();
-//         // ldrb r2, [r4, r1]
-//         let ldr_insn = ThumbInstruction::decode(0x5c62, 0x6).unwrap();
-
-//         let mut core = Core::new();
-//         core.set_reg(0, 0x12345678);
-//         core.set_reg(2, 0);
-//         core.set_reg(1, 0x4);
-//         core.set_reg(4, 0xc);
-
-//         #[rustfmt::skip]
-//         let bytes = vec![
-//             /* 00h: */ 0xaa, 0xbb, 0xcc, 0xdd,
-//             /* 04h: */ 0xaa, 0xbb, 0xcc, 0xdd,
-//             /* 08h: */ 0xaa, 0xbb, 0xcc, 0xdd,
-//             /* 0ch: */ 0xaa, 0xbb, 0xcc, 0xdd,
-//             /* 10h: */ 0xaa, 0xbb, 0xcc, 0xdd,
-//         ];
-//         let mut mem = BoxedMemory::new(bytes.into_boxed_slice(), 0xffff_ffff);
-
-//         assert_eq!(format!("{}", str_insn), "str\tr0, [r4, r1]");
-//         assert_eq!(format!("{}", ldr_insn), "ldrb\tr2, [r4, r1]");
-//         core.exec_thumb(&mut mem, str_insn).unwrap();
-//         assert_eq!(mem.read_32(0x10), 0x12345678);
-//         core.exec_thumb(&mut mem, ldr_insn).unwrap();
-//         assert_eq!(core.get_reg(2), 0x78);
-//     }
-
-//     #[allow(overflowing_literals)]
-//     #[test]
-//     fn format8() {
-//         let mut core = Core::new();
-//         #[rustfmt::skip]
-//         let bytes = vec![
-//             /* 00h: */ 0xaa, 0xbb, 0xcc, 0xdd, 0xaa, 0xbb, 0xcc, 0xdd,
-//             /* 08h: */ 0xaa, 0xbb, 0xcc, 0xdd, 0xaa, 0xbb, 0xcc, 0xdd,
-//             /* 10h: */ 0xaa, 0xbb, 0xcc, 0xdd, 0xaa, 0xbb, 0xcc, 0xdd,
-//         ];
-//         let mut mem = BoxedMemory::new(bytes.into_boxed_slice(), 0xffff_ffff);
-
-//         core.gpr[4] = 0x12345678;
-//         core.gpr[3] = 0x2;
-//         core.gpr[0] = 0x4;
-//         // strh r4, [r3, r0]
-//         let decoded = ThumbInstruction::decode(0x521c, 0).unwrap();
-//         assert_eq!(format!("{}", decoded), "strh\tr4, [r3, r0]");
-//         core.exec_thumb(&mut mem, decoded).unwrap();
-//         assert_eq!(&mem.get_bytes(0x6)[..4], [0x78, 0x56, 0xaa, 0xbb]);
-
-//         // ldsb r2, [r7, r1]
-//         core.gpr[2] = 0;
-//         core.gpr[7] = 0x10;
-//         core.gpr[1] = 0x5;
-//         let decoded = ThumbInstruction::decode(0x567a, 0).unwrap();
-//         assert_eq!(format!("{}", decoded), "ldsb\tr2, [r7, r1]");
-//         core.exec_thumb(&mut mem, decoded).unwrap();
-//         assert_eq!(core.gpr[2], mem.read_8(0x15) as i8 as u32);
-
-//         // ldsh r3, [r4, r2]
-//         core.gpr[3] = 0x0;
-//         core.gpr[4] = 0x0;
-//         core.gpr[2] = 0x6;
-//         let decoded = ThumbInstruction::decode(0x5ea3, 0).unwrap();
-//         assert_eq!(format!("{}", decoded), "ldsh\tr3, [r4, r2]");
-//         core.exec_thumb(&mut mem, decoded).unwrap();
-//         assert_eq!(core.gpr[3], 0x5678);
-//     }
-// }
@@ -0,0 +1,584 @@
+//! High level emulation of the GBA bios.
+//!
+//! When enabled, software interrupts are handled by native implementations instead of jumping into the bios.
+//! Combined with `builtin_bios`, games can run without a dump of the real bios.
+use std::f64::consts::PI;
+
+use bit::BitIndex;
+
+use super::arm7tdmi::{Core, CpuAction, CpuMode, CpuState};
+use super::iodev::HaltState;
+use super::sysbus::{SysBus, BIOS_OPEN_BUS_AFTER_BOOT, BIOS_OPEN_BUS_AFTER_SWI};
+use super::Bus;
+
+pub const BIOS_SIZE: usize = 16 * 1024;
+
+/// BIOS IRQ flags, set by the user's IRQ handler and checked by IntrWait
+const BIOS_INTR_CHECK: u32 = 0x0300_7FF8;
+/// SoftReset jumps to EWRAM instead of ROM when this byte is non-zero
+const BIOS_RESET_FLAG: u32 = 0x0300_7FFA;
+
+const BUILTIN_BIOS_CODE: &[(usize, u32)] = &[
+    // exception vectors
+    (0x00, 0xEA00_007E), // b reset_handler
+    (0x04, 0xE1B0_F00E), // movs pc, lr
+    (0x08, 0xE1B0_F00E), // movs pc, lr
+    (0x0C, 0xE25E_F004), // subs pc, lr, #4
+    (0x10, 0xE25E_F008), // subs pc, lr, #8
+    (0x14, 0xEAFF_FFFE), // b .
+    (0x18, 0xEA00_0042), // b irq_handler
+    (0x1C, 0xE25E_F004), // subs pc, lr, #4
+    // irq_handler, at the same address as the real bios
+    (0x128, 0xE92D_500F), // stmfd sp!, {r0-r3, r12, lr}
+    (0x12C, 0xE3A0_0301), // mov r0, #0x04000000
+    (0x130, 0xE28F_E000), // add lr, pc, #0
+    (0x134, 0xE510_F004), // ldr pc, [r0, #-4]
+    (0x138, 0xE8BD_500F), // ldmfd sp!, {r0-r3, r12, lr}
+    (0x13C, 0xE25E_F004), // subs pc, lr, #4
+    // reset_handler, setup the stacks and jump to the rom
+    (0x200, 0xE3A0_00D2), // mov r0, #0xd2
+    (0x204, 0xE129_F000), // msr cpsr_fc, r0
+    (0x208, 0xE59F_D030), // ldr sp, =0x03007fa0
+    (0x20C, 0xE3A0_00D3), // mov r0, #0xd3
+    (0x210, 0xE129_F000), // msr cpsr_fc, r0
+    (0x214, 0xE59F_D028), // ldr sp, =0x03007fe0
+    (0x218, 0xE3A0_001F), // mov r0, #0x1f
+    (0x21C, 0xE129_F000), // msr cpsr_fc, r0
+    (0x220, 0xE59F_D020), // ldr sp, =0x03007f00
+    (0x224, 0xE3A0_E302), // mov lr, #0x08000000
+    (0x228, 0xE12F_FF1E), // bx lr
+    (0x240, 0x0300_7FA0),
+    (0x244, 0x0300_7FE0),
+    (0x248, 0x0300_7F00),
+];
+
+/// Creates a minimal bios image that only contains the exception vectors, an IRQ dispatcher and a boot stub.
+/// Meant to be used together with bios HLE.
+pub fn builtin_bios() -> Box<[u8]> {
+    let mut bios = vec![0; BIOS_SIZE];
+    for &(addr, word) in BUILTIN_BIOS_CODE {
+        bios[addr..addr + 4].copy_from_slice(&word.to_le_bytes());
+    }
+    bios.into_boxed_slice()
+}
+
+/// Handles a software interrupt natively.
+/// Returns None if the swi is not implemented, and should be handled by the bios instead.
+pub(crate) fn hle_swi(cpu: &mut Core, sb: &mut SysBus, swi: u8) -> Option<CpuAction> {
+    trace!("HLE swi {:#x}", swi);
+    let action = dispatch_swi(cpu, sb, swi)?;
+    // the real bios returns through its swi handler, which leaves its last fetch on the bus
+    let open_bus = match swi {
+        0x00 => BIOS_OPEN_BUS_AFTER_BOOT,
+        _ => BIOS_OPEN_BUS_AFTER_SWI,
+    };
+    sb.set_bios_open_bus_value(open_bus);
+    Some(action)
+}
+
+fn dispatch_swi(cpu: &mut Core, sb: &mut SysBus, swi: u8) -> Option<CpuAction> {
+    match swi {
+        0x00 => return Some(soft_reset(cpu, sb)),
+        0x01 => register_ram_reset(sb, cpu.gpr[0]),
+        0x02 => sb.io.haltcnt = HaltState::Halt,
+        0x04 => {
+            let discard_old = cpu.gpr[0] != 0;
+            let flags = cpu.gpr[1] as u16;
+            return Some(intr_wait(cpu, sb, discard_old, flags));
+        }
+        0x05 => {
+            cpu.gpr[0] = 1;
+            cpu.gpr[1] = 1;
+            return Some(intr_wait(cpu, sb, true, 1));
+        }
+        0x06 => div(cpu, cpu.gpr[0] as i32, cpu.gpr[1] as i32),
+        0x07 => div(cpu, cpu.gpr[1] as i32, cpu.gpr[0] as i32),
+        0x08 => cpu.gpr[0] = (cpu.gpr[0] as f64).sqrt() as u32,
+        0x09 => cpu.gpr[0] = arctan(cpu.gpr[0] as i32) as u32,
+        0x0A => cpu.gpr[0] = arctan2(cpu.gpr[0] as i32, cpu.gpr[1] as i32) as u32 & 0xffff,
+        0x0B => cpu_set(sb, cpu.gpr[0], cpu.gpr[1], cpu.gpr[2]),
+        0x0C => cpu_fast_set(sb, cpu.gpr[0], cpu.gpr[1], cpu.gpr[2]),
+        0x0D => cpu.gpr[0] = 0xBAAE_187F,
+        0x0E => bg_affine_set(sb, cpu.gpr[0], cpu.gpr[1], cpu.gpr[2]),
+        0x0F => obj_affine_set(sb, cpu.gpr[0], cpu.gpr[1], cpu.gpr[2], cpu.gpr[3]),
+        0x11 => {
+            let data = lz77_decompress(sb, cpu.gpr[0]);
+            write_wram(sb, cpu.gpr[1], &data);
+        }
+        0x12 => {
+            let data = lz77_decompress(sb, cpu.gpr[0]);
+            write_vram(sb, cpu.gpr[1], &data);
+        }
+        0x13 => {
+            let data = huffman_decompress(sb, cpu.gpr[0]);
+            write_wram(sb, cpu.gpr[1], &data);
+        }
+        0x14 => {
+            let data = rl_decompress(sb, cpu.gpr[0]);
+            write_wram(sb, cpu.gpr[1], &data);
+        }
+        0x15 => {
+            let data = rl_decompress(sb, cpu.gpr[0]);
+            write_vram(sb, cpu.gpr[1], &data);
+        }
+        _ => {
+            warn!("HLE: unimplemented swi {:#x}", swi);
+            return None;
+        }
+    }
+    Some(CpuAction::AdvancePC)
+}
+
+/// Makes the cpu execute the current instruction again, once it returns from an IRQ
+fn restart_current_instruction(cpu: &mut Core, sb: &mut SysBus) -> CpuAction {
+    cpu.pc = cpu.get_next_pc();
+    match cpu.cpsr.state() {
+        CpuState::ARM => cpu.reload_pipeline32(sb),
+        CpuState::THUMB => cpu.reload_pipeline16(sb),
+    }
+    CpuAction::FlushPipeline
+}
+
+fn soft_reset(cpu: &mut Core, sb: &mut SysBus) -> CpuAction {
+    let entry = if sb.read_8(BIOS_RESET_FLAG) != 0 {
+        0x0200_0000
+    } else {
+        0x0800_0000
+    };
+    for addr in (0x0300_7E00..0x0300_8000).step_by(4) {
+        sb.write_32(addr, 0);
+    }
+
+    cpu.change_mode(cpu.cpsr.mode(), CpuMode::System);
+    cpu.gpr = [0; 15];
+    cpu.skip_bios();
+    cpu.pc = entry;
+    cpu.reload_pipeline32(sb);
+
+    CpuAction::FlushPipeline
+}
+
+fn fill(sb: &mut SysBus, start: u32, end: u32, value: u32) {
+    for addr in (start..end).step_by(4) {
+        sb.write_32(addr, value);
+    }
+}
+
+fn register_ram_reset(sb: &mut SysBus, flags: u32) {
+    // the screen is always forced blank
+    sb.write_16(0x0400_0000, 0x80);
+
+    if flags.bit(0) {
+        fill(sb, 0x0200_0000, 0x0204_0000, 0);
+    }
+    if flags.bit(1) {
+        // the last 0x200 bytes are reserved for the bios and stacks
+        fill(sb, 0x0300_0000, 0x0300_7E00, 0);
+    }
+    if flags.bit(2) {
+        fill(sb, 0x0500_0000, 0x0500_0400, 0);
+    }
+    if flags.bit(3) {
+        fill(sb, 0x0600_0000, 0x0601_8000, 0);
+    }
+    if flags.bit(4) {
+        fill(sb, 0x0700_0000, 0x0700_0400, 0);
+    }
+    if flags.bit(5) {
+        fill(sb, 0x0400_0120, 0x0400_0130, 0);
+        sb.write_16(0x0400_0134, 0x8000);
+    }
+    if flags.bit(6) {
+        fill(sb, 0x0400_0060, 0x0400_0088, 0);
+        fill(sb, 0x0400_0090, 0x0400_00A0, 0);
+    }
+    if flags.bit(7) {
+        fill(sb, 0x0400_0004, 0x0400_0060, 0);
+        fill(sb, 0x0400_00B0, 0x0400_00E0, 0);
+        fill(sb, 0x0400_0100, 0x0400_0110, 0);
+        sb.write_16(0x0400_0132, 0);
+        sb.write_16(0x0400_0200, 0);
+        sb.write_16(0x0400_0204, 0);
+        sb.write_16(0x0400_0208, 0);
+    }
+}
+
+fn intr_wait(cpu: &mut Core, sb: &mut SysBus, discard_old: bool, flags: u16) -> CpuAction {
+    sb.io.intc.interrupt_master_enable = true;
+
+    let intr_check = sb.read_16(BIOS_INTR_CHECK);
+    if discard_old && !cpu.hle_intr_waiting {
+        sb.write_16(BIOS_INTR_CHECK, intr_check & !flags);
+    } else if intr_check & flags != 0 {
+        sb.write_16(BIOS_INTR_CHECK, intr_check & !flags);
+        cpu.hle_intr_waiting = false;
+        return CpuAction::AdvancePC;
+    }
+
+    // Halt, and check the flags again once an interrupt was serviced
+    cpu.hle_intr_waiting = true;
+    sb.io.haltcnt = HaltState::Halt;
+    restart_current_instruction(cpu, sb)
+}
+
+fn div(cpu: &mut Core, numerator: i32, denominator: i32) {
+    if denominator == 0 {
+        // the real bios gets stuck in an endless loop
+        warn!("HLE: division by zero ({} / 0)", numerator);
+        cpu.gpr[0] = if numerator < 0 { -1i32 as u32 } else { 1 };
+        cpu.gpr[1] = numerator as u32;
+        cpu.gpr[3] = 1;
+        return;
+    }
+    let quotient = numerator.wrapping_div(denominator);
+    cpu.gpr[0] = quotient as u32;
+    cpu.gpr[1] = numerator.wrapping_rem(denominator) as u32;
+    cpu.gpr[3] = quotient.wrapping_abs() as u32;
+}
+
+/// Polynomial approximation used by the bios, the argument is in 1.14 fixed point
+fn arctan(i: i32) -> i32 {
+    let a = -(i.wrapping_mul(i) >> 14);
+    let mut b = (0xA9_i32.wrapping_mul(a) >> 14) + 0x390;
+    b = (b.wrapping_mul(a) >> 14) + 0x91C;
+    b = (b.wrapping_mul(a) >> 14) + 0xFB6;
+    b = (b.wrapping_mul(a) >> 14) + 0x16AA;
+    b = (b.wrapping_mul(a) >> 14) + 0x2081;
+    b = (b.wrapping_mul(a) >> 14) + 0x3651;
+    b = (b.wrapping_mul(a) >> 14) + 0xA2F9;
+    i.wrapping_mul(b) >> 16
+}
+
+/// Returns the angle of (x, y) in the range of 0..0x10000
+fn arctan2(x: i32, y: i32) -> i32 {
+    if y == 0 {
+        return if x >= 0 { 0 } else { 0x8000 };
+    }
+    if x == 0 {
+        return if y >= 0 { 0x4000 } else { 0xC000 };
+    }
+    if y >= 0 {
+        if x >= 0 {
+            if x >= y {
+                return arctan(y.wrapping_shl(14).wrapping_div(x));
+            }
+        } else if x.wrapping_neg() >= y {
+            return arctan(y.wrapping_shl(14).wrapping_div(x)) + 0x8000;
+        }
+        0x4000 - arctan(x.wrapping_shl(14).wrapping_div(y))
+    } else {
+        if x <= 0 {
+            if x.wrapping_neg() > y.wrapping_neg() {
+                return arctan(y.wrapping_shl(14).wrapping_div(x)) + 0x8000;
+            }
+        } else if x >= y.wrapping_neg() {
+            return arctan(y.wrapping_shl(14).wrapping_div(x)) + 0x10000;
+        }
+        0xC000 - arctan(x.wrapping_shl(14).wrapping_div(y))
+    }
+}
+
+fn cpu_set(sb: &mut SysBus, src: u32, dst: u32, ctrl: u32) {
+    let count = ctrl.bit_range(0..21);
+    let fixed = ctrl.bit(24);
+    if ctrl.bit(26) {
+        let (src, dst) = (src & !3, dst & !3);
+        let fill_value = sb.read_32(src);
+        for i in 0..count {
+            let value = if fixed {
+                fill_value
+            } else {
+                sb.read_32(src + 4 * i)
+            };
+            sb.write_32(dst + 4 * i, value);
+        }
+    } else {
+        let (src, dst) = (src & !1, dst & !1);
+        let fill_value = sb.read_16(src);
+        for i in 0..count {
+            let value = if fixed {
+                fill_value
+            } else {
+                sb.read_16(src + 2 * i)
+            };
+            sb.write_16(dst + 2 * i, value);
+        }
+    }
+}
+
+fn cpu_fast_set(sb: &mut SysBus, src: u32, dst: u32, ctrl: u32) {
+    // always transfers blocks of 8 words
+    let count = (ctrl.bit_range(0..21) + 7) & !7;
+    cpu_set(sb, src, dst, count | (ctrl & (1 << 24)) | (1 << 26));
+}
+
+#[inline]
+fn affine_angle(value: u16) -> f64 {
+    ((value >> 8) as f64) / 128.0 * PI
+}
+
+fn bg_affine_set(sb: &mut SysBus, src: u32, dst: u32, count: u32) {
+    let (mut src, mut dst) = (src, dst);
+    for _ in 0..count {
+        let ox = sb.read_32(src) as i32 as f64 / 256.0;
+        let oy = sb.read_32(src + 4) as i32 as f64 / 256.0;
+        let cx = sb.read_16(src + 8) as i16 as f64;
+        let cy = sb.read_16(src + 10) as i16 as f64;
+        let sx = sb.read_16(src + 12) as i16 as f64 / 256.0;
+        let sy = sb.read_16(src + 14) as i16 as f64 / 256.0;
+        let theta = affine_angle(sb.read_16(src + 16));
+
+        let (sin, cos) = theta.sin_cos();
+        let pa = cos * sx;
+        let pb = -sin * sx;
+        let pc = sin * sy;
+        let pd = cos * sy;
+        let x = ox - (pa * cx + pb * cy);
+        let y = oy - (pc * cx + pd * cy);
+
+        sb.write_16(dst, (pa * 256.0) as i16 as u16);
+        sb.write_16(dst + 2, (pb * 256.0) as i16 as u16);
+        sb.write_16(dst + 4, (pc * 256.0) as i16 as u16);
+        sb.write_16(dst + 6, (pd * 256.0) as i16 as u16);
+        sb.write_32(dst + 8, (x * 256.0) as i32 as u32);
+        sb.write_32(dst + 12, (y * 256.0) as i32 as u32);
+
+        src += 20;
+        dst += 16;
+    }
+}
+
+fn obj_affine_set(sb: &mut SysBus, src: u32, dst: u32, count: u32, offset: u32) {
+    let (mut src, mut dst) = (src, dst);
+    for _ in 0..count {
+        let sx = sb.read_16(src) as i16 as f64 / 256.0;
+        let sy = sb.read_16(src + 2) as i16 as f64 / 256.0;
+        let theta = affine_angle(sb.read_16(src + 4));
+
+        let (sin, cos) = theta.sin_cos();
+        sb.write_16(dst, (cos * sx * 256.0) as i16 as u16);
+        sb.write_16(dst + offset, (-sin * sx * 256.0) as i16 as u16);
+        sb.write_16(dst + 2 * offset, (sin * sy * 256.0) as i16 as u16);
+        sb.write_16(dst + 3 * offset, (cos * sy * 256.0) as i16 as u16);
+
+        src += 8;
+        dst += 4 * offset;
+    }
+}
+
+/// Returns the decompressed size from the header of the compressed data
+#[inline]
+fn decompressed_size(header: u32) -> usize {
+    (header >> 8) as usize
+}
+
+fn lz77_decompress(sb: &SysBus, src: u32) -> Vec<u8> {
+    let size = decompressed_size(sb.read_32(src));
+    let mut output = Vec::with_capacity(size);
+    let mut src = src + 4;
+
+    while output.len() < size {
+        let flags = sb.read_8(src);
+        src += 1;
+        for block in (0..8).rev() {
+            if output.len() >= size {
+                break;
+            }
+            if flags.bit(block) {
+                let b0 = sb.read_8(src) as usize;
+                let b1 = sb.read_8(src + 1) as usize;
+                src += 2;
+                let length = (b0 >> 4) + 3;
+                let disp = ((b0 & 0xf) << 8 | b1) + 1;
+                for _ in 0..length {
+                    let value = if disp <= output.len() {
+                        output[output.len() - disp]
+                    } else {
+                        0
+                    };
+                    output.push(value);
+                }
+            } else {
+                output.push(sb.read_8(src));
+                src += 1;
+            }
+        }
+    }
+    output.truncate(size);
+    output
+}
+
+fn rl_decompress(sb: &SysBus, src: u32) -> Vec<u8> {
+    let size = decompressed_size(sb.read_32(src));
+    let mut output = Vec::with_capacity(size);
+    let mut src = src + 4;
+
+    while output.len() < size {
+        let flag = sb.read_8(src);
+        src += 1;
+        if flag.bit(7) {
+            let length = (flag & 0x7f) as usize + 3;
+            let value = sb.read_8(src);
+            src += 1;
+            output.resize(output.len() + length, value);
+        } else {
+            let length = (flag & 0x7f) as usize + 1;
+            for _ in 0..length {
+                output.push(sb.read_8(src));
+                src += 1;
+            }
+        }
+    }
+    output.truncate(size);
+    output
+}
+
+fn huffman_decompress(sb: &SysBus, src: u32) -> Vec<u8> {
+    let header = sb.read_32(src);
+    let size = decompressed_size(header);
+    let bits = header & 0xf;
+    // a bad data size would never fill the output
+    if bits == 0 || bits > 8 {
+        return Vec::new();
+    }
+    let data_mask = (1u32 << bits) - 1;
+
+    let tree_size = (sb.read_8(src + 4) as u32 + 1) * 2;
+    let tree_root = src + 5;
+    let mut stream = src + 4 + tree_size;
+
+    let mut output = Vec::with_capacity(size);
+    let mut out_word = 0u32;
+    let mut out_bits = 0;
+
+    let mut node_addr = tree_root;
+    let mut node = sb.read_8(node_addr);
+    'decode: while output.len() < size {
+        let word = sb.read_32(stream);
+        stream += 4;
+        for bit in (0..32).rev() {
+            let direction = word.bit(bit);
+            let child = (node_addr & !1) + ((node & 0x3f) as u32) * 2 + 2 + direction as u32;
+            let is_leaf = if direction { node.bit(6) } else { node.bit(7) };
+            if is_leaf {
+                out_word |= (sb.read_8(child) as u32 & data_mask) << out_bits;
+                out_bits += bits;
+                // sizes that don't divide 32 drop the bits that don't fit
+                if out_bits + bits > 32 {
+                    output.extend_from_slice(&out_word.to_le_bytes());
+                    out_word = 0;
+                    out_bits = 0;
+                    if output.len() >= size {
+                        break 'decode;
+                    }
+                }
+                node_addr = tree_root;
+            } else {
+                node_addr = child;
+            }
+            node = sb.read_8(node_addr);
+        }
+    }
+    output.truncate(size);
+    output
+}
+
+fn write_wram(sb: &mut SysBus, dst: u32, data: &[u8]) {
+    for (i, byte) in data.iter().enumerate() {
+        sb.write_8(dst + i as u32, *byte);
+    }
+}
+
+/// VRAM can't be written with 8bit accesses
+fn write_vram(sb: &mut SysBus, dst: u32, data: &[u8]) {
+    for (i, pair) in data.chunks(2).enumerate() {
+        let value = pair[0] as u16 | (*pair.get(1).unwrap_or(&0) as u16) << 8;
+        sb.write_16(dst + 2 * i as u32, value);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::gba::tests::{make_mock_gba_with_bios, make_rom};
+    use crate::GameBoyAdvance;
+
+    fn make_hle_gba(code: &[u32]) -> GameBoyAdvance {
+        let mut gba = make_mock_gba_with_bios(builtin_bios(), &make_rom(code));
+        gba.set_bios_hle(true);
+        gba
+    }
+
+    #[test]
+    fn test_hle_div_from_builtin_bios_boot() {
+        let mut gba = make_hle_gba(&[
+            0xE3A0_0064, // mov r0, #100
+            0xE3A0_1007, // mov r1, #7
+            0xEF06_0000, // swi 0x060000
+            0xEAFF_FFFE, // b .
+        ]);
+        gba.frame();
+
+        assert_eq!(gba.cpu.get_cpu_state(), CpuState::ARM);
+        assert_eq!(gba.cpu.cpsr.mode(), CpuMode::System);
+        assert_eq!(gba.cpu.gpr[13], 0x0300_7F00);
+        assert_eq!(gba.cpu.gpr[0], 14);
+        assert_eq!(gba.cpu.gpr[1], 2);
+        assert_eq!(gba.cpu.gpr[3], 14);
+        assert_eq!(gba.sysbus.debug_read_32(0), BIOS_OPEN_BUS_AFTER_SWI);
+    }
+
+    #[test]
+    fn test_hle_arctan2() {
+        assert_eq!(arctan2(0x4000, 0), 0);
+        assert_eq!(arctan2(0, 0x4000), 0x4000);
+        assert_eq!(arctan2(-0x4000, 0), 0x8000);
+        assert_eq!(arctan2(0, -0x4000), 0xC000);
+        let diagonal = arctan2(0x4000, 0x4000);
+        assert!((diagonal - 0x2000).abs() <= 2);
+    }
+
+    #[test]
+    fn test_hle_arctan_extreme_inputs() {
+        // the registers may hold anything, garbage in must not bring the emulator down
+        let extremes = [i32::MIN, i32::MIN + 1, -1, 1, i32::MAX];
+        for &x in extremes.iter() {
+            arctan(x);
+            for &y in extremes.iter() {
+                assert!((0..=0x10000).contains(&arctan2(x, y)));
+            }
+        }
+    }
+
+    #[test]
+    fn test_hle_decompress() {
+        let mut gba = make_hle_gba(&[]);
+        let src = 0x0200_0000;
+        // RL: 4 times 0xaa followed by the raw bytes 1, 2
+        let rl = [0x30, 0x06, 0x00, 0x00, 0x81, 0xaa, 0x01, 0x01, 0x02];
+        write_wram(&mut gba.sysbus, src, &rl);
+        assert_eq!(
+            rl_decompress(&gba.sysbus, src),
+            vec![0xaa, 0xaa, 0xaa, 0xaa, 0x01, 0x02]
+        );
+
+        // LZ77: "abc" followed by a back reference of 6 bytes with a distance of 3
+        let lz = [0x10, 0x09, 0x00, 0x00, 0x10, b'a', b'b', b'c', 0x30, 0x02];
+        write_wram(&mut gba.sysbus, src, &lz);
+        assert_eq!(lz77_decompress(&gba.sysbus, src), b"abcabcabc".to_vec());
+
+        // Huffman: a root whose children are both leaves, 0 and 1, and a stream of 0x55555555
+        let mut huffman = vec![0x21, 0x04, 0x00, 0x00, 0x01, 0xc0, 0x00, 0x01];
+        huffman.extend_from_slice(&0x5555_5555u32.to_le_bytes());
+        write_wram(&mut gba.sysbus, src, &huffman);
+        assert_eq!(huffman_decompress(&gba.sysbus, src), vec![0xaa; 4]);
+        huffman[0] = 0x23;
+        write_wram(&mut gba.sysbus, src, &huffman);
+        assert_eq!(huffman_decompress(&gba.sysbus, src).len(), 4);
+        // a data size of 0 is rejected instead of looping forever
+        huffman[0] = 0x20;
+        write_wram(&mut gba.sysbus, src, &huffman);
+        assert!(huffman_decompress(&gba.sysbus, src).is_empty());
+    }
+}
@@ -111,6 +111,13 @@ impl BackupFile {
         &mut self.buffer
     }
 
+    /// Overwrites the start of the buffer with `bytes`, anything past the buffer size is dropped
+    pub fn load(&mut self, bytes: &[u8]) {
+        let len = bytes.len().min(self.buffer.len());
+        self.buffer[..len].copy_from_slice(&bytes[..len]);
+        self.flush();
+    }
+
     pub fn flush(&mut self) {
         if let Some(file) = &mut self.file {
             file.seek(SeekFrom::Start(0)).unwrap();
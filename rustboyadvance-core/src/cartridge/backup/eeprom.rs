@@ -161,7 +161,7 @@ impl EepromChip {
                 }
             }
             RxAddress(insn) => {
-                if self.rx_count == self.addr_bits.into() {
+                if self.rx_count == Into::<usize>::into(self.addr_bits) {
                     self.address = (self.rx_buffer as usize) * 8;
                     trace!(
                         "{:?} mode , recvd address = {:#x} (rx_buffer={:#x})",
@@ -254,6 +254,16 @@ impl EepromChip {
         result
     }
 
+    /// The bit `clock_data_out` would return, without clocking the chip
+    fn peek_data_out(&self) -> u8 {
+        use SpiState::*;
+        match self.state {
+            TxDummy => 0,
+            TxData => ((self.tx_buffer >> 63) & 1) as u8,
+            _ => self.chip_ready as u8,
+        }
+    }
+
     pub(in crate) fn is_transmitting(&self) -> bool {
         use SpiState::*;
         match self.state {
@@ -319,6 +329,40 @@ impl EepromController {
         }
     }
 
+    /// The size of the save, which can still grow to 8k while the eeprom type is being detected
+    pub fn capacity(&self) -> usize {
+        if self.detect {
+            EepromType::Eeprom8k.size()
+        } else {
+            self.chip.borrow().memory.bytes().len()
+        }
+    }
+
+    /// Copies the eeprom contents into `buffer`, returns the number of bytes copied
+    pub fn copy_bytes_to(&self, buffer: &mut [u8]) -> usize {
+        let chip = self.chip.borrow();
+        let bytes = chip.memory.bytes();
+        let len = bytes.len().min(buffer.len());
+        buffer[..len].copy_from_slice(&bytes[..len]);
+        len
+    }
+
+    /// Replaces the eeprom contents.
+    /// While the type is not detected yet, only an 8k eeprom could have data past the first 512 bytes.
+    pub fn load_bytes(&mut self, bytes: &[u8]) {
+        let chip = self.chip.get_mut();
+        let has_8k_data = bytes
+            .iter()
+            .skip(EepromType::Eeprom512.size())
+            .any(|&b| b != 0xff);
+        if self.detect && has_8k_data {
+            info!("save has more than 512 bytes, assuming eeprom type is Eeprom8k");
+            chip.set_type(EepromType::Eeprom8k);
+            self.detect = false;
+        }
+        chip.memory.load(bytes);
+    }
+
     pub fn write_half(&mut self, address: u32, value: u16) {
         assert!(!self.detect);
         self.chip.borrow_mut().clock_data_in(address, value as u8);
@@ -330,6 +374,12 @@ impl EepromController {
         chip.clock_data_out(address) as u16
     }
 
+    /// Same as `read_half`, without clocking the chip, so it is fine to call before the type is
+    /// detected
+    pub fn peek_half(&self) -> u16 {
+        self.chip.borrow().peek_data_out() as u16
+    }
+
     pub fn on_dma3_transfer(&mut self, src: u32, dst: u32, count: usize) {
         use EepromType::*;
         if self.detect {
@@ -520,4 +570,43 @@ mod tests {
             assert_eq!(0, chip.tx_count);
         }
     }
+
+    #[test]
+    fn test_load_bytes() {
+        let mut small = vec![0xff; 0x2000];
+        small[0x10] = 0x42;
+        let mut eeprom = EepromController::new(None);
+        eeprom.load_bytes(&small);
+        assert_eq!(eeprom.capacity(), 0x2000);
+        assert!(eeprom.detect);
+
+        let mut large = small.clone();
+        large[0x1000] = 0x17;
+        let mut eeprom = EepromController::new(None);
+        eeprom.load_bytes(&large);
+        assert!(!eeprom.detect);
+        let mut buffer = vec![0; 0x2000];
+        assert_eq!(eeprom.copy_bytes_to(&mut buffer), 0x2000);
+        assert_eq!(buffer, large);
+    }
+
+    #[test]
+    fn test_peek_does_not_clock() {
+        let mut spi = EepromController::new_with_type(None, EepromType::Eeprom512);
+        spi.chip.borrow_mut().memory.bytes_mut()[16] = 0x80;
+        for half in make_spi_read_request(2).into_iter() {
+            spi.write_half(EEPROM_BASE_ADDR, half);
+        }
+        spi.consume_dummy_cycles();
+
+        assert_eq!(spi.peek_half(), 1);
+        assert_eq!(spi.peek_half(), 1);
+        assert_eq!(spi.chip.borrow().tx_count, 0);
+        assert_eq!(spi.read_half(EEPROM_BASE_ADDR), 1);
+        assert_eq!(spi.peek_half(), 0);
+
+        // and it doesn't need the type to be detected
+        spi.detect = true;
+        spi.peek_half();
+    }
 }
@@ -7,7 +7,9 @@ use super::super::{GBAError, GBAResult};
 use super::backup::eeprom::*;
 use super::backup::flash::*;
 use super::backup::{BackupFile, BackupType};
+use super::gpio::Gpio;
 use super::header;
+use super::rtc::{Rtc, RtcTimeSource};
 use super::BackupMedia;
 use super::Cartridge;
 
@@ -20,6 +22,8 @@ pub struct GamepakBuilder {
     save_path: Option<PathBuf>,
     save_type: BackupType,
     create_backup_file: bool,
+    force_rtc: bool,
+    rtc_time_source: RtcTimeSource,
 }
 
 impl GamepakBuilder {
@@ -30,6 +34,8 @@ impl GamepakBuilder {
             save_path: None,
             bytes: None,
             create_backup_file: true,
+            force_rtc: false,
+            rtc_time_source: RtcTimeSource::default(),
         }
     }
 
@@ -83,6 +89,17 @@ impl GamepakBuilder {
         self
     }
 
+    /// Connects a real-time clock to the gpio port, even if the game is not known to have one
+    pub fn with_rtc(mut self) -> Self {
+        self.force_rtc = true;
+        self
+    }
+
+    pub fn rtc_time_source(mut self, time_source: RtcTimeSource) -> Self {
+        self.rtc_time_source = time_source;
+        self
+    }
+
     pub fn build(mut self) -> GBAResult<Cartridge> {
         let (bytes, symbols) = if let Some(bytes) = self.bytes {
             match load_from_bytes(bytes.to_vec())? {
@@ -102,6 +119,12 @@ impl GamepakBuilder {
             ))
         }?;
 
+        if bytes.len() < header::HEADER_SIZE {
+            return Err(GBAError::CartridgeLoadError(format!(
+                "rom is too small ({} bytes) to have a header",
+                bytes.len()
+            )));
+        }
         let header = header::parse(&bytes);
         info!("Loaded ROM: {:?}", header);
 
@@ -124,6 +147,17 @@ impl GamepakBuilder {
             }
         }
 
+        let gpio = if self.force_rtc || detect_rtc(&header.game_code) {
+            info!("Cartridge has a real-time clock");
+            let rtc_path = self
+                .save_path
+                .as_ref()
+                .map(|path| path.with_extension(RTC_FILE_EXT));
+            Some(Gpio::new(Some(Rtc::new(self.rtc_time_source, rtc_path))))
+        } else {
+            None
+        };
+
         let backup = create_backup(self.save_type, self.save_path);
 
         let size = bytes.len();
@@ -133,11 +167,13 @@ impl GamepakBuilder {
             size: size,
             backup: backup,
             symbols: symbols,
+            gpio: gpio,
         })
     }
 }
 
 const BACKUP_FILE_EXT: &'static str = "sav";
+const RTC_FILE_EXT: &'static str = "rtc";
 fn create_backup(backup_type: BackupType, rom_path: Option<PathBuf>) -> BackupMedia {
     let backup_path = if let Some(rom_path) = rom_path {
         Some(rom_path.with_extension(BACKUP_FILE_EXT))
@@ -168,3 +204,21 @@ fn detect_backup_type(bytes: &[u8]) -> Option<BackupType> {
     }
     None
 }
+
+/// Games that have a real-time clock, by the first 3 characters of the game code
+const RTC_GAME_CODES: &'static [&'static str] = &[
+    "AXV", // Pokemon Ruby
+    "AXP", // Pokemon Sapphire
+    "BPE", // Pokemon Emerald
+    "U3I", // Boktai: The Sun Is in Your Hand
+    "U32", // Boktai 2: Solar Boy Django
+    "U33", // Shin Bokura no Taiyou: Gyakushuu no Sabata
+    "BR4", // Rockman EXE 4.5: Real Operation
+    "BKA", // Sennen Kazoku
+];
+
+fn detect_rtc(game_code: &str) -> bool {
+    RTC_GAME_CODES
+        .iter()
+        .any(|code| game_code.starts_with(code))
+}
@@ -0,0 +1,78 @@
+//! General purpose I/O port of the cartridge, used to connect extra hardware such as a real-time clock
+use serde::{Deserialize, Serialize};
+
+use super::rtc::Rtc;
+
+pub const GPIO_PORT_DATA: u32 = 0xC4;
+pub const GPIO_PORT_DIRECTION: u32 = 0xC6;
+pub const GPIO_PORT_CONTROL: u32 = 0xC8;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Gpio {
+    /// Pins as last written by the cpu
+    data: u8,
+    /// Bit set means the pin is an output of the cpu
+    direction: u8,
+    /// When cleared the port is write-only, and reads return the rom contents
+    readable: bool,
+
+    pub rtc: Option<Rtc>,
+}
+
+impl Gpio {
+    pub fn new(rtc: Option<Rtc>) -> Gpio {
+        Gpio {
+            data: 0,
+            direction: 0,
+            readable: false,
+            rtc: rtc,
+        }
+    }
+
+    #[inline]
+    pub fn is_gpio_access(offset: u32) -> bool {
+        offset >= GPIO_PORT_DATA && offset <= GPIO_PORT_CONTROL + 1
+    }
+
+    #[inline]
+    pub fn is_readable(&self) -> bool {
+        self.readable
+    }
+
+    pub fn update(&mut self, cycles: usize) {
+        if let Some(rtc) = &mut self.rtc {
+            rtc.update(cycles);
+        }
+    }
+
+    pub fn read(&self, offset: u32) -> u8 {
+        match offset {
+            GPIO_PORT_DATA => {
+                let device_pins = match &self.rtc {
+                    Some(rtc) => rtc.read_pins(),
+                    None => 0,
+                };
+                ((self.data & self.direction) | (device_pins & !self.direction)) & 0xf
+            }
+            GPIO_PORT_DIRECTION => self.direction,
+            GPIO_PORT_CONTROL => self.readable as u8,
+            _ => 0,
+        }
+    }
+
+    pub fn write(&mut self, offset: u32, value: u8) {
+        match offset {
+            GPIO_PORT_DATA => {
+                self.data = value & 0xf;
+                if let Some(rtc) = &mut self.rtc {
+                    // pins configured as inputs are pulled up to the device's output
+                    let pins = (self.data & self.direction) | (rtc.read_pins() & !self.direction);
+                    rtc.write_pins(pins);
+                }
+            }
+            GPIO_PORT_DIRECTION => self.direction = value & 0xf,
+            GPIO_PORT_CONTROL => self.readable = value & 1 != 0,
+            _ => {}
+        }
+    }
+}
@@ -1,6 +1,9 @@
 use serde::{Deserialize, Serialize};
 use std::str::from_utf8;
 
+/// Size of the cartridge header, without the multiboot entries
+pub const HEADER_SIZE: usize = 0xc0;
+
 /// From GBATEK
 ///
 /// The first 192 bytes at 8000000h-80000BFh in ROM are used as cartridge header. The same header is also used for Multiboot images at 2000000h-20000BFh (plus some additional multiboot entries at 20000C0h and up).
@@ -17,6 +17,11 @@ mod builder;
 mod loader;
 pub use builder::GamepakBuilder;
 
+pub mod gpio;
+pub mod rtc;
+use gpio::Gpio;
+pub use rtc::RtcTimeSource;
+
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub enum BackupMedia {
     Sram(BackupFile),
@@ -30,22 +35,113 @@ pub type SymbolTable = HashMap<String, u32>;
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct Cartridge {
     pub header: CartridgeHeader,
+    /// Not part of save states, the rom is attached again when restoring
+    #[serde(skip)]
     bytes: Box<[u8]>,
     size: usize,
     symbols: Option<SymbolTable>, // TODO move it somewhere else
-    pub(in crate) backup: BackupMedia,
+    pub(crate) backup: BackupMedia,
+    pub(crate) gpio: Option<Gpio>,
 }
 
 impl Cartridge {
     pub fn get_symbols(&self) -> &Option<SymbolTable> {
         &self.symbols
     }
+
+    pub fn get_rom_bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    pub(crate) fn take_rom_bytes(&mut self) -> Box<[u8]> {
+        std::mem::take(&mut self.bytes)
+    }
+
+    pub(crate) fn set_rom_bytes(&mut self, bytes: Box<[u8]>) {
+        self.bytes = bytes;
+    }
+
+    /// The most bytes of save memory the game can use, 0 when it has none
+    pub fn get_backup_capacity(&self) -> usize {
+        match &self.backup {
+            BackupMedia::Sram(memory) => memory.bytes().len(),
+            BackupMedia::Flash(flash) => flash.memory.bytes().len(),
+            BackupMedia::Eeprom(eeprom) => eeprom.capacity(),
+            BackupMedia::Undetected => 0,
+        }
+    }
+
+    /// Copies the save memory into `buffer`, returns the number of bytes copied
+    pub fn copy_backup_to(&self, buffer: &mut [u8]) -> usize {
+        let bytes = match &self.backup {
+            BackupMedia::Sram(memory) => memory.bytes(),
+            BackupMedia::Flash(flash) => flash.memory.bytes(),
+            BackupMedia::Eeprom(eeprom) => return eeprom.copy_bytes_to(buffer),
+            BackupMedia::Undetected => &[],
+        };
+        let len = bytes.len().min(buffer.len());
+        buffer[..len].copy_from_slice(&bytes[..len]);
+        len
+    }
+
+    /// Replaces the save memory, for frontends that keep the saves themselves
+    pub fn load_backup(&mut self, bytes: &[u8]) {
+        match &mut self.backup {
+            BackupMedia::Sram(memory) => memory.load(bytes),
+            BackupMedia::Flash(flash) => flash.memory.load(bytes),
+            BackupMedia::Eeprom(eeprom) => eeprom.load_bytes(bytes),
+            BackupMedia::Undetected => {}
+        }
+    }
+
+    pub fn has_rtc(&self) -> bool {
+        match &self.gpio {
+            Some(gpio) => gpio.rtc.is_some(),
+            None => false,
+        }
+    }
+
+    #[inline]
+    fn is_gpio_readable(&self, offset: u32) -> bool {
+        match &self.gpio {
+            Some(gpio) => gpio.is_readable() && Gpio::is_gpio_access(offset),
+            None => false,
+        }
+    }
+
+    pub fn update(&mut self, cycles: usize) {
+        if let Some(gpio) = &mut self.gpio {
+            gpio.update(cycles);
+        }
+    }
 }
 
 use super::sysbus::consts::*;
 
 pub const EEPROM_BASE_ADDR: u32 = 0x0DFF_FF00;
 
+impl Cartridge {
+    fn is_eeprom_access(&self, addr: u32) -> bool {
+        addr & 0xff000000 == GAMEPAK_WS2_HI
+            && (self.bytes.len() <= 16 * 1024 * 1024 || addr >= EEPROM_BASE_ADDR)
+    }
+
+    /// Same as `read_16`, without clocking the eeprom
+    pub fn debug_read_16(&self, addr: u32) -> u16 {
+        if self.is_eeprom_access(addr) {
+            if let BackupMedia::Eeprom(spi) = &self.backup {
+                return spi.peek_half();
+            }
+        }
+        self.read_16(addr)
+    }
+
+    /// Same as `read_32`, without clocking the eeprom
+    pub fn debug_read_32(&self, addr: u32) -> u32 {
+        self.debug_read_16(addr) as u32 | (self.debug_read_16(addr + 2) as u32) << 16
+    }
+}
+
 impl Bus for Cartridge {
     fn read_8(&self, addr: Addr) -> u8 {
         let offset = (addr & 0x01ff_ffff) as usize;
@@ -55,9 +151,13 @@ impl Bus for Cartridge {
                 BackupMedia::Flash(flash) => flash.read(addr),
                 _ => 0,
             },
+            GAMEPAK_WS0_LO if self.is_gpio_readable(offset as u32) => {
+                self.gpio.as_ref().unwrap().read(offset as u32)
+            }
             _ => {
                 if offset >= self.size {
-                    0xDD // TODO - open bus implementation
+                    // the address lines are left on the bus, each halfword reads as its address / 2
+                    ((offset >> 1) >> ((offset & 1) * 8)) as u8
                 } else {
                     unsafe { *self.bytes.get_unchecked(offset as usize) }
                 }
@@ -66,9 +166,7 @@ impl Bus for Cartridge {
     }
 
     fn read_16(&self, addr: u32) -> u16 {
-        if addr & 0xff000000 == GAMEPAK_WS2_HI
-            && (self.bytes.len() <= 16 * 1024 * 1024 || addr >= EEPROM_BASE_ADDR)
-        {
+        if self.is_eeprom_access(addr) {
             if let BackupMedia::Eeprom(spi) = &self.backup {
                 return spi.read_half(addr);
             }
@@ -83,14 +181,17 @@ impl Bus for Cartridge {
                 BackupMedia::Sram(memory) => memory.write((addr & 0x7FFF) as usize, value),
                 _ => {}
             },
+            GAMEPAK_WS0_LO if Gpio::is_gpio_access(addr & 0x01ff_ffff) => {
+                if let Some(gpio) = &mut self.gpio {
+                    gpio.write(addr & 0x01ff_ffff, value);
+                }
+            }
             _ => {} // TODO allow the debugger to write
         };
     }
 
     fn write_16(&mut self, addr: u32, value: u16) {
-        if addr & 0xff000000 == GAMEPAK_WS2_HI
-            && (self.bytes.len() <= 16 * 1024 * 1024 || addr >= EEPROM_BASE_ADDR)
-        {
+        if self.is_eeprom_access(addr) {
             if let BackupMedia::Eeprom(spi) = &mut self.backup {
                 return spi.write_half(addr, value);
             }
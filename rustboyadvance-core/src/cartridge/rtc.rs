@@ -0,0 +1,438 @@
+//! Seiko S-3511 real-time clock, connected to the cartridge gpio port
+use std::path::PathBuf;
+
+use byteorder::{ByteOrder, LittleEndian};
+use num::FromPrimitive;
+use serde::{Deserialize, Serialize};
+use time::{Date, OffsetDateTime};
+
+use super::backup::{BackupFile, BackupMemoryInterface};
+
+const CYCLES_PER_SECOND: u64 = 16 * 1024 * 1024;
+
+/// Control register: 24-hour mode
+const CONTROL_24H: u8 = 1 << 6;
+/// Control register: set when the clock lost its power
+const CONTROL_POWER_FAIL: u8 = 1 << 7;
+const CONTROL_WRITE_MASK: u8 = 0b0110_1010;
+
+/// The file layout is the control register followed by the clock offset in seconds
+const RTC_FILE_SIZE: usize = 16;
+
+/// GPIO pins
+const PIN_SCK: u8 = 1 << 0;
+const PIN_SIO: u8 = 1 << 1;
+const PIN_CS: u8 = 1 << 2;
+
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
+pub enum RtcTimeSource {
+    /// Follows the local time of the host
+    Host,
+    /// Starts at a fixed unix timestamp and advances with the emulated cycles,
+    /// for reproducible runs
+    Emulated(i64),
+}
+
+impl Default for RtcTimeSource {
+    #[cfg(not(target_arch = "wasm32"))]
+    fn default() -> RtcTimeSource {
+        RtcTimeSource::Host
+    }
+
+    #[cfg(target_arch = "wasm32")]
+    fn default() -> RtcTimeSource {
+        // the system clock is not available here
+        RtcTimeSource::Emulated(0)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Primitive, Copy, Clone, PartialEq)]
+enum RtcCommand {
+    Reset = 0,
+    DateTime = 2,
+    ForceIrq = 3,
+    Control = 4,
+    Time = 6,
+}
+
+impl RtcCommand {
+    fn num_bytes(&self) -> usize {
+        match self {
+            RtcCommand::Reset | RtcCommand::ForceIrq => 0,
+            RtcCommand::Control => 1,
+            RtcCommand::Time => 3,
+            RtcCommand::DateTime => 7,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
+enum RtcState {
+    /// Waiting for CS to rise
+    Idle,
+    /// Receiving the command byte
+    Command,
+    /// Shifting parameter bytes in or out
+    Transfer { command: RtcCommand, read: bool },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Rtc {
+    control: u8,
+    /// Seconds between the time source and the time the game has set
+    offset: i64,
+    time_source: RtcTimeSource,
+    emulated_cycles: u64,
+
+    state: RtcState,
+    prev_pins: u8,
+    /// SIO as driven by the rtc
+    sio_out: bool,
+    shift_reg: u8,
+    bits: usize,
+    buffer: [u8; 7],
+    byte_index: usize,
+
+    file: Option<BackupFile>,
+}
+
+fn to_bcd(value: u8) -> u8 {
+    ((value / 10) << 4) | (value % 10)
+}
+
+fn from_bcd(value: u8) -> u8 {
+    (value >> 4) * 10 + (value & 0xf)
+}
+
+impl Rtc {
+    pub fn new(time_source: RtcTimeSource, rtc_path: Option<PathBuf>) -> Rtc {
+        let mut rtc = Rtc {
+            control: CONTROL_24H,
+            offset: 0,
+            time_source: time_source,
+            emulated_cycles: 0,
+            state: RtcState::Idle,
+            prev_pins: 0,
+            sio_out: false,
+            shift_reg: 0,
+            bits: 0,
+            buffer: [0; 7],
+            byte_index: 0,
+            file: None,
+        };
+        if let Some(path) = rtc_path {
+            let is_new = !path.is_file();
+            let file = BackupFile::new(RTC_FILE_SIZE, Some(path));
+            if !is_new {
+                let bytes = file.bytes();
+                rtc.control = bytes[0];
+                rtc.offset = LittleEndian::read_i64(&bytes[8..16]);
+            }
+            rtc.file = Some(file);
+            rtc.flush();
+        }
+        rtc
+    }
+
+    fn flush(&mut self) {
+        let mut bytes = [0; RTC_FILE_SIZE];
+        bytes[0] = self.control;
+        LittleEndian::write_i64(&mut bytes[8..16], self.offset);
+        if let Some(file) = &mut self.file {
+            for (i, &b) in bytes.iter().enumerate() {
+                file.write(i, b);
+            }
+        }
+    }
+
+    pub fn update(&mut self, cycles: usize) {
+        self.emulated_cycles += cycles as u64;
+    }
+
+    /// The clock of the time source, in local time
+    fn source_timestamp(&self) -> i64 {
+        match self.time_source {
+            RtcTimeSource::Host => {
+                let now =
+                    OffsetDateTime::try_now_local().unwrap_or_else(|_| OffsetDateTime::now_utc());
+                now.unix_timestamp() + now.offset().as_seconds() as i64
+            }
+            RtcTimeSource::Emulated(start) => {
+                start + (self.emulated_cycles / CYCLES_PER_SECOND) as i64
+            }
+        }
+    }
+
+    fn now(&self) -> OffsetDateTime {
+        OffsetDateTime::from_unix_timestamp(self.source_timestamp() + self.offset)
+    }
+
+    fn encode_hour(&self, hour: u8) -> u8 {
+        let pm = if hour >= 12 { 0x40 } else { 0 };
+        if self.control & CONTROL_24H != 0 {
+            to_bcd(hour) | pm
+        } else {
+            to_bcd(hour % 12) | pm
+        }
+    }
+
+    fn encode_time(&self, now: &OffsetDateTime) -> [u8; 3] {
+        [
+            self.encode_hour(now.hour()),
+            to_bcd(now.minute()),
+            to_bcd(now.second()),
+        ]
+    }
+
+    fn encode_date_time(&self) -> [u8; 7] {
+        let now = self.now();
+        let time = self.encode_time(&now);
+        [
+            to_bcd((now.year() % 100) as u8),
+            to_bcd(now.month()),
+            to_bcd(now.day()),
+            now.weekday().number_days_from_sunday(),
+            time[0],
+            time[1],
+            time[2],
+        ]
+    }
+
+    /// Moves the clock to the date and time written by the game
+    fn set_date_time(&mut self, bytes: &[u8; 7]) {
+        let year = 2000 + from_bcd(bytes[0]) as i32;
+        let hour = from_bcd(bytes[4] & 0x3f);
+        let hour = if self.control & CONTROL_24H == 0 && bytes[4] & 0x40 != 0 {
+            hour % 12 + 12
+        } else {
+            hour
+        };
+        let set = Date::try_from_ymd(year, from_bcd(bytes[1]), from_bcd(bytes[2]))
+            .and_then(|date| date.try_with_hms(hour, from_bcd(bytes[5]), from_bcd(bytes[6])));
+        match set {
+            Ok(set) => {
+                self.offset = set.assume_utc().unix_timestamp() - self.source_timestamp();
+                self.flush();
+            }
+            Err(e) => warn!("RTC: ignoring invalid date {:x?} ({})", bytes, e),
+        }
+    }
+
+    fn set_time(&mut self, bytes: &[u8]) {
+        let mut date_time = self.encode_date_time();
+        date_time[4..7].copy_from_slice(bytes);
+        self.set_date_time(&date_time);
+    }
+
+    fn begin_command(&mut self, byte: u8) {
+        // the command is sent msb first, but some games send it lsb first
+        let byte = if byte & 0xf == 0b0110 {
+            byte
+        } else {
+            byte.reverse_bits()
+        };
+        if byte & 0xf != 0b0110 {
+            warn!("RTC: invalid command byte {:#x}", byte);
+            self.state = RtcState::Idle;
+            return;
+        }
+        let read = byte & 0x80 != 0;
+        let command = match RtcCommand::from_u8((byte >> 4) & 7) {
+            Some(command) => command,
+            None => {
+                warn!("RTC: unknown command {:#x}", byte);
+                self.state = RtcState::Idle;
+                return;
+            }
+        };
+        trace!("RTC: command {:?} read={}", command, read);
+        self.byte_index = 0;
+        match command {
+            RtcCommand::Reset => {
+                // back to 2000-01-01 00:00:00
+                self.control = 0;
+                self.offset = 946684800 - self.source_timestamp();
+                self.flush();
+            }
+            RtcCommand::ForceIrq => {}
+            RtcCommand::Control => self.buffer[0] = self.control,
+            RtcCommand::DateTime => self.buffer = self.encode_date_time(),
+            RtcCommand::Time => {
+                let time = self.encode_time(&self.now());
+                self.buffer[..3].copy_from_slice(&time);
+            }
+        }
+        self.state = if command.num_bytes() > 0 {
+            RtcState::Transfer { command, read }
+        } else {
+            RtcState::Idle
+        };
+    }
+
+    fn end_write(&mut self, command: RtcCommand) {
+        match command {
+            RtcCommand::Control => {
+                self.control =
+                    (self.buffer[0] & CONTROL_WRITE_MASK) | (self.control & CONTROL_POWER_FAIL);
+                self.flush();
+            }
+            RtcCommand::DateTime => {
+                let bytes = self.buffer;
+                self.set_date_time(&bytes);
+            }
+            RtcCommand::Time => {
+                let bytes = [self.buffer[0], self.buffer[1], self.buffer[2]];
+                self.set_time(&bytes);
+            }
+            _ => {}
+        }
+    }
+
+    /// Latches a bit on the rising edge of SCK
+    fn clock(&mut self, sio: bool) {
+        match self.state {
+            RtcState::Idle => {}
+            RtcState::Command => {
+                self.shift_reg |= (sio as u8) << self.bits;
+                self.bits += 1;
+                if self.bits == 8 {
+                    let byte = self.shift_reg;
+                    self.shift_reg = 0;
+                    self.bits = 0;
+                    self.begin_command(byte);
+                }
+            }
+            RtcState::Transfer { command, read } => {
+                if read {
+                    self.sio_out = (self.buffer[self.byte_index] >> self.bits) & 1 != 0;
+                } else {
+                    self.shift_reg |= (sio as u8) << self.bits;
+                }
+                self.bits += 1;
+                if self.bits == 8 {
+                    if !read {
+                        self.buffer[self.byte_index] = self.shift_reg;
+                    }
+                    self.shift_reg = 0;
+                    self.bits = 0;
+                    self.byte_index += 1;
+                    if self.byte_index == command.num_bytes() {
+                        if !read {
+                            self.end_write(command);
+                        }
+                        self.state = RtcState::Idle;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Called when the cpu writes to the gpio data register
+    pub fn write_pins(&mut self, pins: u8) {
+        let prev = self.prev_pins;
+        self.prev_pins = pins;
+
+        if pins & PIN_CS == 0 {
+            self.state = RtcState::Idle;
+            self.shift_reg = 0;
+            self.bits = 0;
+            return;
+        }
+        if prev & PIN_CS == 0 {
+            self.state = RtcState::Command;
+            return;
+        }
+        if prev & PIN_SCK == 0 && pins & PIN_SCK != 0 {
+            self.clock(pins & PIN_SIO != 0);
+        }
+    }
+
+    /// The pins as driven by the rtc
+    pub fn read_pins(&self) -> u8 {
+        if self.sio_out {
+            PIN_SIO
+        } else {
+            0
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn send_byte(rtc: &mut Rtc, byte: u8, msb_first: bool) {
+        for i in 0..8 {
+            let bit = if msb_first {
+                (byte >> (7 - i)) & 1
+            } else {
+                (byte >> i) & 1
+            };
+            rtc.write_pins(PIN_CS | (bit << 1));
+            rtc.write_pins(PIN_CS | PIN_SCK | (bit << 1));
+        }
+    }
+
+    fn recv_byte(rtc: &mut Rtc) -> u8 {
+        let mut byte = 0;
+        for i in 0..8 {
+            rtc.write_pins(PIN_CS);
+            rtc.write_pins(PIN_CS | PIN_SCK);
+            byte |= ((rtc.read_pins() & PIN_SIO) >> 1) << i;
+        }
+        byte
+    }
+
+    fn begin(rtc: &mut Rtc) {
+        rtc.write_pins(PIN_SCK);
+        rtc.write_pins(PIN_SCK | PIN_CS);
+    }
+
+    fn end(rtc: &mut Rtc) {
+        rtc.write_pins(PIN_SCK);
+    }
+
+    fn read_date_time(rtc: &mut Rtc) -> [u8; 7] {
+        let mut bytes = [0; 7];
+        begin(rtc);
+        send_byte(rtc, 0x65, true);
+        for b in bytes.iter_mut() {
+            *b = recv_byte(rtc);
+        }
+        end(rtc);
+        bytes
+    }
+
+    #[test]
+    fn test_read_emulated_date_time() {
+        // 2004-12-31 23:59:58, a friday
+        let mut rtc = Rtc::new(RtcTimeSource::Emulated(1104537598), None);
+        assert_eq!(
+            read_date_time(&mut rtc),
+            [0x04, 0x12, 0x31, 5, 0x23 | 0x40, 0x59, 0x58]
+        );
+
+        rtc.update(2 * CYCLES_PER_SECOND as usize);
+        assert_eq!(
+            read_date_time(&mut rtc),
+            [0x05, 0x01, 0x01, 6, 0x00, 0x00, 0x00]
+        );
+    }
+
+    #[test]
+    fn test_write_date_time() {
+        let mut rtc = Rtc::new(RtcTimeSource::Emulated(0), None);
+        begin(&mut rtc);
+        send_byte(&mut rtc, 0x64, true);
+        for &b in [0x19, 0x07, 0x14, 0, 0x10, 0x30, 0x00].iter() {
+            send_byte(&mut rtc, b, false);
+        }
+        end(&mut rtc);
+
+        rtc.update(CYCLES_PER_SECOND as usize);
+        assert_eq!(
+            read_date_time(&mut rtc),
+            [0x19, 0x07, 0x14, 0, 0x10, 0x30, 0x01]
+        );
+    }
+}
@@ -5,12 +5,13 @@ use crate::arm7tdmi::arm::ArmInstruction;
 use crate::arm7tdmi::thumb::ThumbInstruction;
 use crate::arm7tdmi::CpuState;
 use crate::disass::Disassembler;
+use crate::sysbus::{WatchKind, Watchpoint};
 use crate::util::{read_bin_file, write_bin_file};
 use crate::{Addr, Bus};
 
 // use super::palette_view::create_palette_view;
 // use super::tile_view::create_tile_view;
-use super::{parser::Value, Debugger, DebuggerError, DebuggerResult};
+use super::{parser::Value, Breakpoint, Debugger, DebuggerError, DebuggerResult};
 
 use ansi_term::Colour;
 
@@ -52,12 +53,17 @@ pub enum Command {
     HexDump(Addr, u32),
     MemWrite(MemWriteCommandSize, Addr, u32),
     Disass(DisassMode, Addr, u32),
-    AddBreakpoint(Addr),
+    AddBreakpoint(Breakpoint),
     DelBreakpoint(Addr),
+    IgnoreBreakpoint(Addr, u32),
     // PaletteView,
     // TileView(u32),
     ClearBreakpoints,
     ListBreakpoints,
+    AddWatchpoint(Watchpoint),
+    DelWatchpoint(Addr),
+    ClearWatchpoints,
+    ListWatchpoints,
     Reset,
     Quit,
     TraceToggle(TraceFlags),
@@ -79,6 +85,7 @@ impl Debugger {
             }
             GpuInfo => println!("GPU: {:#?}", self.gba.sysbus.io.gpu),
             Step(count) => {
+                self.discard_watch_hits();
                 for _ in 0..count {
                     self.gba.cpu.step(&mut self.gba.sysbus);
                     while self.gba.cpu.last_executed.is_none() {
@@ -102,21 +109,28 @@ impl Debugger {
                             ))
                         );
                     }
+                    if self.report_watch_hits() {
+                        break;
+                    }
                 }
                 println!("{}\n", self.gba.cpu);
             }
-            Continue => loop {
-                self.gba.key_poll();
-                match self.gba.check_breakpoint() {
-                    Some(addr) => {
-                        println!("Breakpoint reached! @{:x}", addr);
+            Continue => {
+                self.discard_watch_hits();
+                // step first, so continuing from a breakpoint doesn't stop on it again
+                loop {
+                    self.gba.key_poll();
+                    self.gba.cpu.step(&mut self.gba.sysbus);
+                    if self.report_watch_hits() {
                         break;
                     }
-                    _ => {
-                        self.gba.cpu.step(&mut self.gba.sysbus);
+                    if let Some(addr) = self.gba.check_breakpoint() {
+                        if self.breakpoint_hit(addr) {
+                            break;
+                        }
                     }
                 }
-            },
+            }
             Frame(count) => {
                 let start = time::Instant::now();
                 for _ in 0..count {
@@ -124,6 +138,8 @@ impl Debugger {
                 }
                 let end = time::Instant::now();
                 println!("that took {:?} seconds", end - start);
+                // frames don't stop at watchpoints
+                self.discard_watch_hits();
             }
             HexDump(addr, nbytes) => {
                 let bytes = self.gba.sysbus.get_bytes(addr..addr + nbytes);
@@ -155,16 +171,56 @@ impl Debugger {
                 print!("Quitting!");
                 self.stop();
             }
-            AddBreakpoint(addr) => match self.gba.add_breakpoint(addr) {
-                Some(index) => println!("Added breakpoint [{}] 0x{:08x}", index, addr),
-                None => println!("Breakpint already exists."),
-            },
+            AddBreakpoint(breakpoint) => {
+                let description = breakpoint.to_string();
+                match self.add_breakpoint(breakpoint) {
+                    Ok(index) => println!("Added breakpoint [{}] {}", index, description),
+                    Err(e) => println!("Error: {:?}", e),
+                }
+            }
             DelBreakpoint(addr) => self.delete_breakpoint(addr),
-            ClearBreakpoints => self.gba.cpu.breakpoints.clear(),
+            IgnoreBreakpoint(addr, count) => match self.get_breakpoint_mut(addr) {
+                Some((index, breakpoint)) => {
+                    breakpoint.ignore_count = count;
+                    println!(
+                        "Will ignore the next {} hits of breakpoint [{}]",
+                        count, index
+                    );
+                }
+                None => println!("No breakpoint at 0x{:08x}", addr),
+            },
+            ClearBreakpoints => self.clear_breakpoints(),
             ListBreakpoints => {
                 println!("breakpoint list:");
-                for (i, b) in self.gba.cpu.breakpoints.iter().enumerate() {
-                    println!("[{}] 0x{:08x}", i, b)
+                for (i, &addr) in self.gba.cpu.breakpoints.iter().enumerate() {
+                    match self.breakpoints.get(&addr) {
+                        Some(b) => println!("[{}] {}", i, b),
+                        None => println!("[{}] {}", i, Breakpoint::new(addr)),
+                    }
+                }
+            }
+            AddWatchpoint(watchpoint) => {
+                self.gba.sysbus.add_watch(watchpoint);
+                self.watchpoints.push(watchpoint);
+                println!("Added watchpoint {}", describe_watchpoint(&watchpoint));
+            }
+            DelWatchpoint(addr) => {
+                let (deleted, kept): (Vec<Watchpoint>, Vec<Watchpoint>) =
+                    self.watchpoints.drain(..).partition(|w| w.addr == addr);
+                self.watchpoints = kept;
+                for watchpoint in &deleted {
+                    self.gba.sysbus.remove_watch(watchpoint);
+                }
+            }
+            ClearWatchpoints => {
+                for watchpoint in self.watchpoints.drain(..) {
+                    self.gba.sysbus.remove_watch(&watchpoint);
+                }
+            }
+            ListWatchpoints => {
+                println!("watchpoint list:");
+                for (i, w) in self.watchpoints.iter().enumerate() {
+                    println!("[{}] {}", i, describe_watchpoint(w))
                 }
             }
             // PaletteView => create_palette_view(&self.gba.sysbus.palette_ram.mem),
@@ -242,6 +298,56 @@ impl Debugger {
         }
     }
 
+    fn get_watchpoint_args(
+        &self,
+        command: &str,
+        kind: WatchKind,
+        args: Vec<Value>,
+    ) -> DebuggerResult<Watchpoint> {
+        let mut watchpoint = match args.len() {
+            1..=3 => Watchpoint::new(self.val_address(&args[0])?, 1, kind),
+            _ => {
+                return Err(DebuggerError::InvalidCommandFormat(format!(
+                    "{} <addr> [len] [value]",
+                    command
+                )))
+            }
+        };
+        if args.len() >= 2 {
+            watchpoint.len = self.val_number(&args[1])?;
+        }
+        if args.len() == 3 {
+            watchpoint.value = Some(self.val_number(&args[2])?);
+        }
+        Ok(watchpoint)
+    }
+
+    /// Parses `<addr> [if <condition>]`
+    fn get_breakpoint_args(
+        &self,
+        command: &str,
+        temporary: bool,
+        mut args: Vec<Value>,
+    ) -> DebuggerResult<Breakpoint> {
+        let condition = match args.last() {
+            Some(Value::Condition(_)) => match args.pop() {
+                Some(Value::Condition(condition)) => Some(*condition),
+                _ => unreachable!(),
+            },
+            _ => None,
+        };
+        if args.len() != 1 {
+            return Err(DebuggerError::InvalidCommandFormat(format!(
+                "{} <addr> [if <condition>]",
+                command
+            )));
+        }
+        let mut breakpoint = Breakpoint::new(self.val_address(&args[0])?);
+        breakpoint.condition = condition;
+        breakpoint.temporary = temporary;
+        Ok(breakpoint)
+    }
+
     fn get_disassembler_args(&self, args: Vec<Value>) -> DebuggerResult<(Addr, u32)> {
         match args.len() {
             2 => {
@@ -413,14 +519,21 @@ impl Debugger {
 
                 Ok(Command::Disass(DisassMode::ModeThumb, addr, n))
             }
-            "b" | "break" => {
-                if args.len() != 1 {
+            "b" | "break" => Ok(Command::AddBreakpoint(
+                self.get_breakpoint_args(&command, false, args)?,
+            )),
+            "tb" | "tbreak" => Ok(Command::AddBreakpoint(
+                self.get_breakpoint_args(&command, true, args)?,
+            )),
+            "ignore" => {
+                if args.len() != 2 {
                     Err(DebuggerError::InvalidCommandFormat(
-                        "break <addr>".to_string(),
+                        "ignore <addr> <count>".to_string(),
                     ))
                 } else {
                     let addr = self.val_address(&args[0])?;
-                    Ok(Command::AddBreakpoint(addr))
+                    let count = self.val_number(&args[1])?;
+                    Ok(Command::IgnoreBreakpoint(addr, count))
                 }
             }
             "bd" | "breakdel" => match args.len() {
@@ -433,6 +546,32 @@ impl Debugger {
                     "breakdel [addr]",
                 ))),
             },
+            "watch" => Ok(Command::AddWatchpoint(self.get_watchpoint_args(
+                &command,
+                WatchKind::Write,
+                args,
+            )?)),
+            "rwatch" => Ok(Command::AddWatchpoint(self.get_watchpoint_args(
+                &command,
+                WatchKind::Read,
+                args,
+            )?)),
+            "awatch" => Ok(Command::AddWatchpoint(self.get_watchpoint_args(
+                &command,
+                WatchKind::ReadWrite,
+                args,
+            )?)),
+            "wd" | "watchdel" => match args.len() {
+                0 => Ok(Command::ClearWatchpoints),
+                1 => {
+                    let addr = self.val_address(&args[0])?;
+                    Ok(Command::DelWatchpoint(addr))
+                }
+                _ => Err(DebuggerError::InvalidCommandFormat(String::from(
+                    "watchdel [addr]",
+                ))),
+            },
+            "wl" => Ok(Command::ListWatchpoints),
             // "palette-view" => Ok(Command::PaletteView),
             // "tiles" => {
             //     if args.len() != 1 {
@@ -503,3 +642,21 @@ impl Debugger {
         }
     }
 }
+
+fn describe_watchpoint(watchpoint: &Watchpoint) -> String {
+    let kind = match watchpoint.kind {
+        WatchKind::Read => "read",
+        WatchKind::Write => "write",
+        WatchKind::ReadWrite => "access",
+    };
+    let mut description = format!(
+        "{} 0x{:08x}..0x{:08x}",
+        kind,
+        watchpoint.addr,
+        watchpoint.addr as u64 + watchpoint.len as u64
+    );
+    if let Some(value) = watchpoint.value {
+        description += &format!(" == 0x{:x}", value);
+    }
+    description
+}
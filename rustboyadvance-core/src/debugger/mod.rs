@@ -1,3 +1,5 @@
+use std::collections::HashMap;
+use std::fmt;
 use std::fs::File;
 use std::io::{prelude::*, BufReader};
 
@@ -6,11 +8,12 @@ use rustyline::Editor;
 
 use colored::*;
 
+use super::sysbus::Watchpoint;
+use super::Addr;
 use super::GameBoyAdvance;
-use super::{Addr, Bus};
 
 mod parser;
-use parser::{parse_expr, DerefType, Expr, Value};
+use parser::{parse_expr, BinaryOp, DerefType, Expr, UnaryOp, Value};
 
 mod command;
 use command::Command;
@@ -35,10 +38,57 @@ impl From<::std::io::Error> for DebuggerError {
 
 type DebuggerResult<T> = Result<T, DebuggerError>;
 
+#[derive(Debug, PartialEq, Clone)]
+pub struct Breakpoint {
+    pub addr: Addr,
+    /// Only stops when this evaluates to non zero
+    pub condition: Option<Value>,
+    /// How many times the breakpoint was reached with its condition true
+    pub hit_count: u32,
+    /// Hits left to skip before stopping
+    pub ignore_count: u32,
+    /// Deleted after the first stop
+    pub temporary: bool,
+}
+
+impl Breakpoint {
+    pub fn new(addr: Addr) -> Breakpoint {
+        Breakpoint {
+            addr,
+            condition: None,
+            hit_count: 0,
+            ignore_count: 0,
+            temporary: false,
+        }
+    }
+}
+
+impl fmt::Display for Breakpoint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "0x{:08x}", self.addr)?;
+        if let Some(condition) = &self.condition {
+            write!(f, " if {}", condition)?;
+        }
+        write!(f, " (hit {} times", self.hit_count)?;
+        if self.ignore_count != 0 {
+            write!(f, ", ignoring the next {}", self.ignore_count)?;
+        }
+        if self.temporary {
+            write!(f, ", temporary")?;
+        }
+        write!(f, ")")
+    }
+}
+
 pub struct Debugger {
     pub gba: GameBoyAdvance,
     running: bool,
     pub previous_command: Option<Command>,
+    /// The conditions and counters of the breakpoints, by address. Only the ones in
+    /// `gba.cpu.breakpoints` exist, the others have no entry here (e.g. after loading a state).
+    breakpoints: HashMap<Addr, Breakpoint>,
+    /// The watchpoints added from the prompt, the sysbus may hold others, e.g. of a script
+    watchpoints: Vec<Watchpoint>,
 }
 
 impl Debugger {
@@ -47,6 +97,8 @@ impl Debugger {
             gba: gba,
             running: false,
             previous_command: None,
+            breakpoints: HashMap::new(),
+            watchpoints: Vec::new(),
         }
     }
 
@@ -63,6 +115,112 @@ impl Debugger {
 
     pub fn delete_breakpoint(&mut self, addr: u32) {
         self.gba.cpu.breakpoints.retain(|&a| a != addr);
+        self.breakpoints.remove(&addr);
+    }
+
+    pub fn clear_breakpoints(&mut self) {
+        self.gba.cpu.breakpoints.clear();
+        self.breakpoints.clear();
+    }
+
+    /// Adds a breakpoint, or replaces the one at the same address, returns its index
+    pub fn add_breakpoint(&mut self, breakpoint: Breakpoint) -> DebuggerResult<usize> {
+        let addr = breakpoint.addr;
+        self.breakpoints.insert(addr, breakpoint);
+        match self.gba.add_breakpoint(addr) {
+            Some(index) => Ok(index),
+            None => self.find_breakpoint(addr).ok_or_else(|| {
+                DebuggerError::InvalidArgument(format!(
+                    "failed to add a breakpoint at 0x{:08x}",
+                    addr
+                ))
+            }),
+        }
+    }
+
+    /// Returns the index of the breakpoint at `addr` in `gba.cpu.breakpoints`
+    fn find_breakpoint(&self, addr: Addr) -> Option<usize> {
+        self.gba.cpu.breakpoints.iter().position(|&a| a == addr)
+    }
+
+    /// The condition and counters of the breakpoint at `addr`, plain ones are created on demand
+    fn get_breakpoint_mut(&mut self, addr: Addr) -> Option<(usize, &mut Breakpoint)> {
+        let index = self.find_breakpoint(addr)?;
+        let breakpoint = self
+            .breakpoints
+            .entry(addr)
+            .or_insert_with(|| Breakpoint::new(addr));
+        Some((index, breakpoint))
+    }
+
+    /// Called when execution reached the breakpoint at `addr`, returns whether to stop there
+    fn breakpoint_hit(&mut self, addr: Addr) -> bool {
+        let index = match self.find_breakpoint(addr) {
+            Some(index) => index,
+            None => {
+                println!("Breakpoint reached! @{:x}", addr);
+                return true;
+            }
+        };
+
+        let condition = self
+            .breakpoints
+            .get(&addr)
+            .and_then(|b| b.condition.clone());
+        if let Some(condition) = &condition {
+            match self.eval_value(condition) {
+                Ok(0) => return false,
+                Ok(_) => {}
+                Err(e) => {
+                    println!(
+                        "Error evaluating the condition of breakpoint [{}]: {:?}",
+                        index, e
+                    );
+                    return true;
+                }
+            }
+        }
+
+        let breakpoint = self
+            .breakpoints
+            .entry(addr)
+            .or_insert_with(|| Breakpoint::new(addr));
+        breakpoint.hit_count += 1;
+        if breakpoint.ignore_count > 0 {
+            breakpoint.ignore_count -= 1;
+            return false;
+        }
+        println!(
+            "Breakpoint [{}] reached! @{:x} (hit {} times)",
+            index, addr, breakpoint.hit_count
+        );
+        if breakpoint.temporary {
+            self.delete_breakpoint(addr);
+        }
+        true
+    }
+
+    /// Drops the hits of our watchpoints that were not reported, the ones of others are left alone
+    fn discard_watch_hits(&mut self) {
+        self.gba.sysbus.take_watch_hits_of(&self.watchpoints);
+    }
+
+    /// Prints the accesses that hit our watchpoints since the last call, returns whether there
+    /// were any
+    fn report_watch_hits(&mut self) -> bool {
+        let hits = self.gba.sysbus.take_watch_hits_of(&self.watchpoints);
+        for hit in &hits {
+            println!(
+                "Watchpoint hit! {}, next instruction @{:x}",
+                hit,
+                self.gba.cpu.get_next_pc()
+            );
+        }
+        let dropped = self.gba.sysbus.take_dropped_watch_hits();
+        if dropped > 0 {
+            println!("{} more watchpoint hits were dropped", dropped);
+        }
+        !hits.is_empty()
     }
 
     fn decode_reg(&self, s: &str) -> DebuggerResult<usize> {
@@ -101,24 +259,35 @@ impl Debugger {
         }
     }
 
+    /// Symbols come first, then registers, then `cpsr` and its `cpsr.n/z/c/v/t` flags
+    fn val_identifier(&self, ident: &str) -> DebuggerResult<u32> {
+        let symbol = if let Some(symbols) = self.gba.sysbus.cartridge.get_symbols() {
+            symbols.get(ident)
+        } else {
+            None
+        };
+        if let Some(address) = symbol {
+            return Ok(*address);
+        }
+
+        let cpsr = &self.gba.cpu.cpsr;
+        let value = match ident {
+            "cpsr" => cpsr.get(),
+            "cpsr.n" => cpsr.N() as u32,
+            "cpsr.z" => cpsr.Z() as u32,
+            "cpsr.c" => cpsr.C() as u32,
+            "cpsr.v" => cpsr.V() as u32,
+            "cpsr.t" => bool::from(cpsr.state()) as u32,
+            // otherwise, decode as register (TODO special token to separate symbol and register)
+            _ => self.gba.cpu.get_reg(self.decode_reg(ident)?),
+        };
+        Ok(value)
+    }
+
     fn val_address(&self, arg: &Value) -> DebuggerResult<Addr> {
         match arg {
             Value::Num(n) => Ok(*n),
-            Value::Identifier(ident) => {
-                let symbol = if let Some(symbols) = self.gba.sysbus.cartridge.get_symbols() {
-                    symbols.get(ident)
-                } else {
-                    None
-                };
-
-                if let Some(address) = symbol {
-                    Ok(*address)
-                } else {
-                    // otherwise, decode as register (TODO special token to separate symbol and register)
-                    let reg = self.decode_reg(&ident)?;
-                    Ok(self.gba.cpu.get_reg(reg))
-                }
-            }
+            Value::Identifier(ident) => self.val_identifier(ident),
             v => Err(DebuggerError::InvalidArgument(format!(
                 "addr: expected a number or register, got {:?}",
                 v
@@ -126,19 +295,71 @@ impl Debugger {
         }
     }
 
-    fn eval_assignment(&mut self, lvalue: Value, rvalue: Value) -> DebuggerResult<()> {
-        let lvalue = self.val_reg(&lvalue)?;
-        let rvalue = match rvalue {
-            Value::Deref(addr_value, deref_type) => {
-                let addr = self.val_address(&addr_value)?;
+    /// Evaluates an expression with wrapping unsigned arithmetic, booleans are 1 or 0
+    fn eval_value(&self, value: &Value) -> DebuggerResult<u32> {
+        let result = match value {
+            Value::Num(n) => *n,
+            Value::Boolean(b) => *b as u32,
+            Value::Identifier(ident) => self.val_identifier(ident)?,
+            Value::Deref(addr, deref_type) => {
+                let addr = self.eval_value(addr)?;
                 match deref_type {
-                    DerefType::Word => self.gba.sysbus.read_32(addr),
-                    DerefType::HalfWord => self.gba.sysbus.read_16(addr) as u32,
-                    DerefType::Byte => self.gba.sysbus.read_8(addr) as u32,
+                    DerefType::Word => self.gba.sysbus.debug_read_32(addr),
+                    DerefType::HalfWord => self.gba.sysbus.debug_read_16(addr) as u32,
+                    DerefType::Byte => self.gba.sysbus.debug_read_8(addr) as u32,
+                }
+            }
+            Value::Unary(op, v) => {
+                let v = self.eval_value(v)?;
+                match op {
+                    UnaryOp::Neg => v.wrapping_neg(),
+                    UnaryOp::Not => !v,
+                    UnaryOp::LogicalNot => (v == 0) as u32,
+                }
+            }
+            Value::Binary(BinaryOp::LogicalAnd, lhs, rhs) => {
+                (self.eval_value(lhs)? != 0 && self.eval_value(rhs)? != 0) as u32
+            }
+            Value::Binary(BinaryOp::LogicalOr, lhs, rhs) => {
+                (self.eval_value(lhs)? != 0 || self.eval_value(rhs)? != 0) as u32
+            }
+            Value::Binary(op, lhs, rhs) => {
+                let lhs = self.eval_value(lhs)?;
+                let rhs = self.eval_value(rhs)?;
+                match op {
+                    BinaryOp::Mul => lhs.wrapping_mul(rhs),
+                    BinaryOp::Div | BinaryOp::Rem if rhs == 0 => {
+                        return Err(DebuggerError::InvalidArgument(format!(
+                            "division by zero in {}",
+                            value
+                        )))
+                    }
+                    BinaryOp::Div => lhs / rhs,
+                    BinaryOp::Rem => lhs % rhs,
+                    BinaryOp::Add => lhs.wrapping_add(rhs),
+                    BinaryOp::Sub => lhs.wrapping_sub(rhs),
+                    BinaryOp::Shl => lhs.checked_shl(rhs).unwrap_or(0),
+                    BinaryOp::Shr => lhs.checked_shr(rhs).unwrap_or(0),
+                    BinaryOp::BitAnd => lhs & rhs,
+                    BinaryOp::BitXor => lhs ^ rhs,
+                    BinaryOp::BitOr => lhs | rhs,
+                    BinaryOp::Eq => (lhs == rhs) as u32,
+                    BinaryOp::Ne => (lhs != rhs) as u32,
+                    BinaryOp::Lt => (lhs < rhs) as u32,
+                    BinaryOp::Le => (lhs <= rhs) as u32,
+                    BinaryOp::Gt => (lhs > rhs) as u32,
+                    BinaryOp::Ge => (lhs >= rhs) as u32,
+                    BinaryOp::LogicalAnd | BinaryOp::LogicalOr => unreachable!(),
                 }
             }
-            _ => self.val_address(&rvalue)?,
+            Value::Condition(v) => self.eval_value(v)?,
         };
+        Ok(result)
+    }
+
+    fn eval_assignment(&mut self, lvalue: Value, rvalue: Value) -> DebuggerResult<()> {
+        let lvalue = self.val_reg(&lvalue)?;
+        let rvalue = self.eval_value(&rvalue)?;
         self.gba.cpu.set_reg(lvalue, rvalue);
         Ok(())
     }
@@ -227,3 +448,130 @@ impl Debugger {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gba::tests::{make_mock_gba, make_rom};
+    use crate::sysbus::WatchKind;
+    use crate::Bus;
+
+    fn make_debugger(rom: &[u8]) -> Debugger {
+        Debugger::new(make_mock_gba(rom))
+    }
+
+    fn run(debugger: &mut Debugger, line: &str) {
+        let expr = parse_expr(line).unwrap();
+        debugger.eval_expr(expr);
+    }
+
+    fn parse_condition(s: &str) -> Value {
+        match parse_expr(&format!("x if {}", s)).unwrap() {
+            Expr::Command(_, mut args) => args.pop().unwrap(),
+            _ => unreachable!(),
+        }
+    }
+
+    fn eval(debugger: &Debugger, s: &str) -> u32 {
+        debugger.eval_value(&parse_condition(s)).unwrap()
+    }
+
+    #[test]
+    fn test_eval_value() {
+        let mut debugger = make_debugger(&[0; 0x200]);
+        debugger.gba.cpu.set_reg(0, 5);
+        debugger.gba.cpu.set_reg(1, 0x0300_0000);
+        debugger.gba.sysbus.write_32(0x0300_0000, 0x1234_5678);
+
+        assert_eq!(eval(&debugger, "r0 * 3 - 1"), 14);
+        assert_eq!(eval(&debugger, "0 - r0"), 0u32.wrapping_sub(5));
+        assert_eq!(eval(&debugger, "r0 == 5 && r0 != 4"), 1);
+        assert_eq!(eval(&debugger, "r0 < 5 || !true"), 0);
+        assert_eq!(eval(&debugger, "u8*[r1 + 1]"), 0x56);
+        assert_eq!(eval(&debugger, "u16*[r1] | 1 << 16"), 0x1_5678);
+        assert_eq!(eval(&debugger, "*(u32*)r1 >> 28"), 1);
+        assert_eq!(eval(&debugger, "cpsr.t"), 0);
+        assert_eq!(eval(&debugger, "cpsr & 0x1f"), 0x1f);
+        // short circuits before dividing by zero
+        assert_eq!(eval(&debugger, "false && 1 / 0"), 0);
+        assert!(debugger.eval_value(&parse_condition("r0 / 0")).is_err());
+    }
+
+    #[test]
+    fn test_conditional_breakpoints() {
+        // add r0, r0, #1; b 0x08000000
+        let mut debugger = make_debugger(&make_rom(&[0xe280_0001, 0xeaff_fffd]));
+
+        run(&mut debugger, "break 0x08000000 if r0 == 5");
+        run(&mut debugger, "c");
+        assert_eq!(debugger.gba.cpu.get_next_pc(), 0x0800_0000);
+        assert_eq!(debugger.gba.cpu.get_reg(0), 5);
+        assert_eq!(debugger.breakpoints[&0x0800_0000].hit_count, 1);
+
+        // replaces the breakpoint, hits only count from now
+        run(&mut debugger, "break 0x08000000");
+        run(&mut debugger, "ignore 0x08000000 2");
+        run(&mut debugger, "c");
+        assert_eq!(debugger.gba.cpu.get_reg(0), 8);
+        assert_eq!(debugger.breakpoints[&0x0800_0000].hit_count, 3);
+        assert_eq!(debugger.breakpoints[&0x0800_0000].ignore_count, 0);
+
+        run(&mut debugger, "tbreak 0x08000004");
+        run(&mut debugger, "c");
+        assert_eq!(debugger.gba.cpu.get_next_pc(), 0x0800_0004);
+        assert_eq!(debugger.gba.cpu.breakpoints, vec![0x0800_0000]);
+        assert_eq!(debugger.breakpoints.len(), 1);
+
+        run(&mut debugger, "bd");
+        assert!(debugger.gba.cpu.breakpoints.is_empty());
+        assert!(debugger.breakpoints.is_empty());
+    }
+
+    #[test]
+    fn test_breakpoint_conditions_leave_watch_hits_alone() {
+        // add r0, r0, #1; b 0x08000000
+        let mut debugger = make_debugger(&make_rom(&[0xe280_0001, 0xeaff_fffd]));
+
+        // a watchpoint of someone else, e.g. a script, that already caught a write
+        let foreign = Watchpoint::new(0x0300_0000, 4, WatchKind::ReadWrite);
+        debugger.gba.sysbus.add_watch(foreign);
+        debugger.gba.sysbus.write_32(0x0300_0000, 3);
+
+        run(&mut debugger, "break 0x08000000 if r0 == u32*[0x03000000]");
+        run(&mut debugger, "c");
+        assert_eq!(debugger.gba.cpu.get_reg(0), 3);
+        // neither continuing nor evaluating the condition touched the hits of the foreign watchpoint
+        let hits = debugger.gba.sysbus.take_watch_hits_of(&[foreign]);
+        assert_eq!(hits.len(), 1);
+        assert!(hits[0].is_write);
+    }
+
+    #[test]
+    fn test_breakpoints_follow_the_cpu_list() {
+        // add r0, r0, #1; b 0x08000000
+        let mut debugger = make_debugger(&make_rom(&[0xe280_0001, 0xeaff_fffd]));
+
+        // as if loaded from a state
+        debugger.gba.cpu.breakpoints.push(0x0800_0000);
+        run(&mut debugger, "break 0x08000000 if r0 == 2");
+        run(&mut debugger, "c");
+        assert_eq!(debugger.gba.cpu.get_reg(0), 2);
+        assert_eq!(debugger.breakpoints[&0x0800_0000].hit_count, 1);
+
+        // the state had no breakpoints
+        debugger.gba.cpu.breakpoints.clear();
+        assert_eq!(
+            debugger
+                .add_breakpoint(Breakpoint::new(0x0800_0004))
+                .unwrap(),
+            0
+        );
+        assert_eq!(
+            debugger
+                .add_breakpoint(Breakpoint::new(0x0800_0000))
+                .unwrap(),
+            1
+        );
+        assert_eq!(debugger.breakpoints[&0x0800_0000].hit_count, 0);
+    }
+}
@@ -1,11 +1,13 @@
+use std::fmt;
+
 use nom;
 use nom::branch::alt;
 use nom::bytes::complete::{tag, take_while1, take_while_m_n};
 use nom::character::complete::{char, digit1, multispace0, multispace1};
-use nom::combinator::{cut, map, map_res, opt};
+use nom::combinator::{all_consuming, cut, map, map_res, not, opt, verify};
 use nom::error::{context, convert_error, ParseError, VerboseError};
-use nom::multi::separated_list;
-use nom::sequence::{delimited, preceded, separated_pair, terminated, tuple};
+use nom::multi::{fold_many0, separated_list};
+use nom::sequence::{delimited, pair, preceded, separated_pair, terminated, tuple};
 use nom::IResult;
 
 use super::{DebuggerError, DebuggerResult};
@@ -17,12 +19,45 @@ pub enum DerefType {
     Byte,
 }
 
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum UnaryOp {
+    Neg,
+    Not,
+    LogicalNot,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum BinaryOp {
+    Mul,
+    Div,
+    Rem,
+    Add,
+    Sub,
+    Shl,
+    Shr,
+    BitAnd,
+    BitXor,
+    BitOr,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    LogicalAnd,
+    LogicalOr,
+}
+
 #[derive(Debug, PartialEq, Clone)]
 pub enum Value {
     Num(u32),
     Boolean(bool),
     Identifier(String),
     Deref(Box<Value>, DerefType),
+    Unary(UnaryOp, Box<Value>),
+    Binary(BinaryOp, Box<Value>, Box<Value>),
+    /// The `if <expr>` that ends a command
+    Condition(Box<Value>),
 }
 
 #[derive(Debug, PartialEq)]
@@ -34,6 +69,71 @@ pub enum Expr {
     Empty,
 }
 
+impl fmt::Display for UnaryOp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = match self {
+            UnaryOp::Neg => "-",
+            UnaryOp::Not => "~",
+            UnaryOp::LogicalNot => "!",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+impl fmt::Display for BinaryOp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = match self {
+            BinaryOp::Mul => "*",
+            BinaryOp::Div => "/",
+            BinaryOp::Rem => "%",
+            BinaryOp::Add => "+",
+            BinaryOp::Sub => "-",
+            BinaryOp::Shl => "<<",
+            BinaryOp::Shr => ">>",
+            BinaryOp::BitAnd => "&",
+            BinaryOp::BitXor => "^",
+            BinaryOp::BitOr => "|",
+            BinaryOp::Eq => "==",
+            BinaryOp::Ne => "!=",
+            BinaryOp::Lt => "<",
+            BinaryOp::Le => "<=",
+            BinaryOp::Gt => ">",
+            BinaryOp::Ge => ">=",
+            BinaryOp::LogicalAnd => "&&",
+            BinaryOp::LogicalOr => "||",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+// Nested operations are always parenthesized, so this parses back to the same value
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fn operand(v: &Value) -> String {
+            match v {
+                Value::Unary(..) | Value::Binary(..) => format!("({})", v),
+                _ => v.to_string(),
+            }
+        }
+        match self {
+            Value::Num(n) => write!(f, "0x{:x}", n),
+            Value::Boolean(b) => write!(f, "{}", b),
+            Value::Identifier(ident) => write!(f, "{}", ident),
+            Value::Deref(addr, t) => {
+                let t = match t {
+                    DerefType::Word => "u32",
+                    DerefType::HalfWord => "u16",
+                    DerefType::Byte => "u8",
+                };
+                write!(f, "{}*[{}]", t, addr)
+            }
+            Value::Unary(op, v) => write!(f, "{}{}", op, operand(v)),
+            Value::Binary(op, lhs, rhs) => write!(f, "{} {} {}", operand(lhs), op, operand(rhs)),
+            Value::Condition(v) => write!(f, "if {}", v),
+        }
+    }
+}
+
 fn parse_u32_hex<'a, E: ParseError<&'a str>>(i: &'a str) -> IResult<&'a str, u32, E> {
     let (i, _) = context("hex", tag("0x"))(i)?;
     map_res(take_while_m_n(1, 8, |c: char| c.is_digit(16)), |s| {
@@ -104,15 +204,215 @@ fn parse_value<'a, E: ParseError<&'a str>>(i: &'a str) -> IResult<&'a str, Value
     )(i)
 }
 
+fn parse_unary_op<'a, E: ParseError<&'a str>>(i: &'a str) -> IResult<&'a str, UnaryOp, E> {
+    alt((
+        map(char('-'), |_| UnaryOp::Neg),
+        map(char('~'), |_| UnaryOp::Not),
+        map(terminated(char('!'), not(char('='))), |_| {
+            UnaryOp::LogicalNot
+        }),
+    ))(i)
+}
+
+fn parse_product_op<'a, E: ParseError<&'a str>>(i: &'a str) -> IResult<&'a str, BinaryOp, E> {
+    alt((
+        map(char('*'), |_| BinaryOp::Mul),
+        map(char('/'), |_| BinaryOp::Div),
+        map(char('%'), |_| BinaryOp::Rem),
+    ))(i)
+}
+
+fn parse_sum_op<'a, E: ParseError<&'a str>>(i: &'a str) -> IResult<&'a str, BinaryOp, E> {
+    alt((
+        map(char('+'), |_| BinaryOp::Add),
+        map(char('-'), |_| BinaryOp::Sub),
+    ))(i)
+}
+
+fn parse_shift_op<'a, E: ParseError<&'a str>>(i: &'a str) -> IResult<&'a str, BinaryOp, E> {
+    alt((
+        map(tag("<<"), |_| BinaryOp::Shl),
+        map(tag(">>"), |_| BinaryOp::Shr),
+    ))(i)
+}
+
+fn parse_bit_and_op<'a, E: ParseError<&'a str>>(i: &'a str) -> IResult<&'a str, BinaryOp, E> {
+    map(terminated(char('&'), not(char('&'))), |_| BinaryOp::BitAnd)(i)
+}
+
+fn parse_bit_xor_op<'a, E: ParseError<&'a str>>(i: &'a str) -> IResult<&'a str, BinaryOp, E> {
+    map(char('^'), |_| BinaryOp::BitXor)(i)
+}
+
+fn parse_bit_or_op<'a, E: ParseError<&'a str>>(i: &'a str) -> IResult<&'a str, BinaryOp, E> {
+    map(terminated(char('|'), not(char('|'))), |_| BinaryOp::BitOr)(i)
+}
+
+fn parse_comparison_op<'a, E: ParseError<&'a str>>(i: &'a str) -> IResult<&'a str, BinaryOp, E> {
+    alt((
+        map(tag("=="), |_| BinaryOp::Eq),
+        map(tag("!="), |_| BinaryOp::Ne),
+        map(tag("<="), |_| BinaryOp::Le),
+        map(tag(">="), |_| BinaryOp::Ge),
+        map(char('<'), |_| BinaryOp::Lt),
+        map(char('>'), |_| BinaryOp::Gt),
+    ))(i)
+}
+
+fn parse_logical_and_op<'a, E: ParseError<&'a str>>(i: &'a str) -> IResult<&'a str, BinaryOp, E> {
+    map(tag("&&"), |_| BinaryOp::LogicalAnd)(i)
+}
+
+fn parse_logical_or_op<'a, E: ParseError<&'a str>>(i: &'a str) -> IResult<&'a str, BinaryOp, E> {
+    map(tag("||"), |_| BinaryOp::LogicalOr)(i)
+}
+
+/// Parses `operand (op operand)*` as a left associative chain
+fn parse_binary<'a, E: ParseError<&'a str>>(
+    i: &'a str,
+    operand: fn(&'a str) -> IResult<&'a str, Value, E>,
+    operator: fn(&'a str) -> IResult<&'a str, BinaryOp, E>,
+) -> IResult<&'a str, Value, E> {
+    let (i, first) = operand(i)?;
+    fold_many0(
+        pair(
+            preceded(multispace0, operator),
+            cut(preceded(multispace0, operand)),
+        ),
+        first,
+        |lhs, (op, rhs)| Value::Binary(op, Box::new(lhs), Box::new(rhs)),
+    )(i)
+}
+
+/// Symbols and register names, unlike command arguments these can't contain `-`
+fn parse_symbol<'a, E: ParseError<&'a str>>(i: &'a str) -> IResult<&'a str, Value, E> {
+    map(
+        take_while1(|c: char| c.is_alphanumeric() || c == '_' || c == '.'),
+        |s: &str| match s {
+            "true" => Value::Boolean(true),
+            "false" => Value::Boolean(false),
+            _ => Value::Identifier(String::from(s)),
+        },
+    )(i)
+}
+
+/// `u8*[addr]`, `u16*[addr]` or `u32*[addr]`
+fn parse_sized_deref<'a, E: ParseError<&'a str>>(i: &'a str) -> IResult<&'a str, Value, E> {
+    map(
+        pair(
+            terminated(
+                alt((
+                    map(tag("u32"), |_| DerefType::Word),
+                    map(tag("u16"), |_| DerefType::HalfWord),
+                    map(tag("u8"), |_| DerefType::Byte),
+                )),
+                tuple((multispace0, char('*'), multispace0)),
+            ),
+            cut(delimited(
+                terminated(char('['), multispace0),
+                parse_operation,
+                preceded(multispace0, char(']')),
+            )),
+        ),
+        |(t, addr)| Value::Deref(Box::new(addr), t),
+    )(i)
+}
+
+fn parse_primary<'a, E: ParseError<&'a str>>(i: &'a str) -> IResult<&'a str, Value, E> {
+    alt((
+        delimited(
+            terminated(char('('), multispace0),
+            parse_operation,
+            cut(preceded(multispace0, char(')'))),
+        ),
+        parse_sized_deref,
+        parse_deref,
+        parse_num,
+        parse_symbol,
+    ))(i)
+}
+
+fn parse_unary<'a, E: ParseError<&'a str>>(i: &'a str) -> IResult<&'a str, Value, E> {
+    alt((
+        map(
+            pair(parse_unary_op, cut(preceded(multispace0, parse_unary))),
+            |(op, v)| Value::Unary(op, Box::new(v)),
+        ),
+        parse_primary,
+    ))(i)
+}
+
+fn parse_product<'a, E: ParseError<&'a str>>(i: &'a str) -> IResult<&'a str, Value, E> {
+    parse_binary(i, parse_unary, parse_product_op)
+}
+
+fn parse_sum<'a, E: ParseError<&'a str>>(i: &'a str) -> IResult<&'a str, Value, E> {
+    parse_binary(i, parse_product, parse_sum_op)
+}
+
+fn parse_shift<'a, E: ParseError<&'a str>>(i: &'a str) -> IResult<&'a str, Value, E> {
+    parse_binary(i, parse_sum, parse_shift_op)
+}
+
+fn parse_bit_and<'a, E: ParseError<&'a str>>(i: &'a str) -> IResult<&'a str, Value, E> {
+    parse_binary(i, parse_shift, parse_bit_and_op)
+}
+
+fn parse_bit_xor<'a, E: ParseError<&'a str>>(i: &'a str) -> IResult<&'a str, Value, E> {
+    parse_binary(i, parse_bit_and, parse_bit_xor_op)
+}
+
+fn parse_bit_or<'a, E: ParseError<&'a str>>(i: &'a str) -> IResult<&'a str, Value, E> {
+    parse_binary(i, parse_bit_xor, parse_bit_or_op)
+}
+
+fn parse_comparison<'a, E: ParseError<&'a str>>(i: &'a str) -> IResult<&'a str, Value, E> {
+    parse_binary(i, parse_bit_or, parse_comparison_op)
+}
+
+fn parse_logical_and<'a, E: ParseError<&'a str>>(i: &'a str) -> IResult<&'a str, Value, E> {
+    parse_binary(i, parse_comparison, parse_logical_and_op)
+}
+
+/// An arithmetic or boolean expression, operators bind like they do in rust
+fn parse_operation<'a, E: ParseError<&'a str>>(i: &'a str) -> IResult<&'a str, Value, E> {
+    context("operation", |i| {
+        parse_binary(i, parse_logical_and, parse_logical_or_op)
+    })(i)
+}
+
+fn parse_argument<'a, E: ParseError<&'a str>>(i: &'a str) -> IResult<&'a str, Value, E> {
+    verify(parse_value, |v: &Value| {
+        *v != Value::Identifier("if".to_string())
+    })(i)
+}
+
+fn parse_condition<'a, E: ParseError<&'a str>>(i: &'a str) -> IResult<&'a str, Value, E> {
+    context(
+        "condition",
+        preceded(
+            tuple((multispace0, tag("if"), multispace1)),
+            cut(map(
+                all_consuming(terminated(parse_operation, multispace0)),
+                |v| Value::Condition(Box::new(v)),
+            )),
+        ),
+    )(i)
+}
+
 fn parse_command<'a, E: ParseError<&'a str>>(i: &'a str) -> IResult<&str, Expr, E> {
     context(
         "command",
         map(
             tuple((
                 terminated(parse_identifier, multispace0),
-                separated_list(multispace1, parse_value),
+                separated_list(multispace1, parse_argument),
+                opt(parse_condition),
             )),
-            |(cmd, args)| Expr::Command(cmd, args),
+            |(cmd, mut args, condition)| {
+                args.extend(condition);
+                Expr::Command(cmd, args)
+            },
         ),
     )(i)
 }
@@ -124,7 +424,7 @@ fn parse_assignment<'a, E: ParseError<&'a str>>(i: &'a str) -> IResult<&str, Exp
             separated_pair(
                 parse_value,
                 preceded(multispace0, char('=')),
-                cut(preceded(multispace0, parse_value)),
+                cut(preceded(multispace0, parse_operation)),
             ),
             |(lvalue, rvalue)| Expr::Assignment(lvalue, rvalue),
         ),
@@ -161,53 +461,44 @@ mod tests {
 
     #[test]
     fn test_parse_empty_expr() {
-        assert_eq!(parse_expr("   "), Ok(Expr::Empty));
+        assert_eq!(parse_expr("   ").unwrap(), Expr::Empty);
     }
 
     #[test]
     fn test_parse_command_expr() {
         assert_eq!(
-            parse_expr("command"),
-            Ok(Expr::Command(
-                Value::Identifier("command".to_string()),
-                vec![]
-            ))
+            parse_expr("command").unwrap(),
+            Expr::Command(Value::Identifier("command".to_string()), vec![])
         );
         assert_eq!(
-            parse_expr("command   arg0   0x1337   true  "),
-            Ok(Expr::Command(
+            parse_expr("command   arg0   0x1337   true  ").unwrap(),
+            Expr::Command(
                 Value::Identifier("command".to_string()),
                 vec![
                     Value::Identifier("arg0".to_string()),
                     Value::Num(0x1337),
                     Value::Boolean(true)
                 ]
-            ))
+            )
         );
     }
 
     #[test]
     fn test_parse_assignment_expr() {
         assert_eq!(
-            parse_expr("  pc   = 0x1337 "),
-            Ok(Expr::Assignment(
-                Value::Identifier("pc".to_string()),
-                Value::Num(0x1337)
-            ))
+            parse_expr("  pc   = 0x1337 ").unwrap(),
+            Expr::Assignment(Value::Identifier("pc".to_string()), Value::Num(0x1337))
         );
         assert_eq!(
-            parse_expr("aaa   = false "),
-            Ok(Expr::Assignment(
-                Value::Identifier("aaa".to_string()),
-                Value::Boolean(false)
-            ))
+            parse_expr("aaa   = false ").unwrap(),
+            Expr::Assignment(Value::Identifier("aaa".to_string()), Value::Boolean(false))
         );
         assert_eq!(
-            parse_expr("  pc   = lr "),
-            Ok(Expr::Assignment(
+            parse_expr("  pc   = lr ").unwrap(),
+            Expr::Assignment(
                 Value::Identifier("pc".to_string()),
                 Value::Identifier("lr".to_string())
-            ))
+            )
         );
     }
 
@@ -231,4 +522,105 @@ mod tests {
             ))
         );
     }
+
+    fn parse_ok(i: &str) -> Value {
+        match parse_operation::<VerboseError<&str>>(i) {
+            Ok(("", v)) => v,
+            r => panic!("failed to parse {:?}: {:?}", i, r),
+        }
+    }
+
+    fn binary(op: BinaryOp, lhs: Value, rhs: Value) -> Value {
+        Value::Binary(op, Box::new(lhs), Box::new(rhs))
+    }
+
+    #[test]
+    fn test_parse_operation() {
+        let r0 = || Value::Identifier("r0".to_string());
+        assert_eq!(
+            parse_ok("r0 + 2 * 3"),
+            binary(
+                BinaryOp::Add,
+                r0(),
+                binary(BinaryOp::Mul, Value::Num(2), Value::Num(3))
+            )
+        );
+        assert_eq!(
+            parse_ok("(r0+2)*3"),
+            binary(
+                BinaryOp::Mul,
+                binary(BinaryOp::Add, r0(), Value::Num(2)),
+                Value::Num(3)
+            )
+        );
+        assert_eq!(
+            parse_ok("r0 & 1 == 1 || !cpsr.z"),
+            binary(
+                BinaryOp::LogicalOr,
+                binary(
+                    BinaryOp::Eq,
+                    binary(BinaryOp::BitAnd, r0(), Value::Num(1)),
+                    Value::Num(1)
+                ),
+                Value::Unary(
+                    UnaryOp::LogicalNot,
+                    Box::new(Value::Identifier("cpsr.z".to_string()))
+                )
+            )
+        );
+        assert_eq!(
+            parse_ok("u16*[r0 + 0x10] >> 2"),
+            binary(
+                BinaryOp::Shr,
+                Value::Deref(
+                    Box::new(binary(BinaryOp::Add, r0(), Value::Num(0x10))),
+                    DerefType::HalfWord
+                ),
+                Value::Num(2)
+            )
+        );
+        assert!(parse_operation::<VerboseError<&str>>("r0 +").is_err());
+
+        let s = "r0 == 5 && u8*[0x3000000] > -3 - ~sp % 2";
+        let v = parse_ok(s);
+        assert_eq!(parse_ok(&v.to_string()), v);
+    }
+
+    #[test]
+    fn test_parse_command_condition() {
+        assert_eq!(
+            parse_expr("break 0x08000100 if r0 == 5 && u8*[0x3000000] > 3 ").unwrap(),
+            Expr::Command(
+                Value::Identifier("break".to_string()),
+                vec![
+                    Value::Num(0x08000100),
+                    Value::Condition(Box::new(binary(
+                        BinaryOp::LogicalAnd,
+                        binary(
+                            BinaryOp::Eq,
+                            Value::Identifier("r0".to_string()),
+                            Value::Num(5)
+                        ),
+                        binary(
+                            BinaryOp::Gt,
+                            Value::Deref(Box::new(Value::Num(0x3000000)), DerefType::Byte),
+                            Value::Num(3)
+                        )
+                    )))
+                ]
+            )
+        );
+        assert!(parse_expr("break main if r0 = 5").is_err());
+        assert_eq!(
+            parse_expr("iffy = r1 - 1").unwrap(),
+            Expr::Assignment(
+                Value::Identifier("iffy".to_string()),
+                binary(
+                    BinaryOp::Sub,
+                    Value::Identifier("r1".to_string()),
+                    Value::Num(1)
+                )
+            )
+        );
+    }
 }
@@ -1,11 +1,24 @@
 use super::cartridge::BackupMedia;
 use super::iodev::consts::{REG_FIFO_A, REG_FIFO_B};
-use super::sysbus::SysBus;
+use super::sched::{EventType, Scheduler};
+use super::sysbus::{is_gamepak_addr, MemoryAccessType, MemoryAccessWidth, SysBus};
 use super::{Bus, Interrupt, IrqBitmask};
 
 use num::FromPrimitive;
 use serde::{Deserialize, Serialize};
 
+/// Cycles from a dma being triggered until it takes over the bus
+const DMA_STARTUP_CYCLES: usize = 2;
+
+/// Video capture dma3 runs once per scanline on these lines, and stops on the line after
+const VIDEO_CAPTURE_LINES: std::ops::Range<usize> = 2..162;
+
+const TIMING_IMMEDIATE: u16 = 0;
+const TIMING_VBLANK: u16 = 1;
+const TIMING_HBLANK: u16 = 2;
+/// Sound fifo for dma1 and dma2, video capture for dma3
+const TIMING_SPECIAL: u16 = 3;
+
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct DmaChannel {
     id: usize,
@@ -19,8 +32,10 @@ pub struct DmaChannel {
     internal: DmaInternalRegs,
 
     running: bool,
-    cycles: usize,
-    start_cycles: usize,
+    /// Units left in the current transfer
+    remaining: u32,
+    /// The next unit is the first of the transfer
+    first_unit: bool,
     fifo_mode: bool,
     irq: Interrupt,
 }
@@ -45,8 +60,8 @@ impl DmaChannel {
             dst: 0,
             wc: 0,
             ctrl: DmaChannelCtrl(0),
-            cycles: 0,
-            start_cycles: 0,
+            remaining: 0,
+            first_unit: false,
             fifo_mode: false,
             internal: Default::default(),
         }
@@ -82,6 +97,37 @@ impl DmaChannel {
         self.wc = value as u32;
     }
 
+    /// dma0 can't access the gamepak, only dma3 can write to it
+    fn src_mask(&self) -> u32 {
+        if self.id == 0 {
+            0x07ff_ffff
+        } else {
+            0x0fff_ffff
+        }
+    }
+
+    fn dst_mask(&self) -> u32 {
+        if self.id == 3 {
+            0x0fff_ffff
+        } else {
+            0x07ff_ffff
+        }
+    }
+
+    /// The number of units a transfer moves, a count of zero means the maximum
+    fn latched_count(&self) -> u32 {
+        match (self.id, self.wc) {
+            (3, 0) => 0x1_0000,
+            (3, wc) => wc & 0xffff,
+            (_, wc) if wc & 0x3fff == 0 => 0x4000,
+            (_, wc) => wc & 0x3fff,
+        }
+    }
+
+    fn is_video_capture(&self) -> bool {
+        self.id == 3 && self.ctrl.timing() == TIMING_SPECIAL
+    }
+
     pub fn write_dma_ctrl(&mut self, value: u16) -> bool {
         let ctrl = DmaChannelCtrl(value);
         let timing = ctrl.timing();
@@ -95,13 +141,12 @@ impl DmaChannel {
                 self.dst,
                 self.wc
             );
-            self.start_cycles = self.cycles;
             self.running = true;
-            start_immediately = timing == 0;
-            self.internal.src_addr = self.src;
-            self.internal.dst_addr = self.dst;
-            self.internal.count = self.wc;
-            self.fifo_mode = timing == 3
+            start_immediately = timing == TIMING_IMMEDIATE;
+            self.internal.src_addr = self.src & self.src_mask();
+            self.internal.dst_addr = self.dst & self.dst_mask();
+            self.internal.count = self.latched_count();
+            self.fifo_mode = timing == TIMING_SPECIAL
                 && ctrl.repeat()
                 && (self.id == 1 || self.id == 2)
                 && (self.dst == REG_FIFO_A || self.dst == REG_FIFO_B);
@@ -113,6 +158,42 @@ impl DmaChannel {
         return start_immediately;
     }
 
+    /// Sets up the next transfer, returns false if the channel was disabled in the meantime
+    fn start_transfer(&mut self) -> bool {
+        if !(self.running && self.ctrl.is_enabled()) {
+            return false;
+        }
+        // sound dma always moves 4 words, whatever the word count says
+        self.remaining = if self.fifo_mode {
+            4
+        } else {
+            self.internal.count
+        };
+        self.first_unit = true;
+        true
+    }
+
+    fn end_transfer(&mut self, irqs: &mut IrqBitmask) {
+        if self.ctrl.is_triggering_irq() {
+            irqs.add_irq(self.irq);
+        }
+        // an immediate dma runs once, even with the repeat bit set
+        if self.ctrl.repeat() && self.ctrl.timing() != TIMING_IMMEDIATE {
+            self.internal.count = self.latched_count();
+            /* reload */
+            if 3 == self.ctrl.dst_adj() {
+                self.internal.dst_addr = self.dst & self.dst_mask();
+            }
+        } else {
+            self.stop();
+        }
+    }
+
+    fn stop(&mut self) {
+        self.running = false;
+        self.ctrl.set_enabled(false);
+    }
+
     #[inline]
     fn xfer_adj_addrs(&mut self, word_size: u32) {
         match self.ctrl.src_adj() {
@@ -121,6 +202,10 @@ impl DmaChannel {
             /* Fixed */ 2 => {}
             _ => panic!("forbidden DMA source address adjustment"),
         }
+        if self.fifo_mode {
+            // the fifo is a single register
+            return;
+        }
         match self.ctrl.dst_adj() {
             /* Increment[+Reload] */ 0 | 3 => self.internal.dst_addr += word_size,
             /* Decrement */ 1 => self.internal.dst_addr -= word_size,
@@ -129,68 +214,70 @@ impl DmaChannel {
         }
     }
 
-    fn xfer(&mut self, sb: &mut SysBus, irqs: &mut IrqBitmask) {
-        let word_size = if self.ctrl.is_32bit() { 4 } else { 2 };
-        let count = match self.internal.count {
-            0 => match self.id {
-                3 => 0x1_0000,
-                _ => 0x0_4000,
-            },
-            _ => self.internal.count,
+    /// Moves a single unit of the transfer of channel `id`, returns the cycles it took.
+    /// The channel lives on the bus it transfers over, so it is looked up again after the access.
+    fn xfer_unit(sb: &mut SysBus, id: usize, sequential: bool, irqs: &mut IrqBitmask) -> usize {
+        let channel = &mut sb.io.dmac.channels[id];
+        let (word_size, width) = if channel.fifo_mode || channel.ctrl.is_32bit() {
+            (4, MemoryAccessWidth::MemoryAccess32)
+        } else {
+            (2, MemoryAccessWidth::MemoryAccess16)
         };
+        let src = channel.internal.src_addr & !(word_size - 1);
+        let dst = channel.internal.dst_addr & !(word_size - 1);
 
-        if self.id == 3 && word_size == 2 {
-            if let BackupMedia::Eeprom(eeprom) = &mut sb.cartridge.backup {
-                eeprom.on_dma3_transfer(
-                    self.internal.src_addr,
-                    self.internal.dst_addr,
-                    count as usize,
-                )
+        let mut cycles = 0;
+        let access = if channel.first_unit {
+            channel.first_unit = false;
+            let count = channel.remaining as usize;
+            // internal processing time
+            cycles += if is_gamepak_addr(src) && is_gamepak_addr(dst) {
+                4
+            } else {
+                2
+            };
+            if is_gamepak_addr(src) || is_gamepak_addr(dst) {
+                sb.prefetch.flush();
             }
-        }
-
-        let fifo_mode = self.fifo_mode;
-
-        if fifo_mode {
-            for _ in 0..4 {
-                let v = sb.read_32(self.internal.src_addr & !3);
-                sb.write_32(self.internal.dst_addr & !3, v);
-                self.internal.src_addr += 4;
-            }
-        } else if word_size == 4 {
-            for _ in 0..count {
-                let w = sb.read_32(self.internal.src_addr & !3);
-                sb.write_32(self.internal.dst_addr & !3, w);
-                self.xfer_adj_addrs(word_size);
+            if id == 3 && word_size == 2 {
+                if let BackupMedia::Eeprom(eeprom) = &mut sb.cartridge.backup {
+                    eeprom.on_dma3_transfer(src, dst, count)
+                }
             }
+            MemoryAccessType::NonSeq
+        } else if sequential {
+            MemoryAccessType::Seq
         } else {
-            for _ in 0..count {
-                let hw = sb.read_16(self.internal.src_addr & !1);
-                sb.write_16(self.internal.dst_addr & !1, hw);
-                self.xfer_adj_addrs(word_size)
-            }
-        }
-        if self.ctrl.is_triggering_irq() {
-            irqs.add_irq(self.irq);
-        }
-        if self.ctrl.repeat() {
-            self.start_cycles = self.cycles;
-            /* reload */
-            if 3 == self.ctrl.dst_adj() {
-                self.internal.dst_addr = self.dst;
-            }
+            MemoryAccessType::NonSeq
+        };
+        cycles += sb.get_cycles(src, access, width) + sb.get_cycles(dst, access, width);
+
+        if word_size == 4 {
+            let w = sb.read_32(src);
+            sb.write_32(dst, w);
         } else {
-            self.running = false;
-            self.ctrl.set_enabled(false);
+            let hw = sb.read_16(src);
+            sb.write_16(dst, hw);
+        }
+
+        let channel = &mut sb.io.dmac.channels[id];
+        channel.xfer_adj_addrs(word_size);
+        channel.remaining -= 1;
+        if channel.remaining == 0 {
+            channel.end_transfer(irqs);
+            sb.io.dmac.pending_set &= !(1 << id);
         }
+        cycles
     }
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct DmaController {
     pub channels: [DmaChannel; 4],
+    /// Channels that own a transfer in progress, the lowest one has the bus
     pending_set: u8,
-    cycles: usize,
+    /// The channel that moved the previous unit, its next access is sequential
+    last_channel: Option<usize>,
 }
 
 impl DmaController {
@@ -203,7 +290,7 @@ impl DmaController {
                 DmaChannel::new(3),
             ],
             pending_set: 0,
-            cycles: 0,
+            last_channel: None,
         }
     }
 
@@ -211,16 +298,33 @@ impl DmaController {
         self.pending_set != 0
     }
 
-    pub fn perform_work(&mut self, sb: &mut SysBus, irqs: &mut IrqBitmask) {
-        for id in 0..4 {
-            if self.pending_set & (1 << id) != 0 {
-                self.channels[id].xfer(sb, irqs);
-            }
+    /// Moves a single unit for the channel with the highest priority, returns the cycles it took.
+    /// Going unit by unit lets a channel with higher priority that was triggered in the meantime
+    /// take over the bus, the preempted transfer continues once it is done.
+    pub fn perform_work(sb: &mut SysBus, irqs: &mut IrqBitmask) -> usize {
+        let dmac = &mut sb.io.dmac;
+        let id = dmac.pending_set.trailing_zeros() as usize;
+        let sequential = dmac.last_channel == Some(id);
+        dmac.last_channel = Some(id);
+        DmaChannel::xfer_unit(sb, id, sequential, irqs)
+    }
+
+    /// Starts the transfer of the channel once the startup delay is over
+    fn trigger(&mut self, id: usize, scheduler: &mut Scheduler) {
+        let event = EventType::DmaActivateChannel(id);
+        if self.pending_set & (1 << id) == 0 && !scheduler.is_scheduled(event) {
+            scheduler.schedule(event, DMA_STARTUP_CYCLES);
+        }
+    }
+
+    /// Handles the `DmaActivateChannel` event
+    pub fn activate_channel(&mut self, id: usize) {
+        if self.channels[id].start_transfer() {
+            self.pending_set |= 1 << id;
         }
-        self.pending_set = 0;
     }
 
-    pub fn write_16(&mut self, channel_id: usize, ofs: u32, value: u16) {
+    pub fn write_16(&mut self, channel_id: usize, ofs: u32, value: u16, scheduler: &mut Scheduler) {
         match ofs {
             0 => self.channels[channel_id].write_src_low(value),
             2 => self.channels[channel_id].write_src_high(value),
@@ -229,39 +333,52 @@ impl DmaController {
             8 => self.channels[channel_id].write_word_count(value),
             10 => {
                 if self.channels[channel_id].write_dma_ctrl(value) {
-                    self.pending_set |= 1 << channel_id;
-                } else {
+                    self.trigger(channel_id, scheduler);
+                } else if !self.channels[channel_id].is_running() {
                     self.pending_set &= !(1 << channel_id);
+                    scheduler.cancel(EventType::DmaActivateChannel(channel_id));
                 }
             }
             _ => panic!("Invalid dma offset {:x}", ofs),
         }
     }
 
-    pub fn notify_vblank(&mut self) {
+    fn notify(&mut self, timing: u16, scheduler: &mut Scheduler) {
         for i in 0..4 {
-            if self.channels[i].ctrl.is_enabled() && self.channels[i].ctrl.timing() == 1 {
-                self.pending_set |= 1 << i;
+            if self.channels[i].ctrl.is_enabled() && self.channels[i].ctrl.timing() == timing {
+                self.trigger(i, scheduler);
             }
         }
     }
 
-    pub fn notify_hblank(&mut self) {
-        for i in 0..4 {
-            if self.channels[i].ctrl.is_enabled() && self.channels[i].ctrl.timing() == 2 {
-                self.pending_set |= 1 << i;
-            }
+    pub fn notify_vblank(&mut self, scheduler: &mut Scheduler) {
+        self.notify(TIMING_VBLANK, scheduler);
+    }
+
+    pub fn notify_hblank(&mut self, scheduler: &mut Scheduler) {
+        self.notify(TIMING_HBLANK, scheduler);
+    }
+
+    /// Called at the start of every scanline, drives the video capture dma3
+    pub fn notify_scanline(&mut self, vcount: usize, scheduler: &mut Scheduler) {
+        if !(self.channels[3].ctrl.is_enabled() && self.channels[3].is_video_capture()) {
+            return;
+        }
+        if VIDEO_CAPTURE_LINES.contains(&vcount) {
+            self.trigger(3, scheduler);
+        } else if vcount == VIDEO_CAPTURE_LINES.end {
+            self.channels[3].stop();
         }
     }
 
-    pub fn notify_sound_fifo(&mut self, fifo_addr: u32) {
+    pub fn notify_sound_fifo(&mut self, fifo_addr: u32, scheduler: &mut Scheduler) {
         for i in 1..=2 {
             if self.channels[i].ctrl.is_enabled()
                 && self.channels[i].running
-                && self.channels[i].ctrl.timing() == 3
+                && self.channels[i].ctrl.timing() == TIMING_SPECIAL
                 && self.channels[i].dst == fifo_addr
             {
-                self.pending_set |= 1 << i;
+                self.trigger(i, scheduler);
             }
         }
     }
@@ -1,19 +1,27 @@
 /// Struct containing everything
 use std::cell::RefCell;
+use std::convert::Infallible;
 use std::rc::Rc;
 
 use bincode;
 use serde::{Deserialize, Serialize};
 
 use super::arm7tdmi;
-use super::cartridge::Cartridge;
+use super::cartridge::{self, Cartridge};
+use super::dma::DmaController;
 use super::gpu::*;
 use super::interrupt::*;
 use super::iodev::*;
+use super::savestate::{self, SaveStateInfo, SaveStateResult};
+use super::sched::EventType;
+use super::sio::{NoCable, SERIAL_POLL_CYCLES};
 use super::sound::SoundController;
 use super::sysbus::SysBus;
 
-use super::{AudioInterface, InputInterface, VideoInterface};
+use super::{AudioInterface, InputInterface, SerialInterface, VideoInterface};
+
+/// 228 lines of 1232 cycles
+pub const CYCLES_PER_FRAME: u64 = 280896;
 
 pub struct GameBoyAdvance {
     pub sysbus: Box<SysBus>,
@@ -22,10 +30,10 @@ pub struct GameBoyAdvance {
     pub video_device: Rc<RefCell<dyn VideoInterface>>,
     pub audio_device: Rc<RefCell<dyn AudioInterface>>,
     pub input_device: Rc<RefCell<dyn InputInterface>>,
+    pub serial_device: Rc<RefCell<dyn SerialInterface>>,
 
-    pub cycles_to_next_event: usize,
-
-    overshoot_cycles: usize,
+    /// sha256 of the loaded rom, to tell which rom a save state belongs to
+    rom_digest: [u8; 32],
 }
 
 #[derive(Serialize, Deserialize)]
@@ -34,16 +42,29 @@ struct SaveState {
     cpu: arm7tdmi::Core,
 }
 
+/// Serializes the same as `SaveState`, without cloning the emulator state
+#[derive(Serialize)]
+struct SaveStateRef<'a> {
+    sysbus: &'a SysBus,
+    cpu: &'a arm7tdmi::Core,
+}
+
+fn sha256_digest(bytes: &[u8]) -> [u8; 32] {
+    use sha2::{Digest, Sha256};
+    let mut hasher = Sha256::new();
+    hasher.input(bytes);
+    let mut digest = [0; 32];
+    digest.copy_from_slice(hasher.result().as_slice());
+    digest
+}
+
 /// Checks if the bios provided is the real one,
 /// Otherwise output a log warning to the user
 fn check_real_bios(bios: &[u8]) {
-    use sha2::{Digest, Sha256};
-    let mut hasher = Sha256::new();
-    hasher.input(bios);
-    let digest = hasher.result();
+    let digest = sha256_digest(bios);
 
     let expected_hash = hex!("fd2547724b505f487e6dcb29ec2ecff3af35a841a77ab2e85fd87350abd36570");
-    if digest.as_slice() != &expected_hash[..] {
+    if digest != expected_hash {
         warn!("This is not the real bios, some games may not be compatible");
     }
 }
@@ -66,6 +87,7 @@ impl GameBoyAdvance {
         let sysbus = Box::new(SysBus::new(io, bios_rom, gamepak));
 
         let cpu = arm7tdmi::Core::new();
+        let rom_digest = sha256_digest(sysbus.cartridge.get_rom_bytes());
 
         let mut gba = GameBoyAdvance {
             cpu: cpu,
@@ -74,9 +96,9 @@ impl GameBoyAdvance {
             video_device: video_device,
             audio_device: audio_device,
             input_device: input_device,
+            serial_device: Rc::new(RefCell::new(NoCable)),
 
-            cycles_to_next_event: 1,
-            overshoot_cycles: 0,
+            rom_digest: rom_digest,
         };
 
         gba.sysbus.created();
@@ -84,43 +106,92 @@ impl GameBoyAdvance {
         gba
     }
 
+    /// Save states don't contain the bios and the rom, so they have to be supplied again
     pub fn from_saved_state(
         savestate: &[u8],
+        bios_rom: Box<[u8]>,
+        rom: Box<[u8]>,
         video_device: Rc<RefCell<dyn VideoInterface>>,
         audio_device: Rc<RefCell<dyn AudioInterface>>,
         input_device: Rc<RefCell<dyn InputInterface>>,
-    ) -> bincode::Result<GameBoyAdvance> {
-        let decoded: Box<SaveState> = bincode::deserialize_from(savestate)?;
+    ) -> SaveStateResult<GameBoyAdvance> {
+        let (info, payload) = savestate::decode(savestate)?;
+        let game_code = cartridge::header::parse(&rom).game_code;
+        let rom_digest = sha256_digest(&rom);
+        savestate::verify_rom(&info, &game_code, &rom_digest)?;
 
-        Ok(GameBoyAdvance {
+        let mut decoded: Box<SaveState> = bincode::deserialize_from(payload.as_slice())?;
+        decoded.sysbus.attach_rom_and_bios(bios_rom, rom);
+
+        let mut gba = GameBoyAdvance {
             cpu: decoded.cpu,
             sysbus: decoded.sysbus,
 
             video_device: video_device,
             audio_device: audio_device,
             input_device: input_device,
+            serial_device: Rc::new(RefCell::new(NoCable)),
+
+            rom_digest: rom_digest,
+        };
 
-            cycles_to_next_event: 1,
+        gba.sysbus.created();
 
-            overshoot_cycles: 0,
-        })
+        Ok(gba)
+    }
+
+    fn save_state_info(&self, with_thumbnail: bool) -> SaveStateInfo {
+        SaveStateInfo {
+            emulator_version: savestate::EMULATOR_VERSION.to_string(),
+            game_title: self.get_game_title(),
+            game_code: self.get_game_code(),
+            rom_digest: self.rom_digest,
+            timestamp: savestate::current_timestamp(),
+            thumbnail: if with_thumbnail {
+                Some(self.get_frame_buffer().to_vec())
+            } else {
+                None
+            },
+        }
     }
 
-    pub fn save_state(&self) -> bincode::Result<Vec<u8>> {
-        let s = SaveState {
-            cpu: self.cpu.clone(),
-            sysbus: self.sysbus.clone(),
+    fn save_state_impl(&self, with_thumbnail: bool) -> SaveStateResult<Vec<u8>> {
+        let s = SaveStateRef {
+            sysbus: &self.sysbus,
+            cpu: &self.cpu,
         };
 
-        bincode::serialize(&s)
+        let payload = bincode::serialize(&s)?;
+        savestate::encode(&self.save_state_info(with_thumbnail), &payload)
     }
 
-    pub fn restore_state(&mut self, bytes: &[u8]) -> bincode::Result<()> {
-        let decoded: Box<SaveState> = bincode::deserialize_from(bytes)?;
+    pub fn save_state(&self) -> SaveStateResult<Vec<u8>> {
+        self.save_state_impl(false)
+    }
+
+    /// Same as `save_state`, but also stores a copy of the current frame
+    pub fn save_state_with_thumbnail(&self) -> SaveStateResult<Vec<u8>> {
+        self.save_state_impl(true)
+    }
+
+    pub fn restore_state(&mut self, bytes: &[u8]) -> SaveStateResult<()> {
+        let (info, payload) = savestate::decode(bytes)?;
+        savestate::verify_rom(&info, &self.get_game_code(), &self.rom_digest)?;
+        let mut decoded: Box<SaveState> = bincode::deserialize_from(payload.as_slice())?;
+        decoded.sysbus.take_rom_and_bios(&mut self.sysbus);
+        decoded.sysbus.take_watches(&mut self.sysbus);
+        // an emulator setting rather than part of the state
+        decoded
+            .sysbus
+            .prefetch
+            .set_emulated(self.sysbus.prefetch.is_emulated());
+        #[cfg(feature = "block_cache")]
+        decoded
+            .cpu
+            .set_block_cache(self.cpu.is_block_cache_enabled());
 
         self.cpu = decoded.cpu;
         self.sysbus = decoded.sysbus;
-        self.cycles_to_next_event = 1;
 
         self.sysbus.created();
 
@@ -131,10 +202,25 @@ impl GameBoyAdvance {
         self.sysbus.cartridge.header.game_title.clone()
     }
 
+    /// sha256 of the loaded rom
+    pub fn get_rom_digest(&self) -> &[u8; 32] {
+        &self.rom_digest
+    }
+
     pub fn get_game_code(&self) -> String {
         self.sysbus.cartridge.header.game_code.clone()
     }
 
+    /// Plugs a link cable into the serial port
+    pub fn set_serial_device(&mut self, serial_device: Rc<RefCell<dyn SerialInterface>>) {
+        self.serial_device = serial_device;
+    }
+
+    /// Replaces the input device, e.g to record or replay a movie
+    pub fn set_input_device(&mut self, input_device: Rc<RefCell<dyn InputInterface>>) {
+        self.input_device = input_device;
+    }
+
     #[inline]
     pub fn key_poll(&mut self) {
         self.sysbus.io.keyinput = self.input_device.borrow_mut().poll();
@@ -142,20 +228,26 @@ impl GameBoyAdvance {
 
     pub fn frame(&mut self) {
         self.key_poll();
+        self.run_until_frame_end(|gba| {
+            gba.step();
+            Ok::<(), Infallible>(())
+        })
+        .unwrap_or_else(|never| match never {});
+    }
 
-        let mut remaining_cycles = 280896 - self.overshoot_cycles;
-
-        while remaining_cycles > 0 {
-            let cycles = self.step();
-            if remaining_cycles >= cycles {
-                remaining_cycles -= cycles;
-            } else {
-                self.overshoot_cycles = cycles - remaining_cycles;
-                return;
-            }
+    /// Runs the rest of the frame, calling `step` instead of `GameBoyAdvance::step` to move
+    /// forward, e.g to stop after every instruction. Stops early at the first error `step` returns.
+    pub fn run_until_frame_end<E, F>(&mut self, mut step: F) -> Result<(), E>
+    where
+        F: FnMut(&mut GameBoyAdvance) -> Result<(), E>,
+    {
+        // frames start at fixed scheduler times, so an overshoot is made up for by the next frame
+        let timestamp = self.sysbus.io.scheduler.timestamp();
+        let frame_end = (timestamp / CYCLES_PER_FRAME + 1) * CYCLES_PER_FRAME;
+        while self.sysbus.io.scheduler.timestamp() < frame_end {
+            step(self)?;
         }
-
-        self.overshoot_cycles = 0;
+        Ok(())
     }
 
     pub fn add_breakpoint(&mut self, addr: u32) -> Option<usize> {
@@ -179,71 +271,165 @@ impl GameBoyAdvance {
         None
     }
 
+    /// Enables high level emulation of the bios calls, see `bios::hle_swi`
+    pub fn set_bios_hle(&mut self, enable: bool) {
+        self.cpu.bios_hle = enable;
+    }
+
+    /// The gamepak prefetch buffer can be left out, e.g to compare the performance
+    pub fn set_prefetch_emulation(&mut self, enable: bool) {
+        self.sysbus.prefetch.set_emulated(enable);
+    }
+
     pub fn skip_bios(&mut self) {
         self.cpu.skip_bios();
         self.sysbus.io.gpu.skip_bios();
     }
 
-    pub fn step_cpu(&mut self, io: &mut IoDevices) -> usize {
-        if io.intc.irq_pending() {
+    pub fn step_cpu(&mut self) -> usize {
+        if self.sysbus.io.intc.irq_pending() {
             self.cpu.irq(&mut self.sysbus);
-            io.haltcnt = HaltState::Running;
+            self.sysbus.io.haltcnt = HaltState::Running;
         }
         let previous_cycles = self.cpu.cycles;
         self.cpu.step(&mut self.sysbus);
         self.cpu.cycles - previous_cycles
     }
 
-    pub fn step(&mut self) -> usize {
-        // I hate myself for doing this, but rust left me no choice.
-        let io = unsafe {
-            let ptr = &mut *self.sysbus as *mut SysBus;
-            &mut (*ptr).io as &mut IoDevices
-        };
+    /// Runs a single cpu instruction, along with the dma transfers and the events that come first.
+    /// While the cpu is halted, runs until the next event instead.
+    pub fn step_instruction(&mut self) -> usize {
+        let start_time = self.sysbus.io.scheduler.timestamp();
+
+        loop {
+            if self.sysbus.io.dmac.is_active() {
+                let cycles = self.dma_step();
+                self.sysbus.io.scheduler.update(cycles);
+                self.handle_events();
+                continue;
+            }
+            let io = &mut self.sysbus.io;
+            if HaltState::Running == io.haltcnt {
+                let cycles = self.step_cpu();
+                self.sysbus.io.scheduler.update(cycles);
+                self.handle_events();
+                break;
+            } else if io.intc.interrupt_enable.0 & io.intc.interrupt_flags.0 != 0 {
+                io.haltcnt = HaltState::Running;
+            } else {
+                io.scheduler.skip_to_next_event();
+                self.handle_events();
+                break;
+            }
+        }
+
+        let cycles = (self.sysbus.io.scheduler.timestamp() - start_time) as usize;
+        self.sysbus.cartridge.update(cycles);
+        cycles
+    }
 
+    /// Moves a single unit of the active dma transfer, the cpu is stalled meanwhile
+    pub(crate) fn dma_step(&mut self) -> usize {
         let mut irqs = IrqBitmask(0);
+        let cycles = DmaController::perform_work(&mut self.sysbus, &mut irqs);
+        self.sysbus.io.intc.request_irqs(irqs);
+        cycles
+    }
 
-        let mut cycles_left = self.cycles_to_next_event;
-        let mut cycles_to_next_event = std::usize::MAX;
-        let mut cycles = 0;
-
-        while cycles_left > 0 {
-            let mut irqs = IrqBitmask(0);
-            let _cycles = if !io.dmac.is_active() {
-                if HaltState::Running == io.haltcnt {
-                    self.step_cpu(io)
-                } else {
-                    cycles = cycles_left;
-                    break;
-                }
+    /// Runs until the next event is due and handles it, returns the number of cycles that passed
+    pub fn step(&mut self) -> usize {
+        let start_time = self.sysbus.io.scheduler.timestamp();
+
+        while !self.sysbus.io.scheduler.has_pending_events() {
+            if self.sysbus.io.dmac.is_active() {
+                let cycles = self.dma_step();
+                self.sysbus.io.scheduler.update(cycles);
+                continue;
+            }
+            let io = &mut self.sysbus.io;
+            if HaltState::Running == io.haltcnt {
+                let cycles = self.step_cpu();
+                self.sysbus.io.scheduler.update(cycles);
+            } else if io.intc.interrupt_enable.0 & io.intc.interrupt_flags.0 != 0 {
+                // halt ends on any enabled interrupt, even with IME cleared
+                io.haltcnt = HaltState::Running;
             } else {
-                io.dmac.perform_work(&mut self.sysbus, &mut irqs);
-                io.intc.request_irqs(irqs);
-                return cycles;
-            };
+                // nothing happens until the next event
+                io.scheduler.skip_to_next_event();
+            }
+        }
+        self.handle_events();
 
-            cycles += _cycles;
-            if cycles_left < _cycles {
-                break;
+        let cycles = (self.sysbus.io.scheduler.timestamp() - start_time) as usize;
+        self.sysbus.cartridge.update(cycles);
+        cycles
+    }
+
+    /// Handles every event that is due, each one scheduling its successor relative to its own
+    /// due time, so running late doesn't make the hardware drift
+    pub(crate) fn handle_events(&mut self) {
+        let io = &mut self.sysbus.io;
+        let mut irqs = IrqBitmask(0);
+
+        while let Some(event) = io.scheduler.pop_pending_event() {
+            match event.typ {
+                EventType::Gpu(completed) => {
+                    io.gpu.on_state_completed(
+                        completed,
+                        &mut io.dmac,
+                        &mut io.scheduler,
+                        &mut irqs,
+                        &self.video_device,
+                    );
+                    let next_state = io.gpu.state;
+                    io.scheduler.schedule_at(
+                        EventType::Gpu(next_state),
+                        event.time + next_state.cycles() as u64,
+                    );
+                }
+                EventType::TimerOverflow(id) => {
+                    let overflows = io.timers.handle_overflow_event(
+                        id,
+                        event.time,
+                        &mut io.scheduler,
+                        &mut irqs,
+                    );
+                    // timers 0 and 1 drive the sound fifos
+                    for timer_id in 0..2 {
+                        if overflows & (1 << timer_id) != 0 {
+                            io.sound.handle_timer_overflow(
+                                &mut io.dmac,
+                                &mut io.scheduler,
+                                timer_id,
+                                1,
+                            );
+                        }
+                    }
+                }
+                EventType::DmaActivateChannel(id) => io.dmac.activate_channel(id),
+                EventType::SoundSample => {
+                    io.sound.on_sample(&self.audio_device);
+                    io.scheduler.schedule_at(
+                        EventType::SoundSample,
+                        event.time + io.sound.cycles_per_sample() as u64,
+                    );
+                }
+                EventType::SerialTransferComplete => {
+                    io.sio
+                        .on_transfer_complete(&mut irqs, &mut io.scheduler, &self.serial_device)
+                }
+                EventType::SerialPoll => {
+                    io.sio
+                        .poll(&mut irqs, io.scheduler.timestamp(), &self.serial_device);
+                    io.scheduler.schedule_at(
+                        EventType::SerialPoll,
+                        event.time + SERIAL_POLL_CYCLES as u64,
+                    );
+                }
             }
-            cycles_left -= _cycles;
         }
 
-        // update gpu & sound
-        io.timers.update(cycles, &mut self.sysbus, &mut irqs);
-        io.gpu.update(
-            cycles,
-            &mut self.sysbus,
-            &mut irqs,
-            &mut cycles_to_next_event,
-            &self.video_device,
-        );
-        io.sound
-            .update(cycles, &mut cycles_to_next_event, &self.audio_device);
-        self.cycles_to_next_event = cycles_to_next_event;
         io.intc.request_irqs(irqs);
-
-        cycles
     }
 
     /// Query the emulator for the recently drawn framebuffer.
@@ -253,8 +439,9 @@ impl GameBoyAdvance {
     }
 }
 
+/// The mock GBA the tests of every module are built on
 #[cfg(test)]
-mod tests {
+pub(crate) mod tests {
     use super::*;
     use std::cell::RefCell;
     use std::rc::Rc;
@@ -262,10 +449,10 @@ mod tests {
     use super::super::bus::Bus;
     use super::super::cartridge::GamepakBuilder;
 
-    struct DummyInterface {}
+    pub(crate) struct DummyInterface {}
 
     impl DummyInterface {
-        fn new() -> DummyInterface {
+        pub(crate) fn new() -> DummyInterface {
             DummyInterface {}
         }
     }
@@ -274,8 +461,16 @@ mod tests {
     impl AudioInterface for DummyInterface {}
     impl InputInterface for DummyInterface {}
 
-    fn make_mock_gba(rom: &[u8]) -> GameBoyAdvance {
-        let bios = vec![0; 0x4000].into_boxed_slice();
+    /// Starts at the beginning of `rom`, with a blank bios that is skipped
+    pub(crate) fn make_mock_gba(rom: &[u8]) -> GameBoyAdvance {
+        let mut gba = make_mock_gba_with_bios(vec![0; 0x4000].into_boxed_slice(), rom);
+        gba.skip_bios();
+
+        gba
+    }
+
+    /// Boots `bios` first, e.g. `builtin_bios()`
+    pub(crate) fn make_mock_gba_with_bios(bios: Box<[u8]>, rom: &[u8]) -> GameBoyAdvance {
         let cartridge = GamepakBuilder::new()
             .buffer(rom)
             .with_sram()
@@ -283,11 +478,203 @@ mod tests {
             .build()
             .unwrap();
         let dummy = Rc::new(RefCell::new(DummyInterface::new()));
-        let mut gba =
-            GameBoyAdvance::new(bios, cartridge, dummy.clone(), dummy.clone(), dummy.clone());
-        gba.skip_bios();
+        GameBoyAdvance::new(bios, cartridge, dummy.clone(), dummy.clone(), dummy.clone())
+    }
 
-        gba
+    /// A 0x200 byte rom that starts with the ARM instructions in `code`
+    pub(crate) fn make_rom(code: &[u32]) -> Vec<u8> {
+        let mut rom = vec![0; 0x200.max(4 * code.len())];
+        for (i, insn) in code.iter().enumerate() {
+            rom[4 * i..4 * i + 4].copy_from_slice(&insn.to_le_bytes());
+        }
+        rom
+    }
+
+    #[test]
+    fn test_halt_ends_on_timer_interrupt() {
+        // swi 0x02 (Halt), then a branch-to-self loop
+        let mut gba = make_mock_gba(&make_rom(&[0xef02_0000, 0xeaff_fffe]));
+        gba.set_bios_hle(true);
+
+        // timer 0 overflows after 0x100 cycles, IME stays cleared
+        gba.sysbus.write_16(0x0400_0200, 1 << 3);
+        gba.sysbus.write_16(0x0400_0100, 0xff00);
+        gba.sysbus.write_16(0x0400_0102, 1 << 7 | 1 << 6);
+
+        gba.step();
+        assert_eq!(gba.sysbus.io.haltcnt, HaltState::Halt);
+        assert_eq!(gba.sysbus.io.scheduler.timestamp(), 0x100);
+        assert!(gba.sysbus.io.intc.interrupt_flags.Timer0_Overflow());
+
+        gba.step();
+        assert_eq!(gba.sysbus.io.haltcnt, HaltState::Running);
+        assert_eq!(gba.cpu.get_next_pc(), 0x0800_0004);
+    }
+
+    #[test]
+    fn test_dma_timing_and_priority() {
+        use super::super::sysbus::{MemoryAccessType::*, MemoryAccessWidth::*};
+
+        const IWRAM: u32 = 0x0300_0000;
+        const EWRAM: u32 = 0x0200_0000;
+        const DMA0: u32 = 0x0400_00b0;
+        const DMA3: u32 = 0x0400_00d4;
+
+        let mut gba = make_mock_gba(&[0; 0x200]);
+        for i in 0..4 {
+            gba.sysbus.write_16(IWRAM + 2 * i, 0x1110 * (i as u16 + 1));
+        }
+        let unit_cycles = |gba: &GameBoyAdvance, access| {
+            gba.sysbus.get_cycles(IWRAM, access, MemoryAccess16)
+                + gba.sysbus.get_cycles(EWRAM, access, MemoryAccess16)
+        };
+
+        // dma3 copies 4 halfwords, starting 2 cycles after being enabled
+        gba.sysbus.write_32(DMA3, IWRAM);
+        gba.sysbus.write_32(DMA3 + 4, EWRAM);
+        gba.sysbus.write_16(DMA3 + 8, 4);
+        gba.sysbus.write_16(DMA3 + 10, 1 << 15);
+        assert!(!gba.sysbus.io.dmac.is_active());
+        gba.sysbus.io.scheduler.update(2);
+        gba.handle_events();
+        assert!(gba.sysbus.io.dmac.is_active());
+
+        assert_eq!(gba.dma_step(), 2 + unit_cycles(&gba, NonSeq));
+        assert_eq!(gba.dma_step(), unit_cycles(&gba, Seq));
+
+        // dma0 takes over the bus in the middle of the transfer
+        gba.sysbus.write_32(DMA0, IWRAM);
+        gba.sysbus.write_32(DMA0 + 4, IWRAM + 0x100);
+        gba.sysbus.write_16(DMA0 + 8, 1);
+        gba.sysbus.write_16(DMA0 + 10, 1 << 15 | 1 << 14);
+        gba.sysbus.io.scheduler.update(2);
+        gba.handle_events();
+        gba.dma_step();
+        assert_eq!(gba.sysbus.read_16(IWRAM + 0x100), 0x1110);
+        assert!(gba.sysbus.io.intc.interrupt_flags.DMA0());
+        assert_eq!(gba.sysbus.read_16(EWRAM + 4), 0);
+
+        // and dma3 continues where it left off
+        assert_eq!(gba.dma_step(), unit_cycles(&gba, NonSeq));
+        assert_eq!(gba.dma_step(), unit_cycles(&gba, Seq));
+        assert!(!gba.sysbus.io.dmac.is_active());
+        assert_eq!(gba.sysbus.read_16(EWRAM + 6), 0x4440);
+        assert_eq!(gba.sysbus.read_16(DMA3 + 10) & 1 << 15, 0);
+    }
+
+    #[test]
+    fn test_prefetch_fills_from_scheduler_time() {
+        use super::super::sysbus::{MemoryAccessType::*, MemoryAccessWidth::*};
+
+        const ROM: u32 = 0x0800_0000;
+        let mut gba = make_mock_gba(&[0; 0x200]);
+        gba.sysbus.write_16(0x0400_0204, 1 << 14);
+        let sb = &mut gba.sysbus;
+        let miss = sb.get_cycles(ROM, NonSeq, MemoryAccess16);
+        assert_eq!(sb.get_fetch_cycles(0, ROM, NonSeq, MemoryAccess16), miss);
+
+        // the cpu didn't run, e.g because it was halted, but the buffer kept filling
+        sb.io.scheduler.update(100);
+        assert_eq!(sb.get_fetch_cycles(0, ROM + 2, Seq, MemoryAccess16), 1);
+
+        // a data access takes over the bus and flushes the buffer
+        sb.get_cpu_cycles(ROM + 0x100, NonSeq, MemoryAccess16);
+        sb.io.scheduler.update(100);
+        let miss = sb.get_cycles(ROM + 4, Seq, MemoryAccess16);
+        assert_eq!(sb.get_fetch_cycles(0, ROM + 4, Seq, MemoryAccess16), miss);
+    }
+
+    #[test]
+    fn test_arm_pipeline_reload_fetches_words() {
+        use super::super::sysbus::{MemoryAccessType::*, MemoryAccessWidth::*};
+
+        const ROM: u32 = 0x0800_0000;
+        let mut gba = make_mock_gba(&[0; 0x200]);
+        let expected = gba.sysbus.get_cycles(ROM, NonSeq, MemoryAccess32)
+            + gba.sysbus.get_cycles(ROM + 4, Seq, MemoryAccess32);
+        let start = gba.cpu.cycles();
+        gba.cpu.pc = ROM;
+        gba.cpu.reload_pipeline32(&mut gba.sysbus);
+        assert_eq!(gba.cpu.cycles() - start, expected);
+    }
+
+    #[test]
+    fn test_open_bus_and_bios_protection() {
+        let program: [u32; 5] = [
+            0xe3a0_1401, // mov r1, #0x01000000
+            0xe591_0000, // ldr r0, [r1]
+            0xe3a0_3000, // mov r3, #0
+            0xe593_2000, // ldr r2, [r3]
+            0xeaff_fffe, // b .
+        ];
+        let mut gba = make_mock_gba(&make_rom(&program));
+        gba.step();
+
+        // unmapped memory reads the opcode prefetched 8 bytes after the load
+        assert_eq!(gba.cpu.get_reg(0), program[3]);
+        // the bios can't be read from the rom
+        assert_eq!(gba.cpu.get_reg(2), 0xe129_f000);
+        assert_eq!(gba.sysbus.read_32(0x0800_1000), 0x0801_0800);
+    }
+
+    #[test]
+    fn test_memory_watchpoints() {
+        use super::super::sysbus::{MemoryAccessWidth::*, WatchHit, WatchKind, Watchpoint};
+
+        let program: [u32; 7] = [
+            0xe3a0_1403, // mov r1, #0x03000000
+            0xe3a0_0010, // mov r0, #0x10
+            0xe5c1_0001, // strb r0, [r1, #1]
+            0xe591_2000, // ldr r2, [r1]
+            0xe3a0_0020, // mov r0, #0x20
+            0xe5c1_0001, // strb r0, [r1, #1]
+            0xeaff_fffe, // b .
+        ];
+        let mut gba = make_mock_gba(&make_rom(&program));
+
+        let mut write_watch = Watchpoint::new(0x0300_0001, 1, WatchKind::Write);
+        write_watch.value = Some(0x20);
+        gba.sysbus.add_watch(write_watch);
+        gba.sysbus
+            .add_watch(Watchpoint::new(0x0300_0000, 1, WatchKind::Read));
+        // opcode fetches don't count
+        gba.sysbus
+            .add_watch(Watchpoint::new(0x0800_0000, 0x200, WatchKind::ReadWrite));
+
+        // watchpoints are kept when a state is restored
+        let state = gba.save_state().unwrap();
+        gba.restore_state(&state).unwrap();
+        assert_eq!(gba.sysbus.get_watches().len(), 3);
+
+        // runs into the final loop
+        for _ in 0..program.len() + 4 {
+            gba.step_instruction();
+        }
+        assert_eq!(
+            gba.sysbus.take_watch_hits(),
+            vec![
+                WatchHit {
+                    addr: 0x0300_0000,
+                    value: 0x1000,
+                    width: MemoryAccess32,
+                    is_write: false,
+                },
+                WatchHit {
+                    addr: 0x0300_0001,
+                    value: 0x20,
+                    width: MemoryAccess8,
+                    is_write: true,
+                },
+            ]
+        );
+
+        // debug reads don't count either
+        gba.sysbus.debug_read_32(0x0300_0000);
+        gba.sysbus.get_bytes(0x0300_0000..0x0300_0004);
+        assert!(!gba.sysbus.has_watch_hits());
+
+        assert!(gba.sysbus.remove_watch(&write_watch));
+        assert!(!gba.sysbus.remove_watch(&write_watch));
     }
 
     #[test]
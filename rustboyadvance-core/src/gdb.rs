@@ -1,70 +1,79 @@
 use super::arm7tdmi::CpuState;
-use super::core::GameBoyAdvance;
-use super::interrupt::*;
-use super::iodev::IoDevices;
-use super::sysbus::SysBus;
-use super::Bus;
+use super::sysbus::{WatchHit, WatchKind, Watchpoint};
+use super::GameBoyAdvance;
+use super::{Addr, Bus};
 
 use byteorder::{LittleEndian, ReadBytesExt};
-use gdbstub::{Access, Target, TargetState};
+use gdbstub::{Access, AccessKind, Target, TargetState};
 
 use std::io::Cursor;
 
+/// gdbstub keeps the watchpoints gdb asks for, so it has to be told about every access
+const WATCH_ALL: Watchpoint = Watchpoint {
+    addr: 0,
+    len: Addr::MAX,
+    kind: WatchKind::ReadWrite,
+    value: None,
+};
+
+fn log_watch_hits(hits: Vec<WatchHit>, log_mem_access: &mut impl FnMut(Access<u32>)) {
+    for hit in hits {
+        // gdbstub compares addresses, not ranges, so each byte is logged on its own
+        for i in 0..hit.width.size() {
+            log_mem_access(Access {
+                kind: if hit.is_write {
+                    AccessKind::Write
+                } else {
+                    AccessKind::Read
+                },
+                addr: hit.addr.wrapping_add(i),
+                val: (hit.value >> (8 * i)) as u8,
+            });
+        }
+    }
+}
+
 impl Target for GameBoyAdvance {
     type Usize = u32;
     type Error = ();
 
     fn step(
         &mut self,
-        mut _log_mem_access: impl FnMut(Access<u32>),
+        mut log_mem_access: impl FnMut(Access<u32>),
     ) -> Result<TargetState, Self::Error> {
-        static mut S_TOTAL_CYCLES: usize = 0;
-
-        let io = unsafe {
-            let ptr = &mut *self.sysbus as *mut SysBus;
-            &mut (*ptr).io as &mut IoDevices
-        };
-
-        // clear any pending DMAs
-        let mut irqs = IrqBitmask(0);
-        while io.dmac.is_active() {
-            io.dmac.perform_work(&mut self.sysbus, &mut irqs);
+        // only the hits recorded during this step are gdb's
+        let mut start = self.sysbus.watch_hit_count();
+        self.sysbus.add_watch(WATCH_ALL);
+
+        // let the dma finish first, it stalls the CPU
+        while self.sysbus.io.dmac.is_active() {
+            let cycles = self.dma_step();
+            self.sysbus.io.scheduler.update(cycles);
+            self.handle_events();
+            // a long transfer would fill the hit list, so log as it goes
+            let hits = self.sysbus.take_watch_hits_after(start, &WATCH_ALL);
+            start = self.sysbus.watch_hit_count();
+            log_watch_hits(hits, &mut log_mem_access);
         }
-        io.intc.request_irqs(irqs);
 
         // run the CPU, ignore haltcnt
-        let cycles = self.step_cpu(io);
-        io.timers.update(cycles, &mut self.sysbus, &mut irqs);
-
-        unsafe {
-            S_TOTAL_CYCLES += cycles;
-        }
-
-        if self.cycles_to_next_event <= unsafe { S_TOTAL_CYCLES } {
-            let mut cycles_to_next_event = std::usize::MAX;
-            io.gpu.update(
-                unsafe { S_TOTAL_CYCLES },
-                &mut self.sysbus,
-                &mut irqs,
-                &mut cycles_to_next_event,
-                &self.video_device,
+        let cycles = self.step_cpu();
+        self.sysbus.io.scheduler.update(cycles);
+        self.handle_events();
+        self.sysbus.cartridge.update(cycles);
+
+        self.sysbus.remove_watch(&WATCH_ALL);
+        let hits = self.sysbus.take_watch_hits_after(start, &WATCH_ALL);
+        log_watch_hits(hits, &mut log_mem_access);
+
+        let dropped = self.sysbus.take_dropped_watch_hits();
+        if dropped > 0 {
+            warn!(
+                "gdb: {} memory accesses were not logged, the watch hit list was full",
+                dropped
             );
-            io.sound.update(
-                unsafe { S_TOTAL_CYCLES },
-                &mut cycles_to_next_event,
-                &self.audio_device,
-            );
-            self.cycles_to_next_event = cycles_to_next_event;
-
-            unsafe {
-                S_TOTAL_CYCLES = 0;
-            };
-        } else {
-            self.cycles_to_next_event -= unsafe { S_TOTAL_CYCLES };
         }
 
-        io.intc.request_irqs(irqs);
-
         Ok(TargetState::Running)
     }
 
@@ -124,3 +133,62 @@ impl Target for GameBoyAdvance {
         )
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gba::tests::{make_mock_gba, make_rom};
+
+    fn make_gba() -> GameBoyAdvance {
+        make_mock_gba(&make_rom(&[
+            0xe3a0_1403, // mov r1, #0x03000000
+            0xe581_1000, // str r1, [r1]
+            0xeaff_fffe, // b .
+        ]))
+    }
+
+    #[test]
+    fn test_step_logs_memory_accesses() {
+        let mut gba = make_gba();
+
+        let mut accesses = Vec::new();
+        for _ in 0..6 {
+            Target::step(&mut gba, |access: Access<u32>| {
+                accesses.push((access.addr, access.val))
+            })
+            .unwrap();
+        }
+        assert_eq!(
+            accesses,
+            vec![
+                (0x0300_0000, 0x00),
+                (0x0300_0001, 0x00),
+                (0x0300_0002, 0x00),
+                (0x0300_0003, 0x03)
+            ]
+        );
+        assert!(gba.sysbus.get_watches().is_empty());
+    }
+
+    #[test]
+    fn test_step_leaves_other_watch_hits() {
+        let mut gba = make_gba();
+        let watch = Watchpoint {
+            addr: 0x0300_0000,
+            len: 4,
+            kind: WatchKind::Write,
+            value: None,
+        };
+        gba.sysbus.add_watch(watch);
+
+        let mut accesses = 0;
+        for _ in 0..6 {
+            Target::step(&mut gba, |_| accesses += 1).unwrap();
+        }
+        assert_eq!(accesses, 4);
+        let hits = gba.sysbus.take_watch_hits();
+        assert_eq!(hits.len(), 1);
+        assert_eq!((hits[0].addr, hits[0].value), (0x0300_0000, 0x0300_0000));
+        assert_eq!(gba.sysbus.get_watches(), &[watch]);
+    }
+}
@@ -4,8 +4,10 @@ use std::rc::Rc;
 
 use serde::{Deserialize, Serialize};
 
+use super::dma::DmaController;
 use super::interrupt::IrqBitmask;
-use super::sysbus::{BoxedMemory, SysBus};
+use super::sched::Scheduler;
+use super::sysbus::BoxedMemory;
 use super::Bus;
 use super::VideoInterface;
 
@@ -78,6 +80,14 @@ impl GpuState {
             _ => false,
         }
     }
+
+    /// How long the gpu stays in this state
+    pub fn cycles(&self) -> usize {
+        match self {
+            HDraw | VBlankHDraw => CYCLES_HDRAW,
+            HBlank | VBlankHBlank => CYCLES_HBLANK,
+        }
+    }
 }
 
 use GpuState::*;
@@ -171,9 +181,6 @@ type VideoDeviceRcRefCell = Rc<RefCell<dyn VideoInterface>>;
 pub struct Gpu {
     pub state: GpuState,
 
-    /// how many cycles left until next gpu state ?
-    cycles_left_for_current_state: usize,
-
     // registers
     pub vcount: usize, // VCOUNT
     pub dispcnt: DisplayControl,
@@ -226,7 +233,6 @@ impl Gpu {
 
             state: HDraw,
             vcount: 0,
-            cycles_left_for_current_state: CYCLES_HDRAW,
 
             palette_ram: BoxedMemory::new(vec![0; PALETTE_RAM_SIZE].into_boxed_slice()),
             vram: BoxedMemory::new(vec![0; VIDEO_RAM_SIZE].into_boxed_slice()),
@@ -344,7 +350,18 @@ impl Gpu {
                 self.render_mode4(2);
                 self.finalize_scanline(2, 2);
             }
-            _ => panic!("{:?} not supported", self.dispcnt.mode()),
+            5 => {
+                self.render_mode5(2);
+                self.finalize_scanline(2, 2);
+            }
+            _ => {
+                // modes 6 and 7 are invalid, the hardware only displays the backdrop
+                let y = self.vcount;
+                let backdrop = Rgb15(self.palette_ram.read_16(0)).to_rgb24();
+                for x in 0..DISPLAY_WIDTH {
+                    self.frame_buffer[index2d!(x, y, DISPLAY_WIDTH)] = backdrop;
+                }
+            }
         }
         // self.mosaic_sfx();
     }
@@ -371,10 +388,17 @@ impl Gpu {
         &self.frame_buffer
     }
 
+    /// For drawing over the frame, e.g by scripts
+    pub(crate) fn get_frame_buffer_mut(&mut self) -> &mut [u32] {
+        &mut self.frame_buffer
+    }
+
+    /// Moves on to the next state, the caller schedules its completion
     pub fn on_state_completed(
         &mut self,
         completed: GpuState,
-        sb: &mut SysBus,
+        dmac: &mut DmaController,
+        scheduler: &mut Scheduler,
         irqs: &mut IrqBitmask,
         video_device: &VideoDeviceRcRefCell,
     ) {
@@ -382,13 +406,12 @@ impl Gpu {
             HDraw => {
                 // Transition to HBlank
                 self.state = HBlank;
-                self.cycles_left_for_current_state = CYCLES_HBLANK;
                 self.dispstat.set_hblank_flag(true);
 
                 if self.dispstat.hblank_irq_enable() {
                     irqs.set_LCD_HBlank(true);
                 };
-                sb.io.dmac.notify_hblank();
+                dmac.notify_hblank(scheduler);
             }
             HBlank => {
                 self.update_vcount(self.vcount + 1, irqs);
@@ -402,7 +425,6 @@ impl Gpu {
                         self.bg_aff[i].internal_x += self.bg_aff[i].pb as i16 as i32;
                         self.bg_aff[i].internal_y += self.bg_aff[i].pd as i16 as i32;
                     }
-                    self.cycles_left_for_current_state = CYCLES_HDRAW;
                 } else {
                     // latch BG2/3 reference points on vblank
                     for i in 0..2 {
@@ -416,15 +438,13 @@ impl Gpu {
                         irqs.set_LCD_VBlank(true);
                     };
 
-                    sb.io.dmac.notify_vblank();
+                    dmac.notify_vblank(scheduler);
                     video_device.borrow_mut().render(&self.frame_buffer);
                     self.obj_buffer_reset();
-                    self.cycles_left_for_current_state = CYCLES_HDRAW;
                     self.state = VBlankHDraw;
                 }
             }
             VBlankHDraw => {
-                self.cycles_left_for_current_state = CYCLES_HBLANK;
                 self.state = VBlankHBlank;
 
                 self.dispstat.set_hblank_flag(true);
@@ -437,45 +457,18 @@ impl Gpu {
 
                 if self.vcount < DISPLAY_HEIGHT + VBLANK_LINES - 1 {
                     self.dispstat.set_hblank_flag(false);
-                    self.cycles_left_for_current_state = CYCLES_HDRAW;
                     self.state = VBlankHDraw;
                 } else {
                     self.update_vcount(0, irqs);
                     self.dispstat.set_vblank_flag(false);
                     self.render_scanline();
-                    self.cycles_left_for_current_state = CYCLES_HDRAW;
                     self.state = HDraw;
                 }
             }
         };
-    }
-
-    // Returns the new gpu state
-    pub fn update(
-        &mut self,
-        cycles: usize,
-        sb: &mut SysBus,
-        irqs: &mut IrqBitmask,
-        cycles_to_next_event: &mut usize,
-        video_device: &VideoDeviceRcRefCell,
-    ) {
-        if self.cycles_left_for_current_state <= cycles {
-            let overshoot = cycles - self.cycles_left_for_current_state;
-
-            self.on_state_completed(self.state, sb, irqs, video_device);
-
-            // handle the overshoot
-            if overshoot < self.cycles_left_for_current_state {
-                self.cycles_left_for_current_state -= overshoot;
-            } else {
-                panic!("OH SHIT");
-            }
-        } else {
-            self.cycles_left_for_current_state -= cycles;
-        }
 
-        if self.cycles_left_for_current_state < *cycles_to_next_event {
-            *cycles_to_next_event = self.cycles_left_for_current_state;
+        if let HBlank | VBlankHBlank = completed {
+            dmac.notify_scanline(self.vcount, scheduler);
         }
     }
 }
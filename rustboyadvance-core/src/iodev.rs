@@ -3,6 +3,8 @@ use super::gpu::regs::WindowFlags;
 use super::gpu::*;
 use super::interrupt::InterruptController;
 use super::keypad;
+use super::sched::{EventType, Scheduler};
+use super::sio::{SerialController, SERIAL_POLL_CYCLES};
 use super::sound::SoundController;
 use super::sysbus::SysBusPtr;
 use super::timer::Timers;
@@ -21,11 +23,13 @@ pub enum HaltState {
 
 #[derive(Clone, Serialize, Deserialize)]
 pub struct IoDevices {
+    pub scheduler: Scheduler,
     pub intc: InterruptController,
     pub gpu: Box<Gpu>,
     pub sound: Box<SoundController>,
     pub timers: Timers,
     pub dmac: DmaController,
+    pub sio: SerialController,
     pub keyinput: u16,
     pub post_boot_flag: bool,
     pub waitcnt: WaitControl, // TODO also implement 4000800
@@ -40,11 +44,17 @@ pub struct IoDevices {
 
 impl IoDevices {
     pub fn new(gpu: Box<Gpu>, sound_controller: Box<SoundController>) -> IoDevices {
+        let mut scheduler = Scheduler::new();
+        scheduler.schedule(EventType::Gpu(gpu.state), gpu.state.cycles());
+        scheduler.schedule(EventType::SoundSample, sound_controller.cycles_per_sample());
+        scheduler.schedule(EventType::SerialPoll, SERIAL_POLL_CYCLES);
         IoDevices {
+            scheduler: scheduler,
             gpu: gpu,
             sound: sound_controller,
             timers: Timers::new(),
             dmac: DmaController::new(),
+            sio: SerialController::new(),
             intc: InterruptController::new(),
             post_boot_flag: false,
             haltcnt: HaltState::Running,
@@ -92,7 +102,8 @@ impl Bus for IoDevices {
             REG_IE => io.intc.interrupt_enable.0 as u16,
             REG_IF => io.intc.interrupt_flags.0 as u16,
 
-            REG_TM0CNT_L..=REG_TM3CNT_H => io.timers.handle_read(io_addr),
+            REG_TM0CNT_L..=REG_TM3CNT_H => io.timers.handle_read(io_addr, io.scheduler.timestamp()),
+            REG_SIOMULTI0..=REG_SIOMLT_SEND | REG_RCNT => io.sio.handle_read(io_addr),
 
             SOUND_BASE..=SOUND_END => io.sound.handle_read(io_addr),
             REG_DMA0CNT_H => io.dmac.channels[0].ctrl.0,
@@ -222,7 +233,12 @@ impl Bus for IoDevices {
             REG_IE => io.intc.interrupt_enable.0 = value,
             REG_IF => io.intc.interrupt_flags.0 &= !value,
 
-            REG_TM0CNT_L..=REG_TM3CNT_H => io.timers.handle_write(io_addr, value),
+            REG_TM0CNT_L..=REG_TM3CNT_H => {
+                io.timers.handle_write(io_addr, value, &mut io.scheduler)
+            }
+            REG_SIOMULTI0..=REG_SIOMLT_SEND | REG_RCNT => {
+                io.sio.handle_write(io_addr, value, &mut io.scheduler)
+            }
 
             SOUND_BASE..=SOUND_END => {
                 io.sound.handle_write(io_addr, value);
@@ -231,7 +247,8 @@ impl Bus for IoDevices {
             DMA_BASE..=REG_DMA3CNT_H => {
                 let ofs = io_addr - DMA_BASE;
                 let channel_id = (ofs / 12) as usize;
-                io.dmac.write_16(channel_id, ofs % 12, value)
+                io.dmac
+                    .write_16(channel_id, ofs % 12, value, &mut io.scheduler)
             }
 
             REG_WAITCNT => {
@@ -296,7 +313,7 @@ bitfield! {
     pub ws2_second_access, _:      10, 10;
     #[allow(non_snake_case)]
     PHI_terminal_output, _:    12, 11;
-    prefetch, _:               14;
+    pub prefetch, _:           14;
 }
 
 #[rustfmt::skip]
@@ -391,14 +408,14 @@ pub mod consts {
     pub const REG_TM2CNT_H: Addr = 0x0400_010A;     //  2    R/W    Timer 2 Control
     pub const REG_TM3CNT_L: Addr = 0x0400_010C;     //  2    R/W    Timer 3 Counter/Reload
     pub const REG_TM3CNT_H: Addr = 0x0400_010E;     //  2    R/W    Timer 3 Control
-    // pub const REG_SIODATA32: Addr = 0x0400_0120;    //  4    R/W    SIO Data (Normal-32bit Mode; shared with below)
-    // pub const REG_SIOMULTI0: Addr = 0x0400_0120;    //  2    R/W    SIO Data 0 (Parent)    (Multi-Player Mode)
-    // pub const REG_SIOMULTI1: Addr = 0x0400_0122;    //  2    R/W    SIO Data 1 (1st Child) (Multi-Player Mode)
-    // pub const REG_SIOMULTI2: Addr = 0x0400_0124;    //  2    R/W    SIO Data 2 (2nd Child) (Multi-Player Mode)
-    // pub const REG_SIOMULTI3: Addr = 0x0400_0126;    //  2    R/W    SIO Data 3 (3rd Child) (Multi-Player Mode)
-    // pub const REG_SIOCNT: Addr = 0x0400_0128;       //  2    R/W    SIO Control Register
-    // pub const REG_SIOMLT_SEND: Addr = 0x0400_012A;  //  2    R/W    SIO Data (Local of MultiPlayer; shared below)
-    // pub const REG_SIODATA8: Addr = 0x0400_012A;     //  2    R/W    SIO Data (Normal-8bit and UART Mode)
+    pub const REG_SIODATA32: Addr = 0x0400_0120;    //  4    R/W    SIO Data (Normal-32bit Mode; shared with below)
+    pub const REG_SIOMULTI0: Addr = 0x0400_0120;    //  2    R/W    SIO Data 0 (Parent)    (Multi-Player Mode)
+    pub const REG_SIOMULTI1: Addr = 0x0400_0122;    //  2    R/W    SIO Data 1 (1st Child) (Multi-Player Mode)
+    pub const REG_SIOMULTI2: Addr = 0x0400_0124;    //  2    R/W    SIO Data 2 (2nd Child) (Multi-Player Mode)
+    pub const REG_SIOMULTI3: Addr = 0x0400_0126;    //  2    R/W    SIO Data 3 (3rd Child) (Multi-Player Mode)
+    pub const REG_SIOCNT: Addr = 0x0400_0128;       //  2    R/W    SIO Control Register
+    pub const REG_SIOMLT_SEND: Addr = 0x0400_012A;  //  2    R/W    SIO Data (Local of MultiPlayer; shared below)
+    pub const REG_SIODATA8: Addr = 0x0400_012A;     //  2    R/W    SIO Data (Normal-8bit and UART Mode)
     pub const REG_KEYINPUT: Addr = 0x0400_0130;     //  2    R      Key Status
     pub const REG_KEYCNT: Addr = 0x0400_0132;       //  2    R/W    Key Interrupt Control
     pub const REG_RCNT: Addr = 0x0400_0134;         //  2    R/W    SIO Mode Select/General Purpose Data
@@ -499,13 +516,13 @@ pub fn io_reg_string(addr: u32) -> &'static str {
         REG_TM2CNT_H => "REG_TM2CNT_H",
         REG_TM3CNT_L => "REG_TM3CNT_L",
         REG_TM3CNT_H => "REG_TM3CNT_H",
-        // REG_SIODATA32 => "REG_SIODATA32",
+        REG_SIODATA32 => "REG_SIODATA32",
         // REG_SIOMULTI0 => "REG_SIOMULTI0",
-        // REG_SIOMULTI1 => "REG_SIOMULTI1",
-        // REG_SIOMULTI2 => "REG_SIOMULTI2",
-        // REG_SIOMULTI3 => "REG_SIOMULTI3",
-        // REG_SIOCNT => "REG_SIOCNT",
-        // REG_SIOMLT_SEND => "REG_SIOMLT_SEND",
+        REG_SIOMULTI1 => "REG_SIOMULTI1",
+        REG_SIOMULTI2 => "REG_SIOMULTI2",
+        REG_SIOMULTI3 => "REG_SIOMULTI3",
+        REG_SIOCNT => "REG_SIOCNT",
+        REG_SIOMLT_SEND => "REG_SIOMLT_SEND",
         // REG_SIODATA8 => "REG_SIODATA8",
         REG_KEYINPUT => "REG_KEYINPUT",
         REG_KEYCNT => "REG_KEYCNT",
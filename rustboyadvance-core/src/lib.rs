@@ -31,6 +31,7 @@ use std::fmt;
 #[macro_use]
 pub mod util;
 pub mod arm7tdmi;
+pub mod bios;
 pub mod cartridge;
 pub mod disass;
 pub mod gpu;
@@ -46,6 +47,16 @@ pub use gba::GameBoyAdvance;
 pub mod bus;
 pub mod dma;
 pub mod keypad;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod link;
+#[cfg(any(test, feature = "lockstep"))]
+pub mod lockstep;
+pub mod movie;
+pub mod prefetch;
+pub mod rewind;
+pub mod savestate;
+pub mod sched;
+pub mod sio;
 pub mod timer;
 pub use bus::*;
 
@@ -55,6 +66,9 @@ pub mod gdb;
 #[cfg(feature = "debugger")]
 pub mod debugger;
 
+#[cfg(feature = "scripting")]
+pub mod scripting;
+
 pub trait VideoInterface {
     #[allow(unused_variables)]
     fn render(&mut self, buffer: &[u32]) {}
@@ -80,6 +94,41 @@ pub trait InputInterface {
     }
 }
 
+pub trait SerialInterface {
+    /// Whether a link cable is plugged in
+    fn is_connected(&self) -> bool {
+        false
+    }
+
+    /// Our position in a multiplayer session, 0 being the parent
+    fn player_id(&self) -> usize {
+        0
+    }
+
+    /// Called every `sio::SERIAL_POLL_CYCLES` with the current cycle, before `slave_transfer`
+    /// A cable shared with other emulators waits here until they reached the same cycle
+    #[allow(unused_variables)]
+    fn sync(&mut self, timestamp: u64) {}
+
+    /// Sends our data to the other side of the cable when we drive the clock at `timestamp`
+    #[allow(unused_variables)]
+    fn start_master_transfer(&mut self, data: sio::SerialData, timestamp: u64) {}
+
+    /// Checks whether the other side answered the transfer begun by `start_master_transfer`
+    /// Once `give_up` is set the transfer ends with whatever arrived so far
+    #[allow(unused_variables)]
+    fn poll_master_transfer(&mut self, give_up: bool) -> sio::TransferStatus {
+        sio::TransferStatus::Done(None)
+    }
+
+    /// Polls for a transfer started by the other side of the cable, without blocking
+    /// Returns None if no transfer took place yet
+    #[allow(unused_variables)]
+    fn slave_transfer(&mut self, data: sio::SerialData) -> Option<sio::SerialData> {
+        None
+    }
+}
+
 #[derive(Debug)]
 pub enum GBAError {
     IO(::std::io::Error),
@@ -127,8 +176,12 @@ pub mod prelude {
     #[cfg(feature = "debugger")]
     pub use super::debugger::Debugger;
     pub use super::gpu::{DISPLAY_HEIGHT, DISPLAY_WIDTH};
+    pub use super::rewind::Rewinder;
+    pub use super::savestate::SaveStateError;
     pub use super::util::{read_bin_file, write_bin_file};
     pub use super::Bus;
-    pub use super::{AudioInterface, InputInterface, StereoSample, VideoInterface};
+    pub use super::{
+        AudioInterface, InputInterface, SerialInterface, StereoSample, VideoInterface,
+    };
     pub use super::{GBAError, GBAResult, GameBoyAdvance};
 }
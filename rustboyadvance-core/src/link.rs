@@ -0,0 +1,943 @@
+//! Link cable emulation between GameBoyAdvance instances
+//!
+//! The player hosting the session is the multiplayer parent, every other player connects to it.
+//! The players run in lock-step: every `sio::SERIAL_POLL_CYCLES` each one sends its cycle count
+//! to its peers and waits until they reached the same cycle, and the side driving the clock waits
+//! at the transfer point for its peers to answer. A transfer is thus seen at the same cycle on
+//! every side, no matter how the host schedules the emulators.
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+#[cfg(unix)]
+use std::path::Path;
+use std::sync::mpsc::{channel, Receiver, Sender, TryRecvError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use byteorder::{ByteOrder, LittleEndian};
+
+use super::sio::{SerialData, TransferStatus};
+use super::SerialInterface;
+
+pub const MAX_PLAYERS: usize = 4;
+
+/// How long a player that already connected waits for the host to greet it
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// How long a player waits for the host to start the session
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+
+/// How long a player waits for a peer to catch up before dropping it
+pub const DEFAULT_SYNC_TIMEOUT: Duration = Duration::from_secs(10);
+
+const PACKET_SIZE: usize = 24;
+
+/// The data mode of packets that carry no data
+const NO_DATA: u8 = 0xff;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum LinkPacket {
+    /// Sent by the host to every player once the session is established
+    Hello {
+        player_id: usize,
+        num_players: usize,
+    },
+    /// The sender reached `timestamp`, every transfer it drove before then was sent already
+    Sync { timestamp: u64 },
+    /// Data from the side driving the clock, sent at `timestamp`
+    Request {
+        seq: u32,
+        data: SerialData,
+        timestamp: u64,
+    },
+    /// A peer's answer to a `Request`, None if it wasn't ready to take part in the transfer
+    Reply { seq: u32, data: Option<SerialData> },
+    /// The combined data of all players, sent by the multiplayer parent
+    Result { seq: u32, data: SerialData },
+}
+
+impl LinkPacket {
+    fn encode(&self) -> [u8; PACKET_SIZE] {
+        let mut buf = [0; PACKET_SIZE];
+        let (kind, seq, data) = match *self {
+            LinkPacket::Hello {
+                player_id,
+                num_players,
+            } => {
+                buf[2] = player_id as u8;
+                buf[3] = num_players as u8;
+                (0, 0, None)
+            }
+            LinkPacket::Request {
+                seq,
+                data,
+                timestamp,
+            } => {
+                LittleEndian::write_u64(&mut buf[16..24], timestamp);
+                (1, seq, Some(data))
+            }
+            LinkPacket::Reply { seq, data } => (2, seq, data),
+            LinkPacket::Result { seq, data } => (3, seq, Some(data)),
+            LinkPacket::Sync { timestamp } => {
+                LittleEndian::write_u64(&mut buf[16..24], timestamp);
+                (4, 0, None)
+            }
+        };
+        buf[0] = kind;
+        LittleEndian::write_u32(&mut buf[4..8], seq);
+        match data {
+            Some(SerialData::Normal8bit(value)) => {
+                buf[1] = 0;
+                buf[8] = value;
+            }
+            Some(SerialData::Normal32bit(value)) => {
+                buf[1] = 1;
+                LittleEndian::write_u32(&mut buf[8..12], value);
+            }
+            Some(SerialData::Multiplayer(values)) => {
+                buf[1] = 2;
+                LittleEndian::write_u16_into(&values, &mut buf[8..16]);
+            }
+            Some(SerialData::Uart(value)) => {
+                buf[1] = 3;
+                buf[8] = value;
+            }
+            None => buf[1] = NO_DATA,
+        }
+        buf
+    }
+
+    fn decode(buf: &[u8]) -> io::Result<LinkPacket> {
+        let invalid = || io::Error::from(io::ErrorKind::InvalidData);
+        let seq = LittleEndian::read_u32(&buf[4..8]);
+        let timestamp = LittleEndian::read_u64(&buf[16..24]);
+        let data = match buf[1] {
+            0 => Some(SerialData::Normal8bit(buf[8])),
+            1 => Some(SerialData::Normal32bit(LittleEndian::read_u32(&buf[8..12]))),
+            2 => {
+                let mut values = [0; 4];
+                LittleEndian::read_u16_into(&buf[8..16], &mut values);
+                Some(SerialData::Multiplayer(values))
+            }
+            3 => Some(SerialData::Uart(buf[8])),
+            NO_DATA => None,
+            _ => return Err(invalid()),
+        };
+        match buf[0] {
+            0 => Ok(LinkPacket::Hello {
+                player_id: buf[2] as usize,
+                num_players: buf[3] as usize,
+            }),
+            1 => Ok(LinkPacket::Request {
+                seq,
+                data: data.ok_or_else(invalid)?,
+                timestamp,
+            }),
+            2 => Ok(LinkPacket::Reply { seq, data }),
+            3 => Ok(LinkPacket::Result {
+                seq,
+                data: data.ok_or_else(invalid)?,
+            }),
+            4 => Ok(LinkPacket::Sync { timestamp }),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+/// A connection to a single peer
+pub trait LinkConnection: Send {
+    fn send(&mut self, packet: &LinkPacket) -> io::Result<()>;
+
+    /// Returns the next packet if one has arrived, without blocking
+    fn try_recv(&mut self) -> io::Result<Option<LinkPacket>>;
+
+    fn recv_timeout(&mut self, timeout: Duration) -> io::Result<Option<LinkPacket>> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            if let Some(packet) = self.try_recv()? {
+                return Ok(Some(packet));
+            }
+            if Instant::now() >= deadline {
+                return Ok(None);
+            }
+            thread::yield_now();
+        }
+    }
+}
+
+/// A connection to an emulator running in the same process
+pub struct LocalConnection {
+    tx: Sender<LinkPacket>,
+    rx: Receiver<LinkPacket>,
+}
+
+impl LocalConnection {
+    pub fn pair() -> (LocalConnection, LocalConnection) {
+        let (tx_a, rx_a) = channel();
+        let (tx_b, rx_b) = channel();
+        (
+            LocalConnection { tx: tx_a, rx: rx_b },
+            LocalConnection { tx: tx_b, rx: rx_a },
+        )
+    }
+}
+
+impl LinkConnection for LocalConnection {
+    fn send(&mut self, packet: &LinkPacket) -> io::Result<()> {
+        self.tx
+            .send(*packet)
+            .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))
+    }
+
+    fn try_recv(&mut self) -> io::Result<Option<LinkPacket>> {
+        match self.rx.try_recv() {
+            Ok(packet) => Ok(Some(packet)),
+            Err(TryRecvError::Empty) => Ok(None),
+            Err(TryRecvError::Disconnected) => Err(io::Error::from(io::ErrorKind::BrokenPipe)),
+        }
+    }
+}
+
+/// A connection over a non-blocking socket
+pub struct StreamConnection<S: Read + Write + Send> {
+    stream: S,
+    rx_buf: Vec<u8>,
+}
+
+impl<S: Read + Write + Send> StreamConnection<S> {
+    fn new(stream: S) -> StreamConnection<S> {
+        StreamConnection {
+            stream,
+            rx_buf: Vec::with_capacity(PACKET_SIZE),
+        }
+    }
+}
+
+impl<S: Read + Write + Send> LinkConnection for StreamConnection<S> {
+    fn send(&mut self, packet: &LinkPacket) -> io::Result<()> {
+        let buf = packet.encode();
+        let mut written = 0;
+        while written < buf.len() {
+            match self.stream.write(&buf[written..]) {
+                Ok(0) => return Err(io::Error::from(io::ErrorKind::WriteZero)),
+                Ok(n) => written += n,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => thread::yield_now(),
+                Err(e) => return Err(e),
+            }
+        }
+        self.stream.flush()
+    }
+
+    fn try_recv(&mut self) -> io::Result<Option<LinkPacket>> {
+        let mut buf = [0; PACKET_SIZE];
+        let wanted = PACKET_SIZE - self.rx_buf.len();
+        match self.stream.read(&mut buf[..wanted]) {
+            Ok(0) => return Err(io::Error::from(io::ErrorKind::UnexpectedEof)),
+            Ok(n) => self.rx_buf.extend_from_slice(&buf[..n]),
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {}
+            Err(e) => return Err(e),
+        }
+        if self.rx_buf.len() == PACKET_SIZE {
+            let packet = LinkPacket::decode(&self.rx_buf)?;
+            self.rx_buf.clear();
+            Ok(Some(packet))
+        } else {
+            Ok(None)
+        }
+    }
+}
+
+fn tcp_connection(stream: TcpStream) -> io::Result<Box<dyn LinkConnection>> {
+    stream.set_nodelay(true)?;
+    stream.set_nonblocking(true)?;
+    Ok(Box::new(StreamConnection::new(stream)))
+}
+
+#[cfg(unix)]
+fn unix_connection(stream: UnixStream) -> io::Result<Box<dyn LinkConnection>> {
+    stream.set_nonblocking(true)?;
+    Ok(Box::new(StreamConnection::new(stream)))
+}
+
+/// A transfer driven by our clock, waiting for the peers to answer
+struct PendingTransfer {
+    seq: u32,
+    data: SerialData,
+    /// Which peers answered so far, in the same order as `LinkCable::peers`
+    answered: Vec<bool>,
+    received: Option<SerialData>,
+}
+
+impl PendingTransfer {
+    fn add_reply(&mut self, reply: SerialData) {
+        if !same_mode(&reply, &self.data) {
+            return;
+        }
+        self.received = match (self.received, reply) {
+            (None, SerialData::Multiplayer(values)) => {
+                let mut merged = match self.data {
+                    SerialData::Multiplayer(ours) => ours,
+                    _ => unreachable!(),
+                };
+                merge_multiplayer(&mut merged, &values);
+                Some(SerialData::Multiplayer(merged))
+            }
+            (Some(SerialData::Multiplayer(mut merged)), SerialData::Multiplayer(values)) => {
+                merge_multiplayer(&mut merged, &values);
+                Some(SerialData::Multiplayer(merged))
+            }
+            (_, reply) => Some(reply),
+        };
+    }
+}
+
+/// A transfer driven by the clock of a peer, answered once we reach its cycle
+#[derive(Debug, Copy, Clone)]
+struct PeerRequest {
+    seq: u32,
+    data: SerialData,
+    timestamp: u64,
+}
+
+struct Peer {
+    connection: Box<dyn LinkConnection>,
+    /// The cycle the peer reached, as far as we know
+    timestamp: u64,
+    requests: VecDeque<PeerRequest>,
+}
+
+impl Peer {
+    fn new(connection: Box<dyn LinkConnection>) -> Peer {
+        Peer {
+            connection,
+            timestamp: 0,
+            requests: VecDeque::new(),
+        }
+    }
+
+    /// Whether the peer drives a transfer and can't go on until we answered it
+    fn is_waiting(&self) -> bool {
+        self.requests
+            .iter()
+            .any(|request| !matches!(request.data, SerialData::Uart(_)))
+    }
+
+    /// Whether every transfer the peer drove before `timestamp` arrived
+    fn caught_up(&self, timestamp: u64) -> bool {
+        self.timestamp >= timestamp || self.is_waiting()
+    }
+}
+
+/// One end of a link cable, plugged into the serial port of a `GameBoyAdvance`
+pub struct LinkCable {
+    player_id: usize,
+    /// The parent is connected to every child, a child is only connected to the parent
+    peers: Vec<Peer>,
+    seq: u32,
+    /// Our cycle at the last sync, the requests of the peers made before it are due
+    timestamp: u64,
+    transfer: Option<PendingTransfer>,
+    /// A multiplayer child that answered the transfer `seq` waits for its result
+    awaiting_result: Option<u32>,
+    result: Option<SerialData>,
+    timeout: Duration,
+}
+
+impl LinkCable {
+    fn new(player_id: usize, peers: Vec<Box<dyn LinkConnection>>) -> LinkCable {
+        LinkCable {
+            player_id,
+            peers: peers.into_iter().map(Peer::new).collect(),
+            seq: 0,
+            timestamp: 0,
+            transfer: None,
+            awaiting_result: None,
+            result: None,
+            timeout: DEFAULT_SYNC_TIMEOUT,
+        }
+    }
+
+    /// Sets how long to wait for a peer to catch up before dropping it
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    /// Hosts a session, `peers` being the connections to the other players in order
+    pub fn host(mut peers: Vec<Box<dyn LinkConnection>>) -> io::Result<LinkCable> {
+        let num_players = peers.len() + 1;
+        if num_players > MAX_PLAYERS {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "too many players",
+            ));
+        }
+        for (i, peer) in peers.iter_mut().enumerate() {
+            peer.send(&LinkPacket::Hello {
+                player_id: i + 1,
+                num_players,
+            })?;
+        }
+        Ok(LinkCable::new(0, peers))
+    }
+
+    /// Joins a session hosted on the other side of `connection`
+    pub fn join(
+        mut connection: Box<dyn LinkConnection>,
+        timeout: Duration,
+    ) -> io::Result<LinkCable> {
+        match connection.recv_timeout(timeout)? {
+            Some(LinkPacket::Hello { player_id, .. }) if player_id < MAX_PLAYERS => {
+                Ok(LinkCable::new(player_id, vec![connection]))
+            }
+            Some(_) => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "expected a hello packet",
+            )),
+            None => Err(io::Error::from(io::ErrorKind::TimedOut)),
+        }
+    }
+
+    /// Creates a session between emulators running in the same process
+    pub fn local(num_players: usize) -> io::Result<Vec<LinkCable>> {
+        let mut host_ends = Vec::new();
+        let mut child_ends = Vec::new();
+        for _ in 1..num_players {
+            let (host_end, child_end) = LocalConnection::pair();
+            host_ends.push(Box::new(host_end) as Box<dyn LinkConnection>);
+            child_ends.push(Box::new(child_end) as Box<dyn LinkConnection>);
+        }
+        let mut cables = vec![LinkCable::host(host_ends)?];
+        for child_end in child_ends {
+            cables.push(LinkCable::join(child_end, DEFAULT_TIMEOUT)?);
+        }
+        Ok(cables)
+    }
+
+    /// Hosts a session over TCP, waiting for `num_players - 1` players to connect
+    pub fn host_tcp<A: ToSocketAddrs>(addr: A, num_players: usize) -> io::Result<LinkCable> {
+        let listener = TcpListener::bind(addr)?;
+        let mut peers = Vec::new();
+        while peers.len() + 1 < num_players {
+            let (stream, peer_addr) = listener.accept()?;
+            info!(
+                "link: player {} connected from {}",
+                peers.len() + 1,
+                peer_addr
+            );
+            peers.push(tcp_connection(stream)?);
+        }
+        LinkCable::host(peers)
+    }
+
+    pub fn connect_tcp<A: ToSocketAddrs>(addr: A) -> io::Result<LinkCable> {
+        let connection = tcp_connection(TcpStream::connect(addr)?)?;
+        // the host only greets us once every player has connected
+        LinkCable::join(connection, HANDSHAKE_TIMEOUT)
+    }
+
+    /// Hosts a session over a unix domain socket
+    #[cfg(unix)]
+    pub fn host_unix<P: AsRef<Path>>(path: P, num_players: usize) -> io::Result<LinkCable> {
+        let listener = UnixListener::bind(path)?;
+        let mut peers = Vec::new();
+        while peers.len() + 1 < num_players {
+            let (stream, _) = listener.accept()?;
+            info!("link: player {} connected", peers.len() + 1);
+            peers.push(unix_connection(stream)?);
+        }
+        LinkCable::host(peers)
+    }
+
+    #[cfg(unix)]
+    pub fn connect_unix<P: AsRef<Path>>(path: P) -> io::Result<LinkCable> {
+        let connection = unix_connection(UnixStream::connect(path)?)?;
+        LinkCable::join(connection, HANDSHAKE_TIMEOUT)
+    }
+
+    /// Drops a peer that failed, the game will see an unplugged cable from now on
+    fn disconnect(&mut self, index: usize, err: io::Error) {
+        warn!("link: lost connection to peer ({})", err);
+        self.peers.remove(index);
+        if let Some(transfer) = &mut self.transfer {
+            transfer.answered.remove(index);
+        }
+    }
+
+    /// Sends a packet to the peer at `index`, returns false if the peer was dropped
+    fn send(&mut self, index: usize, packet: LinkPacket) -> bool {
+        match self.peers[index].connection.send(&packet) {
+            Ok(()) => true,
+            Err(e) => {
+                self.disconnect(index, e);
+                false
+            }
+        }
+    }
+
+    /// Takes the next packet of the peer at `index`, returns false if none arrived yet
+    fn receive(&mut self, index: usize) -> io::Result<bool> {
+        let packet = match self.peers[index].connection.try_recv()? {
+            Some(packet) => packet,
+            None => return Ok(false),
+        };
+        let peer = &mut self.peers[index];
+        match packet {
+            LinkPacket::Sync { timestamp } => peer.timestamp = timestamp,
+            LinkPacket::Request {
+                seq,
+                data,
+                timestamp,
+            } => {
+                peer.timestamp = timestamp;
+                peer.requests.push_back(PeerRequest {
+                    seq,
+                    data,
+                    timestamp,
+                });
+            }
+            LinkPacket::Reply { seq, data } => match &mut self.transfer {
+                Some(transfer) if transfer.seq == seq => {
+                    transfer.answered[index] = true;
+                    if let Some(data) = data {
+                        transfer.add_reply(data);
+                    }
+                }
+                _ => {
+                    // an answer to a transfer we already gave up on
+                }
+            },
+            LinkPacket::Result { seq, data } => {
+                if self.awaiting_result == Some(seq) {
+                    self.awaiting_result = None;
+                    self.result = Some(data);
+                }
+            }
+            LinkPacket::Hello { .. } => {}
+        }
+        Ok(true)
+    }
+
+    /// Takes the packets of the peer at `index` until `done` holds, dropping the peer if that
+    /// takes longer than `timeout`. Returns false if the peer was dropped.
+    fn wait_for_peer<F>(&mut self, index: usize, done: F) -> bool
+    where
+        F: Fn(&LinkCable) -> bool,
+    {
+        let deadline = Instant::now() + self.timeout;
+        while !done(self) {
+            match self.receive(index) {
+                Ok(true) => {}
+                Ok(false) if Instant::now() < deadline => thread::yield_now(),
+                Ok(false) => {
+                    self.disconnect(index, io::Error::from(io::ErrorKind::TimedOut));
+                    return false;
+                }
+                Err(e) => {
+                    self.disconnect(index, e);
+                    return false;
+                }
+            }
+        }
+        true
+    }
+
+    /// Waits until every peer either reached `timestamp` or waits for us to answer a transfer
+    fn wait_for_peers(&mut self, timestamp: u64) {
+        for index in (0..self.peers.len()).rev() {
+            self.wait_for_peer(index, |cable| cable.peers[index].caught_up(timestamp));
+        }
+    }
+
+    /// Turns down the requests of the peer at `index` made before `timestamp`
+    /// Returns false if the peer was dropped.
+    fn reject_requests(&mut self, index: usize, timestamp: u64) -> bool {
+        while let Some(request) = self.peers[index].requests.front().copied() {
+            if request.timestamp >= timestamp {
+                break;
+            }
+            self.peers[index].requests.pop_front();
+            if let SerialData::Uart(_) = request.data {
+                // nothing is sent back in uart mode
+                continue;
+            }
+            let reply = LinkPacket::Reply {
+                seq: request.seq,
+                data: None,
+            };
+            if !self.send(index, reply) {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+fn same_mode(a: &SerialData, b: &SerialData) -> bool {
+    std::mem::discriminant(a) == std::mem::discriminant(b)
+}
+
+impl SerialInterface for LinkCable {
+    fn is_connected(&self) -> bool {
+        !self.peers.is_empty()
+    }
+
+    fn player_id(&self) -> usize {
+        self.player_id
+    }
+
+    fn sync(&mut self, timestamp: u64) {
+        // what the game didn't take at the last sync finds nobody on our side
+        for index in (0..self.peers.len()).rev() {
+            self.reject_requests(index, self.timestamp);
+        }
+        self.timestamp = timestamp;
+        for index in (0..self.peers.len()).rev() {
+            self.send(index, LinkPacket::Sync { timestamp });
+        }
+        self.wait_for_peers(timestamp);
+    }
+
+    fn start_master_transfer(&mut self, data: SerialData, timestamp: u64) {
+        self.seq = self.seq.wrapping_add(1);
+        let seq = self.seq;
+
+        for index in (0..self.peers.len()).rev() {
+            self.send(index, LinkPacket::Sync { timestamp });
+        }
+        self.wait_for_peers(timestamp);
+
+        // a peer that waits for us drives the clock as well, which the hardware doesn't support.
+        // Neither side gets an answer.
+        let answered = self.peers.iter().map(Peer::is_waiting).collect();
+        self.transfer = Some(PendingTransfer {
+            seq,
+            data,
+            answered,
+            received: None,
+        });
+        for index in (0..self.peers.len()).rev() {
+            if self.peers[index].is_waiting() {
+                self.reject_requests(index, u64::MAX);
+            } else {
+                self.send(
+                    index,
+                    LinkPacket::Request {
+                        seq,
+                        data,
+                        timestamp,
+                    },
+                );
+            }
+        }
+        if let SerialData::Uart(_) = data {
+            // nothing is sent back in uart mode
+            self.transfer = None;
+        }
+    }
+
+    fn poll_master_transfer(&mut self, _give_up: bool) -> TransferStatus {
+        if self.transfer.is_none() {
+            return TransferStatus::Done(None);
+        }
+        // the peers answer once they reach the cycle of the transfer, we stay right here until then
+        for index in (0..self.peers.len()).rev() {
+            let answered = |cable: &LinkCable| {
+                cable.transfer.as_ref().unwrap().answered[index] || cable.peers[index].is_waiting()
+            };
+            if self.wait_for_peer(index, answered)
+                && !self.transfer.as_ref().unwrap().answered[index]
+                && self.reject_requests(index, u64::MAX)
+            {
+                // the peer started a transfer of its own meanwhile
+                self.transfer.as_mut().unwrap().answered[index] = true;
+            }
+        }
+        let transfer = self.transfer.take().unwrap();
+
+        if let Some(result @ SerialData::Multiplayer(_)) = transfer.received {
+            let result = LinkPacket::Result {
+                seq: transfer.seq,
+                data: result,
+            };
+            for index in (0..self.peers.len()).rev() {
+                self.send(index, result);
+            }
+        }
+        TransferStatus::Done(transfer.received)
+    }
+
+    fn slave_transfer(&mut self, data: SerialData) -> Option<SerialData> {
+        if let Some(result) = self.result.take() {
+            return Some(result);
+        }
+        for index in (0..self.peers.len()).rev() {
+            while let Some(request) = self.peers[index].requests.front().copied() {
+                if request.timestamp >= self.timestamp {
+                    break;
+                }
+                self.peers[index].requests.pop_front();
+                let reply = LinkPacket::Reply {
+                    seq: request.seq,
+                    data: Some(data),
+                };
+                match request.data {
+                    SerialData::Uart(_) => return Some(request.data),
+                    SerialData::Multiplayer(_) => {
+                        // the transfer is over once the parent sends the merged data
+                        self.awaiting_result = Some(request.seq);
+                        if !self.send(index, reply) {
+                            break;
+                        }
+                    }
+                    _ => {
+                        let sent = self.send(index, reply);
+                        if same_mode(&request.data, &data) {
+                            return Some(request.data);
+                        }
+                        if !sent {
+                            break;
+                        }
+                    }
+                }
+            }
+        }
+        None
+    }
+}
+
+fn merge_multiplayer(merged: &mut [u16; 4], values: &[u16; 4]) {
+    for (dst, &src) in merged.iter_mut().zip(values.iter()) {
+        // undriven slots read as 0xffff
+        *dst &= src;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    use crate::bios::builtin_bios;
+    use crate::gba::tests::{make_mock_gba_with_bios, make_rom};
+    use crate::iodev::consts::*;
+    use crate::{Bus, GameBoyAdvance};
+
+    /// b .
+    const IDLE: &[u32] = &[0xEAFF_FFFE];
+
+    fn make_linked_gba(cable: LinkCable, code: &[u32]) -> GameBoyAdvance {
+        let rom = make_rom(code);
+        let mut gba = make_mock_gba_with_bios(builtin_bios(), &rom);
+        gba.set_bios_hle(true);
+        gba.set_serial_device(Rc::new(RefCell::new(cable)));
+        gba
+    }
+
+    #[derive(Debug, PartialEq)]
+    struct TransferResult {
+        siocnt: u16,
+        multi: [u16; 4],
+        data8: u16,
+        data32: u32,
+    }
+
+    /// Starts a transfer on every player, then runs each one on its own thread until all of them
+    /// raised the serial irq. `late_player` starts running a while after the others.
+    fn run_session(
+        cables: Vec<LinkCable>,
+        code: &'static [u32],
+        params: Vec<(u32, u16)>,
+        late_player: Option<usize>,
+    ) -> Vec<TransferResult> {
+        let num_players = cables.len();
+        let done = Arc::new(AtomicUsize::new(0));
+        let threads: Vec<_> = cables
+            .into_iter()
+            .zip(params)
+            .enumerate()
+            .map(|(player, (cable, (send, siocnt)))| {
+                let done = done.clone();
+                thread::spawn(move || {
+                    let mut gba = make_linked_gba(cable, code);
+                    gba.sysbus.write_16(REG_SIOCNT, siocnt & !(1 << 7));
+                    gba.sysbus.write_32(REG_SIODATA32, send);
+                    gba.sysbus.write_16(REG_SIOMLT_SEND, send as u16);
+                    gba.sysbus.write_16(REG_SIOCNT, siocnt);
+                    if late_player == Some(player) {
+                        thread::sleep(Duration::from_millis(50));
+                    }
+                    for _ in 0..10 {
+                        gba.frame();
+                        if gba.sysbus.io.intc.interrupt_flags.SerialCommunication() {
+                            break;
+                        }
+                    }
+                    let mut multi = [0; 4];
+                    for (i, value) in multi.iter_mut().enumerate() {
+                        *value = gba.sysbus.read_16(REG_SIOMULTI0 + 2 * i as u32);
+                    }
+                    let result = TransferResult {
+                        siocnt: gba.sysbus.read_16(REG_SIOCNT),
+                        multi,
+                        data8: gba.sysbus.read_16(REG_SIODATA8),
+                        data32: gba.sysbus.read_32(REG_SIODATA32),
+                    };
+                    // keep the cable plugged in until every player is done
+                    done.fetch_add(1, Ordering::SeqCst);
+                    while done.load(Ordering::SeqCst) < num_players {
+                        gba.frame();
+                    }
+                    result
+                })
+            })
+            .collect();
+        threads
+            .into_iter()
+            .map(|thread| thread.join().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_packet_roundtrip() {
+        let packets = [
+            LinkPacket::Hello {
+                player_id: 2,
+                num_players: 3,
+            },
+            LinkPacket::Sync {
+                timestamp: 0x1234_5678_9abc,
+            },
+            LinkPacket::Request {
+                seq: 7,
+                data: SerialData::Normal32bit(0xdead_beef),
+                timestamp: 0x1_0000_0000,
+            },
+            LinkPacket::Reply {
+                seq: 8,
+                data: Some(SerialData::Multiplayer([1, 2, 3, 4])),
+            },
+            LinkPacket::Reply { seq: 9, data: None },
+            LinkPacket::Result {
+                seq: 9,
+                data: SerialData::Uart(0x42),
+            },
+        ];
+        for packet in packets.iter() {
+            assert_eq!(LinkPacket::decode(&packet.encode()).unwrap(), *packet);
+        }
+    }
+
+    #[test]
+    fn test_normal_mode_loopback() {
+        let cables = LinkCable::local(2).unwrap();
+        // 8bit, the host drives the clock and the other side uses the external clock
+        let results = run_session(
+            cables,
+            IDLE,
+            vec![(0x12, 1 | 1 << 7 | 1 << 14), (0x34, 1 << 7 | 1 << 14)],
+            None,
+        );
+        assert_eq!(results[0].siocnt & (1 << 7), 0);
+        assert_eq!(results[1].siocnt & (1 << 7), 0);
+        assert_eq!(results[0].data8, 0x34);
+        assert_eq!(results[1].data8, 0x12);
+    }
+
+    #[test]
+    fn test_unanswered_transfer_times_out() {
+        let mut cables = LinkCable::local(2).unwrap();
+        // the other player stays plugged in, but never runs
+        let _child = cables.pop().unwrap();
+        cables[0].set_timeout(Duration::from_millis(100));
+        let results = run_session(cables, IDLE, vec![(0x12, 1 | 1 << 7 | 1 << 14)], None);
+        assert_eq!(results[0].siocnt & (1 << 7), 0);
+        assert_eq!(results[0].data8, 0xff);
+    }
+
+    #[test]
+    fn test_tcp_loopback() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+        let host = LinkCable::host(vec![tcp_connection(server).unwrap()]).unwrap();
+        let child = LinkCable::join(tcp_connection(client).unwrap(), DEFAULT_TIMEOUT).unwrap();
+        assert_eq!(child.player_id(), 1);
+
+        // 32bit, this time the child drives the clock
+        let results = run_session(
+            vec![host, child],
+            IDLE,
+            vec![
+                (0x1234_5678, 1 << 12 | 1 << 7 | 1 << 14),
+                (0x9abc_def0, 1 << 12 | 1 | 1 << 7 | 1 << 14),
+            ],
+            None,
+        );
+        assert_eq!(results[0].siocnt & (1 << 7), 0);
+        assert_eq!(results[1].siocnt & (1 << 7), 0);
+        assert_eq!(results[0].data32, 0x9abc_def0);
+        assert_eq!(results[1].data32, 0x1234_5678);
+    }
+
+    #[test]
+    fn test_multiplayer_loopback() {
+        let cables = LinkCable::local(3).unwrap();
+        // multiplayer mode at 115200 bps, only the parent sets the start bit
+        let results = run_session(
+            cables,
+            IDLE,
+            vec![
+                (0x1111, 3 | 1 << 13 | 1 << 7 | 1 << 14),
+                (0x2222, 3 | 1 << 13 | 1 << 14),
+                (0x3333, 3 | 1 << 13 | 1 << 14),
+            ],
+            None,
+        );
+        for (player_id, result) in results.iter().enumerate() {
+            assert_eq!(result.multi, [0x1111, 0x2222, 0x3333, 0xffff]);
+            assert_eq!((result.siocnt >> 4) & 3, player_id as u16);
+            assert_eq!(result.siocnt & (1 << 3), 1 << 3);
+        }
+    }
+
+    #[test]
+    fn test_transfers_are_deterministic() {
+        // both players count in SIODATA32 until the transfer is over, so what each one receives
+        // depends on the exact cycle the other one reached
+        const COUNTER: &[u32] = &[
+            0xE3A0_0301, // mov r0, #0x04000000
+            0xE280_0C01, // add r0, r0, #0x100
+            0xE281_1001, // loop: add r1, r1, #1
+            0xE580_1020, // str r1, [r0, #0x20]
+            0xE1D0_22B8, // ldrh r2, [r0, #0x28]
+            0xE312_0080, // tst r2, #0x80
+            0x1AFF_FFFA, // bne loop
+            0xEAFF_FFFE, // b .
+        ];
+        let run = |late_player| {
+            // 32bit, the host drives the clock
+            run_session(
+                LinkCable::local(2).unwrap(),
+                COUNTER,
+                vec![
+                    (0, 1 << 12 | 1 | 1 << 7 | 1 << 14),
+                    (0, 1 << 12 | 1 << 7 | 1 << 14),
+                ],
+                late_player,
+            )
+        };
+        let expected = run(None);
+        assert_eq!(expected[0].siocnt & (1 << 7), 0);
+        assert_eq!(expected[1].siocnt & (1 << 7), 0);
+        assert_ne!(expected[0].data32, 0);
+        assert_ne!(expected[1].data32, 0);
+        for &late_player in [Some(0), Some(1), None].iter() {
+            assert_eq!(run(late_player), expected);
+        }
+    }
+}
@@ -0,0 +1,335 @@
+//! Differential testing of the cpu execution back-ends.
+//!
+//! Two emulators run the same program side by side, each executing through a different back-end.
+//! After every instruction the registers, the cpsr, the timing and the memory writes of both are
+//! compared, and the first instruction they disagree on is reported.
+//! `Lockstep::fuzz` does the same for random instructions with random operands.
+use std::fmt;
+
+use super::arm7tdmi::arm::{ArmFormat, ArmHalfwordTransferType, ArmInstruction};
+use super::arm7tdmi::thumb::{ThumbFormat, ThumbInstruction};
+use super::arm7tdmi::{AluOpCode, CpuState, DecodedInstruction, InstructionDecoder, REG_PC};
+use super::bus::Bus;
+use super::gba::GameBoyAdvance;
+use super::Addr;
+
+/// Where the random instructions are placed
+const FUZZ_ADDR: Addr = 0x0300_0000;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Backend {
+    /// Handlers looked up in the tables generated by build.rs
+    #[cfg(feature = "arm7tdmi_dispatch_table")]
+    DispatchTable,
+    /// `InstructionDecoder` and a `match` on the instruction format
+    Decoder,
+    /// Blocks of decoded instructions, see `arm7tdmi::block_cache`
+    #[cfg(feature = "block_cache")]
+    BlockCache,
+}
+
+impl Backend {
+    /// Every back-end built into this configuration
+    pub fn all() -> Vec<Backend> {
+        vec![
+            #[cfg(feature = "arm7tdmi_dispatch_table")]
+            Backend::DispatchTable,
+            Backend::Decoder,
+            #[cfg(feature = "block_cache")]
+            Backend::BlockCache,
+        ]
+    }
+
+    pub fn configure(self, gba: &mut GameBoyAdvance) {
+        gba.cpu.set_decoder_dispatch(self == Backend::Decoder);
+        #[cfg(feature = "block_cache")]
+        gba.cpu.set_block_cache(self == Backend::BlockCache);
+    }
+}
+
+/// The first instruction after which the two emulators disagree
+#[derive(Debug, Clone)]
+pub struct Divergence {
+    /// Number of instructions that both emulators agreed on
+    pub step: usize,
+    pub pc: Addr,
+    pub opcode: u32,
+    pub instruction: String,
+    pub differences: Vec<String>,
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "diverged after {} steps at {:08x}: {:08x} {}",
+            self.step, self.pc, self.opcode, self.instruction
+        )?;
+        for difference in &self.differences {
+            writeln!(f, "    {}", difference)?;
+        }
+        Ok(())
+    }
+}
+
+/// Without the debugger only the instruction format is known
+fn disassemble(state: CpuState, opcode: u32, pc: Addr) -> String {
+    let insn = match state {
+        CpuState::ARM => DecodedInstruction::Arm(ArmInstruction::decode(opcode, pc)),
+        CpuState::THUMB => DecodedInstruction::Thumb(ThumbInstruction::decode(opcode as u16, pc)),
+    };
+    #[cfg(feature = "debugger")]
+    return format!("{}", insn);
+    #[cfg(not(feature = "debugger"))]
+    return match insn {
+        DecodedInstruction::Arm(insn) => format!("{:?}", insn.fmt),
+        DecodedInstruction::Thumb(insn) => format!("{:?}", insn.fmt),
+    };
+}
+
+pub struct Lockstep {
+    pub a: GameBoyAdvance,
+    pub b: GameBoyAdvance,
+    steps: usize,
+}
+
+impl Lockstep {
+    /// `a` and `b` should be in the same state, e.g both freshly created from the same rom
+    pub fn new(
+        mut a: GameBoyAdvance,
+        backend_a: Backend,
+        mut b: GameBoyAdvance,
+        backend_b: Backend,
+    ) -> Lockstep {
+        backend_a.configure(&mut a);
+        backend_b.configure(&mut b);
+        a.sysbus.trace_writes(true);
+        b.sysbus.trace_writes(true);
+        Lockstep { a, b, steps: 0 }
+    }
+
+    /// Number of instructions executed so far
+    pub fn steps(&self) -> usize {
+        self.steps
+    }
+
+    /// Executes a single instruction on both emulators and compares them
+    pub fn step(&mut self) -> Result<(), Divergence> {
+        let state = self.a.cpu.get_cpu_state();
+        let pc = self.a.cpu.get_next_pc();
+        let opcode = self.a.cpu.get_next_opcode();
+
+        let cycles_a = self.a.step_instruction();
+        let cycles_b = self.b.step_instruction();
+
+        let mut differences = Vec::new();
+        for r in 0..=REG_PC {
+            let (reg_a, reg_b) = (self.a.cpu.get_reg(r), self.b.cpu.get_reg(r));
+            if reg_a != reg_b {
+                differences.push(format!("r{}: {:08x} != {:08x}", r, reg_a, reg_b));
+            }
+        }
+        let (cpsr_a, cpsr_b) = (self.a.cpu.cpsr.get(), self.b.cpu.cpsr.get());
+        if cpsr_a != cpsr_b {
+            differences.push(format!("cpsr: {:08x} != {:08x}", cpsr_a, cpsr_b));
+        }
+        if cycles_a != cycles_b {
+            differences.push(format!("cycles: {} != {}", cycles_a, cycles_b));
+        }
+        let (writes_a, writes_b) = (self.a.sysbus.take_writes(), self.b.sysbus.take_writes());
+        if writes_a != writes_b {
+            differences.push(format!("writes: {:x?} != {:x?}", writes_a, writes_b));
+        }
+
+        if differences.is_empty() {
+            self.steps += 1;
+            Ok(())
+        } else {
+            Err(Divergence {
+                step: self.steps,
+                pc,
+                opcode,
+                instruction: disassemble(state, opcode, pc),
+                differences,
+            })
+        }
+    }
+
+    /// Executes up to `steps` instructions, stopping at the first divergence
+    pub fn run(&mut self, steps: usize) -> Result<(), Divergence> {
+        for _ in 0..steps {
+            self.step()?;
+        }
+        Ok(())
+    }
+
+    /// Executes `iterations` random instructions of the given state on both emulators, each one
+    /// with random registers and flags. The same `seed` generates the same instructions.
+    pub fn fuzz(
+        &mut self,
+        state: CpuState,
+        seed: u64,
+        iterations: usize,
+    ) -> Result<(), Divergence> {
+        let mut rng = Rng::new(seed);
+        for _ in 0..iterations {
+            let opcode = loop {
+                let opcode = match state {
+                    CpuState::ARM => rng.next_u32(),
+                    CpuState::THUMB => rng.next_u32() & 0xffff,
+                };
+                if is_fuzzable(state, opcode) {
+                    break opcode;
+                }
+            };
+            let mut regs = [0; 15];
+            for reg in regs.iter_mut() {
+                *reg = rng.next_u32();
+                // keep the loads and stores away from the io and video hardware
+                if (0x04..=0x07).contains(&(*reg >> 24)) {
+                    *reg ^= 0x1000_0000;
+                }
+            }
+            // random flags, irqs masked, system mode
+            let mut cpsr = (rng.next_u32() & 0xf000_0000) | 0xdf;
+            if state == CpuState::THUMB {
+                cpsr |= 1 << 5;
+            }
+
+            load_case(&mut self.a, state, opcode, &regs, cpsr);
+            load_case(&mut self.b, state, opcode, &regs, cpsr);
+            self.step()?;
+        }
+        Ok(())
+    }
+}
+
+fn load_case(gba: &mut GameBoyAdvance, state: CpuState, opcode: u32, regs: &[u32; 15], cpsr: u32) {
+    match state {
+        CpuState::ARM => gba.sysbus.write_32(FUZZ_ADDR, opcode),
+        CpuState::THUMB => gba.sysbus.write_16(FUZZ_ADDR, opcode as u16),
+    }
+    gba.cpu.gpr = *regs;
+    gba.cpu.cpsr.set(cpsr);
+    gba.cpu.pc = FUZZ_ADDR;
+    match state {
+        CpuState::ARM => gba.cpu.reload_pipeline32(&mut gba.sysbus),
+        CpuState::THUMB => gba.cpu.reload_pipeline16(&mut gba.sysbus),
+    }
+    gba.sysbus.take_writes();
+}
+
+/// Leaves out the encodings the interpreter doesn't handle, and the ones that could switch to a
+/// mode without an spsr
+fn is_fuzzable(state: CpuState, opcode: u32) -> bool {
+    match state {
+        CpuState::ARM => {
+            if opcode >> 28 == 0xf {
+                return false;
+            }
+            let insn = ArmInstruction::decode(opcode, FUZZ_ADDR);
+            match insn.fmt {
+                ArmFormat::Undefined | ArmFormat::MoveToStatus | ArmFormat::MoveToFlags => false,
+                ArmFormat::DataProcessing => {
+                    let compare = matches!(
+                        insn.opcode(),
+                        AluOpCode::TST | AluOpCode::TEQ | AluOpCode::CMP | AluOpCode::CMN
+                    );
+                    let set_cond = insn.set_cond_flag();
+                    (set_cond || !compare) && !(set_cond && insn.rd() == REG_PC)
+                }
+                // the user bank transfer isn't available in system mode
+                ArmFormat::BlockDataTransfer => !insn.psr_and_force_user_flag(),
+                ArmFormat::HalfwordDataTransferRegOffset
+                | ArmFormat::HalfwordDataTransferImmediateOffset => {
+                    match insn.halfword_data_transfer_type() {
+                        Ok(ArmHalfwordTransferType::UnsignedHalfwords) => true,
+                        Ok(_) => insn.load_flag(),
+                        Err(_) => false,
+                    }
+                }
+                _ => true,
+            }
+        }
+        CpuState::THUMB => {
+            ThumbInstruction::decode(opcode as u16, FUZZ_ADDR).fmt != ThumbFormat::Undefined
+        }
+    }
+}
+
+/// xorshift64*, good enough for picking instructions
+struct Rng(u64);
+
+impl Rng {
+    fn new(seed: u64) -> Rng {
+        Rng(seed.max(1))
+    }
+
+    fn next_u32(&mut self) -> u32 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        (self.0.wrapping_mul(0x2545_f491_4f6c_dd1d) >> 32) as u32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gba::tests::{make_mock_gba, make_rom};
+
+    fn make_lockstep(rom: &[u8], backend_a: Backend, backend_b: Backend) -> Lockstep {
+        Lockstep::new(make_mock_gba(rom), backend_a, make_mock_gba(rom), backend_b)
+    }
+
+    fn check(result: Result<(), Divergence>) {
+        if let Err(divergence) = result {
+            panic!("{}", divergence);
+        }
+    }
+
+    #[cfg(feature = "arm7tdmi_dispatch_table")]
+    #[test]
+    fn test_program_in_lockstep() {
+        let program: [u32; 9] = [
+            0xe3a0_0403, // mov r0, #0x03000000
+            0xe3a0_1001, // mov r1, #1
+            0xe1a0_2001, // loop: mov r2, r1
+            0xe080_1101, // add r1, r0, r1, lsl #2
+            0xe4c0_2001, // strb r2, [r0], #1
+            0xe1d0_30b0, // ldrh r3, [r0]
+            0xe213_30ff, // ands r3, r3, #0xff
+            0x0aff_fff9, // beq loop
+            0xeaff_fffe, // b .
+        ];
+        let rom = make_rom(&program);
+
+        let mut lockstep = make_lockstep(&rom, Backend::DispatchTable, Backend::Decoder);
+        check(lockstep.run(1000));
+        assert_eq!(lockstep.steps(), 1000);
+    }
+
+    #[test]
+    fn test_fuzz_arm_and_thumb() {
+        #[cfg(feature = "block_cache")]
+        let backend = Backend::BlockCache;
+        #[cfg(all(feature = "arm7tdmi_dispatch_table", not(feature = "block_cache")))]
+        let backend = Backend::DispatchTable;
+        #[cfg(not(feature = "arm7tdmi_dispatch_table"))]
+        let backend = Backend::Decoder;
+
+        let mut lockstep = make_lockstep(&[0; 0x200], backend, Backend::Decoder);
+        check(lockstep.fuzz(CpuState::ARM, 0x5eed, 5000));
+        check(lockstep.fuzz(CpuState::THUMB, 0x5eed, 5000));
+        assert_eq!(lockstep.steps(), 10000);
+    }
+
+    #[test]
+    fn test_divergence_is_reported() {
+        let mut lockstep = make_lockstep(&[0; 0x200], Backend::Decoder, Backend::Decoder);
+        lockstep.b.cpu.gpr[1] = 1;
+        let divergence = lockstep.step().unwrap_err();
+        assert_eq!(divergence.step, 0);
+        assert_eq!(divergence.differences, vec!["r1: 00000000 != 00000001"]);
+    }
+}
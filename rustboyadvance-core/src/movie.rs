@@ -0,0 +1,395 @@
+//! Recording and playback of input movies
+//!
+//! A movie file is laid out as follows:
+//!   magic           8 bytes  "RBAMOVIE"
+//!   format version  u32 LE
+//!   movie           bincode encoded `Movie`
+//!
+//! Input is polled once per frame, so a movie is simply the `KEYINPUT` value of every frame.
+//! Checksums of the work ram are recorded along the way to detect when a playback desyncs.
+use std::cell::RefCell;
+use std::error::Error;
+use std::fmt;
+use std::rc::Rc;
+
+use bincode;
+use byteorder::{ByteOrder, LittleEndian};
+use serde::{Deserialize, Serialize};
+
+use super::savestate::{SaveStateError, SaveStateResult};
+use super::{GameBoyAdvance, InputInterface};
+
+pub const MOVIE_MAGIC: [u8; 8] = *b"RBAMOVIE";
+pub const MOVIE_VERSION: u32 = 1;
+
+/// Number of frames between two ram checksums
+pub const DEFAULT_CHECKSUM_INTERVAL: u32 = 60;
+
+const HEADER_SIZE: usize = 12;
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub enum MovieStart {
+    PowerOn { skip_bios: bool },
+    SaveState(Vec<u8>),
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Movie {
+    pub game_code: String,
+    /// sha256 of the rom
+    pub rom_digest: [u8; 32],
+    pub start: MovieStart,
+    /// `KEYINPUT` of every frame
+    pub keyinput: Vec<u16>,
+    pub checksum_interval: u32,
+    /// Work ram checksum after every `checksum_interval` frames
+    pub checksums: Vec<u32>,
+}
+
+#[derive(Debug)]
+pub enum MovieError {
+    InvalidMagic,
+    UnsupportedVersion(u32),
+    /// The movie was recorded with a different rom
+    RomMismatch,
+    SaveState(SaveStateError),
+    Corrupted(String),
+}
+
+impl fmt::Display for MovieError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MovieError::InvalidMagic => write!(f, "not a movie"),
+            MovieError::UnsupportedVersion(version) => {
+                write!(f, "unsupported movie format version {}", version)
+            }
+            MovieError::RomMismatch => write!(f, "movie was recorded with a different rom"),
+            MovieError::SaveState(err) => {
+                write!(f, "failed to load the start of the movie: {}", err)
+            }
+            MovieError::Corrupted(reason) => write!(f, "corrupted movie: {}", reason),
+        }
+    }
+}
+
+impl Error for MovieError {}
+
+impl From<bincode::Error> for MovieError {
+    fn from(err: bincode::Error) -> MovieError {
+        MovieError::Corrupted(err.to_string())
+    }
+}
+
+impl From<SaveStateError> for MovieError {
+    fn from(err: SaveStateError) -> MovieError {
+        MovieError::SaveState(err)
+    }
+}
+
+pub type MovieResult<T> = Result<T, MovieError>;
+
+/// Playback no longer matches the recording
+#[derive(Debug, PartialEq)]
+pub enum MovieDesync {
+    /// Input was polled a different number of times than frames were emulated
+    FrameCount { expected: usize, found: usize },
+    RamChecksum {
+        frame: usize,
+        expected: u32,
+        found: u32,
+    },
+}
+
+impl fmt::Display for MovieDesync {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MovieDesync::FrameCount { expected, found } => write!(
+                f,
+                "movie desynced, expected frame {} but input was polled for frame {}",
+                expected, found
+            ),
+            MovieDesync::RamChecksum {
+                frame,
+                expected,
+                found,
+            } => write!(
+                f,
+                "movie desynced at frame {}, ram checksum is {:08x} instead of {:08x}",
+                frame, found, expected
+            ),
+        }
+    }
+}
+
+impl Error for MovieDesync {}
+
+/// FNV-1a over both work rams
+fn ram_checksum(gba: &GameBoyAdvance) -> u32 {
+    let (ewram, iwram) = gba.sysbus.get_work_ram();
+    ewram
+        .iter()
+        .chain(iwram.iter())
+        .fold(0x811c_9dc5, |hash, &byte| {
+            (hash ^ byte as u32).wrapping_mul(0x0100_0193)
+        })
+}
+
+impl Movie {
+    fn new(gba: &GameBoyAdvance, start: MovieStart) -> Movie {
+        Movie {
+            game_code: gba.get_game_code(),
+            rom_digest: *gba.get_rom_digest(),
+            start: start,
+            keyinput: Vec::new(),
+            checksum_interval: DEFAULT_CHECKSUM_INTERVAL,
+            checksums: Vec::new(),
+        }
+    }
+
+    /// A movie that starts from a freshly created emulator
+    pub fn power_on(gba: &GameBoyAdvance, skip_bios: bool) -> Movie {
+        Movie::new(gba, MovieStart::PowerOn { skip_bios })
+    }
+
+    /// A movie that starts from the current state of the emulator
+    pub fn from_current_state(gba: &GameBoyAdvance) -> SaveStateResult<Movie> {
+        let state = gba.save_state()?;
+        Ok(Movie::new(gba, MovieStart::SaveState(state)))
+    }
+
+    pub fn frame_count(&self) -> usize {
+        self.keyinput.len()
+    }
+
+    /// Brings the emulator to the start of the movie.
+    /// For movies that start at power on, the emulator must have been freshly created.
+    pub fn start(&self, gba: &mut GameBoyAdvance) -> MovieResult<()> {
+        if gba.get_rom_digest() != &self.rom_digest {
+            return Err(MovieError::RomMismatch);
+        }
+        match &self.start {
+            MovieStart::PowerOn { skip_bios } => {
+                if *skip_bios {
+                    gba.skip_bios();
+                }
+            }
+            MovieStart::SaveState(state) => gba.restore_state(state)?,
+        }
+        Ok(())
+    }
+
+    pub fn to_bytes(&self) -> MovieResult<Vec<u8>> {
+        let mut bytes = vec![0; HEADER_SIZE];
+        bytes[0..8].copy_from_slice(&MOVIE_MAGIC);
+        LittleEndian::write_u32(&mut bytes[8..12], MOVIE_VERSION);
+        bytes.extend_from_slice(&bincode::serialize(self)?);
+        Ok(bytes)
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> MovieResult<Movie> {
+        if bytes.len() < HEADER_SIZE || bytes[0..8] != MOVIE_MAGIC {
+            return Err(MovieError::InvalidMagic);
+        }
+        let version = LittleEndian::read_u32(&bytes[8..12]);
+        if version != MOVIE_VERSION {
+            return Err(MovieError::UnsupportedVersion(version));
+        }
+        Ok(bincode::deserialize(&bytes[HEADER_SIZE..])?)
+    }
+}
+
+/// Records the input of the wrapped device
+pub struct MovieRecorder {
+    inner: Rc<RefCell<dyn InputInterface>>,
+    movie: Movie,
+}
+
+impl MovieRecorder {
+    pub fn new(inner: Rc<RefCell<dyn InputInterface>>, movie: Movie) -> MovieRecorder {
+        MovieRecorder { inner, movie }
+    }
+
+    /// Must be called after every emulated frame
+    pub fn on_frame(&mut self, gba: &GameBoyAdvance) {
+        let interval = self.movie.checksum_interval as usize;
+        if self.movie.frame_count() % interval == 0 {
+            self.movie.checksums.push(ram_checksum(gba));
+        }
+    }
+
+    pub fn movie(&self) -> &Movie {
+        &self.movie
+    }
+
+    pub fn into_movie(self) -> Movie {
+        self.movie
+    }
+}
+
+impl InputInterface for MovieRecorder {
+    fn poll(&mut self) -> u16 {
+        let keyinput = self.inner.borrow_mut().poll();
+        self.movie.keyinput.push(keyinput);
+        keyinput
+    }
+}
+
+/// Replays the input of a movie, and hands over to the wrapped device once it is over
+pub struct MoviePlayer {
+    inner: Rc<RefCell<dyn InputInterface>>,
+    movie: Movie,
+    polled_frames: usize,
+    emulated_frames: usize,
+}
+
+impl MoviePlayer {
+    pub fn new(inner: Rc<RefCell<dyn InputInterface>>, movie: Movie) -> MoviePlayer {
+        MoviePlayer {
+            inner: inner,
+            movie: movie,
+            polled_frames: 0,
+            emulated_frames: 0,
+        }
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.emulated_frames >= self.movie.frame_count()
+    }
+
+    pub fn current_frame(&self) -> usize {
+        self.emulated_frames
+    }
+
+    /// Must be called after every emulated frame, reports when playback went out of sync
+    pub fn on_frame(&mut self, gba: &GameBoyAdvance) -> Result<(), MovieDesync> {
+        if self.is_finished() {
+            return Ok(());
+        }
+        self.emulated_frames += 1;
+        if self.polled_frames != self.emulated_frames {
+            return Err(MovieDesync::FrameCount {
+                expected: self.emulated_frames,
+                found: self.polled_frames,
+            });
+        }
+        let interval = self.movie.checksum_interval as usize;
+        if self.emulated_frames % interval == 0 {
+            if let Some(&expected) = self
+                .movie
+                .checksums
+                .get(self.emulated_frames / interval - 1)
+            {
+                let found = ram_checksum(gba);
+                if found != expected {
+                    return Err(MovieDesync::RamChecksum {
+                        frame: self.emulated_frames,
+                        expected,
+                        found,
+                    });
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl InputInterface for MoviePlayer {
+    fn poll(&mut self) -> u16 {
+        match self.movie.keyinput.get(self.polled_frames) {
+            Some(&keyinput) => {
+                self.polled_frames += 1;
+                keyinput
+            }
+            None => self.inner.borrow_mut().poll(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::bios::builtin_bios;
+    use crate::gba::tests::{make_mock_gba_with_bios, make_rom};
+    use crate::keypad::KEYINPUT_ALL_RELEASED;
+
+    /// Presses a different combination of keys every frame
+    struct ChangingInput(u16);
+
+    impl InputInterface for ChangingInput {
+        fn poll(&mut self) -> u16 {
+            self.0 = self.0.wrapping_add(1);
+            KEYINPUT_ALL_RELEASED & !(self.0 & 0xff)
+        }
+    }
+
+    fn make_gba(input: Rc<RefCell<dyn InputInterface>>) -> GameBoyAdvance {
+        // sum up KEYINPUT in iwram forever
+        let program: [u32; 8] = [
+            0xE3A0_0301, // mov r0, #0x04000000
+            0xE280_0C01, // add r0, r0, #0x100
+            0xE3A0_1403, // mov r1, #0x03000000
+            0xE1D0_23B0, // ldrh r2, [r0, #0x30]
+            0xE591_3000, // ldr r3, [r1]
+            0xE083_3002, // add r3, r3, r2
+            0xE581_3000, // str r3, [r1]
+            0xEAFF_FFFA, // b 0x0c
+        ];
+        let mut gba = make_mock_gba_with_bios(builtin_bios(), &make_rom(&program));
+        gba.set_input_device(input);
+        gba
+    }
+
+    #[test]
+    fn test_record_and_play() {
+        let live = Rc::new(RefCell::new(ChangingInput(0)));
+        let mut gba = make_gba(live.clone());
+        let mut movie = Movie::power_on(&gba, true);
+        movie.checksum_interval = 2;
+        movie.start(&mut gba).unwrap();
+
+        let recorder = Rc::new(RefCell::new(MovieRecorder::new(live.clone(), movie)));
+        gba.set_input_device(recorder.clone());
+        for _ in 0..10 {
+            gba.frame();
+            recorder.borrow_mut().on_frame(&gba);
+        }
+        let movie = recorder.borrow().movie().clone();
+        assert_eq!(movie.frame_count(), 10);
+        assert_eq!(movie.checksums.len(), 5);
+
+        let movie = Movie::from_bytes(&movie.to_bytes().unwrap()).unwrap();
+        let mut gba = make_gba(live.clone());
+        movie.start(&mut gba).unwrap();
+        let player = Rc::new(RefCell::new(MoviePlayer::new(live.clone(), movie.clone())));
+        gba.set_input_device(player.clone());
+        while !player.borrow().is_finished() {
+            gba.frame();
+            player.borrow_mut().on_frame(&gba).unwrap();
+        }
+
+        // replaying into a different state desyncs at the first checksum
+        let mut gba = make_gba(live.clone());
+        movie.start(&mut gba).unwrap();
+        gba.frame();
+        let player = Rc::new(RefCell::new(MoviePlayer::new(live.clone(), movie)));
+        gba.set_input_device(player.clone());
+        gba.frame();
+        assert_eq!(player.borrow_mut().on_frame(&gba), Ok(()));
+        gba.frame();
+        match player.borrow_mut().on_frame(&gba) {
+            Err(MovieDesync::RamChecksum { frame: 2, .. }) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+        gba.key_poll();
+        gba.frame();
+        let result = player.borrow_mut().on_frame(&gba);
+        match result {
+            Err(MovieDesync::FrameCount {
+                expected: 3,
+                found: 4,
+            }) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,158 @@
+//! Game Pak prefetch buffer.
+//!
+//! While the cpu isn't using the gamepak bus, the gamepak keeps reading the halfwords that follow
+//! the last opcode fetch into a buffer, and sequential opcode fetches from the buffer take a single
+//! cycle. Data accesses to the gamepak and dma transfers flush the buffer.
+use std::cmp;
+
+use serde::{Deserialize, Serialize};
+
+use super::sysbus::MemoryAccessWidth;
+use super::Addr;
+
+/// The buffer holds up to 8 halfwords
+const PREFETCH_CAPACITY: usize = 8;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct GamepakPrefetch {
+    /// Whether the emulator models the buffer at all
+    emulated: bool,
+    /// The prefetch bit of WAITCNT
+    enabled: bool,
+    /// Address of the oldest buffered halfword, None when nothing is being prefetched
+    head: Option<Addr>,
+    count: usize,
+    /// Cycles until the halfword that is being read right now is ready
+    countdown: usize,
+    /// Scheduler time at which the cpu last released the gamepak bus
+    last_access_end: u64,
+}
+
+impl Default for GamepakPrefetch {
+    fn default() -> GamepakPrefetch {
+        GamepakPrefetch {
+            emulated: true,
+            enabled: false,
+            head: None,
+            count: 0,
+            countdown: 0,
+            last_access_end: 0,
+        }
+    }
+}
+
+impl GamepakPrefetch {
+    pub fn new() -> GamepakPrefetch {
+        GamepakPrefetch::default()
+    }
+
+    pub fn set_emulated(&mut self, emulated: bool) {
+        if self.emulated != emulated {
+            self.emulated = emulated;
+            self.flush();
+        }
+    }
+
+    pub fn is_emulated(&self) -> bool {
+        self.emulated
+    }
+
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+        if !enabled {
+            self.flush();
+        }
+    }
+
+    #[inline]
+    pub fn is_active(&self) -> bool {
+        self.emulated && self.enabled
+    }
+
+    /// Drops the buffered halfwords, e.g when a data access or the dma takes over the gamepak bus
+    pub fn flush(&mut self) {
+        self.head = None;
+        self.count = 0;
+    }
+
+    /// Returns the cycles an opcode fetch from the gamepak at scheduler time `now` takes.
+    /// `cycles` is the cost of the access without the buffer, and `s_cycles16` the time it takes
+    /// to prefetch a single halfword.
+    pub fn access(
+        &mut self,
+        now: u64,
+        addr: Addr,
+        width: MemoryAccessWidth,
+        cycles: usize,
+        s_cycles16: usize,
+    ) -> usize {
+        // the prefetcher had the bus for itself since the last access
+        let idle = now.saturating_sub(self.last_access_end);
+        self.fill(cmp::min(idle, usize::MAX as u64) as usize, s_cycles16);
+
+        let halfwords = match width {
+            MemoryAccessWidth::MemoryAccess32 => 2,
+            _ => 1,
+        };
+        let cycles = if self.head == Some(addr) {
+            self.consume(halfwords, s_cycles16)
+        } else {
+            // anything else breaks the sequence, prefetching restarts behind this access
+            self.count = 0;
+            self.countdown = s_cycles16;
+            cycles
+        };
+        self.head = Some(addr.wrapping_add(2 * halfwords as u32));
+        self.last_access_end = now + cycles as u64;
+        cycles
+    }
+
+    fn fill(&mut self, mut cycles: usize, s_cycles16: usize) {
+        if self.head.is_none() {
+            return;
+        }
+        while self.count < PREFETCH_CAPACITY {
+            if cycles < self.countdown {
+                self.countdown -= cycles;
+                break;
+            }
+            cycles -= self.countdown;
+            self.count += 1;
+            self.countdown = s_cycles16;
+        }
+    }
+
+    /// Halfwords missing from the buffer have to wait for the prefetcher
+    fn consume(&mut self, halfwords: usize, s_cycles16: usize) -> usize {
+        let mut cycles = 0;
+        for _ in 0..halfwords {
+            if self.count > 0 {
+                self.count -= 1;
+            } else {
+                cycles += self.countdown;
+                self.countdown = s_cycles16;
+            }
+        }
+        cmp::max(cycles, 1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use MemoryAccessWidth::*;
+
+    #[test]
+    fn test_prefetch_fills_while_idle() {
+        let mut prefetch = GamepakPrefetch::new();
+        prefetch.set_enabled(true);
+
+        // the first fetch misses, then 2 halfwords are prefetched during 7 idle cycles
+        assert_eq!(prefetch.access(0, 0x0800_0000, MemoryAccess16, 5, 3), 5);
+        assert_eq!(prefetch.access(12, 0x0800_0002, MemoryAccess32, 6, 3), 1);
+        // the next halfword is ready 2 cycles later
+        assert_eq!(prefetch.access(13, 0x0800_0006, MemoryAccess16, 3, 3), 2);
+        // and a jump starts over
+        assert_eq!(prefetch.access(100, 0x0800_1000, MemoryAccess16, 5, 3), 5);
+    }
+}
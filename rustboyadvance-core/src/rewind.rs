@@ -0,0 +1,254 @@
+//! Rewinding of gameplay
+//!
+//! The `Rewinder` takes a save state every few frames. Only the newest snapshot is kept whole,
+//! every older snapshot is stored as a delta against the snapshot that followed it, so stepping
+//! back is a matter of restoring the newest snapshot and undoing one delta at a time.
+use std::collections::VecDeque;
+
+use super::savestate::SaveStateResult;
+use super::GameBoyAdvance;
+
+/// Number of frames between two snapshots
+pub const DEFAULT_REWIND_INTERVAL: usize = 4;
+/// Memory the snapshots may take, in bytes
+pub const DEFAULT_REWIND_BUDGET: usize = 32 * 1024 * 1024;
+
+/// Zero runs shorter than this are kept inside a literal run, since splitting costs two varints
+const MIN_ZERO_RUN: usize = 8;
+
+fn write_varint(out: &mut Vec<u8>, mut value: usize) {
+    while value >= 0x80 {
+        out.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+fn read_varint(bytes: &[u8], pos: &mut usize) -> usize {
+    let mut value = 0;
+    let mut shift = 0;
+    loop {
+        let byte = bytes[*pos];
+        *pos += 1;
+        value |= ((byte & 0x7f) as usize) << shift;
+        if byte & 0x80 == 0 {
+            return value;
+        }
+        shift += 7;
+    }
+}
+
+#[inline]
+fn byte_at(bytes: &[u8], index: usize) -> u8 {
+    bytes.get(index).cloned().unwrap_or(0)
+}
+
+/// Encodes `target` relative to `base` as runs of (zero bytes to skip, xor'ed literal bytes)
+fn encode_delta(base: &[u8], target: &[u8]) -> Vec<u8> {
+    let len = base.len().max(target.len());
+    let mut delta = Vec::new();
+    write_varint(&mut delta, target.len());
+
+    let mut pos = 0;
+    while pos < len {
+        let run_start = pos;
+        while pos < len && byte_at(base, pos) == byte_at(target, pos) {
+            pos += 1;
+        }
+        if pos == len {
+            break;
+        }
+        let literal_start = pos;
+        let mut zeros = 0;
+        while pos < len && zeros < MIN_ZERO_RUN {
+            if byte_at(base, pos) == byte_at(target, pos) {
+                zeros += 1;
+            } else {
+                zeros = 0;
+            }
+            pos += 1;
+        }
+        pos -= zeros;
+
+        write_varint(&mut delta, literal_start - run_start);
+        write_varint(&mut delta, pos - literal_start);
+        for i in literal_start..pos {
+            delta.push(byte_at(base, i) ^ byte_at(target, i));
+        }
+    }
+    delta
+}
+
+fn apply_delta(base: &[u8], delta: &[u8]) -> Vec<u8> {
+    let mut pos = 0;
+    let target_len = read_varint(delta, &mut pos);
+
+    let mut result = base.to_vec();
+    result.resize(base.len().max(target_len), 0);
+    let mut offset = 0;
+    while pos < delta.len() {
+        offset += read_varint(delta, &mut pos);
+        let literal_len = read_varint(delta, &mut pos);
+        for (dst, src) in result[offset..offset + literal_len]
+            .iter_mut()
+            .zip(&delta[pos..pos + literal_len])
+        {
+            *dst ^= src;
+        }
+        offset += literal_len;
+        pos += literal_len;
+    }
+    result.truncate(target_len);
+    result
+}
+
+pub struct Rewinder {
+    interval: usize,
+    budget: usize,
+    frames_since_snapshot: usize,
+    /// The newest snapshot
+    current: Option<Vec<u8>>,
+    /// Deltas that turn a snapshot into the one before it, the oldest first
+    deltas: VecDeque<Vec<u8>>,
+    deltas_size: usize,
+}
+
+impl Default for Rewinder {
+    fn default() -> Rewinder {
+        Rewinder::new(DEFAULT_REWIND_INTERVAL, DEFAULT_REWIND_BUDGET)
+    }
+}
+
+impl Rewinder {
+    pub fn new(interval: usize, budget: usize) -> Rewinder {
+        Rewinder {
+            interval: interval.max(1),
+            budget: budget,
+            frames_since_snapshot: 0,
+            current: None,
+            deltas: VecDeque::new(),
+            deltas_size: 0,
+        }
+    }
+
+    /// Forgets all the snapshots, e.g when another game is loaded
+    pub fn clear(&mut self) {
+        self.frames_since_snapshot = 0;
+        self.current = None;
+        self.deltas.clear();
+        self.deltas_size = 0;
+    }
+
+    /// Number of steps that can be rewound
+    pub fn len(&self) -> usize {
+        self.deltas.len() + self.current.is_some() as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.current.is_none()
+    }
+
+    /// Memory taken by the snapshots, in bytes
+    pub fn memory_usage(&self) -> usize {
+        self.current.as_ref().map_or(0, |s| s.len()) + self.deltas_size
+    }
+
+    /// Must be called after every emulated frame
+    pub fn on_frame(&mut self, gba: &GameBoyAdvance) -> SaveStateResult<()> {
+        self.frames_since_snapshot += 1;
+        if self.current.is_some() && self.frames_since_snapshot < self.interval {
+            return Ok(());
+        }
+        self.frames_since_snapshot = 0;
+
+        let snapshot = gba.save_state()?;
+        if let Some(previous) = self.current.take() {
+            let delta = encode_delta(&snapshot, &previous);
+            self.deltas_size += delta.len();
+            self.deltas.push_back(delta);
+        }
+        self.current = Some(snapshot);
+
+        while self.memory_usage() > self.budget {
+            match self.deltas.pop_front() {
+                Some(delta) => self.deltas_size -= delta.len(),
+                None => break,
+            }
+        }
+        Ok(())
+    }
+
+    /// Restores the previous snapshot.
+    /// Returns false if there was nothing left to rewind to.
+    pub fn rewind_one_step(&mut self, gba: &mut GameBoyAdvance) -> SaveStateResult<bool> {
+        let mut rewound = self.frames_since_snapshot > 0;
+        if !rewound {
+            if let Some(delta) = self.deltas.pop_back() {
+                self.deltas_size -= delta.len();
+                let current = self.current.as_ref().unwrap();
+                self.current = Some(apply_delta(current, &delta));
+                rewound = true;
+            }
+        }
+        self.frames_since_snapshot = 0;
+        if let Some(current) = &self.current {
+            gba.restore_state(current)?;
+        }
+        Ok(rewound && self.current.is_some())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::bios::builtin_bios;
+    use crate::gba::tests::{make_mock_gba_with_bios, make_rom};
+
+    #[test]
+    fn test_delta_roundtrip() {
+        let base: Vec<u8> = (0..1000).map(|i| (i % 251) as u8).collect();
+        let mut target = base.clone();
+        target[3] ^= 0xff;
+        target[500..520].iter_mut().for_each(|b| *b = 0);
+        target.extend_from_slice(&[1, 2, 3]);
+
+        let delta = encode_delta(&base, &target);
+        assert!(delta.len() < 64);
+        assert_eq!(apply_delta(&base, &delta), target);
+        assert_eq!(apply_delta(&target, &encode_delta(&target, &base)), base);
+        assert_eq!(encode_delta(&base, &base).len(), 2);
+    }
+
+    #[test]
+    fn test_rewind() {
+        // loop forever incrementing r0
+        let rom = make_rom(&[0xE280_0001, 0xEAFF_FFFD]);
+        let mut gba = make_mock_gba_with_bios(builtin_bios(), &rom);
+        gba.skip_bios();
+
+        let mut rewinder = Rewinder::new(2, DEFAULT_REWIND_BUDGET);
+        let mut history = vec![];
+        for _ in 0..10 {
+            gba.frame();
+            rewinder.on_frame(&gba).unwrap();
+            history.push(gba.cpu.get_reg(0));
+        }
+        assert_eq!(rewinder.len(), 5);
+
+        // snapshots were taken after frames 1, 3, 5, 7 and 9
+        for &frame in [9, 7, 5, 3, 1].iter() {
+            assert!(rewinder.rewind_one_step(&mut gba).unwrap());
+            assert_eq!(gba.cpu.get_reg(0), history[frame - 1]);
+        }
+        assert!(!rewinder.rewind_one_step(&mut gba).unwrap());
+        assert_eq!(gba.cpu.get_reg(0), history[0]);
+
+        let mut rewinder = Rewinder::new(1, 0);
+        for _ in 0..3 {
+            gba.frame();
+            rewinder.on_frame(&gba).unwrap();
+        }
+        assert_eq!(rewinder.len(), 1);
+    }
+}
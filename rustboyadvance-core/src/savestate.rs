@@ -0,0 +1,343 @@
+//! Framing of save states
+//!
+//! A save state file is laid out as follows:
+//!   magic           8 bytes  "RBASTATE"
+//!   format version  u32 LE
+//!   info length     u32 LE
+//!   info            bincode encoded `SaveStateInfo`
+//!   payload         bincode encoded emulator state, in the layout of the format version
+//!
+//! The layout of `SaveStateInfo` must stay the same across format versions,
+//! so older states can always be identified before migrating their payload.
+use std::error::Error;
+use std::fmt;
+
+use bincode;
+use byteorder::{ByteOrder, LittleEndian};
+use serde::{Deserialize, Serialize};
+
+pub const SAVE_STATE_MAGIC: [u8; 8] = *b"RBASTATE";
+
+/// Bump this whenever the layout of the emulator state changes, and add a migration
+pub const SAVE_STATE_VERSION: u32 = 1;
+
+pub const EMULATOR_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+const FRAME_HEADER_SIZE: usize = 16;
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct SaveStateInfo {
+    pub emulator_version: String,
+    pub game_title: String,
+    pub game_code: String,
+    /// sha256 of the rom
+    pub rom_digest: [u8; 32],
+    /// Seconds since the unix epoch
+    pub timestamp: u64,
+    /// A copy of the frame buffer at the time of saving
+    pub thumbnail: Option<Vec<u32>>,
+}
+
+#[derive(Debug)]
+pub enum SaveStateError {
+    /// Not a save state, or one from before states were framed
+    InvalidMagic,
+    UnsupportedVersion(u32),
+    /// The state was created by a newer version of the emulator
+    NewerEmulatorVersion(String),
+    GameMismatch {
+        expected: String,
+        found: String,
+    },
+    /// Same game code, but a different rom (e.g another revision or a hack)
+    RomMismatch,
+    Corrupted(String),
+}
+
+impl fmt::Display for SaveStateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SaveStateError::InvalidMagic => write!(f, "not a save state"),
+            SaveStateError::UnsupportedVersion(version) => {
+                write!(f, "unsupported save state format version {}", version)
+            }
+            SaveStateError::NewerEmulatorVersion(version) => write!(
+                f,
+                "save state was created by a newer emulator version ({} > {})",
+                version, EMULATOR_VERSION
+            ),
+            SaveStateError::GameMismatch { expected, found } => write!(
+                f,
+                "save state belongs to game {}, but {} is loaded",
+                found, expected
+            ),
+            SaveStateError::RomMismatch => {
+                write!(
+                    f,
+                    "save state was created with a different rom of this game"
+                )
+            }
+            SaveStateError::Corrupted(reason) => write!(f, "corrupted save state: {}", reason),
+        }
+    }
+}
+
+impl Error for SaveStateError {}
+
+impl From<bincode::Error> for SaveStateError {
+    fn from(err: bincode::Error) -> SaveStateError {
+        SaveStateError::Corrupted(err.to_string())
+    }
+}
+
+pub type SaveStateResult<T> = Result<T, SaveStateError>;
+
+/// Upgrades a payload to the next format version
+pub type Migration = fn(&SaveStateInfo, Vec<u8>) -> SaveStateResult<Vec<u8>>;
+
+/// Migrations by the format version they upgrade from.
+/// Versions that are older than the first entry can't be loaded anymore.
+const MIGRATIONS: &[(u32, Migration)] = &[];
+
+/// Version 1 is the first framed format, earlier states have no magic
+const OLDEST_SUPPORTED_VERSION: u32 = 1;
+
+pub(crate) fn current_timestamp() -> u64 {
+    #[cfg(not(target_arch = "wasm32"))]
+    {
+        use std::time::{SystemTime, UNIX_EPOCH};
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0)
+    }
+    #[cfg(target_arch = "wasm32")]
+    {
+        0
+    }
+}
+
+pub(crate) fn encode(info: &SaveStateInfo, payload: &[u8]) -> SaveStateResult<Vec<u8>> {
+    let info = bincode::serialize(info)?;
+    let mut bytes = vec![0; FRAME_HEADER_SIZE];
+    bytes[0..8].copy_from_slice(&SAVE_STATE_MAGIC);
+    LittleEndian::write_u32(&mut bytes[8..12], SAVE_STATE_VERSION);
+    LittleEndian::write_u32(&mut bytes[12..16], info.len() as u32);
+    bytes.extend_from_slice(&info);
+    bytes.extend_from_slice(payload);
+    Ok(bytes)
+}
+
+fn parse_frame<'a>(
+    bytes: &'a [u8],
+    migrations: &[(u32, Migration)],
+) -> SaveStateResult<(u32, SaveStateInfo, &'a [u8])> {
+    if bytes.len() < FRAME_HEADER_SIZE || bytes[0..8] != SAVE_STATE_MAGIC {
+        return Err(SaveStateError::InvalidMagic);
+    }
+    let version = LittleEndian::read_u32(&bytes[8..12]);
+    let oldest_version = migrations
+        .first()
+        .map_or(OLDEST_SUPPORTED_VERSION, |(from, _)| *from);
+    if version < oldest_version || version > SAVE_STATE_VERSION {
+        return Err(SaveStateError::UnsupportedVersion(version));
+    }
+    let info_len = LittleEndian::read_u32(&bytes[12..16]) as usize;
+    let payload_start = match FRAME_HEADER_SIZE.checked_add(info_len) {
+        Some(start) if start <= bytes.len() => start,
+        _ => return Err(SaveStateError::Corrupted("truncated header".to_string())),
+    };
+    let info: SaveStateInfo = bincode::deserialize(&bytes[FRAME_HEADER_SIZE..payload_start])?;
+    Ok((version, info, &bytes[payload_start..]))
+}
+
+fn parse_version(version: &str) -> Vec<u32> {
+    version
+        .split('.')
+        .map(|part| part.parse().unwrap_or(0))
+        .collect()
+}
+
+/// Reads the info of a save state without loading it, e.g to show its thumbnail
+pub fn read_info(bytes: &[u8]) -> SaveStateResult<SaveStateInfo> {
+    parse_frame(bytes, MIGRATIONS).map(|(_, info, _)| info)
+}
+
+/// Verifies the frame and migrates the payload to the current format version
+pub(crate) fn decode(bytes: &[u8]) -> SaveStateResult<(SaveStateInfo, Vec<u8>)> {
+    decode_with(bytes, MIGRATIONS)
+}
+
+fn decode_with(
+    bytes: &[u8],
+    migrations: &[(u32, Migration)],
+) -> SaveStateResult<(SaveStateInfo, Vec<u8>)> {
+    let (version, info, payload) = parse_frame(bytes, migrations)?;
+    if parse_version(&info.emulator_version) > parse_version(EMULATOR_VERSION) {
+        return Err(SaveStateError::NewerEmulatorVersion(
+            info.emulator_version.clone(),
+        ));
+    }
+    let mut payload = payload.to_vec();
+    for (_, migration) in migrations.iter().filter(|(from, _)| *from >= version) {
+        payload = migration(&info, payload)?;
+    }
+    Ok((info, payload))
+}
+
+/// Checks that the state was created with the loaded rom
+pub(crate) fn verify_rom(
+    info: &SaveStateInfo,
+    game_code: &str,
+    rom_digest: &[u8; 32],
+) -> SaveStateResult<()> {
+    if info.game_code != game_code {
+        return Err(SaveStateError::GameMismatch {
+            expected: game_code.to_string(),
+            found: info.game_code.clone(),
+        });
+    }
+    if &info.rom_digest != rom_digest {
+        return Err(SaveStateError::RomMismatch);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use crate::bios::builtin_bios;
+    use crate::gba::tests::{make_mock_gba_with_bios, make_rom, DummyInterface};
+    use crate::GameBoyAdvance;
+
+    /// An idle rom whose header holds `game_code` and `revision`
+    fn make_game_rom(game_code: &[u8; 4], revision: u8) -> Vec<u8> {
+        // b .
+        let mut rom = make_rom(&[0xEAFF_FFFE]);
+        rom[0xac..0xb0].copy_from_slice(game_code);
+        rom[0xbc] = revision;
+        rom
+    }
+
+    #[test]
+    fn test_save_state_roundtrip() {
+        let mut gba = make_mock_gba_with_bios(builtin_bios(), &make_game_rom(b"ABCE", 0));
+        gba.frame();
+        let state = gba.save_state_with_thumbnail().unwrap();
+
+        let info = read_info(&state).unwrap();
+        assert_eq!(info.game_code, "ABCE");
+        assert_eq!(info.emulator_version, EMULATOR_VERSION);
+        assert_eq!(info.thumbnail.as_ref().unwrap().len(), 240 * 160);
+
+        let pc = gba.cpu.get_next_pc();
+        gba.frame();
+        gba.restore_state(&state).unwrap();
+        assert_eq!(gba.cpu.get_next_pc(), pc);
+        assert_eq!(
+            gba.sysbus.cartridge.get_rom_bytes(),
+            &make_game_rom(b"ABCE", 0)[..]
+        );
+
+        let dummy = Rc::new(RefCell::new(DummyInterface::new()));
+        let restored = GameBoyAdvance::from_saved_state(
+            &state,
+            builtin_bios(),
+            make_game_rom(b"ABCE", 0).into_boxed_slice(),
+            dummy.clone(),
+            dummy.clone(),
+            dummy.clone(),
+        )
+        .unwrap();
+        assert_eq!(restored.cpu.get_next_pc(), pc);
+        // the bios is not part of the state
+        let bios = builtin_bios();
+        assert!(!state.windows(bios.len()).any(|w| w == &*bios));
+        match GameBoyAdvance::from_saved_state(
+            &state,
+            builtin_bios(),
+            make_game_rom(b"ABCE", 1).into_boxed_slice(),
+            dummy.clone(),
+            dummy.clone(),
+            dummy.clone(),
+        ) {
+            Err(SaveStateError::RomMismatch) => {}
+            _ => panic!("restored a state with the wrong rom"),
+        }
+    }
+
+    #[test]
+    fn test_save_state_mismatch() {
+        let state = make_mock_gba_with_bios(builtin_bios(), &make_game_rom(b"ABCE", 0))
+            .save_state()
+            .unwrap();
+
+        match make_mock_gba_with_bios(builtin_bios(), &make_game_rom(b"XYZE", 0))
+            .restore_state(&state)
+        {
+            Err(SaveStateError::GameMismatch { expected, found }) => {
+                assert_eq!(expected, "XYZE");
+                assert_eq!(found, "ABCE");
+            }
+            other => panic!("unexpected result {:?}", other),
+        }
+        match make_mock_gba_with_bios(builtin_bios(), &make_game_rom(b"ABCE", 1))
+            .restore_state(&state)
+        {
+            Err(SaveStateError::RomMismatch) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+
+        let mut gba = make_mock_gba_with_bios(builtin_bios(), &make_game_rom(b"ABCE", 0));
+        match gba.restore_state(&state[8..]) {
+            Err(SaveStateError::InvalidMagic) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+        let mut newer = state.clone();
+        LittleEndian::write_u32(&mut newer[8..12], SAVE_STATE_VERSION + 1);
+        match gba.restore_state(&newer) {
+            Err(SaveStateError::UnsupportedVersion(v)) => assert_eq!(v, SAVE_STATE_VERSION + 1),
+            other => panic!("unexpected result {:?}", other),
+        }
+        let mut truncated = state.clone();
+        LittleEndian::write_u32(&mut truncated[12..16], u32::max_value());
+        match gba.restore_state(&truncated) {
+            Err(SaveStateError::Corrupted(_)) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_save_state_migration() {
+        // a made up version 0, whose payload started with a frame overshoot counter
+        fn drop_overshoot(_: &SaveStateInfo, payload: Vec<u8>) -> SaveStateResult<Vec<u8>> {
+            match payload.get(8..) {
+                Some(rest) => Ok(rest.to_vec()),
+                None => Err(SaveStateError::Corrupted("truncated payload".to_string())),
+            }
+        }
+        let migrations: &[(u32, Migration)] = &[(0, drop_overshoot)];
+
+        let gba = make_mock_gba_with_bios(builtin_bios(), &make_game_rom(b"ABCE", 0));
+        let state = gba.save_state().unwrap();
+        let (info, payload) = decode(&state).unwrap();
+        let mut v0_payload = bincode::serialize(&123u64).unwrap();
+        v0_payload.extend_from_slice(&payload);
+        let mut v0_state = encode(&info, &v0_payload).unwrap();
+        LittleEndian::write_u32(&mut v0_state[8..12], 0);
+
+        let (migrated_info, migrated) = decode_with(&v0_state, migrations).unwrap();
+        assert_eq!(migrated_info, info);
+        assert_eq!(migrated, payload);
+        // current states skip the migration
+        assert_eq!(decode_with(&state, migrations).unwrap().1, payload);
+        // without a migration from version 0 it is rejected
+        match decode(&v0_state) {
+            Err(SaveStateError::UnsupportedVersion(0)) => {}
+            other => panic!("unexpected result {:?}", other.map(|(info, _)| info)),
+        }
+    }
+}
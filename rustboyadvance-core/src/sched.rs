@@ -0,0 +1,140 @@
+//! Event scheduler, the hardware is driven by timestamped events instead of polling every device
+use serde::{Deserialize, Serialize};
+
+use super::gpu::GpuState;
+
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
+pub enum EventType {
+    /// The gpu finished the given state
+    Gpu(GpuState),
+    TimerOverflow(usize),
+    /// A dma channel was triggered, and takes over the bus now
+    DmaActivateChannel(usize),
+    /// Time to mix a new audio sample
+    SoundSample,
+    SerialTransferComplete,
+    /// Lets the serial controller check for transfers driven by the other side of the cable
+    SerialPoll,
+}
+
+#[derive(Serialize, Deserialize, Debug, Copy, Clone)]
+pub struct Event {
+    pub typ: EventType,
+    pub time: u64,
+}
+
+/// Events are kept sorted by time, there are only a handful pending at once
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Scheduler {
+    /// Cycles since power on
+    timestamp: u64,
+    events: Vec<Event>,
+}
+
+impl Scheduler {
+    pub fn new() -> Scheduler {
+        Scheduler::default()
+    }
+
+    #[inline]
+    pub fn timestamp(&self) -> u64 {
+        self.timestamp
+    }
+
+    /// Schedules an event `cycles` from now
+    pub fn schedule(&mut self, typ: EventType, cycles: usize) {
+        self.schedule_at(typ, self.timestamp + cycles as u64);
+    }
+
+    /// Events due at the same time are handled in the order they were scheduled
+    pub fn schedule_at(&mut self, typ: EventType, time: u64) {
+        let index = self
+            .events
+            .iter()
+            .position(|event| event.time > time)
+            .unwrap_or(self.events.len());
+        self.events.insert(index, Event { typ, time });
+    }
+
+    /// Removes every pending event of the given type
+    pub fn cancel(&mut self, typ: EventType) {
+        self.events.retain(|event| event.typ != typ);
+    }
+
+    pub fn is_scheduled(&self, typ: EventType) -> bool {
+        self.events.iter().any(|event| event.typ == typ)
+    }
+
+    #[inline]
+    pub fn update(&mut self, cycles: usize) {
+        self.timestamp += cycles as u64;
+    }
+
+    /// Timestamp of the closest event, if there is one
+    #[inline]
+    pub fn next_event_time(&self) -> Option<u64> {
+        self.events.first().map(|event| event.time)
+    }
+
+    #[inline]
+    pub fn has_pending_events(&self) -> bool {
+        self.next_event_time()
+            .map_or(false, |time| time <= self.timestamp)
+    }
+
+    /// Jumps right to the closest event, e.g while the cpu is halted
+    pub fn skip_to_next_event(&mut self) {
+        if let Some(time) = self.next_event_time() {
+            if time > self.timestamp {
+                self.timestamp = time;
+            }
+        }
+    }
+
+    /// Removes the closest event if it is due
+    pub fn pop_pending_event(&mut self) -> Option<Event> {
+        if self.has_pending_events() {
+            Some(self.events.remove(0))
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_events_are_ordered() {
+        let mut sched = Scheduler::new();
+        sched.schedule(EventType::TimerOverflow(0), 100);
+        sched.schedule(EventType::SoundSample, 50);
+        sched.schedule(EventType::TimerOverflow(1), 100);
+        assert_eq!(sched.next_event_time(), Some(50));
+        assert!(sched.pop_pending_event().is_none());
+
+        sched.update(100);
+        let order: Vec<EventType> = std::iter::from_fn(|| sched.pop_pending_event())
+            .map(|event| event.typ)
+            .collect();
+        assert_eq!(
+            order,
+            vec![
+                EventType::SoundSample,
+                EventType::TimerOverflow(0),
+                EventType::TimerOverflow(1)
+            ]
+        );
+
+        sched.schedule(EventType::SerialPoll, 10);
+        sched.schedule(EventType::SoundSample, 20);
+        sched.cancel(EventType::SerialPoll);
+        sched.skip_to_next_event();
+        assert_eq!(sched.timestamp(), 120);
+        assert_eq!(
+            sched.pop_pending_event().unwrap().typ,
+            EventType::SoundSample
+        );
+    }
+}
@@ -0,0 +1,842 @@
+//! Lua scripting, for bots and research tools
+//!
+//! Scripts talk to the emulator through a few global tables:
+//!   emu       on_frame(fn), on_exec(addr, fn), on_read(addr, fn [, len]), on_write(addr, fn [, len])
+//!             register callbacks and return an id for remove_hook(id), frame_count()
+//!   memory    read8/16/32(addr), write8/16/32(addr, value), through the bus like the cpu
+//!   cpu       get_reg(r), set_reg(r, value), cpsr(). r15 is the address of the next instruction
+//!   joypad    set(keys) holds a mask of the key constants (joypad.A, joypad.START, ...) down
+//!             until release(), get() returns the keys the game sees
+//!   gui       pixel(x, y, color), rect(x, y, w, h, color), fill(x, y, w, h, color), clear()
+//!   savestate save() returns the state as a string, load(state)
+//!
+//! Frame callbacks run at the end of every frame, exec callbacks get the address before the
+//! instruction there executes, and memory callbacks get the address, value and width of every
+//! cpu or dma access that touches the watched bytes. Accesses made by scripts don't trigger them.
+//!
+//! Gui colors are 0xAARRGGBB, an alpha of 0 being transparent. What scripts draw is blended onto
+//! the frame buffer at the end of the frame and cleared before the next one.
+use std::cell::RefCell;
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::path::Path;
+use std::rc::Rc;
+
+use mlua::{Function, IntoLuaMulti, Lua, RegistryKey, Table};
+
+use super::arm7tdmi::CpuState;
+use super::gpu::{DISPLAY_HEIGHT, DISPLAY_WIDTH};
+use super::iodev::HaltState;
+use super::keypad::{Keys, KEYINPUT_ALL_RELEASED};
+use super::sysbus::{WatchKind, Watchpoint};
+use super::{Addr, Bus, GameBoyAdvance};
+
+#[derive(Debug)]
+pub enum ScriptError {
+    IO(io::Error),
+    Lua(mlua::Error),
+}
+
+impl fmt::Display for ScriptError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ScriptError::IO(err) => write!(f, "failed to read script: {}", err),
+            ScriptError::Lua(err) => write!(f, "script error: {}", err),
+        }
+    }
+}
+
+impl Error for ScriptError {}
+
+impl From<io::Error> for ScriptError {
+    fn from(err: io::Error) -> ScriptError {
+        ScriptError::IO(err)
+    }
+}
+
+impl From<mlua::Error> for ScriptError {
+    fn from(err: mlua::Error) -> ScriptError {
+        ScriptError::Lua(err)
+    }
+}
+
+pub type ScriptResult<T> = Result<T, ScriptError>;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum HookKind {
+    Frame,
+    Exec(Addr),
+    Read(Addr, u32),
+    Write(Addr, u32),
+}
+
+impl HookKind {
+    fn watches(&self, addr: Addr, len: u32) -> bool {
+        let (start, end) = (addr as u64, addr as u64 + len as u64);
+        match *self {
+            HookKind::Read(watch_addr, watch_len) | HookKind::Write(watch_addr, watch_len) => {
+                start < watch_addr as u64 + watch_len as u64 && (watch_addr as u64) < end
+            }
+            _ => false,
+        }
+    }
+}
+
+struct Hook {
+    id: u32,
+    kind: HookKind,
+    callback: RegistryKey,
+}
+
+/// What the lua side of the engine shares with it
+struct ScriptState {
+    hooks: Vec<Hook>,
+    next_hook_id: u32,
+    /// The sysbus watchpoints of the memory hooks, the others belong to someone else
+    watches: Vec<Watchpoint>,
+    /// Set when the memory hooks changed, so the sysbus watches have to follow
+    watches_dirty: bool,
+    /// Keys held down by the script
+    joypad: Option<u16>,
+    overlay: Vec<u32>,
+    overlay_drawn: bool,
+    frame_count: u64,
+}
+
+impl ScriptState {
+    fn add_hook(&mut self, lua: &Lua, kind: HookKind, callback: Function) -> mlua::Result<u32> {
+        let callback = lua.create_registry_value(callback)?;
+        self.next_hook_id += 1;
+        self.hooks.push(Hook {
+            id: self.next_hook_id,
+            kind,
+            callback,
+        });
+        self.watches_dirty = true;
+        Ok(self.next_hook_id)
+    }
+
+    fn remove_hook(&mut self, lua: &Lua, id: u32) -> mlua::Result<bool> {
+        match self.hooks.iter().position(|hook| hook.id == id) {
+            Some(index) => {
+                let hook = self.hooks.remove(index);
+                lua.remove_registry_value(hook.callback)?;
+                self.watches_dirty = true;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    fn has_exec_hook(&self, pc: Addr) -> bool {
+        self.hooks
+            .iter()
+            .any(|hook| hook.kind == HookKind::Exec(pc))
+    }
+
+    /// Exec and memory hooks need the emulator to stop after every instruction
+    fn needs_stepping(&self) -> bool {
+        self.hooks.iter().any(|hook| hook.kind != HookKind::Frame)
+    }
+
+    fn draw_pixel(&mut self, x: i64, y: i64, color: u32) {
+        if x >= 0 && y >= 0 && (x as usize) < DISPLAY_WIDTH && (y as usize) < DISPLAY_HEIGHT {
+            self.overlay[y as usize * DISPLAY_WIDTH + x as usize] = color;
+            self.overlay_drawn = true;
+        }
+    }
+
+    fn fill(&mut self, x: i64, y: i64, w: i64, h: i64, color: u32) {
+        for y in y.max(0)..(y + h).min(DISPLAY_HEIGHT as i64) {
+            for x in x.max(0)..(x + w).min(DISPLAY_WIDTH as i64) {
+                self.draw_pixel(x, y, color);
+            }
+        }
+    }
+
+    fn rect(&mut self, x: i64, y: i64, w: i64, h: i64, color: u32) {
+        if w <= 0 || h <= 0 {
+            return;
+        }
+        self.fill(x, y, w, 1, color);
+        self.fill(x, y + h - 1, w, 1, color);
+        self.fill(x, y, 1, h, color);
+        self.fill(x + w - 1, y, 1, h, color);
+    }
+
+    fn clear_overlay(&mut self) {
+        if self.overlay_drawn {
+            self.overlay.iter_mut().for_each(|color| *color = 0);
+            self.overlay_drawn = false;
+        }
+    }
+}
+
+/// Blends an 0xAARRGGBB color over an 0x00RRGGBB pixel
+fn blend(pixel: u32, color: u32) -> u32 {
+    let alpha = color >> 24;
+    match alpha {
+        0 => pixel,
+        0xff => color & 0xff_ffff,
+        _ => (0..3).fold(0, |result, channel| {
+            let shift = channel * 8;
+            let src = (color >> shift) & 0xff;
+            let dst = (pixel >> shift) & 0xff;
+            result | (((src * alpha + dst * (0xff - alpha)) / 0xff) << shift)
+        }),
+    }
+}
+
+fn runtime_error<E: fmt::Display>(err: E) -> mlua::Error {
+    mlua::Error::RuntimeError(err.to_string())
+}
+
+fn check_register(r: usize) -> mlua::Result<usize> {
+    if r <= 15 {
+        Ok(r)
+    } else {
+        Err(runtime_error(format!("invalid register r{}", r)))
+    }
+}
+
+/// Runs the emulator on behalf of the scripts it loaded, use `run_frame` instead of
+/// `GameBoyAdvance::frame`.
+pub struct ScriptEngine {
+    lua: Lua,
+    state: Rc<RefCell<ScriptState>>,
+}
+
+impl ScriptEngine {
+    pub fn new() -> ScriptResult<ScriptEngine> {
+        let lua = Lua::new();
+        let state = Rc::new(RefCell::new(ScriptState {
+            hooks: Vec::new(),
+            next_hook_id: 0,
+            watches: Vec::new(),
+            watches_dirty: false,
+            joypad: None,
+            overlay: vec![0; DISPLAY_WIDTH * DISPLAY_HEIGHT],
+            overlay_drawn: false,
+            frame_count: 0,
+        }));
+        ScriptEngine::register_api(&lua, &state)?;
+
+        Ok(ScriptEngine { lua, state })
+    }
+
+    /// Creates the globals that don't need the emulator, the rest are bound by `with_api`
+    fn register_api(lua: &Lua, state: &Rc<RefCell<ScriptState>>) -> mlua::Result<()> {
+        let globals = lua.globals();
+
+        let emu = lua.create_table()?;
+        let s = state.clone();
+        emu.set(
+            "on_frame",
+            lua.create_function(move |lua, callback: Function| {
+                s.borrow_mut().add_hook(lua, HookKind::Frame, callback)
+            })?,
+        )?;
+        let s = state.clone();
+        emu.set(
+            "on_exec",
+            lua.create_function(move |lua, (addr, callback): (Addr, Function)| {
+                s.borrow_mut().add_hook(lua, HookKind::Exec(addr), callback)
+            })?,
+        )?;
+        let s = state.clone();
+        emu.set(
+            "on_read",
+            lua.create_function(
+                move |lua, (addr, callback, len): (Addr, Function, Option<u32>)| {
+                    let kind = HookKind::Read(addr, len.unwrap_or(1));
+                    s.borrow_mut().add_hook(lua, kind, callback)
+                },
+            )?,
+        )?;
+        let s = state.clone();
+        emu.set(
+            "on_write",
+            lua.create_function(
+                move |lua, (addr, callback, len): (Addr, Function, Option<u32>)| {
+                    let kind = HookKind::Write(addr, len.unwrap_or(1));
+                    s.borrow_mut().add_hook(lua, kind, callback)
+                },
+            )?,
+        )?;
+        let s = state.clone();
+        emu.set(
+            "remove_hook",
+            lua.create_function(move |lua, id: u32| s.borrow_mut().remove_hook(lua, id))?,
+        )?;
+        let s = state.clone();
+        emu.set(
+            "frame_count",
+            lua.create_function(move |_, ()| Ok(s.borrow().frame_count))?,
+        )?;
+        globals.set("emu", emu)?;
+
+        let joypad = lua.create_table()?;
+        for (name, key) in &[
+            ("A", Keys::ButtonA as usize),
+            ("B", Keys::ButtonB as usize),
+            ("SELECT", Keys::Select as usize),
+            ("START", Keys::Start as usize),
+            ("RIGHT", Keys::Right as usize),
+            ("LEFT", Keys::Left as usize),
+            ("UP", Keys::Up as usize),
+            ("DOWN", Keys::Down as usize),
+            ("R", Keys::ButtonR as usize),
+            ("L", Keys::ButtonL as usize),
+        ] {
+            joypad.set(*name, 1u16 << *key)?;
+        }
+        let s = state.clone();
+        joypad.set(
+            "set",
+            lua.create_function(move |_, keys: u16| {
+                s.borrow_mut().joypad = Some(keys & KEYINPUT_ALL_RELEASED);
+                Ok(())
+            })?,
+        )?;
+        let s = state.clone();
+        joypad.set(
+            "release",
+            lua.create_function(move |_, ()| {
+                s.borrow_mut().joypad = None;
+                Ok(())
+            })?,
+        )?;
+        globals.set("joypad", joypad)?;
+
+        let gui = lua.create_table()?;
+        let s = state.clone();
+        gui.set(
+            "pixel",
+            lua.create_function(move |_, (x, y, color): (i64, i64, u32)| {
+                s.borrow_mut().draw_pixel(x, y, color);
+                Ok(())
+            })?,
+        )?;
+        let s = state.clone();
+        gui.set(
+            "rect",
+            lua.create_function(move |_, (x, y, w, h, color): (i64, i64, i64, i64, u32)| {
+                s.borrow_mut().rect(x, y, w, h, color);
+                Ok(())
+            })?,
+        )?;
+        let s = state.clone();
+        gui.set(
+            "fill",
+            lua.create_function(move |_, (x, y, w, h, color): (i64, i64, i64, i64, u32)| {
+                s.borrow_mut().fill(x, y, w, h, color);
+                Ok(())
+            })?,
+        )?;
+        let s = state.clone();
+        gui.set(
+            "clear",
+            lua.create_function(move |_, ()| {
+                s.borrow_mut().clear_overlay();
+                Ok(())
+            })?,
+        )?;
+        globals.set("gui", gui)?;
+
+        globals.set("memory", lua.create_table()?)?;
+        globals.set("cpu", lua.create_table()?)?;
+        globals.set("savestate", lua.create_table()?)?;
+        Ok(())
+    }
+
+    /// Binds the functions that work on `gba` while `f` runs
+    fn with_api<R, F>(&self, gba: &mut GameBoyAdvance, f: F) -> mlua::Result<R>
+    where
+        F: FnOnce() -> mlua::Result<R>,
+    {
+        let lua = &self.lua;
+        let gba = RefCell::new(gba);
+        let gba = &gba;
+
+        lua.scope(|scope| {
+            let globals = lua.globals();
+
+            let memory: Table = globals.get("memory")?;
+            memory.set(
+                "read8",
+                scope.create_function(|_, addr: Addr| Ok(gba.borrow().sysbus.read_8(addr)))?,
+            )?;
+            memory.set(
+                "read16",
+                scope.create_function(|_, addr: Addr| Ok(gba.borrow().sysbus.read_16(addr)))?,
+            )?;
+            memory.set(
+                "read32",
+                scope.create_function(|_, addr: Addr| Ok(gba.borrow().sysbus.read_32(addr)))?,
+            )?;
+            memory.set(
+                "write8",
+                scope.create_function(|_, (addr, value): (Addr, u8)| {
+                    gba.borrow_mut().sysbus.write_8(addr, value);
+                    Ok(())
+                })?,
+            )?;
+            memory.set(
+                "write16",
+                scope.create_function(|_, (addr, value): (Addr, u16)| {
+                    gba.borrow_mut().sysbus.write_16(addr, value);
+                    Ok(())
+                })?,
+            )?;
+            memory.set(
+                "write32",
+                scope.create_function(|_, (addr, value): (Addr, u32)| {
+                    gba.borrow_mut().sysbus.write_32(addr, value);
+                    Ok(())
+                })?,
+            )?;
+
+            let cpu: Table = globals.get("cpu")?;
+            cpu.set(
+                "get_reg",
+                scope.create_function(|_, r: usize| {
+                    let gba = gba.borrow();
+                    match check_register(r)? {
+                        15 => Ok(gba.cpu.get_next_pc()),
+                        r => Ok(gba.cpu.get_reg(r)),
+                    }
+                })?,
+            )?;
+            cpu.set(
+                "set_reg",
+                scope.create_function(|_, (r, value): (usize, u32)| {
+                    let mut gba = gba.borrow_mut();
+                    let gba = &mut **gba;
+                    if check_register(r)? == 15 {
+                        gba.cpu.set_reg(15, value);
+                        match gba.cpu.cpsr.state() {
+                            CpuState::ARM => gba.cpu.reload_pipeline32(&mut gba.sysbus),
+                            CpuState::THUMB => gba.cpu.reload_pipeline16(&mut gba.sysbus),
+                        }
+                    } else {
+                        gba.cpu.set_reg(r, value);
+                    }
+                    Ok(())
+                })?,
+            )?;
+            cpu.set(
+                "cpsr",
+                scope.create_function(|_, ()| Ok(gba.borrow().cpu.cpsr.get()))?,
+            )?;
+
+            let joypad: Table = globals.get("joypad")?;
+            joypad.set(
+                "get",
+                scope.create_function(|_, ()| {
+                    Ok(!gba.borrow().sysbus.io.keyinput & KEYINPUT_ALL_RELEASED)
+                })?,
+            )?;
+
+            let savestate: Table = globals.get("savestate")?;
+            savestate.set(
+                "save",
+                scope.create_function(|lua, ()| {
+                    let bytes = gba.borrow().save_state().map_err(runtime_error)?;
+                    lua.create_string(&bytes)
+                })?,
+            )?;
+            savestate.set(
+                "load",
+                scope.create_function(|_, bytes: mlua::String| {
+                    gba.borrow_mut()
+                        .restore_state(bytes.as_bytes())
+                        .map_err(runtime_error)?;
+                    Ok(())
+                })?,
+            )?;
+
+            let result = f();
+            // the scripts' own accesses don't trigger the memory hooks
+            let watches = self.state.borrow().watches.clone();
+            gba.borrow_mut().sysbus.take_watch_hits_of(&watches);
+            result
+        })
+    }
+
+    /// Runs the top level of a script, which usually registers its callbacks
+    pub fn load(&mut self, gba: &mut GameBoyAdvance, source: &str, name: &str) -> ScriptResult<()> {
+        let lua = &self.lua;
+        self.with_api(gba, || lua.load(source).set_name(name).exec())?;
+        Ok(())
+    }
+
+    pub fn load_file(&mut self, gba: &mut GameBoyAdvance, path: &Path) -> ScriptResult<()> {
+        let source = std::fs::read_to_string(path)?;
+        self.load(gba, &source, &path.to_string_lossy())
+    }
+
+    pub fn frame_count(&self) -> u64 {
+        self.state.borrow().frame_count
+    }
+
+    /// Calls the callbacks of the hooks that `filter` accepts
+    fn call_hooks<'lua, A, P>(
+        &'lua self,
+        gba: &mut GameBoyAdvance,
+        filter: P,
+        args: A,
+    ) -> mlua::Result<()>
+    where
+        A: IntoLuaMulti<'lua> + Clone,
+        P: Fn(&HookKind) -> bool,
+    {
+        let callbacks = self
+            .state
+            .borrow()
+            .hooks
+            .iter()
+            .filter(|hook| filter(&hook.kind))
+            .map(|hook| self.lua.registry_value::<Function>(&hook.callback))
+            .collect::<mlua::Result<Vec<_>>>()?;
+        if callbacks.is_empty() {
+            return Ok(());
+        }
+        self.with_api(gba, || {
+            for callback in callbacks {
+                callback.call::<_, ()>(args.clone())?;
+            }
+            Ok(())
+        })
+    }
+
+    /// Makes the sysbus watch what the memory hooks are interested in, leaving the watchpoints
+    /// of the debugger and gdb alone
+    fn sync_watches(&self, gba: &mut GameBoyAdvance) {
+        let mut state = self.state.borrow_mut();
+        if !state.watches_dirty {
+            return;
+        }
+        for watchpoint in &state.watches {
+            gba.sysbus.remove_watch(watchpoint);
+        }
+        let watches: Vec<Watchpoint> = state
+            .hooks
+            .iter()
+            .filter_map(|hook| match hook.kind {
+                HookKind::Read(addr, len) => Some(Watchpoint::new(addr, len, WatchKind::Read)),
+                HookKind::Write(addr, len) => Some(Watchpoint::new(addr, len, WatchKind::Write)),
+                _ => None,
+            })
+            .collect();
+        for watchpoint in &watches {
+            gba.sysbus.add_watch(*watchpoint);
+        }
+        state.watches = watches;
+        state.watches_dirty = false;
+    }
+
+    fn step_instruction(&mut self, gba: &mut GameBoyAdvance) -> mlua::Result<()> {
+        let pc = gba.cpu.get_next_pc();
+        let running = gba.sysbus.io.haltcnt == HaltState::Running;
+        if running && self.state.borrow().has_exec_hook(pc) {
+            self.call_hooks(gba, |kind| *kind == HookKind::Exec(pc), pc)?;
+            self.sync_watches(gba);
+        }
+
+        gba.step_instruction();
+
+        if gba.sysbus.has_watch_hits() {
+            let watches = self.state.borrow().watches.clone();
+            for hit in gba.sysbus.take_watch_hits_of(&watches) {
+                let len = hit.width.size();
+                let args = (hit.addr, hit.value, len);
+                self.call_hooks(
+                    gba,
+                    |kind| match kind {
+                        HookKind::Read(..) if !hit.is_write => kind.watches(hit.addr, len),
+                        HookKind::Write(..) if hit.is_write => kind.watches(hit.addr, len),
+                        _ => false,
+                    },
+                    args,
+                )?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Runs a frame like `GameBoyAdvance::frame`, calling the script hooks along the way.
+    /// When a callback fails the frame is left unfinished.
+    pub fn run_frame(&mut self, gba: &mut GameBoyAdvance) -> ScriptResult<()> {
+        {
+            let mut state = self.state.borrow_mut();
+            // the debugger might have removed our watchpoints since
+            let watches = gba.sysbus.get_watches();
+            if state.watches.iter().any(|w| !watches.contains(w)) {
+                state.watches_dirty = true;
+            }
+            state.clear_overlay();
+        }
+
+        gba.key_poll();
+        if let Some(keys) = self.state.borrow().joypad {
+            gba.sysbus.io.keyinput = !keys & KEYINPUT_ALL_RELEASED;
+        }
+
+        gba.run_until_frame_end(|gba| {
+            self.sync_watches(gba);
+            if self.state.borrow().needs_stepping() {
+                self.step_instruction(gba)
+            } else {
+                gba.step();
+                Ok(())
+            }
+        })?;
+
+        self.state.borrow_mut().frame_count += 1;
+        self.call_hooks(gba, |kind| *kind == HookKind::Frame, ())?;
+        self.draw_overlay(gba);
+        Ok(())
+    }
+
+    fn draw_overlay(&self, gba: &mut GameBoyAdvance) {
+        let state = self.state.borrow();
+        if !state.overlay_drawn {
+            return;
+        }
+        let frame_buffer = gba.sysbus.io.gpu.get_frame_buffer_mut();
+        for (pixel, color) in frame_buffer.iter_mut().zip(&state.overlay) {
+            *pixel = blend(*pixel, *color);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gba::tests::{make_mock_gba, make_rom};
+
+    /// A rom that counts up r0, storing it to iwram at 0x03000000
+    fn make_gba() -> GameBoyAdvance {
+        make_mock_gba(&make_rom(&[
+            0xe3a0_1403, // mov r1, #0x03000000
+            0xe280_0001, // loop: add r0, r0, #1
+            0xe581_0000, // str r0, [r1]
+            0xeaff_fffc, // b loop
+        ]))
+    }
+
+    fn load(gba: &mut GameBoyAdvance, source: &str) -> ScriptEngine {
+        let mut engine = ScriptEngine::new().unwrap();
+        engine.load(gba, source, "test").unwrap();
+        engine
+    }
+
+    fn global(engine: &ScriptEngine, name: &str) -> i64 {
+        engine.lua.globals().get(name).unwrap()
+    }
+
+    #[test]
+    fn test_frame_and_memory() {
+        let mut gba = make_gba();
+        let mut engine = load(
+            &mut gba,
+            "
+            frames = 0
+            emu.on_frame(function()
+                frames = emu.frame_count()
+                counter = memory.read32(0x03000000)
+                memory.write16(0x02000000, 0xbeef)
+            end)
+            ",
+        );
+        for _ in 0..3 {
+            engine.run_frame(&mut gba).unwrap();
+        }
+        assert_eq!(global(&engine, "frames"), 3);
+        assert_eq!(
+            global(&engine, "counter"),
+            gba.sysbus.read_32(0x0300_0000) as i64
+        );
+        assert_ne!(global(&engine, "counter"), 0);
+        assert_eq!(gba.sysbus.read_16(0x0200_0000), 0xbeef);
+    }
+
+    #[test]
+    fn test_exec_and_write_hooks() {
+        let mut gba = make_gba();
+        let mut engine = load(
+            &mut gba,
+            "
+            execs, writes, last_write = 0, 0, 0
+            emu.on_exec(0x08000004, function(pc)
+                execs = execs + 1
+                assert(pc == 0x08000004 and cpu.get_reg(15) == pc)
+            end)
+            emu.on_write(0x03000000, function(addr, value, width)
+                writes = writes + 1
+                last_write = value
+                assert(addr == 0x03000000 and width == 4)
+            end, 4)
+            ",
+        );
+        engine.run_frame(&mut gba).unwrap();
+        let execs = global(&engine, "execs");
+        assert!(execs > 0);
+        // the frame might end between the two instructions
+        assert!((global(&engine, "writes") - execs).abs() <= 1);
+        assert_eq!(
+            global(&engine, "last_write"),
+            gba.sysbus.read_32(0x0300_0000) as i64
+        );
+
+        // reads of the script itself don't count
+        engine
+            .load(
+                &mut gba,
+                "emu.on_read(0x03000000, function() reads = 1 end)",
+                "reads",
+            )
+            .unwrap();
+        engine
+            .load(
+                &mut gba,
+                "emu.on_frame(function() memory.read8(0x03000000) end)",
+                "frame",
+            )
+            .unwrap();
+        engine.run_frame(&mut gba).unwrap();
+        engine.run_frame(&mut gba).unwrap();
+        assert!(engine
+            .lua
+            .globals()
+            .get::<_, Option<i64>>("reads")
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn test_foreign_watchpoints_are_left_alone() {
+        let mut gba = make_gba();
+        // e.g set by the debugger
+        let foreign = Watchpoint::new(0x0200_0000, 2, WatchKind::Write);
+        gba.sysbus.add_watch(foreign);
+        let mut engine = load(
+            &mut gba,
+            "
+            hook = emu.on_write(0x03000000, function() writes = (writes or 0) + 1 end, 4)
+            emu.on_frame(function() memory.write16(0x02000000, 0xbeef) end)
+            ",
+        );
+        engine.run_frame(&mut gba).unwrap();
+        assert!(global(&engine, "writes") > 0);
+        assert_eq!(gba.sysbus.get_watches().len(), 2);
+        // the hits of the foreign watchpoint are left for its owner
+        let hits = gba.sysbus.take_watch_hits();
+        assert_eq!(hits.len(), 1);
+        assert_eq!(hits[0].addr, 0x0200_0000);
+
+        engine
+            .load(&mut gba, "emu.remove_hook(hook)", "remove")
+            .unwrap();
+        engine.run_frame(&mut gba).unwrap();
+        assert_eq!(gba.sysbus.get_watches(), &[foreign]);
+
+        // the scripts put their watchpoints back when the debugger clears every one of them
+        engine
+            .load(
+                &mut gba,
+                "emu.on_write(0x03000000, function() cleared = 1 end, 4)",
+                "again",
+            )
+            .unwrap();
+        engine.run_frame(&mut gba).unwrap();
+        gba.sysbus.clear_watches();
+        engine.load(&mut gba, "cleared = 0", "reset").unwrap();
+        engine.run_frame(&mut gba).unwrap();
+        assert_eq!(global(&engine, "cleared"), 1);
+    }
+
+    #[test]
+    fn test_registers_and_joypad() {
+        let mut gba = make_gba();
+        let mut engine = load(
+            &mut gba,
+            "
+            cpu.set_reg(0, 100)
+            joypad.set(joypad.A | joypad.START)
+            emu.on_frame(function() keys = joypad.get() end)
+            ",
+        );
+        assert_eq!(gba.cpu.get_reg(0), 100);
+        engine.run_frame(&mut gba).unwrap();
+        assert!(gba.cpu.get_reg(0) > 100);
+        assert_eq!(global(&engine, "keys"), 0b1001);
+        assert_eq!(gba.sysbus.io.keyinput, KEYINPUT_ALL_RELEASED & !0b1001);
+
+        // jumping back to the start reloads the pipeline
+        engine
+            .load(&mut gba, "cpu.set_reg(15, 0x08000000)", "jump")
+            .unwrap();
+        assert_eq!(gba.cpu.get_next_pc(), 0x0800_0000);
+        assert!(engine.load(&mut gba, "cpu.get_reg(16)", "bad").is_err());
+    }
+
+    #[test]
+    fn test_overlay() {
+        let mut gba = make_gba();
+        let mut engine = load(
+            &mut gba,
+            "
+            emu.on_frame(function()
+                if emu.frame_count() == 1 then
+                    gui.pixel(0, 0, 0xffff0000)
+                    gui.fill(10, 10, 2, 2, 0x800000ff)
+                    gui.rect(-5, -5, 1000, 1000, 0x00ffffff)
+                end
+            end)
+            ",
+        );
+        engine.run_frame(&mut gba).unwrap();
+        let frame = gba.get_frame_buffer();
+        assert_eq!(frame[0], 0xff0000);
+        assert_eq!(frame[10 * DISPLAY_WIDTH + 11], blend(0, 0x800000ff));
+        assert_eq!(frame[12 * DISPLAY_WIDTH + 12], 0);
+        assert_eq!(blend(0x102030, 0xff405060), 0x405060);
+        assert_eq!(blend(0x102030, 0x00405060), 0x102030);
+
+        // the overlay only lasts a frame
+        gba.sysbus.io.gpu.get_frame_buffer_mut()[0] = 0;
+        engine.run_frame(&mut gba).unwrap();
+        assert_eq!(gba.get_frame_buffer()[0], 0);
+    }
+
+    #[test]
+    fn test_savestate() {
+        let mut gba = make_gba();
+        let mut engine = load(
+            &mut gba,
+            "
+            emu.on_write(0x03000000, function() writes = (writes or 0) + 1 end, 4)
+            state = savestate.save()
+            ",
+        );
+        let counter = gba.sysbus.read_32(0x0300_0000);
+        engine.run_frame(&mut gba).unwrap();
+        assert_ne!(gba.sysbus.read_32(0x0300_0000), counter);
+
+        engine
+            .load(&mut gba, "savestate.load(state)", "load")
+            .unwrap();
+        assert_eq!(gba.sysbus.read_32(0x0300_0000), counter);
+
+        // the write hook survives loading the state
+        engine.load(&mut gba, "writes = 0", "reset").unwrap();
+        engine.run_frame(&mut gba).unwrap();
+        assert!(global(&engine, "writes") > 0);
+
+        let err = engine.load(&mut gba, "savestate.load('junk')", "junk");
+        assert!(err.is_err());
+    }
+}
@@ -0,0 +1,477 @@
+//! Serial I/O (SIO) controller, aka the link port
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use bit::BitIndex;
+use serde::{Deserialize, Serialize};
+
+use super::gba::CYCLES_PER_FRAME;
+use super::interrupt::{Interrupt, IrqBitmask};
+use super::iodev::consts::*;
+use super::sched::{EventType, Scheduler};
+use super::SerialInterface;
+
+/// How often to check for transfers driven by the other side of the cable
+pub const SERIAL_POLL_CYCLES: usize = 256;
+
+/// How long the side driving the clock waits for the other side of the cable to answer, about 4
+/// frames. The emulator keeps running meanwhile, checking for answers every `SERIAL_POLL_CYCLES`.
+pub const TRANSFER_TIMEOUT_CYCLES: u64 = 4 * CYCLES_PER_FRAME;
+
+const CYCLES_PER_SECOND: usize = 16 * 1024 * 1024;
+const MULTIPLAYER_BAUD_RATES: [usize; 4] = [9600, 38400, 57600, 115200];
+
+/// The value of the data lines when nothing drives them
+const DISCONNECTED_DATA: u16 = 0xffff;
+
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
+pub enum SerialMode {
+    Normal8bit,
+    Normal32bit,
+    Multiplayer,
+    Uart,
+    GeneralPurpose,
+    JoyBus,
+}
+
+/// Data shifted through the link port during a single transfer
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
+pub enum SerialData {
+    Normal8bit(u8),
+    Normal32bit(u32),
+    /// SIOMULTI0-3, each player only fills its own slot when sending
+    Multiplayer([u16; 4]),
+    Uart(u8),
+}
+
+/// Progress of a transfer driven by our clock
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum TransferStatus {
+    /// Still waiting for the other side of the cable
+    Pending,
+    /// The data shifted in, None if nobody answered
+    Done(Option<SerialData>),
+}
+
+/// The default serial device, a link port with no cable connected
+#[derive(Debug, Default)]
+pub struct NoCable;
+
+impl SerialInterface for NoCable {}
+
+type SerialDeviceRcRefCell = Rc<RefCell<dyn SerialInterface>>;
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SerialController {
+    siocnt: u16,
+    rcnt: u16,
+    /// SIOMULTI0-3, shared with SIODATA32
+    multi: [u16; 4],
+    /// SIOMLT_SEND, shared with SIODATA8
+    send: u16,
+    /// Set once our data went out to the other side of the cable, until the transfer is over.
+    /// Not saved, a transfer pending in a save state starts over when loaded.
+    #[serde(skip)]
+    transfer_deadline: Option<u64>,
+}
+
+impl SerialController {
+    pub fn new() -> SerialController {
+        SerialController {
+            siocnt: 0,
+            rcnt: 0,
+            multi: [0; 4],
+            send: 0,
+            transfer_deadline: None,
+        }
+    }
+
+    pub fn mode(&self) -> SerialMode {
+        if self.rcnt.bit(15) {
+            if self.rcnt.bit(14) {
+                SerialMode::JoyBus
+            } else {
+                SerialMode::GeneralPurpose
+            }
+        } else {
+            match self.siocnt.bit_range(12..14) {
+                0b00 => SerialMode::Normal8bit,
+                0b01 => SerialMode::Normal32bit,
+                0b10 => SerialMode::Multiplayer,
+                0b11 => SerialMode::Uart,
+                _ => unreachable!(),
+            }
+        }
+    }
+
+    #[inline]
+    fn is_internal_clock(&self) -> bool {
+        self.siocnt.bit(0)
+    }
+
+    #[inline]
+    fn is_busy(&self) -> bool {
+        self.siocnt.bit(7)
+    }
+
+    fn baud_rate_cycles(&self) -> usize {
+        CYCLES_PER_SECOND / MULTIPLAYER_BAUD_RATES[self.siocnt.bit_range(0..2) as usize]
+    }
+
+    /// How many cycles it takes to complete a transfer in the current mode
+    fn transfer_cycles(&self) -> usize {
+        match self.mode() {
+            SerialMode::Normal8bit | SerialMode::Normal32bit => {
+                let bits = if self.mode() == SerialMode::Normal8bit {
+                    8
+                } else {
+                    32
+                };
+                // internal clock is either 256KHz or 2MHz
+                let cycles_per_bit = if self.siocnt.bit(1) { 8 } else { 64 };
+                bits * cycles_per_bit
+            }
+            // each player sends a start bit, 16 data bits and a stop bit
+            SerialMode::Multiplayer => 4 * 18 * self.baud_rate_cycles(),
+            // start bit, 8 data bits and a stop bit
+            SerialMode::Uart => 10 * self.baud_rate_cycles(),
+            _ => 0,
+        }
+    }
+
+    fn outgoing_data(&self, player_id: usize) -> SerialData {
+        match self.mode() {
+            SerialMode::Normal8bit => SerialData::Normal8bit(self.send as u8),
+            SerialMode::Normal32bit => {
+                SerialData::Normal32bit(self.multi[0] as u32 | (self.multi[1] as u32) << 16)
+            }
+            SerialMode::Multiplayer => {
+                let mut data = [DISCONNECTED_DATA; 4];
+                data[player_id] = self.send;
+                SerialData::Multiplayer(data)
+            }
+            SerialMode::Uart => SerialData::Uart(self.send as u8),
+            _ => unreachable!(),
+        }
+    }
+
+    fn store_incoming_data(&mut self, data: SerialData) {
+        match data {
+            SerialData::Normal8bit(value) => self.send = (self.send & 0xff00) | value as u16,
+            SerialData::Normal32bit(value) => {
+                self.multi[0] = value as u16;
+                self.multi[1] = (value >> 16) as u16;
+            }
+            SerialData::Multiplayer(values) => self.multi = values,
+            SerialData::Uart(value) => {
+                self.send = (self.send & 0xff00) | value as u16;
+                // receive data flag: 0 means the fifo is not empty
+                self.siocnt.set_bit(5, false);
+            }
+        }
+    }
+
+    fn complete_transfer(&mut self, irqs: &mut IrqBitmask) {
+        self.siocnt.set_bit(7, false);
+        if self.siocnt.bit(14) {
+            irqs.add_irq(Interrupt::SerialCommunication);
+        }
+    }
+
+    pub fn handle_read(&self, io_addr: u32) -> u16 {
+        match io_addr {
+            REG_SIOMULTI0 => self.multi[0],
+            REG_SIOMULTI1 => self.multi[1],
+            REG_SIOMULTI2 => self.multi[2],
+            REG_SIOMULTI3 => self.multi[3],
+            REG_SIOCNT => self.siocnt,
+            REG_SIOMLT_SEND => self.send,
+            REG_RCNT => self.rcnt,
+            _ => unreachable!(),
+        }
+    }
+
+    fn start_transfer(&mut self, scheduler: &mut Scheduler) {
+        self.transfer_deadline = None;
+        scheduler.cancel(EventType::SerialTransferComplete);
+        scheduler.schedule(EventType::SerialTransferComplete, self.transfer_cycles());
+    }
+
+    pub fn handle_write(&mut self, io_addr: u32, value: u16, scheduler: &mut Scheduler) {
+        match io_addr {
+            REG_SIOMULTI0 => self.multi[0] = value,
+            REG_SIOMULTI1 => self.multi[1] = value,
+            REG_SIOMULTI2 => self.multi[2] = value,
+            REG_SIOMULTI3 => self.multi[3] = value,
+            REG_SIOCNT => self.write_siocnt(value, scheduler),
+            REG_SIOMLT_SEND => {
+                self.send = value;
+                if self.mode() == SerialMode::Uart && self.siocnt.bit(10) {
+                    // send data flag: 1 means the fifo is full
+                    self.siocnt.set_bit(4, true);
+                    self.start_transfer(scheduler);
+                }
+            }
+            REG_RCNT => self.rcnt = value,
+            _ => unreachable!(),
+        }
+    }
+
+    fn write_siocnt(&mut self, value: u16, scheduler: &mut Scheduler) {
+        let was_busy = self.is_busy();
+        match self.mode() {
+            SerialMode::Multiplayer => {
+                // SI, SD, ID and error flags are read-only
+                self.siocnt = (value & !0b0111_1100) | (self.siocnt & 0b0111_1100);
+            }
+            SerialMode::Uart => {
+                // send, receive and error flags are read-only
+                self.siocnt = (value & !0b0111_0000) | (self.siocnt & 0b0111_0000);
+            }
+            _ => {
+                // SI is read-only
+                self.siocnt = (value & !0b100) | (self.siocnt & 0b100);
+            }
+        }
+
+        if was_busy || !self.is_busy() {
+            return;
+        }
+        match self.mode() {
+            SerialMode::Normal8bit | SerialMode::Normal32bit if self.is_internal_clock() => {
+                self.start_transfer(scheduler);
+            }
+            SerialMode::Multiplayer if !self.siocnt.bit(2) => {
+                // only the parent may start a transfer
+                self.start_transfer(scheduler);
+            }
+            _ => {}
+        }
+    }
+
+    /// Updates the multiplayer status bits that reflect the cable
+    fn update_multiplayer_status(&mut self, serial_device: &dyn SerialInterface) {
+        let player_id = serial_device.player_id();
+        self.siocnt.set_bit(2, player_id != 0);
+        self.siocnt.set_bit(3, serial_device.is_connected());
+        self.siocnt.set_bit_range(4..6, player_id as u16);
+    }
+
+    /// Sends our data once a transfer driven by our clock is over, then waits for the other side
+    /// to answer
+    fn exchange_data(
+        &mut self,
+        device: &mut dyn SerialInterface,
+        scheduler: &mut Scheduler,
+    ) -> TransferStatus {
+        let deadline = match self.transfer_deadline {
+            Some(deadline) => deadline,
+            None => {
+                device.start_master_transfer(self.outgoing_data(0), scheduler.timestamp());
+                let deadline = scheduler.timestamp() + TRANSFER_TIMEOUT_CYCLES;
+                self.transfer_deadline = Some(deadline);
+                deadline
+            }
+        };
+        let status = device.poll_master_transfer(scheduler.timestamp() >= deadline);
+        match status {
+            TransferStatus::Pending => {
+                scheduler.schedule(EventType::SerialTransferComplete, SERIAL_POLL_CYCLES)
+            }
+            TransferStatus::Done(_) => self.transfer_deadline = None,
+        }
+        status
+    }
+
+    /// Handles the `SerialTransferComplete` event of a transfer driven by our clock
+    pub fn on_transfer_complete(
+        &mut self,
+        irqs: &mut IrqBitmask,
+        scheduler: &mut Scheduler,
+        serial_device: &SerialDeviceRcRefCell,
+    ) {
+        let mode = self.mode();
+        let mut device = serial_device.borrow_mut();
+        match mode {
+            SerialMode::Normal8bit | SerialMode::Normal32bit => {
+                if let TransferStatus::Done(received) = self.exchange_data(&mut *device, scheduler)
+                {
+                    self.store_incoming_data(received.unwrap_or(match mode {
+                        SerialMode::Normal8bit => SerialData::Normal8bit(0xff),
+                        _ => SerialData::Normal32bit(0xffff_ffff),
+                    }));
+                    self.complete_transfer(irqs);
+                }
+            }
+            SerialMode::Multiplayer => {
+                self.update_multiplayer_status(&*device);
+                let outgoing = self.outgoing_data(0);
+                if let TransferStatus::Done(received) = self.exchange_data(&mut *device, scheduler)
+                {
+                    self.store_incoming_data(received.unwrap_or(outgoing));
+                    self.complete_transfer(irqs);
+                }
+            }
+            SerialMode::Uart => {
+                // nothing is sent back in uart mode
+                device.start_master_transfer(self.outgoing_data(0), scheduler.timestamp());
+                self.siocnt.set_bit(4, false);
+                if self.siocnt.bit(14) {
+                    irqs.add_irq(Interrupt::SerialCommunication);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Handles the `SerialPoll` event, checking for transfers driven by the other side
+    pub fn poll(
+        &mut self,
+        irqs: &mut IrqBitmask,
+        timestamp: u64,
+        serial_device: &SerialDeviceRcRefCell,
+    ) {
+        serial_device.borrow_mut().sync(timestamp);
+        let mode = self.mode();
+        match mode {
+            SerialMode::Normal8bit | SerialMode::Normal32bit
+                if self.is_busy() && !self.is_internal_clock() =>
+            {
+                let mut device = serial_device.borrow_mut();
+                if let Some(received) = device.slave_transfer(self.outgoing_data(0)) {
+                    self.store_incoming_data(received);
+                    self.complete_transfer(irqs);
+                }
+            }
+            SerialMode::Multiplayer => {
+                let mut device = serial_device.borrow_mut();
+                self.update_multiplayer_status(&*device);
+                let player_id = device.player_id();
+                if player_id != 0 {
+                    if let Some(received) = device.slave_transfer(self.outgoing_data(player_id)) {
+                        self.store_incoming_data(received);
+                        self.complete_transfer(irqs);
+                    }
+                }
+            }
+            SerialMode::Uart if self.siocnt.bit(11) => {
+                let mut device = serial_device.borrow_mut();
+                if let Some(received) = device.slave_transfer(SerialData::Uart(0)) {
+                    self.store_incoming_data(received);
+                    if self.siocnt.bit(14) {
+                        irqs.add_irq(Interrupt::SerialCommunication);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn no_cable() -> SerialDeviceRcRefCell {
+        Rc::new(RefCell::new(NoCable))
+    }
+
+    #[test]
+    fn test_normal_transfer_without_cable() {
+        let device = no_cable();
+        let mut sio = SerialController::new();
+        let mut scheduler = Scheduler::new();
+        let mut irqs = IrqBitmask(0);
+
+        sio.handle_write(REG_SIOMLT_SEND, 0x42, &mut scheduler);
+        // 8bit mode, internal clock at 256KHz, with irq
+        sio.handle_write(REG_SIOCNT, 1 | 1 << 7 | 1 << 14, &mut scheduler);
+        assert_eq!(sio.mode(), SerialMode::Normal8bit);
+        assert_eq!(scheduler.next_event_time(), Some(8 * 64));
+        assert!(sio.handle_read(REG_SIOCNT).bit(7));
+
+        scheduler.update(8 * 64);
+        let event = scheduler.pop_pending_event().unwrap();
+        assert_eq!(event.typ, EventType::SerialTransferComplete);
+        sio.on_transfer_complete(&mut irqs, &mut scheduler, &device);
+        assert!(!sio.handle_read(REG_SIOCNT).bit(7));
+        assert_eq!(sio.handle_read(REG_SIOMLT_SEND), 0xff);
+        assert!(irqs.SerialCommunication());
+    }
+
+    /// A peer that answers after the given number of polls, if ever
+    struct SlowPeer {
+        polls_left: Option<usize>,
+        sent: Option<SerialData>,
+    }
+
+    impl SerialInterface for SlowPeer {
+        fn start_master_transfer(&mut self, data: SerialData, _timestamp: u64) {
+            self.sent = Some(data);
+        }
+
+        fn poll_master_transfer(&mut self, give_up: bool) -> TransferStatus {
+            match self.polls_left {
+                Some(0) => TransferStatus::Done(Some(SerialData::Normal8bit(0x24))),
+                Some(ref mut n) if !give_up => {
+                    *n -= 1;
+                    TransferStatus::Pending
+                }
+                None if !give_up => TransferStatus::Pending,
+                _ => TransferStatus::Done(None),
+            }
+        }
+    }
+
+    /// Starts an 8bit transfer and runs the scheduler until it is over
+    fn run_master_transfer(device: &SerialDeviceRcRefCell) -> (SerialController, u64) {
+        let mut sio = SerialController::new();
+        let mut scheduler = Scheduler::new();
+        let mut irqs = IrqBitmask(0);
+        sio.handle_write(REG_SIOMLT_SEND, 0x42, &mut scheduler);
+        sio.handle_write(REG_SIOCNT, 1 | 1 << 7, &mut scheduler);
+        while sio.handle_read(REG_SIOCNT).bit(7) {
+            scheduler.skip_to_next_event();
+            let event = scheduler.pop_pending_event().unwrap();
+            assert_eq!(event.typ, EventType::SerialTransferComplete);
+            sio.on_transfer_complete(&mut irqs, &mut scheduler, device);
+        }
+        (sio, scheduler.timestamp())
+    }
+
+    #[test]
+    fn test_master_transfer_waits_for_peer() {
+        let peer = Rc::new(RefCell::new(SlowPeer {
+            polls_left: Some(3),
+            sent: None,
+        }));
+        let (sio, timestamp) = run_master_transfer(&(peer.clone() as SerialDeviceRcRefCell));
+        assert_eq!(peer.borrow().sent, Some(SerialData::Normal8bit(0x42)));
+        assert_eq!(sio.handle_read(REG_SIOMLT_SEND), 0x24);
+        assert_eq!(timestamp, 8 * 64 + 3 * SERIAL_POLL_CYCLES as u64);
+
+        let peer = Rc::new(RefCell::new(SlowPeer {
+            polls_left: None,
+            sent: None,
+        }));
+        let (sio, timestamp) = run_master_transfer(&(peer as SerialDeviceRcRefCell));
+        assert_eq!(sio.handle_read(REG_SIOMLT_SEND), 0xff);
+        assert!(timestamp >= 8 * 64 + TRANSFER_TIMEOUT_CYCLES);
+        assert!(timestamp < 8 * 64 + TRANSFER_TIMEOUT_CYCLES + SERIAL_POLL_CYCLES as u64);
+    }
+
+    #[test]
+    fn test_external_clock_waits_for_peer() {
+        let device = no_cable();
+        let mut sio = SerialController::new();
+        let mut scheduler = Scheduler::new();
+        let mut irqs = IrqBitmask(0);
+
+        sio.handle_write(REG_SIOCNT, 1 << 12 | 1 << 7 | 1 << 14, &mut scheduler);
+        assert_eq!(sio.mode(), SerialMode::Normal32bit);
+        assert!(!scheduler.is_scheduled(EventType::SerialTransferComplete));
+        sio.poll(&mut irqs, scheduler.timestamp(), &device);
+        assert!(sio.handle_read(REG_SIOCNT).bit(7));
+        assert!(!irqs.SerialCommunication());
+    }
+}
@@ -6,6 +6,7 @@ use serde::{Deserialize, Serialize};
 
 use super::dma::DmaController;
 use super::iodev::consts::*;
+use super::sched::Scheduler;
 
 use crate::{AudioInterface, StereoSample};
 
@@ -15,9 +16,11 @@ use fifo::SoundFifo;
 mod dsp;
 use dsp::{CosineResampler, Resampler};
 
+mod psg;
+use psg::{NoiseChannel, SquareChannel, WaveChannel, FRAME_SEQUENCER_CYCLES};
+
 const DMG_RATIOS: [f32; 4] = [0.25, 0.5, 1.0, 0.0];
 const DMA_TIMERS: [usize; 2] = [0, 1];
-const DUTY_RATIOS: [f32; 4] = [0.125, 0.25, 0.5, 0.75];
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 struct DmaSoundChannel {
@@ -58,12 +61,12 @@ const REG_FIFO_A_H: u32 = REG_FIFO_A + 2;
 const REG_FIFO_B_L: u32 = REG_FIFO_B;
 const REG_FIFO_B_H: u32 = REG_FIFO_B + 2;
 
+const REG_WAVE_RAM_END: u32 = REG_WAVE_RAM + 0xe;
+
 type AudioDeviceRcRefCell = Rc<RefCell<dyn AudioInterface>>;
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct SoundController {
-    cycles: usize, // cycles count when we last provided a new sample.
-
     mse: bool,
 
     left_volume: usize,
@@ -80,14 +83,13 @@ pub struct SoundController {
 
     dmg_volume_ratio: f32,
 
-    sqr1_rate: usize,
-    sqr1_timed: bool,
-    sqr1_length: f32,
-    sqr1_duty: f32,
-    sqr1_step_time: usize,
-    sqr1_step_increase: bool,
-    sqr1_initial_vol: usize,
-    sqr1_cur_vol: usize,
+    sqr1: SquareChannel,
+    sqr2: SquareChannel,
+    wave: WaveChannel,
+    noise: NoiseChannel,
+
+    frame_sequencer_cycles: usize,
+    frame_sequencer_step: usize,
 
     sound_bias: u16,
 
@@ -104,7 +106,6 @@ impl SoundController {
     pub fn new(audio_device_sample_rate: f32) -> SoundController {
         let resampler = CosineResampler::new(32768_f32, audio_device_sample_rate);
         SoundController {
-            cycles: 0,
             mse: false,
             left_volume: 0,
             left_sqr1: false,
@@ -116,15 +117,13 @@ impl SoundController {
             right_sqr2: false,
             right_wave: false,
             right_noise: false,
-            dmg_volume_ratio: 0.0,
-            sqr1_rate: 0,
-            sqr1_timed: false,
-            sqr1_length: 0.0,
-            sqr1_duty: DUTY_RATIOS[0],
-            sqr1_step_time: 0,
-            sqr1_step_increase: false,
-            sqr1_initial_vol: 0,
-            sqr1_cur_vol: 0,
+            dmg_volume_ratio: DMG_RATIOS[0],
+            sqr1: SquareChannel::new(),
+            sqr2: SquareChannel::new(),
+            wave: WaveChannel::new(),
+            noise: NoiseChannel::new(),
+            frame_sequencer_cycles: 0,
+            frame_sequencer_step: 0,
             sound_bias: 0x200,
             sample_rate: 32_768f32,
             cycles_per_sample: 512,
@@ -137,18 +136,24 @@ impl SoundController {
 
     pub fn handle_read(&self, io_addr: u32) -> u16 {
         let value = match io_addr {
-            REG_SOUNDCNT_X => cbit(7, self.mse),
+            REG_SOUNDCNT_X => {
+                cbit(0, self.sqr1.is_enabled())
+                    | cbit(1, self.sqr2.is_enabled())
+                    | cbit(2, self.wave.is_enabled())
+                    | cbit(3, self.noise.is_enabled())
+                    | cbit(7, self.mse)
+            }
             REG_SOUNDCNT_L => {
-                self.left_volume as u16
-                    | (self.right_volume as u16) << 4
-                    | cbit(8, self.left_sqr1)
-                    | cbit(9, self.left_sqr2)
-                    | cbit(10, self.left_wave)
-                    | cbit(11, self.left_noise)
-                    | cbit(12, self.right_sqr1)
-                    | cbit(13, self.right_sqr2)
-                    | cbit(14, self.right_wave)
-                    | cbit(15, self.right_noise)
+                self.right_volume as u16
+                    | (self.left_volume as u16) << 4
+                    | cbit(8, self.right_sqr1)
+                    | cbit(9, self.right_sqr2)
+                    | cbit(10, self.right_wave)
+                    | cbit(11, self.right_noise)
+                    | cbit(12, self.left_sqr1)
+                    | cbit(13, self.left_sqr2)
+                    | cbit(14, self.left_wave)
+                    | cbit(15, self.left_noise)
             }
 
             REG_SOUNDCNT_H => {
@@ -168,6 +173,22 @@ impl SoundController {
 
             REG_SOUNDBIAS => self.sound_bias,
 
+            REG_SOUND1CNT_L => self.sqr1.read_sweep(),
+            REG_SOUND1CNT_H => self.sqr1.read_duty_len_env(),
+            REG_SOUND1CNT_X => self.sqr1.read_freq_ctrl(),
+            REG_SOUND2CNT_L => self.sqr2.read_duty_len_env(),
+            REG_SOUND2CNT_H => self.sqr2.read_freq_ctrl(),
+            REG_SOUND3CNT_L => self.wave.read_stop_bank(),
+            REG_SOUND3CNT_H => self.wave.read_len_vol(),
+            REG_SOUND3CNT_X => self.wave.read_freq_ctrl(),
+            REG_SOUND4CNT_L => self.noise.read_len_env(),
+            REG_SOUND4CNT_H => self.noise.read_freq_ctrl(),
+
+            REG_WAVE_RAM..=REG_WAVE_RAM_END => {
+                let ofs = (io_addr - REG_WAVE_RAM) as usize;
+                self.wave.read_wave_ram(ofs) as u16 | (self.wave.read_wave_ram(ofs + 1) as u16) << 8
+            }
+
             _ => {
                 // println!(
                 //     "Unimplemented read from {:x} {}",
@@ -212,20 +233,20 @@ impl SoundController {
 
         match io_addr {
             REG_SOUNDCNT_L => {
-                self.left_volume = value.bit_range(0..2) as usize;
-                self.right_volume = value.bit_range(4..6) as usize;
-                self.left_sqr1 = value.bit(8);
-                self.left_sqr2 = value.bit(9);
-                self.left_wave = value.bit(10);
-                self.left_noise = value.bit(11);
-                self.right_sqr1 = value.bit(12);
-                self.right_sqr2 = value.bit(13);
-                self.right_wave = value.bit(14);
-                self.right_noise = value.bit(15);
+                self.right_volume = value.bit_range(0..3) as usize;
+                self.left_volume = value.bit_range(4..7) as usize;
+                self.right_sqr1 = value.bit(8);
+                self.right_sqr2 = value.bit(9);
+                self.right_wave = value.bit(10);
+                self.right_noise = value.bit(11);
+                self.left_sqr1 = value.bit(12);
+                self.left_sqr2 = value.bit(13);
+                self.left_wave = value.bit(14);
+                self.left_noise = value.bit(15);
             }
 
             REG_SOUNDCNT_H => {
-                self.dmg_volume_ratio = DMG_RATIOS[value.bit_range(0..1) as usize];
+                self.dmg_volume_ratio = DMG_RATIOS[value.bit_range(0..2) as usize];
                 self.dma_sound[0].volume_shift = value.bit(2) as i16;
                 self.dma_sound[1].volume_shift = value.bit(3) as i16;
                 self.dma_sound[0].enable_right = value.bit(8);
@@ -243,20 +264,21 @@ impl SoundController {
                 }
             }
 
-            REG_SOUND1CNT_H => {
-                self.sqr1_length = (64 - value.bit_range(0..5) as usize) as f32 / 256.0;
-                self.sqr1_duty = DUTY_RATIOS[value.bit_range(6..7) as usize];
-                self.sqr1_step_time = value.bit_range(8..10) as usize;
-                self.sqr1_step_increase = value.bit(11);
-                self.sqr1_initial_vol = value.bit_range(12..15) as usize;
-            }
-
-            REG_SOUND1CNT_X => {
-                self.sqr1_rate = value.bit_range(0..10) as usize;
-                self.sqr1_timed = value.bit(14);
-                if value.bit(15) {
-                    self.sqr1_cur_vol = self.sqr1_initial_vol;
-                }
+            REG_SOUND1CNT_L => self.sqr1.write_sweep(value),
+            REG_SOUND1CNT_H => self.sqr1.write_duty_len_env(value),
+            REG_SOUND1CNT_X => self.sqr1.write_freq_ctrl(value),
+            REG_SOUND2CNT_L => self.sqr2.write_duty_len_env(value),
+            REG_SOUND2CNT_H => self.sqr2.write_freq_ctrl(value),
+            REG_SOUND3CNT_L => self.wave.write_stop_bank(value),
+            REG_SOUND3CNT_H => self.wave.write_len_vol(value),
+            REG_SOUND3CNT_X => self.wave.write_freq_ctrl(value),
+            REG_SOUND4CNT_L => self.noise.write_len_env(value),
+            REG_SOUND4CNT_H => self.noise.write_freq_ctrl(value),
+
+            REG_WAVE_RAM..=REG_WAVE_RAM_END => {
+                let ofs = (io_addr - REG_WAVE_RAM) as usize;
+                self.wave.write_wave_ram(ofs, (value & 0xff) as u8);
+                self.wave.write_wave_ram(ofs + 1, (value >> 8) as u8);
             }
 
             REG_FIFO_A_L | REG_FIFO_A_H => {
@@ -297,6 +319,7 @@ impl SoundController {
     pub fn handle_timer_overflow(
         &mut self,
         dmac: &mut DmaController,
+        scheduler: &mut Scheduler,
         timer_id: usize,
         _num_overflows: usize,
     ) {
@@ -311,53 +334,121 @@ impl SoundController {
             if timer_id == dma.timer_select {
                 dma.value = dma.fifo.read();
                 if dma.fifo.count() <= 16 {
-                    dmac.notify_sound_fifo(FIFO_INDEX_TO_REG[fifo]);
+                    dmac.notify_sound_fifo(FIFO_INDEX_TO_REG[fifo], scheduler);
                 }
             }
         }
     }
 
-    pub fn update(
-        &mut self,
-        cycles: usize,
-        cycles_to_next_event: &mut usize,
-        audio_device: &AudioDeviceRcRefCell,
-    ) {
-        self.cycles += cycles;
-        while self.cycles >= self.cycles_per_sample {
-            self.cycles -= self.cycles_per_sample;
+    /// Advances the legacy sound channels and the frame sequencer
+    fn step_psg(&mut self, cycles: usize) {
+        self.sqr1.step(cycles);
+        self.sqr2.step(cycles);
+        self.wave.step(cycles);
+        self.noise.step(cycles);
+
+        self.frame_sequencer_cycles += cycles;
+        while self.frame_sequencer_cycles >= FRAME_SEQUENCER_CYCLES {
+            self.frame_sequencer_cycles -= FRAME_SEQUENCER_CYCLES;
+
+            // length counters are clocked at 256Hz, sweep at 128Hz and envelopes at 64Hz
+            if self.frame_sequencer_step % 2 == 0 {
+                self.sqr1.clock_length();
+                self.sqr2.clock_length();
+                self.wave.clock_length();
+                self.noise.clock_length();
+            }
+            if self.frame_sequencer_step == 2 || self.frame_sequencer_step == 6 {
+                self.sqr1.clock_sweep();
+            }
+            if self.frame_sequencer_step == 7 {
+                self.sqr1.clock_envelope();
+                self.sqr2.clock_envelope();
+                self.noise.clock_envelope();
+            }
 
-            // time to push a new sample!
+            self.frame_sequencer_step = (self.frame_sequencer_step + 1) % 8;
+        }
+    }
 
-            let mut sample = [0f32; 2];
+    /// Mixes the legacy sound channels for one of the stereo channels
+    fn mix_psg(&self, channel: usize) -> i16 {
+        let (volume, sqr1, sqr2, wave, noise) = match channel {
+            0 => (
+                self.left_volume,
+                self.left_sqr1,
+                self.left_sqr2,
+                self.left_wave,
+                self.left_noise,
+            ),
+            1 => (
+                self.right_volume,
+                self.right_sqr1,
+                self.right_sqr2,
+                self.right_wave,
+                self.right_noise,
+            ),
+            _ => unreachable!(),
+        };
 
-            for channel in 0..=1 {
-                let mut dma_sample = 0;
-                for dma in &mut self.dma_sound {
-                    if dma.is_stereo_channel_enabled(channel) {
-                        let value = dma.value as i16;
-                        dma_sample += value * (2 << dma.volume_shift);
-                    }
-                }
+        let mut sample = 0;
+        if sqr1 {
+            sample += self.sqr1.output();
+        }
+        if sqr2 {
+            sample += self.sqr2.output();
+        }
+        if wave {
+            sample += self.wave.output();
+        }
+        if noise {
+            sample += self.noise.output();
+        }
 
-                apply_bias(&mut dma_sample, self.sound_bias.bit_range(0..10) as i16);
-                sample[channel] = dma_sample as i32 as f32;
-            }
+        let sample = sample * (volume as i16 + 1);
+        (sample as f32 * self.dmg_volume_ratio) as i16
+    }
 
-            let stereo_sample = (sample[0], sample[1]);
-            self.resampler.feed(stereo_sample, &mut self.output_buffer);
+    #[inline]
+    pub fn cycles_per_sample(&self) -> usize {
+        self.cycles_per_sample
+    }
 
-            let mut audio = audio_device.borrow_mut();
-            self.output_buffer.drain(..).for_each(|(left, right)| {
-                audio.push_sample((
-                    (left.round() as i16) * (std::i16::MAX / 512),
-                    (right.round() as i16) * (std::i16::MAX / 512),
-                ));
-            });
+    /// Handles the `SoundSample` event, the next one is due in `cycles_per_sample` cycles
+    pub fn on_sample(&mut self, audio_device: &AudioDeviceRcRefCell) {
+        if self.mse {
+            self.step_psg(self.cycles_per_sample);
         }
-        if self.cycles_per_sample < *cycles_to_next_event {
-            *cycles_to_next_event = self.cycles_per_sample;
+
+        let mut sample = [0f32; 2];
+
+        for channel in 0..=1 {
+            let mut dma_sample = 0;
+            for dma in &mut self.dma_sound {
+                if dma.is_stereo_channel_enabled(channel) {
+                    let value = dma.value as i16;
+                    dma_sample += value * (2 << dma.volume_shift);
+                }
+            }
+
+            if self.mse {
+                dma_sample += self.mix_psg(channel);
+            }
+
+            apply_bias(&mut dma_sample, self.sound_bias.bit_range(0..10) as i16);
+            sample[channel] = dma_sample as i32 as f32;
         }
+
+        let stereo_sample = (sample[0], sample[1]);
+        self.resampler.feed(stereo_sample, &mut self.output_buffer);
+
+        let mut audio = audio_device.borrow_mut();
+        self.output_buffer.drain(..).for_each(|(left, right)| {
+            audio.push_sample((
+                (left.round() as i16) * (std::i16::MAX / 512),
+                (right.round() as i16) * (std::i16::MAX / 512),
+            ));
+        });
     }
 }
 
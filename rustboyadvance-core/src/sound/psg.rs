@@ -0,0 +1,601 @@
+//! The legacy GameBoy (DMG) sound channels: two square channels, the wave channel and the noise channel.
+use bit::BitIndex;
+use serde::{Deserialize, Serialize};
+
+/// The frame sequencer ticks at 512Hz, and clocks the length counters, envelopes and sweep.
+pub const FRAME_SEQUENCER_CYCLES: usize = 16 * 1024 * 1024 / 512;
+
+const DUTY_PATTERNS: [[bool; 8]; 4] = [
+    [false, false, false, false, false, false, false, true], // 12.5%
+    [true, false, false, false, false, false, false, true],  // 25%
+    [true, false, false, false, false, true, true, true],    // 50%
+    [false, true, true, true, true, true, true, false],      // 75%
+];
+
+pub const WAVE_RAM_BANK_SIZE: usize = 16;
+
+/// The envelope and sweep timers treat a period of 0 as 8
+fn timer_period(period: u16) -> u16 {
+    if period == 0 {
+        8
+    } else {
+        period
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct LengthCounter {
+    enabled: bool,
+    counter: usize,
+    max: usize,
+}
+
+impl LengthCounter {
+    fn new(max: usize) -> LengthCounter {
+        LengthCounter {
+            enabled: false,
+            counter: 0,
+            max: max,
+        }
+    }
+
+    fn load(&mut self, length: usize) {
+        self.counter = self.max - length;
+    }
+
+    fn restart(&mut self) {
+        if self.counter == 0 {
+            self.counter = self.max;
+        }
+    }
+
+    /// Returns true when the length expired and the channel needs to be silenced
+    fn clock(&mut self) -> bool {
+        if self.enabled && self.counter > 0 {
+            self.counter -= 1;
+            self.counter == 0
+        } else {
+            false
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+struct Envelope {
+    initial_volume: u16,
+    increase: bool,
+    step_time: u16,
+
+    volume: u16,
+    counter: u16,
+}
+
+impl Envelope {
+    /// write bits 8-15 of the channel's envelope register
+    fn write(&mut self, value: u16) {
+        self.step_time = value.bit_range(8..11);
+        self.increase = value.bit(11);
+        self.initial_volume = value.bit_range(12..16);
+    }
+
+    fn read(&self) -> u16 {
+        self.step_time << 8 | (self.increase as u16) << 11 | self.initial_volume << 12
+    }
+
+    fn restart(&mut self) {
+        self.volume = self.initial_volume;
+        self.counter = timer_period(self.step_time);
+    }
+
+    fn clock(&mut self) {
+        self.counter = self.counter.saturating_sub(1);
+        if self.counter == 0 {
+            self.counter = timer_period(self.step_time);
+            if self.step_time == 0 {
+                return;
+            }
+            if self.increase && self.volume < 15 {
+                self.volume += 1;
+            } else if !self.increase && self.volume > 0 {
+                self.volume -= 1;
+            }
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+struct Sweep {
+    shift: u16,
+    decrease: bool,
+    time: u16,
+
+    counter: u16,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SquareChannel {
+    enabled: bool,
+    sweep: Sweep,
+    length: LengthCounter,
+    envelope: Envelope,
+    duty: usize,
+    rate: u16,
+
+    timer: usize,
+    duty_step: usize,
+}
+
+impl SquareChannel {
+    pub fn new() -> SquareChannel {
+        SquareChannel {
+            enabled: false,
+            sweep: Sweep::default(),
+            length: LengthCounter::new(64),
+            envelope: Envelope::default(),
+            duty: 0,
+            rate: 0,
+            timer: 2048 * 16,
+            duty_step: 0,
+        }
+    }
+
+    #[inline]
+    fn period(&self) -> usize {
+        (2048 - self.rate as usize) * 16
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// NR10 - only present on square channel 1
+    pub fn write_sweep(&mut self, value: u16) {
+        self.sweep.shift = value.bit_range(0..3);
+        self.sweep.decrease = value.bit(3);
+        self.sweep.time = value.bit_range(4..7);
+    }
+
+    pub fn read_sweep(&self) -> u16 {
+        self.sweep.shift | (self.sweep.decrease as u16) << 3 | self.sweep.time << 4
+    }
+
+    /// NR11/NR12 or NR21/NR22
+    pub fn write_duty_len_env(&mut self, value: u16) {
+        self.length.load(value.bit_range(0..6) as usize);
+        self.duty = value.bit_range(6..8) as usize;
+        self.envelope.write(value);
+    }
+
+    pub fn read_duty_len_env(&self) -> u16 {
+        (self.duty as u16) << 6 | self.envelope.read()
+    }
+
+    /// NR13/NR14 or NR23/NR24
+    pub fn write_freq_ctrl(&mut self, value: u16) {
+        self.rate = value.bit_range(0..11);
+        self.length.enabled = value.bit(14);
+        if value.bit(15) {
+            self.restart();
+        }
+    }
+
+    pub fn read_freq_ctrl(&self) -> u16 {
+        (self.length.enabled as u16) << 14
+    }
+
+    fn restart(&mut self) {
+        self.enabled = true;
+        self.length.restart();
+        self.envelope.restart();
+        self.sweep.counter = timer_period(self.sweep.time);
+        self.timer = self.period();
+        self.duty_step = 0;
+    }
+
+    pub fn step(&mut self, cycles: usize) {
+        let mut cycles = cycles;
+        while cycles >= self.timer {
+            cycles -= self.timer;
+            self.timer = self.period();
+            self.duty_step = (self.duty_step + 1) % 8;
+        }
+        self.timer -= cycles;
+    }
+
+    pub fn clock_length(&mut self) {
+        if self.length.clock() {
+            self.enabled = false;
+        }
+    }
+
+    pub fn clock_envelope(&mut self) {
+        self.envelope.clock();
+    }
+
+    pub fn clock_sweep(&mut self) {
+        self.sweep.counter = self.sweep.counter.saturating_sub(1);
+        if self.sweep.counter != 0 {
+            return;
+        }
+        self.sweep.counter = timer_period(self.sweep.time);
+        if self.sweep.time == 0 {
+            return;
+        }
+
+        let delta = self.rate >> self.sweep.shift;
+        if self.sweep.decrease {
+            if self.sweep.shift != 0 {
+                self.rate -= delta;
+            }
+        } else {
+            let new_rate = self.rate + delta;
+            if new_rate > 2047 {
+                self.enabled = false;
+            } else if self.sweep.shift != 0 {
+                self.rate = new_rate;
+            }
+        }
+    }
+
+    /// Returns the current amplitude in the range of -15..=15
+    pub fn output(&self) -> i16 {
+        if !self.enabled {
+            return 0;
+        }
+        let volume = self.envelope.volume as i16;
+        if DUTY_PATTERNS[self.duty][self.duty_step] {
+            volume
+        } else {
+            -volume
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct WaveChannel {
+    enabled: bool,
+    playback: bool,
+    two_banks: bool,
+    bank: usize,
+    length: LengthCounter,
+    volume: u16,
+    force_75: bool,
+    rate: u16,
+
+    timer: usize,
+    position: usize,
+
+    wave_ram: [u8; 2 * WAVE_RAM_BANK_SIZE],
+}
+
+impl WaveChannel {
+    pub fn new() -> WaveChannel {
+        WaveChannel {
+            enabled: false,
+            playback: false,
+            two_banks: false,
+            bank: 0,
+            length: LengthCounter::new(256),
+            volume: 0,
+            force_75: false,
+            rate: 0,
+            timer: 2048 * 8,
+            position: 0,
+            wave_ram: [0; 2 * WAVE_RAM_BANK_SIZE],
+        }
+    }
+
+    #[inline]
+    fn period(&self) -> usize {
+        (2048 - self.rate as usize) * 8
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// NR30
+    pub fn write_stop_bank(&mut self, value: u16) {
+        self.two_banks = value.bit(5);
+        self.bank = value.bit(6) as usize;
+        self.playback = value.bit(7);
+        if !self.playback {
+            self.enabled = false;
+        }
+    }
+
+    pub fn read_stop_bank(&self) -> u16 {
+        (self.two_banks as u16) << 5 | (self.bank as u16) << 6 | (self.playback as u16) << 7
+    }
+
+    /// NR31/NR32
+    pub fn write_len_vol(&mut self, value: u16) {
+        self.length.load(value.bit_range(0..8) as usize);
+        self.volume = value.bit_range(13..15);
+        self.force_75 = value.bit(15);
+    }
+
+    pub fn read_len_vol(&self) -> u16 {
+        self.volume << 13 | (self.force_75 as u16) << 15
+    }
+
+    /// NR33/NR34
+    pub fn write_freq_ctrl(&mut self, value: u16) {
+        self.rate = value.bit_range(0..11);
+        self.length.enabled = value.bit(14);
+        if value.bit(15) {
+            self.restart();
+        }
+    }
+
+    pub fn read_freq_ctrl(&self) -> u16 {
+        (self.length.enabled as u16) << 14
+    }
+
+    /// The cpu can only access the bank that is not currently being played
+    pub fn read_wave_ram(&self, ofs: usize) -> u8 {
+        self.wave_ram[(self.bank ^ 1) * WAVE_RAM_BANK_SIZE + ofs]
+    }
+
+    pub fn write_wave_ram(&mut self, ofs: usize, value: u8) {
+        self.wave_ram[(self.bank ^ 1) * WAVE_RAM_BANK_SIZE + ofs] = value;
+    }
+
+    fn restart(&mut self) {
+        self.enabled = self.playback;
+        self.length.restart();
+        self.timer = self.period();
+        self.position = 0;
+    }
+
+    pub fn step(&mut self, cycles: usize) {
+        let num_samples = if self.two_banks { 64 } else { 32 };
+        let mut cycles = cycles;
+        while cycles >= self.timer {
+            cycles -= self.timer;
+            self.timer = self.period();
+            self.position = (self.position + 1) % num_samples;
+        }
+        self.timer -= cycles;
+    }
+
+    pub fn clock_length(&mut self) {
+        if self.length.clock() {
+            self.enabled = false;
+        }
+    }
+
+    fn current_sample(&self) -> u8 {
+        let index = (self.bank * 2 * WAVE_RAM_BANK_SIZE + self.position) % 64;
+        let byte = self.wave_ram[index / 2];
+        if index & 1 == 0 {
+            byte >> 4
+        } else {
+            byte & 0xf
+        }
+    }
+
+    /// Returns the current amplitude in the range of -15..=15
+    pub fn output(&self) -> i16 {
+        if !self.enabled {
+            return 0;
+        }
+        let sample = 2 * self.current_sample() as i16 - 15;
+        if self.force_75 {
+            return sample * 3 / 4;
+        }
+        match self.volume {
+            0 => 0,
+            1 => sample,
+            2 => sample / 2,
+            3 => sample / 4,
+            _ => unreachable!(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct NoiseChannel {
+    enabled: bool,
+    length: LengthCounter,
+    envelope: Envelope,
+    ratio: u16,
+    width7: bool,
+    shift_freq: u16,
+
+    timer: usize,
+    lfsr: u16,
+    output_high: bool,
+}
+
+impl NoiseChannel {
+    pub fn new() -> NoiseChannel {
+        NoiseChannel {
+            enabled: false,
+            length: LengthCounter::new(64),
+            envelope: Envelope::default(),
+            ratio: 0,
+            width7: false,
+            shift_freq: 0,
+            timer: 32,
+            lfsr: 0x4000,
+            output_high: false,
+        }
+    }
+
+    #[inline]
+    fn period(&self) -> usize {
+        let r = if self.ratio == 0 {
+            16
+        } else {
+            32 * self.ratio as usize
+        };
+        r << (self.shift_freq + 1)
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// NR41/NR42
+    pub fn write_len_env(&mut self, value: u16) {
+        self.length.load(value.bit_range(0..6) as usize);
+        self.envelope.write(value);
+    }
+
+    pub fn read_len_env(&self) -> u16 {
+        self.envelope.read()
+    }
+
+    /// NR43/NR44
+    pub fn write_freq_ctrl(&mut self, value: u16) {
+        self.ratio = value.bit_range(0..3);
+        self.width7 = value.bit(3);
+        self.shift_freq = value.bit_range(4..8);
+        self.length.enabled = value.bit(14);
+        if value.bit(15) {
+            self.restart();
+        }
+    }
+
+    pub fn read_freq_ctrl(&self) -> u16 {
+        self.ratio
+            | (self.width7 as u16) << 3
+            | self.shift_freq << 4
+            | (self.length.enabled as u16) << 14
+    }
+
+    fn restart(&mut self) {
+        self.enabled = true;
+        self.length.restart();
+        self.envelope.restart();
+        self.lfsr = if self.width7 { 0x40 } else { 0x4000 };
+        self.timer = self.period();
+    }
+
+    fn clock_lfsr(&mut self) {
+        let carry = self.lfsr & 1 != 0;
+        self.lfsr >>= 1;
+        if carry {
+            self.lfsr ^= if self.width7 { 0x60 } else { 0x6000 };
+        }
+        self.output_high = carry;
+    }
+
+    pub fn step(&mut self, cycles: usize) {
+        // shift clock frequencies 14 and 15 are prohibited, the lfsr is not clocked at all
+        if self.shift_freq >= 14 {
+            return;
+        }
+        let mut cycles = cycles;
+        while cycles >= self.timer {
+            cycles -= self.timer;
+            self.timer = self.period();
+            self.clock_lfsr();
+        }
+        self.timer -= cycles;
+    }
+
+    pub fn clock_length(&mut self) {
+        if self.length.clock() {
+            self.enabled = false;
+        }
+    }
+
+    pub fn clock_envelope(&mut self) {
+        self.envelope.clock();
+    }
+
+    /// Returns the current amplitude in the range of -15..=15
+    pub fn output(&self) -> i16 {
+        if !self.enabled {
+            return 0;
+        }
+        let volume = self.envelope.volume as i16;
+        if self.output_high {
+            volume
+        } else {
+            -volume
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_square_length_expires() {
+        let mut sqr = SquareChannel::new();
+        sqr.write_duty_len_env(62 | 0xf000);
+        sqr.write_freq_ctrl(bit_set(15) | bit_set(14));
+        assert!(sqr.is_enabled());
+        sqr.clock_length();
+        assert!(sqr.is_enabled());
+        sqr.clock_length();
+        assert!(!sqr.is_enabled());
+    }
+
+    #[test]
+    fn test_square_sweep_overflow_disables() {
+        let mut sqr = SquareChannel::new();
+        sqr.write_sweep(1 << 4 | 1);
+        sqr.write_duty_len_env(0xf000);
+        sqr.write_freq_ctrl(bit_set(15) | 2000);
+        sqr.clock_sweep();
+        assert!(!sqr.is_enabled());
+    }
+
+    #[test]
+    fn test_zero_periods_count_as_8() {
+        let mut sqr = SquareChannel::new();
+        sqr.write_sweep(0);
+        sqr.write_duty_len_env(0xf000);
+        sqr.write_freq_ctrl(bit_set(15) | 2000);
+        // new periods without a restart, the timers keep counting from 8
+        sqr.write_sweep(1 << 4 | 1);
+        sqr.write_duty_len_env(0xf000 | 1 << 8);
+        for _ in 0..7 {
+            sqr.clock_envelope();
+            sqr.clock_sweep();
+        }
+        assert_eq!(sqr.envelope.volume, 15);
+        assert!(sqr.is_enabled());
+        sqr.clock_envelope();
+        sqr.clock_sweep();
+        assert_eq!(sqr.envelope.volume, 14);
+        assert!(!sqr.is_enabled());
+        sqr.clock_envelope();
+        assert_eq!(sqr.envelope.volume, 13);
+    }
+
+    #[test]
+    fn test_wave_ram_banks() {
+        let mut wave = WaveChannel::new();
+        // playing bank 0, so the cpu accesses bank 1
+        wave.write_stop_bank(bit_set(7));
+        wave.write_wave_ram(0, 0xab);
+        assert_eq!(wave.read_wave_ram(0), 0xab);
+        // now playing bank 1
+        wave.write_stop_bank(bit_set(7) | bit_set(6));
+        assert_eq!(wave.read_wave_ram(0), 0);
+        wave.write_len_vol(1 << 13);
+        wave.write_freq_ctrl(bit_set(15));
+        assert_eq!(wave.current_sample(), 0xa);
+    }
+
+    #[test]
+    fn test_noise_lfsr_7bit_period() {
+        let mut noise = NoiseChannel::new();
+        noise.write_freq_ctrl(bit_set(15) | bit_set(3));
+        let initial = noise.lfsr;
+        for _ in 0..127 {
+            noise.clock_lfsr();
+        }
+        assert_eq!(noise.lfsr, initial);
+    }
+
+    fn bit_set(idx: u16) -> u16 {
+        1 << idx
+    }
+}
@@ -1,3 +1,4 @@
+use std::cell::{Cell, RefCell};
 use std::fmt;
 use std::ops::{Deref, DerefMut};
 
@@ -6,8 +7,12 @@ use serde::{Deserialize, Serialize};
 use super::cartridge::Cartridge;
 use super::gpu::VIDEO_RAM_SIZE;
 use super::iodev::{IoDevices, WaitControl};
+use super::prefetch::GamepakPrefetch;
 use super::{Addr, Bus};
 
+#[cfg(feature = "block_cache")]
+use bit_set::BitSet;
+
 pub mod consts {
     pub const WORK_RAM_SIZE: usize = 256 * 1024;
     pub const INTERNAL_RAM_SIZE: usize = 32 * 1024;
@@ -44,6 +49,57 @@ pub mod consts {
 
 use consts::*;
 
+pub fn is_gamepak_addr(addr: Addr) -> bool {
+    addr >= GAMEPAK_WS0_LO && addr < SRAM_LO
+}
+
+/// Work ram is split into pages of 256 bytes to track writes to code
+#[cfg(feature = "block_cache")]
+const CODE_PAGE_SHIFT: usize = 8;
+
+/// Returns the page of work ram that `addr` belongs to, mirrors map to the same page
+#[cfg(feature = "block_cache")]
+pub fn code_page(addr: Addr) -> Option<usize> {
+    match addr & 0xff000000 {
+        EWRAM_ADDR => Some((addr & 0x3_ffff) as usize >> CODE_PAGE_SHIFT),
+        IWRAM_ADDR => Some((WORK_RAM_SIZE + (addr & 0x7fff) as usize) >> CODE_PAGE_SHIFT),
+        _ => None,
+    }
+}
+
+/// The pages of work ram that hold cached code, and which of them were written to since
+#[cfg(feature = "block_cache")]
+#[derive(Clone, Debug, Default)]
+pub struct CodePages {
+    watched: BitSet,
+    written: Vec<usize>,
+}
+
+#[cfg(feature = "block_cache")]
+impl CodePages {
+    pub fn watch(&mut self, page: usize) {
+        self.watched.insert(page);
+    }
+
+    #[inline]
+    fn on_write(&mut self, addr: Addr) {
+        if let Some(page) = code_page(addr) {
+            if self.watched.remove(page) {
+                self.written.push(page);
+            }
+        }
+    }
+
+    #[inline]
+    pub fn any_written(&self) -> bool {
+        !self.written.is_empty()
+    }
+
+    pub fn take_written(&mut self) -> Vec<usize> {
+        std::mem::take(&mut self.written)
+    }
+}
+
 #[derive(Debug, Copy, Clone)]
 pub enum MemoryAccessType {
     NonSeq,
@@ -70,7 +126,131 @@ pub enum MemoryAccessWidth {
     MemoryAccess32,
 }
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
+/// A write that went through the bus, see `SysBus::trace_writes`
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct MemoryWrite {
+    pub addr: Addr,
+    pub value: u32,
+    pub width: MemoryAccessWidth,
+}
+
+impl MemoryAccessWidth {
+    pub fn size(&self) -> u32 {
+        match self {
+            MemoryAccessWidth::MemoryAccess8 => 1,
+            MemoryAccessWidth::MemoryAccess16 => 2,
+            MemoryAccessWidth::MemoryAccess32 => 4,
+        }
+    }
+}
+
+/// Which accesses a watch catches
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum WatchKind {
+    Read,
+    Write,
+    ReadWrite,
+}
+
+impl WatchKind {
+    fn catches(&self, is_write: bool) -> bool {
+        match self {
+            WatchKind::Read => !is_write,
+            WatchKind::Write => is_write,
+            WatchKind::ReadWrite => true,
+        }
+    }
+}
+
+/// Watches the accesses to `len` bytes at `addr`, see `SysBus::add_watch`
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Watchpoint {
+    pub addr: Addr,
+    pub len: u32,
+    pub kind: WatchKind,
+    /// When set, only accesses of this value are caught
+    pub value: Option<u32>,
+}
+
+impl Watchpoint {
+    pub fn new(addr: Addr, len: u32, kind: WatchKind) -> Watchpoint {
+        Watchpoint {
+            addr: addr,
+            len: len,
+            kind: kind,
+            value: None,
+        }
+    }
+
+    fn catches(&self, addr: Addr, value: u32, width: MemoryAccessWidth, is_write: bool) -> bool {
+        let start = addr as u64;
+        let watch_start = self.addr as u64;
+        self.kind.catches(is_write)
+            && start < watch_start + self.len as u64
+            && watch_start < start + width.size() as u64
+            && self.value.map_or(true, |expected| expected == value)
+    }
+}
+
+/// An access that touched a watched range, see `SysBus::add_watch`
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct WatchHit {
+    pub addr: Addr,
+    pub value: u32,
+    pub width: MemoryAccessWidth,
+    pub is_write: bool,
+}
+
+impl fmt::Display for WatchHit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let digits = 2 * self.width.size() as usize;
+        write!(
+            f,
+            "{} of {:#0width$x} at {:#010x}",
+            if self.is_write { "write" } else { "read" },
+            self.value,
+            self.addr,
+            width = digits + 2
+        )
+    }
+}
+
+/// Hits past this are dropped until they are taken, in case nobody takes them. The first ones are
+/// kept, and the dropped ones counted, see `SysBus::take_dropped_watch_hits`.
+pub const MAX_WATCH_HITS: usize = 0x1000;
+
+/// Reads only borrow the bus, so hits are collected in a `RefCell`
+#[derive(Debug, Clone, Default)]
+struct MemoryWatches {
+    watches: Vec<Watchpoint>,
+    hits: RefCell<Vec<WatchHit>>,
+    dropped: Cell<usize>,
+}
+
+impl MemoryWatches {
+    fn check(&self, addr: Addr, value: u32, width: MemoryAccessWidth, is_write: bool) {
+        let hit = self
+            .watches
+            .iter()
+            .any(|w| w.catches(addr, value, width, is_write));
+        if !hit {
+            return;
+        }
+        let mut hits = self.hits.borrow_mut();
+        if hits.len() < MAX_WATCH_HITS {
+            hits.push(WatchHit {
+                addr: addr,
+                value: value,
+                width: width,
+                is_write: is_write,
+            });
+        } else {
+            self.dropped.set(self.dropped.get() + 1);
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
 #[repr(transparent)]
 pub struct BoxedMemory {
     pub mem: Box<[u8]>,
@@ -105,6 +285,23 @@ impl Bus for DummyBus {
     fn write_8(&mut self, _addr: Addr, _value: u8) {}
 }
 
+/// Holds a word that was left on the bus, reads of any width pick their bytes out of it
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+struct OpenBus(u32);
+
+impl Bus for OpenBus {
+    fn read_8(&self, addr: Addr) -> u8 {
+        (self.0 >> ((addr & 3) * 8)) as u8
+    }
+
+    fn write_8(&mut self, _addr: Addr, _value: u8) {}
+}
+
+/// The opcode the bios fetches last before jumping to the rom after boot
+pub(crate) const BIOS_OPEN_BUS_AFTER_BOOT: u32 = 0xe129_f000;
+/// The opcode the bios fetches last before returning from a swi, see GBATEK "Unpredictable Things"
+pub(crate) const BIOS_OPEN_BUS_AFTER_SWI: u32 = 0xe3a0_2004;
+
 const CYCLE_LUT_SIZE: usize = 0x10;
 
 #[derive(Serialize, Deserialize, Clone)]
@@ -203,15 +400,32 @@ impl CycleLookupTables {
 pub struct SysBus {
     pub io: IoDevices,
 
+    /// The bios and the rom are left out of save states
+    #[serde(skip)]
     bios: BoxedMemory,
     onboard_work_ram: BoxedMemory,
     internal_work_ram: BoxedMemory,
     pub cartridge: Cartridge,
     dummy: DummyBus,
 
+    /// What reads from unmapped memory return, fed by the cpu prefetch
+    open_bus: OpenBus,
+    /// The bios can only be read while executing from it, other reads see its last fetched opcode
+    bios_open_bus: OpenBus,
+    last_fetch_addr: Addr,
+
     cycle_luts: CycleLookupTables,
+    pub prefetch: GamepakPrefetch,
+
+    #[cfg(feature = "block_cache")]
+    #[serde(skip)]
+    pub code_pages: CodePages,
 
     pub trace_access: bool,
+    #[serde(skip)]
+    write_log: Option<Vec<MemoryWrite>>,
+    #[serde(skip)]
+    watches: MemoryWatches,
 }
 
 #[repr(transparent)]
@@ -252,7 +466,9 @@ macro_rules! memory_map {
         match $addr & 0xff000000 {
             BIOS_ADDR => {
                 if $addr >= 0x4000 {
-                    0
+                    $sb.open_bus.$read_fn($addr)
+                } else if $sb.last_fetch_addr >= 0x4000 {
+                    $sb.bios_open_bus.$read_fn($addr)
                 } else {
                     $sb.bios.$read_fn($addr)
                 }
@@ -283,16 +499,21 @@ macro_rules! memory_map {
             SRAM_LO | SRAM_HI => $sb.cartridge.$read_fn($addr),
             _ => {
                 // warn!("trying to read invalid address {:#x}", $addr);
-                // TODO open bus
-                0
+                $sb.open_bus.$read_fn($addr)
             }
         }
     };
     (write($sb:ident, $write_fn:ident, $addr:expr, $value:expr)) => {
         match $addr & 0xff000000 {
             BIOS_ADDR => {}
-            EWRAM_ADDR => $sb.onboard_work_ram.$write_fn($addr & 0x3_ffff, $value),
-            IWRAM_ADDR => $sb.internal_work_ram.$write_fn($addr & 0x7fff, $value),
+            EWRAM_ADDR => {
+                $sb.on_work_ram_write($addr);
+                $sb.onboard_work_ram.$write_fn($addr & 0x3_ffff, $value)
+            }
+            IWRAM_ADDR => {
+                $sb.on_work_ram_write($addr);
+                $sb.internal_work_ram.$write_fn($addr & 0x7fff, $value)
+            }
             IOMEM_ADDR => {
                 let addr = if $addr & 0xffff == 0x8000 {
                     0x800
@@ -310,7 +531,8 @@ macro_rules! memory_map {
                 $sb.io.gpu.vram.$write_fn(ofs, $value)
             }
             OAM_ADDR => $sb.io.gpu.oam.$write_fn($addr & 0x3ff, $value),
-            GAMEPAK_WS0_LO | GAMEPAK_WS0_HI => {}
+            GAMEPAK_WS0_LO => $sb.cartridge.$write_fn($addr, $value),
+            GAMEPAK_WS0_HI => {}
             GAMEPAK_WS2_HI => $sb.cartridge.$write_fn($addr, $value),
             SRAM_LO | SRAM_HI => $sb.cartridge.$write_fn($addr, $value),
             _ => {
@@ -336,9 +558,19 @@ impl SysBus {
             cartridge: cartridge,
             dummy: DummyBus([0; 4]),
 
+            open_bus: OpenBus::default(),
+            bios_open_bus: OpenBus(BIOS_OPEN_BUS_AFTER_BOOT),
+            last_fetch_addr: 0,
+
             cycle_luts: luts,
+            prefetch: GamepakPrefetch::new(),
+
+            #[cfg(feature = "block_cache")]
+            code_pages: CodePages::default(),
 
             trace_access: false,
+            write_log: None,
+            watches: MemoryWatches::default(),
         }
     }
 
@@ -349,8 +581,202 @@ impl SysBus {
         self.io.set_sysbus_ptr(ptr.clone());
     }
 
+    /// Attaches the bios and the rom to a sysbus restored from a save state
+    pub(crate) fn attach_rom_and_bios(&mut self, bios: Box<[u8]>, rom: Box<[u8]>) {
+        self.bios = BoxedMemory::new(bios);
+        self.cartridge.set_rom_bytes(rom);
+    }
+
+    /// Moves the bios and the rom over from the sysbus of the running game
+    pub(crate) fn take_rom_and_bios(&mut self, other: &mut SysBus) {
+        let bios = std::mem::take(&mut other.bios.mem);
+        let rom = other.cartridge.take_rom_bytes();
+        self.attach_rom_and_bios(bios, rom);
+    }
+
+    /// The on-board and the internal work ram
+    pub fn get_work_ram(&self) -> (&[u8], &[u8]) {
+        (&self.onboard_work_ram.mem, &self.internal_work_ram.mem)
+    }
+
+    /// Opcode fetches go through here, so that the bus knows whether the cpu executes from the bios.
+    /// Fetches don't trigger read watches.
+    #[inline]
+    pub(crate) fn fetch_32(&mut self, addr: Addr) -> u32 {
+        self.last_fetch_addr = addr;
+        memory_map!(read(self, read_32, addr & !3))
+    }
+
+    #[inline]
+    pub(crate) fn fetch_16(&mut self, addr: Addr) -> u16 {
+        self.last_fetch_addr = addr;
+        memory_map!(read(self, read_16, addr & !1))
+    }
+
+    /// Reads memory without triggering watches, for debuggers and frontends
+    pub fn debug_read_8(&self, addr: Addr) -> u8 {
+        memory_map!(read(self, read_8, addr))
+    }
+
+    /// Reads memory without triggering watches, for debuggers and frontends
+    pub fn debug_read_16(&self, addr: Addr) -> u16 {
+        let addr = addr & !1;
+        // reading the eeprom clocks it
+        if addr & 0xff000000 == GAMEPAK_WS2_HI {
+            return self.cartridge.debug_read_16(addr);
+        }
+        memory_map!(read(self, read_16, addr))
+    }
+
+    /// Reads memory without triggering watches, for debuggers and frontends
+    pub fn debug_read_32(&self, addr: Addr) -> u32 {
+        let addr = addr & !3;
+        if addr & 0xff000000 == GAMEPAK_WS2_HI {
+            return self.cartridge.debug_read_32(addr);
+        }
+        memory_map!(read(self, read_32, addr))
+    }
+
+    #[inline]
+    pub(crate) fn open_bus_value(&self) -> u32 {
+        self.open_bus.0
+    }
+
+    /// Called by the cpu after every fetch with the word its prefetch left on the bus
+    #[inline]
+    pub(crate) fn set_open_bus_value(&mut self, value: u32) {
+        self.open_bus.0 = value;
+        if self.last_fetch_addr < 0x4000 {
+            self.bios_open_bus.0 = value;
+        }
+    }
+
+    /// Used by the bios HLE to leave the same value on the bus the real bios would
+    pub(crate) fn set_bios_open_bus_value(&mut self, value: u32) {
+        self.bios_open_bus.0 = value;
+    }
+
+    /// Lets the block cache know about writes to the code it holds
+    #[inline(always)]
+    fn on_work_ram_write(&mut self, _addr: Addr) {
+        #[cfg(feature = "block_cache")]
+        self.code_pages.on_write(_addr);
+    }
+
+    /// Records every write from now on, e.g to compare two emulators in lockstep
+    pub fn trace_writes(&mut self, enable: bool) {
+        self.write_log = if enable { Some(Vec::new()) } else { None };
+    }
+
+    /// Returns the writes recorded since the last call
+    pub fn take_writes(&mut self) -> Vec<MemoryWrite> {
+        self.write_log
+            .as_mut()
+            .map(std::mem::take)
+            .unwrap_or_default()
+    }
+
+    #[inline(always)]
+    fn log_write(&mut self, addr: Addr, value: u32, width: MemoryAccessWidth) {
+        if let Some(log) = &mut self.write_log {
+            log.push(MemoryWrite { addr, value, width });
+        }
+    }
+
+    /// Records the cpu and dma accesses the watchpoint catches from now on, until it is removed.
+    /// Opcode fetches are not recorded.
+    pub fn add_watch(&mut self, watchpoint: Watchpoint) {
+        self.watches.watches.push(watchpoint);
+    }
+
+    /// Returns false if there was no such watchpoint
+    pub fn remove_watch(&mut self, watchpoint: &Watchpoint) -> bool {
+        match self.watches.watches.iter().position(|w| w == watchpoint) {
+            Some(index) => {
+                self.watches.watches.remove(index);
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn get_watches(&self) -> &[Watchpoint] {
+        &self.watches.watches
+    }
+
+    /// Moves the watchpoints over from the sysbus of the running game
+    pub(crate) fn take_watches(&mut self, other: &mut SysBus) {
+        self.watches.watches = std::mem::take(&mut other.watches.watches);
+    }
+
+    pub fn clear_watches(&mut self) {
+        self.watches.watches.clear();
+        self.watches.hits.get_mut().clear();
+        self.watches.dropped.set(0);
+    }
+
+    #[inline]
+    pub fn has_watch_hits(&self) -> bool {
+        !self.watches.hits.borrow().is_empty()
+    }
+
+    /// Returns the watched accesses made since the last call
+    pub fn take_watch_hits(&mut self) -> Vec<WatchHit> {
+        std::mem::take(self.watches.hits.get_mut())
+    }
+
+    /// How many hits were recorded and not taken yet
+    pub fn watch_hit_count(&self) -> usize {
+        self.watches.hits.borrow().len()
+    }
+
+    /// Returns the number of hits dropped since the last call because `MAX_WATCH_HITS` were
+    /// waiting to be taken
+    pub fn take_dropped_watch_hits(&mut self) -> usize {
+        self.watches.dropped.replace(0)
+    }
+
+    /// Returns the hits recorded after the first `start`, for a `watchpoint` that is only added
+    /// for a moment. The hits other watchpoints catch as well are left for their owners.
+    pub fn take_watch_hits_after(
+        &mut self,
+        start: usize,
+        watchpoint: &Watchpoint,
+    ) -> Vec<WatchHit> {
+        let watches = &self.watches.watches;
+        let hits = self.watches.hits.get_mut();
+        let taken = hits.split_off(start.min(hits.len()));
+        hits.extend(taken.iter().filter(|hit| {
+            watches
+                .iter()
+                .any(|w| w != watchpoint && w.catches(hit.addr, hit.value, hit.width, hit.is_write))
+        }));
+        taken
+    }
+
+    /// Like `take_watch_hits`, but only takes the accesses `watchpoints` catch. The rest are left
+    /// for whoever added the other watchpoints.
+    pub fn take_watch_hits_of(&mut self, watchpoints: &[Watchpoint]) -> Vec<WatchHit> {
+        let hits = self.watches.hits.get_mut();
+        let (taken, left) = std::mem::take(hits).into_iter().partition(|hit| {
+            watchpoints
+                .iter()
+                .any(|w| w.catches(hit.addr, hit.value, hit.width, hit.is_write))
+        });
+        *hits = left;
+        taken
+    }
+
+    #[inline(always)]
+    fn check_watches(&self, addr: Addr, value: u32, width: MemoryAccessWidth, is_write: bool) {
+        if !self.watches.watches.is_empty() {
+            self.watches.check(addr, value, width, is_write);
+        }
+    }
+
     pub fn on_waitcnt_written(&mut self, waitcnt: WaitControl) {
         self.cycle_luts.update_gamepak_waitstates(waitcnt);
+        self.prefetch.set_enabled(waitcnt.prefetch());
     }
 
     #[inline(always)]
@@ -380,30 +806,85 @@ impl SysBus {
             },
         }
     }
+
+    /// Like `get_cycles`, for a data access of the cpu.
+    /// Gamepak data accesses take the bus away from the prefetch buffer.
+    #[inline(always)]
+    pub fn get_cpu_cycles(
+        &mut self,
+        addr: Addr,
+        access: MemoryAccessType,
+        width: MemoryAccessWidth,
+    ) -> usize {
+        if self.prefetch.is_active() && is_gamepak_addr(addr) {
+            self.prefetch.flush();
+        }
+        self.get_cycles(addr, access, width)
+    }
+
+    /// Like `get_cycles`, for an opcode fetch that starts `elapsed` cycles into the current cpu
+    /// step. Gamepak fetches go through the prefetch buffer.
+    #[inline(always)]
+    pub fn get_fetch_cycles(
+        &mut self,
+        elapsed: usize,
+        addr: Addr,
+        access: MemoryAccessType,
+        width: MemoryAccessWidth,
+    ) -> usize {
+        let cycles = self.get_cycles(addr, access, width);
+        if self.prefetch.is_active() && is_gamepak_addr(addr) {
+            let now = self.io.scheduler.timestamp() + elapsed as u64;
+            let s_cycles16 = self.cycle_luts.s_cycles16[(addr >> 24) as usize];
+            self.prefetch.access(now, addr, width, cycles, s_cycles16)
+        } else {
+            cycles
+        }
+    }
 }
 
 impl Bus for SysBus {
     fn read_32(&self, addr: Addr) -> u32 {
-        memory_map!(read(self, read_32, addr & !3))
+        let addr = addr & !3;
+        let value = memory_map!(read(self, read_32, addr));
+        self.check_watches(addr, value, MemoryAccessWidth::MemoryAccess32, false);
+        value
     }
 
     fn read_16(&self, addr: Addr) -> u16 {
-        memory_map!(read(self, read_16, addr & !1))
+        let addr = addr & !1;
+        let value = memory_map!(read(self, read_16, addr));
+        self.check_watches(addr, value as u32, MemoryAccessWidth::MemoryAccess16, false);
+        value
     }
 
     fn read_8(&self, addr: Addr) -> u8 {
-        memory_map!(read(self, read_8, addr))
+        let value = memory_map!(read(self, read_8, addr));
+        self.check_watches(addr, value as u32, MemoryAccessWidth::MemoryAccess8, false);
+        value
     }
 
     fn write_32(&mut self, addr: Addr, value: u32) {
-        memory_map!(write(self, write_32, addr & !3, value));
+        let addr = addr & !3;
+        self.log_write(addr, value, MemoryAccessWidth::MemoryAccess32);
+        self.check_watches(addr, value, MemoryAccessWidth::MemoryAccess32, true);
+        memory_map!(write(self, write_32, addr, value));
     }
 
     fn write_16(&mut self, addr: Addr, value: u16) {
-        memory_map!(write(self, write_16, addr & !1, value));
+        let addr = addr & !1;
+        self.log_write(addr, value as u32, MemoryAccessWidth::MemoryAccess16);
+        self.check_watches(addr, value as u32, MemoryAccessWidth::MemoryAccess16, true);
+        memory_map!(write(self, write_16, addr, value));
     }
 
     fn write_8(&mut self, addr: Addr, value: u8) {
+        self.log_write(addr, value as u32, MemoryAccessWidth::MemoryAccess8);
+        self.check_watches(addr, value as u32, MemoryAccessWidth::MemoryAccess8, true);
         memory_map!(write(self, write_8, addr, value));
     }
+
+    fn get_bytes(&self, range: std::ops::Range<u32>) -> Vec<u8> {
+        range.map(|addr| self.debug_read_8(addr)).collect()
+    }
 }
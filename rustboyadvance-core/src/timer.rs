@@ -1,6 +1,6 @@
 use super::interrupt::{Interrupt, IrqBitmask};
 use super::iodev::consts::*;
-use super::sysbus::SysBus;
+use super::sched::{EventType, Scheduler};
 
 use num::FromPrimitive;
 use serde::{Deserialize, Serialize};
@@ -11,13 +11,15 @@ const SHIFT_LUT: [usize; 4] = [0, 6, 8, 10];
 pub struct Timer {
     // registers
     pub ctl: TimerCtl,
+    /// The counter as of `start_time`, see `read_counter` for the current value
     pub data: u16,
     pub initial_data: u16,
 
     irq: Interrupt,
     timer_id: usize,
-    cycles: usize,
     prescalar_shift: usize,
+    /// Scheduler timestamp from which the counter runs on from `data`
+    start_time: u64,
 }
 
 impl Timer {
@@ -31,48 +33,77 @@ impl Timer {
             data: 0,
             ctl: TimerCtl(0),
             initial_data: 0,
-            cycles: 0,
             prescalar_shift: 0,
+            start_time: 0,
         }
     }
 
+    /// Counting the overflows of the previous timer, timer 0 has none and ignores the cascade bit
+    #[inline]
+    fn is_cascading(&self) -> bool {
+        self.timer_id != 0 && self.ctl.cascade()
+    }
+
+    /// Counting by itself, as opposed to being stopped or counting the overflows of the previous timer
+    #[inline]
+    fn is_counting(&self) -> bool {
+        self.ctl.enabled() && !self.is_cascading()
+    }
+
     #[inline]
     fn ticks_to_overflow(&self) -> u32 {
         0x1_0000 - (self.data as u32)
     }
 
-    /// increments the timer with an amount of ticks
-    /// returns the number of times it overflowed
-    fn update(&mut self, ticks: usize, irqs: &mut IrqBitmask) -> usize {
-        let mut ticks = ticks as u32;
-        let mut num_overflows = 0;
+    fn elapsed_ticks(&self, now: u64) -> u64 {
+        if self.is_counting() {
+            (now - self.start_time) >> self.prescalar_shift
+        } else {
+            0
+        }
+    }
 
-        let ticks_remaining = self.ticks_to_overflow();
+    pub fn read_counter(&self, now: u64) -> u16 {
+        // the overflow event is handled before the counter could wrap
+        let ticks = self
+            .elapsed_ticks(now)
+            .min(self.ticks_to_overflow() as u64 - 1);
+        self.data + ticks as u16
+    }
 
-        if ticks >= ticks_remaining {
-            num_overflows += 1;
-            ticks -= ticks_remaining;
-            self.data = self.initial_data;
+    /// Moves the counter up to `now`, keeping the cycles that didn't make a whole tick yet
+    fn sync(&mut self, now: u64) {
+        let ticks = self.elapsed_ticks(now);
+        self.data = self.read_counter(now);
+        self.start_time += ticks << self.prescalar_shift;
+    }
 
-            let ticks_remaining = self.ticks_to_overflow();
-            num_overflows += ticks / ticks_remaining;
-            ticks = ticks % ticks_remaining;
+    fn overflow_time(&self) -> u64 {
+        self.start_time + ((self.ticks_to_overflow() as u64) << self.prescalar_shift)
+    }
 
-            if self.ctl.irq_enabled() {
-                irqs.add_irq(self.irq);
-            }
+    fn reload(&mut self, irqs: &mut IrqBitmask) {
+        self.data = self.initial_data;
+        if self.ctl.irq_enabled() {
+            irqs.add_irq(self.irq);
         }
+    }
 
-        self.data += ticks as u16;
-
-        num_overflows as usize
+    /// Counts one overflow of the previous timer, returns true if this one overflowed as well
+    fn cascade_tick(&mut self, irqs: &mut IrqBitmask) -> bool {
+        if self.data == 0xffff {
+            self.reload(irqs);
+            true
+        } else {
+            self.data += 1;
+            false
+        }
     }
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct Timers {
     timers: [Timer; 4],
-    running_timers: u8,
     pub trace: bool,
 }
 
@@ -93,24 +124,30 @@ impl Timers {
     pub fn new() -> Timers {
         Timers {
             timers: [Timer::new(0), Timer::new(1), Timer::new(2), Timer::new(3)],
-            running_timers: 0,
             trace: false,
         }
     }
 
-    pub fn write_timer_ctl(&mut self, id: usize, value: u16) {
+    pub fn write_timer_ctl(&mut self, id: usize, value: u16, scheduler: &mut Scheduler) {
+        let now = scheduler.timestamp();
         let new_ctl = TimerCtl(value);
         let old_enabled = self[id].ctl.enabled();
         let new_enabled = new_ctl.enabled();
-        let cascade = new_ctl.cascade();
-        self[id].cycles = 0;
-        self[id].prescalar_shift = SHIFT_LUT[new_ctl.prescalar() as usize];
-        self[id].ctl = new_ctl;
-        if new_enabled && !cascade {
-            self.running_timers |= 1 << id;
-        } else {
-            self.running_timers &= !(1 << id);
+
+        let timer = &mut self.timers[id];
+        timer.sync(now);
+        timer.prescalar_shift = SHIFT_LUT[new_ctl.prescalar() as usize];
+        timer.ctl = new_ctl;
+        timer.start_time = now;
+        if new_enabled && !old_enabled {
+            timer.data = timer.initial_data;
         }
+
+        scheduler.cancel(EventType::TimerOverflow(id));
+        if timer.is_counting() {
+            scheduler.schedule_at(EventType::TimerOverflow(id), timer.overflow_time());
+        }
+
         if old_enabled != new_enabled {
             trace!(
                 "TMR{} {}",
@@ -120,79 +157,62 @@ impl Timers {
         }
     }
 
-    pub fn handle_read(&self, io_addr: u32) -> u16 {
+    /// Handles the `TimerOverflow` event of timer `id`, that was due at `time`.
+    /// Returns a bitmask of the timers that overflowed, including the cascading ones.
+    pub fn handle_overflow_event(
+        &mut self,
+        id: usize,
+        time: u64,
+        scheduler: &mut Scheduler,
+        irqs: &mut IrqBitmask,
+    ) -> u8 {
+        let timer = &mut self.timers[id];
+        timer.reload(irqs);
+        timer.start_time = time;
+        scheduler.schedule_at(EventType::TimerOverflow(id), timer.overflow_time());
+
+        let mut overflows = 1 << id;
+        for next in id + 1..4 {
+            let next_timer = &mut self.timers[next];
+            if !(next_timer.ctl.enabled() && next_timer.is_cascading()) {
+                break;
+            }
+            if !next_timer.cascade_tick(irqs) {
+                break;
+            }
+            overflows |= 1 << next;
+        }
+        overflows
+    }
+
+    pub fn handle_read(&self, io_addr: u32, now: u64) -> u16 {
         match io_addr {
-            REG_TM0CNT_L => self.timers[0].data,
+            REG_TM0CNT_L => self.timers[0].read_counter(now),
             REG_TM0CNT_H => self.timers[0].ctl.0,
-            REG_TM1CNT_L => self.timers[1].data,
+            REG_TM1CNT_L => self.timers[1].read_counter(now),
             REG_TM1CNT_H => self.timers[1].ctl.0,
-            REG_TM2CNT_L => self.timers[2].data,
+            REG_TM2CNT_L => self.timers[2].read_counter(now),
             REG_TM2CNT_H => self.timers[2].ctl.0,
-            REG_TM3CNT_L => self.timers[3].data,
+            REG_TM3CNT_L => self.timers[3].read_counter(now),
             REG_TM3CNT_H => self.timers[3].ctl.0,
             _ => unreachable!(),
         }
     }
 
-    pub fn handle_write(&mut self, io_addr: u32, value: u16) {
+    /// Writing the counter only sets the reload value, which is loaded on start and on overflow
+    pub fn handle_write(&mut self, io_addr: u32, value: u16, scheduler: &mut Scheduler) {
         match io_addr {
-            REG_TM0CNT_L => {
-                self.timers[0].data = value;
-                self.timers[0].initial_data = value;
-            }
-            REG_TM0CNT_H => self.write_timer_ctl(0, value),
-
-            REG_TM1CNT_L => {
-                self.timers[1].data = value;
-                self.timers[1].initial_data = value;
-            }
-            REG_TM1CNT_H => self.write_timer_ctl(1, value),
-
-            REG_TM2CNT_L => {
-                self.timers[2].data = value;
-                self.timers[2].initial_data = value;
-            }
-            REG_TM2CNT_H => self.write_timer_ctl(2, value),
-
-            REG_TM3CNT_L => {
-                self.timers[3].data = value;
-                self.timers[3].initial_data = value;
-            }
-            REG_TM3CNT_H => self.write_timer_ctl(3, value),
+            REG_TM0CNT_L => self.timers[0].initial_data = value,
+            REG_TM0CNT_H => self.write_timer_ctl(0, value, scheduler),
+            REG_TM1CNT_L => self.timers[1].initial_data = value,
+            REG_TM1CNT_H => self.write_timer_ctl(1, value, scheduler),
+            REG_TM2CNT_L => self.timers[2].initial_data = value,
+            REG_TM2CNT_H => self.write_timer_ctl(2, value, scheduler),
+            REG_TM3CNT_L => self.timers[3].initial_data = value,
+            REG_TM3CNT_H => self.write_timer_ctl(3, value, scheduler),
             _ => unreachable!(),
         }
     }
-
-    pub fn update(&mut self, cycles: usize, sb: &mut SysBus, irqs: &mut IrqBitmask) {
-        for id in 0..4 {
-            if self.running_timers & (1 << id) == 0 {
-                continue;
-            }
-
-            if !self.timers[id].ctl.cascade() {
-                let timer = &mut self.timers[id];
-
-                let cycles = timer.cycles + cycles;
-                let inc = cycles >> timer.prescalar_shift;
-                let num_overflows = timer.update(inc, irqs);
-                timer.cycles = cycles & ((1 << timer.prescalar_shift) - 1);
-
-                if num_overflows > 0 {
-                    if id != 3 {
-                        let next_timer = &mut self.timers[id + 1];
-                        if next_timer.ctl.cascade() {
-                            next_timer.update(num_overflows, irqs);
-                        }
-                    }
-                    if id == 0 || id == 1 {
-                        sb.io
-                            .sound
-                            .handle_timer_overflow(&mut sb.io.dmac, id, num_overflows);
-                    }
-                }
-            }
-        }
-    }
 }
 
 bitfield! {
@@ -205,3 +225,57 @@ bitfield! {
     irq_enabled, _ : 6;
     enabled, set_enabled : 7;
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_overflow_and_cascade() {
+        let mut scheduler = Scheduler::new();
+        let mut timers = Timers::new();
+        let mut irqs = IrqBitmask(0);
+
+        // timer 0 ticks every 64 cycles from 0xff00, timer 1 counts its overflows
+        timers.handle_write(REG_TM0CNT_L, 0xff00, &mut scheduler);
+        timers.handle_write(REG_TM0CNT_H, 1 << 7 | 1, &mut scheduler);
+        timers.handle_write(REG_TM1CNT_L, 0xffff, &mut scheduler);
+        timers.handle_write(REG_TM1CNT_H, 1 << 7 | 1 << 6 | 1 << 2, &mut scheduler);
+        assert_eq!(scheduler.next_event_time(), Some(64 * 0x100));
+
+        scheduler.update(64 * 10 + 5);
+        assert_eq!(
+            timers.handle_read(REG_TM0CNT_L, scheduler.timestamp()),
+            0xff0a
+        );
+
+        scheduler.update(64 * 0x100);
+        let event = scheduler.pop_pending_event().unwrap();
+        assert_eq!(event.typ, EventType::TimerOverflow(0));
+        let overflows = timers.handle_overflow_event(0, event.time, &mut scheduler, &mut irqs);
+        assert_eq!(overflows, 0b11);
+        assert!(irqs.Timer1_Overflow());
+        assert!(!irqs.Timer0_Overflow());
+        // the next overflow is relative to when this one was due, not to when it was handled
+        assert_eq!(scheduler.next_event_time(), Some(2 * 64 * 0x100));
+        assert_eq!(
+            timers.handle_read(REG_TM0CNT_L, scheduler.timestamp()),
+            0xff00 + 10
+        );
+    }
+
+    #[test]
+    fn test_timer0_ignores_cascade() {
+        let mut scheduler = Scheduler::new();
+        let mut timers = Timers::new();
+
+        timers.handle_write(REG_TM0CNT_L, 0xff00, &mut scheduler);
+        timers.handle_write(REG_TM0CNT_H, 1 << 7 | 1 << 2, &mut scheduler);
+        assert_eq!(scheduler.next_event_time(), Some(0x100));
+        scheduler.update(0x10);
+        assert_eq!(
+            timers.handle_read(REG_TM0CNT_L, scheduler.timestamp()),
+            0xff10
+        );
+    }
+}